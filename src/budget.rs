@@ -0,0 +1,256 @@
+use crate::llm::{ChatBackend, ChatParams, ChatReply};
+use crate::roadmaps::RoadmapError;
+use crate::storage::Storage;
+use chrono::{NaiveDate, Utc};
+use openai::chat::ChatCompletionMessage;
+use openai::Usage;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Estimated OpenAI spend so far on `day` (UTC).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct DailySpend {
+    pub(crate) day: NaiveDate,
+    pub(crate) spent_usd: f64,
+}
+
+/// Where the budget stands today, for the `/budget` endpoint.
+#[derive(Serialize, Debug)]
+pub(crate) struct BudgetReport {
+    pub(crate) day: NaiveDate,
+    pub(crate) spent_usd: f64,
+    pub(crate) cap_usd: Option<f64>,
+    pub(crate) remaining_usd: Option<f64>,
+}
+
+/// Tracks estimated spend per UTC day from completion usage and refuses calls once the
+/// daily cap is reached. Spend is saved to `storage` so restarts don't reset it.
+pub(crate) struct SpendBudget {
+    daily_cap_usd: Option<f64>,
+    prompt_price_per_million: f64,
+    completion_price_per_million: f64,
+    storage: Option<Storage>,
+    spend: Mutex<DailySpend>,
+}
+
+impl SpendBudget {
+    /// A `daily_cap_usd` of `None` never refuses a call, but spend is still tracked.
+    /// Today's saved total is only picked up by `restore`.
+    pub(crate) fn new(
+        daily_cap_usd: Option<f64>,
+        prompt_price_per_million: f64,
+        completion_price_per_million: f64,
+        storage: Option<Storage>,
+    ) -> Self {
+        SpendBudget {
+            daily_cap_usd,
+            prompt_price_per_million,
+            completion_price_per_million,
+            storage,
+            spend: Mutex::new(DailySpend {
+                day: Utc::now().date_naive(),
+                spent_usd: 0.0,
+            }),
+        }
+    }
+
+    /// Picks up today's total from `storage`, if it has one. What's saved already counts
+    /// anything spent since starting.
+    pub(crate) async fn restore(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        let today = Utc::now().date_naive();
+        match storage.spend(today).await {
+            Ok(Some(saved_usd)) => {
+                let mut spend = self.spend.lock().unwrap();
+                if spend.day == today {
+                    spend.spent_usd = spend.spent_usd.max(saved_usd);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring unreadable budget: {e:#}"),
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<(), RoadmapError> {
+        self.check_on(Utc::now().date_naive())
+    }
+
+    fn check_on(&self, today: NaiveDate) -> Result<(), RoadmapError> {
+        let Some(cap_usd) = self.daily_cap_usd else {
+            return Ok(());
+        };
+        let spent_usd = self.spent_on(today);
+        if spent_usd >= cap_usd {
+            Err(RoadmapError::BudgetExceeded { spent_usd, cap_usd })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) async fn record(&self, usage: &Usage) {
+        let today = Utc::now().date_naive();
+        let cost_usd = self.record_on(today, usage);
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.add_spend(today, cost_usd).await {
+                warn!("Failed to save budget: {e:#}");
+            }
+        }
+    }
+
+    /// Adds what `usage` cost to the spend on `today`, returning the cost.
+    fn record_on(&self, today: NaiveDate, usage: &Usage) -> f64 {
+        let cost_usd = (usage.prompt_tokens as f64 * self.prompt_price_per_million
+            + usage.completion_tokens as f64 * self.completion_price_per_million)
+            / 1_000_000.0;
+        let mut spend = self.spend.lock().unwrap();
+        if spend.day != today {
+            info!("Spent ${:.4} on OpenAI on {}", spend.spent_usd, spend.day);
+            *spend = DailySpend {
+                day: today,
+                spent_usd: 0.0,
+            };
+        }
+        spend.spent_usd += cost_usd;
+        cost_usd
+    }
+
+    pub(crate) fn report(&self) -> BudgetReport {
+        let day = Utc::now().date_naive();
+        let spent_usd = self.spent_on(day);
+        BudgetReport {
+            day,
+            spent_usd,
+            cap_usd: self.daily_cap_usd,
+            remaining_usd: self
+                .daily_cap_usd
+                .map(|cap_usd| (cap_usd - spent_usd).max(0.0)),
+        }
+    }
+
+    fn spent_on(&self, day: NaiveDate) -> f64 {
+        let spend = self.spend.lock().unwrap();
+        if spend.day == day {
+            spend.spent_usd
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Wraps a backend so every call is checked against, and charged to, a `SpendBudget`.
+pub(crate) struct BudgetedBackend<B> {
+    inner: B,
+    budget: Arc<SpendBudget>,
+}
+
+impl<B> BudgetedBackend<B> {
+    pub(crate) fn new(inner: B, budget: Arc<SpendBudget>) -> Self {
+        BudgetedBackend { inner, budget }
+    }
+}
+
+#[async_trait]
+impl<B: ChatBackend> ChatBackend for BudgetedBackend<B> {
+    async fn complete(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+    ) -> anyhow::Result<ChatReply> {
+        self.budget.check()?;
+        let reply = self.inner.complete(messages, params).await?;
+        if let Some(usage) = &reply.usage {
+            self.budget.record(usage).await;
+        }
+        Ok(reply)
+    }
+
+    async fn complete_stream(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+        chunks: mpsc::Sender<String>,
+    ) -> anyhow::Result<ChatReply> {
+        self.budget.check()?;
+        let reply = self.inner.complete_stream(messages, params, chunks).await?;
+        if let Some(usage) = &reply.usage {
+            self.budget.record(usage).await;
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::MockChatBackend;
+    use std::env;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn budget_refuses_calls_once_cap_is_spent() {
+        let budget = SpendBudget::new(Some(1.0), 1.0, 2.0, None);
+        let today = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        budget.record_on(today, &usage(400_000, 200_000));
+        assert!(budget.check_on(today).is_ok());
+        budget.record_on(today, &usage(200_000, 0));
+        assert!(matches!(
+            budget.check_on(today),
+            Err(RoadmapError::BudgetExceeded { .. })
+        ));
+        assert!(budget.check_on(today.succ_opt().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn budget_without_cap_never_refuses() {
+        let budget = SpendBudget::new(None, 1.0, 1.0, None);
+        budget.record(&usage(10_000_000, 10_000_000)).await;
+        assert!(budget.check().is_ok());
+        assert_eq!(budget.report().remaining_usd, None);
+        assert_eq!(budget.report().spent_usd, 20.0);
+    }
+
+    #[tokio::test]
+    async fn budget_survives_restart() {
+        let path = env::temp_dir().join("budget_survives_restart.db");
+        let _ = std::fs::remove_file(&path);
+        SpendBudget::new(Some(5.0), 1.0, 1.0, Some(Storage::open(&path).unwrap()))
+            .record(&usage(1_000_000, 1_000_000))
+            .await;
+        let restarted = SpendBudget::new(Some(5.0), 1.0, 1.0, Some(Storage::open(&path).unwrap()));
+        restarted.restore().await;
+        let report = restarted.report();
+        assert_eq!(report.spent_usd, 2.0);
+        assert_eq!(report.remaining_usd, Some(3.0));
+    }
+
+    #[tokio::test]
+    async fn budgeted_backend_charges_and_stops_calls() {
+        let budget = Arc::new(SpendBudget::new(Some(1.0), 1.0, 1.0, None));
+        let backend = BudgetedBackend::new(
+            MockChatBackend::new(&["first", "second"]).usage(usage(1_000_000, 0)),
+            budget.clone(),
+        );
+        let params = ChatParams::new("gpt-4o-mini");
+        assert_eq!(
+            backend.complete(vec![], &params).await.unwrap().content,
+            "first"
+        );
+        let error = backend.complete(vec![], &params).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RoadmapError>(),
+            Some(RoadmapError::BudgetExceeded { .. })
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::utilities::Role;
+use serenity::all::{ChannelId, GetMessages, Http, MessageId, UserId};
+use serenity::async_trait;
+use std::ops::RangeInclusive;
+
+/// Discord won't return more messages than this from one request.
+const MAX_FETCH: usize = 100;
+
+/// The parts of a Discord message that context is built from.
+#[derive(Clone, Debug)]
+pub(crate) struct ContextMessage {
+    pub(crate) author_id: UserId,
+    pub(crate) author_name: String,
+    pub(crate) is_bot: bool,
+    pub(crate) content: String,
+}
+
+impl ContextMessage {
+    /// The message as a turn in the conversation, with the bot's own messages as the
+    /// assistant's and everyone else's labelled with their author.
+    fn turn(&self, bot_id: UserId) -> (Role, String) {
+        if self.author_id == bot_id {
+            (Role::Assistant, self.content.clone())
+        } else {
+            (
+                Role::User,
+                format!("{}: {}", self.author_name, self.content),
+            )
+        }
+    }
+}
+
+/// Anything that can list a channel's history, so context can be tested without Discord.
+#[async_trait]
+pub(crate) trait MessageFetcher: Send + Sync {
+    /// Up to `limit` messages sent in `channel_id` before `before`, newest first.
+    async fn messages_before(
+        &self,
+        channel_id: ChannelId,
+        before: MessageId,
+        limit: u8,
+    ) -> anyhow::Result<Vec<ContextMessage>>;
+}
+
+#[async_trait]
+impl MessageFetcher for Http {
+    async fn messages_before(
+        &self,
+        channel_id: ChannelId,
+        before: MessageId,
+        limit: u8,
+    ) -> anyhow::Result<Vec<ContextMessage>> {
+        let messages = channel_id
+            .messages(self, GetMessages::new().before(before).limit(limit))
+            .await?;
+        Ok(messages
+            .into_iter()
+            .map(|message| ContextMessage {
+                author_id: message.author.id,
+                author_name: message.author.name,
+                is_bot: message.author.bot,
+                content: message.content,
+            })
+            .collect())
+    }
+}
+
+/// Fetches the last `context_length.end()` messages before `before` as conversation
+/// turns, oldest first, leaving out commands and other bots. While the turns don't `fit`,
+/// other people's messages are dropped oldest first, so the requester's own are kept
+/// longest. The newest `context_length.start()` turns are never dropped, since the prompt
+/// cuts them down to fit instead.
+pub(crate) async fn fetch_context(
+    fetcher: &dyn MessageFetcher,
+    channel_id: ChannelId,
+    before: MessageId,
+    requester: UserId,
+    bot_id: UserId,
+    context_length: RangeInclusive<usize>,
+    fits: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<(Role, String)>> {
+    let (min_context, context_length) = context_length.into_inner();
+    if context_length == 0 {
+        return Ok(vec![]);
+    }
+    let limit = context_length.min(MAX_FETCH) as u8;
+    let mut messages: Vec<ContextMessage> = fetcher
+        .messages_before(channel_id, before, limit)
+        .await?
+        .into_iter()
+        .rev()
+        .filter(|message| {
+            let content = message.content.trim();
+            (!message.is_bot || message.author_id == bot_id)
+                && !content.is_empty()
+                && !content.starts_with('!')
+        })
+        .collect();
+    let joined = |messages: &[ContextMessage]| {
+        messages
+            .iter()
+            .map(|message| message.turn(bot_id).1)
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    while !fits(joined(&messages).as_str()) {
+        let droppable = messages.len().saturating_sub(min_context);
+        let Some(unrelated) = messages[..droppable]
+            .iter()
+            .position(|message| message.author_id != requester)
+        else {
+            break;
+        };
+        messages.remove(unrelated);
+    }
+    Ok(messages
+        .iter()
+        .map(|message| message.turn(bot_id))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const REQUESTER: UserId = UserId::new(1);
+    const OTHER: UserId = UserId::new(2);
+    const BOT: UserId = UserId::new(3);
+
+    /// Serves a fixed channel history, newest first, and records the limit asked for.
+    struct FakeFetcher {
+        history: Vec<ContextMessage>,
+        limits: Mutex<Vec<u8>>,
+    }
+
+    impl FakeFetcher {
+        fn new(history: Vec<ContextMessage>) -> Self {
+            FakeFetcher {
+                history,
+                limits: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl MessageFetcher for FakeFetcher {
+        async fn messages_before(
+            &self,
+            _channel_id: ChannelId,
+            _before: MessageId,
+            limit: u8,
+        ) -> anyhow::Result<Vec<ContextMessage>> {
+            self.limits.lock().unwrap().push(limit);
+            Ok(self.history.iter().take(limit as usize).cloned().collect())
+        }
+    }
+
+    fn message(author_id: UserId, content: &str) -> ContextMessage {
+        ContextMessage {
+            author_id,
+            author_name: if author_id == REQUESTER { "ada" } else { "bob" }.to_string(),
+            is_bot: false,
+            content: content.to_string(),
+        }
+    }
+
+    /// The fetched context as "Role: text" lines.
+    async fn context(
+        fetcher: &FakeFetcher,
+        context_length: RangeInclusive<usize>,
+        limit: usize,
+    ) -> Vec<String> {
+        fetch_context(
+            fetcher,
+            ChannelId::new(10),
+            MessageId::new(100),
+            REQUESTER,
+            BOT,
+            context_length,
+            |context| context.chars().count() <= limit,
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(role, text)| format!("{role:?}: {text}"))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn context_is_oldest_first_without_other_bots_or_commands() {
+        let fetcher = FakeFetcher::new(vec![
+            message(REQUESTER, "I know Python"),
+            ContextMessage {
+                is_bot: true,
+                ..message(BOT, "Try pandas next")
+            },
+            message(OTHER, "!request what is pandas"),
+            ContextMessage {
+                is_bot: true,
+                ..message(OTHER, "Hi ada, here's an answer")
+            },
+            message(OTHER, "Welcome!"),
+        ]);
+        assert_eq!(
+            context(&fetcher, 0..=5, 1_000).await,
+            vec![
+                "User: bob: Welcome!",
+                "Assistant: Try pandas next",
+                "User: ada: I know Python"
+            ]
+        );
+        assert_eq!(fetcher.limits.lock().unwrap().as_slice(), &[5]);
+    }
+
+    #[tokio::test]
+    async fn trimming_drops_other_peoples_messages_first() {
+        let fetcher = FakeFetcher::new(vec![
+            message(OTHER, "Anyone up for games later?"),
+            message(REQUESTER, "I want to get into data science"),
+            message(OTHER, "Good morning everyone"),
+            message(REQUESTER, "I know some Python"),
+        ]);
+        assert_eq!(
+            context(&fetcher, 0..=4, 60).await,
+            vec![
+                "User: ada: I know some Python",
+                "User: ada: I want to get into data science"
+            ]
+        );
+        assert_eq!(
+            context(&fetcher, 0..=4, 100).await,
+            vec![
+                "User: ada: I know some Python",
+                "User: ada: I want to get into data science",
+                "User: bob: Anyone up for games later?",
+            ]
+        );
+        // The newest message stays even over budget
+        assert_eq!(
+            context(&fetcher, 1..=4, 60).await,
+            vec![
+                "User: ada: I know some Python",
+                "User: ada: I want to get into data science",
+                "User: bob: Anyone up for games later?",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_is_capped_at_discord_limit() {
+        let fetcher = FakeFetcher::new(vec![]);
+        assert!(context(&fetcher, 0..=0, 1_000).await.is_empty());
+        context(&fetcher, 0..=500, 1_000).await;
+        assert_eq!(fetcher.limits.lock().unwrap().as_slice(), &[100]);
+    }
+}
@@ -1,51 +1,308 @@
-pub fn chunk_string(s: &str, chunk_size: usize) -> Vec<String> {
-    let mut chunks = Vec::new();
-    let mut current_chunk = String::new();
-    let mut current_length = 0;
-
-    for line in s.lines() {
-        let words = line.split_whitespace().collect::<Vec<&str>>();
-
-        for word in words {
-            let word_length = word.chars().count();
-
-            // Check if adding this word would exceed the chunk size
-            if current_length + word_length > chunk_size {
-                // Push the current chunk and start a new one if the current chunk is not empty
-                if !current_chunk.is_empty() {
-                    chunks.push(current_chunk.clone());
-                    current_chunk.clear();
+use std::time::Duration;
+
+/// Longest message Discord accepts, less some headroom.
+pub const DISCORD_MESSAGE_LIMIT: usize = 1_950;
+
+/// Pause between the parts of a reply too long for one message, keeping them in order
+/// and clear of Discord's rate limit.
+pub const PART_DELAY: Duration = Duration::from_millis(250);
+
+/// Room kept in every part for its "Part n/m" header.
+const PART_HEADER_RESERVE: usize = 20;
+
+/// A paragraph, list item, heading or fenced code block, with the separator before it.
+struct Block {
+    separator: &'static str,
+    lines: Vec<String>,
+    is_code: bool,
+}
+
+impl Block {
+    fn render(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// Splits a block longer than `limit` into pieces that fit, at word boundaries for
+    /// prose and line boundaries for code, closing and reopening the fence in each piece.
+    fn split(&self, limit: usize) -> Vec<String> {
+        if !self.is_code {
+            return split_words(self.render().as_str(), limit);
+        }
+        let fence = self.lines[0].as_str();
+        let closed = self.lines.len() > 1
+            && self.lines[self.lines.len() - 1]
+                .trim_start()
+                .starts_with("```");
+        let body = &self.lines[1..self.lines.len() - usize::from(closed)];
+        // Opening fence, closing fence and their newlines
+        let room = limit.saturating_sub(char_len(fence) + 5).max(1);
+        let mut pieces = vec![];
+        let mut current = String::new();
+        for line in body.iter().flat_map(|line| hard_split(line, room)) {
+            if !current.is_empty() && char_len(&current) + 1 + char_len(&line) > room {
+                pieces.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(line.as_str());
+        }
+        pieces.push(current);
+        pieces
+            .into_iter()
+            .map(|piece| format!("{fence}\n{piece}\n```"))
+            .collect()
+    }
+}
+
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
+
+fn is_block_start(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("- ")
+        || line.starts_with("* ")
+        || line.starts_with('#')
+        || line.split_once(". ").is_some_and(|(number, _)| {
+            !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+        })
+}
+
+fn blocks(text: &str) -> Vec<Block> {
+    let mut blocks: Vec<Block> = vec![];
+    let mut blank_before = false;
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let separator = if blank_before { "\n\n" } else { "\n" };
+        if line.trim_start().starts_with("```") {
+            let mut code = vec![line.to_string()];
+            for line in lines.by_ref() {
+                code.push(line.to_string());
+                if line.trim_start().starts_with("```") {
+                    break;
                 }
-                current_length = 0;
             }
+            blocks.push(Block {
+                separator,
+                lines: code,
+                is_code: true,
+            });
+        } else if line.trim().is_empty() {
+            blank_before = true;
+            continue;
+        } else {
+            match blocks.last_mut() {
+                Some(block) if !block.is_code && !blank_before && !is_block_start(line) => {
+                    block.lines.push(line.to_string())
+                }
+                _ => blocks.push(Block {
+                    separator,
+                    lines: vec![line.to_string()],
+                    is_code: false,
+                }),
+            }
+        }
+        blank_before = false;
+    }
+    blocks
+}
 
-            // Add the word to the chunk, handle space addition
-            if current_length > 0 {
-                current_chunk.push(' ');
-                current_length += 1; // for the space
+/// Splits at whitespace into pieces of at most `limit` chars, only cutting a word that's
+/// longer than `limit` on its own.
+fn split_words(text: &str, limit: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut current = String::new();
+    for word in text.split_inclusive(char::is_whitespace) {
+        for word in hard_split(word, limit) {
+            if char_len(current.trim_end()) + char_len(word.trim_end()) > limit {
+                pieces.push(current.trim_end().to_string());
+                current.clear();
             }
-            current_chunk.push_str(word);
-            current_length += word_length;
-
-            // Manage exactly full chunks
-            if current_length == chunk_size {
-                chunks.push(current_chunk.clone());
-                current_chunk.clear();
-                current_length = 0;
+            current.push_str(word.as_str());
+        }
+    }
+    if !current.trim().is_empty() {
+        pieces.push(current.trim_end().to_string());
+    }
+    pieces
+}
+
+fn hard_split(text: &str, limit: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(limit.max(1))
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+/// Splits `text` into Discord messages of at most `DISCORD_MESSAGE_LIMIT` chars.
+pub fn split_for_discord(text: &str) -> Vec<String> {
+    split_for_discord_with_limit(text, DISCORD_MESSAGE_LIMIT)
+}
+
+/// Splits `text` into messages of at most `limit` chars, breaking between paragraphs,
+/// list items and code blocks where possible and never inside a word or code fence.
+/// Anything that has to be split is numbered "Part n/m".
+pub fn split_for_discord_with_limit(text: &str, limit: usize) -> Vec<String> {
+    if char_len(text) <= limit {
+        return vec![text.to_string()];
+    }
+    let body_limit = limit.saturating_sub(PART_HEADER_RESERVE).max(1);
+    let mut parts: Vec<String> = vec![];
+    let mut current = String::new();
+    for block in blocks(text) {
+        let rendered = block.render();
+        let pieces = if char_len(&rendered) > body_limit {
+            block.split(body_limit)
+        } else {
+            vec![rendered]
+        };
+        for piece in pieces {
+            if current.is_empty() {
+                current = piece;
+            } else if char_len(&current) + block.separator.len() + char_len(&piece) <= body_limit {
+                current.push_str(block.separator);
+                current.push_str(piece.as_str());
+            } else {
+                parts.push(std::mem::replace(&mut current, piece));
             }
         }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    let total = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| format!("Part {}/{}\n{}", index + 1, total, part))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Add newline at the end of the line if there's any content
-        if !current_chunk.is_empty() {
-            current_chunk.push('\n');
-            current_length += 1;
+    fn assert_within_limit(parts: &[String], limit: usize) {
+        for part in parts {
+            assert!(
+                char_len(part) <= limit,
+                "{} > {limit}: {part}",
+                char_len(part)
+            );
         }
     }
 
-    // Add the last chunk if not empty
-    if !current_chunk.is_empty() {
-        chunks.push(current_chunk);
+    #[test]
+    fn short_roadmaps_are_left_alone() {
+        let roadmap = "1. Learn Python\n2. Learn statistics";
+        assert_eq!(split_for_discord(roadmap), vec![roadmap.to_string()]);
     }
 
-    chunks
+    #[test]
+    fn exact_boundary_lengths() {
+        let roadmap = "a".repeat(100);
+        assert_eq!(split_for_discord_with_limit(&roadmap, 100), vec![roadmap]);
+        let roadmap = format!("{}\n\n{}", "a".repeat(50), "b".repeat(48));
+        assert_eq!(split_for_discord_with_limit(&roadmap, 100).len(), 1);
+        let roadmap = format!("{}\n\n{}", "a".repeat(50), "b".repeat(50));
+        let parts = split_for_discord_with_limit(&roadmap, 100);
+        assert_eq!(
+            parts,
+            vec![
+                format!("Part 1/2\n{}", "a".repeat(50)),
+                format!("Part 2/2\n{}", "b".repeat(50)),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_between_list_items() {
+        let roadmap = (1..=20)
+            .map(|step| format!("{step}. Learn topic number {step} thoroughly"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let parts = split_for_discord_with_limit(&roadmap, 200);
+        assert!(parts.len() > 1);
+        assert_within_limit(&parts, 200);
+        for (index, part) in parts.iter().enumerate() {
+            let mut lines = part.lines();
+            assert_eq!(
+                lines.next().unwrap(),
+                format!("Part {}/{}", index + 1, parts.len())
+            );
+            assert!(lines.all(|line| line.ends_with("thoroughly")));
+        }
+    }
+
+    #[test]
+    fn long_paragraph_splits_between_words() {
+        let roadmap = "Practice consistently every day ".repeat(40);
+        let parts = split_for_discord_with_limit(roadmap.trim_end(), 300);
+        assert!(parts.len() > 1);
+        assert_within_limit(&parts, 300);
+        let words: Vec<&str> = parts
+            .iter()
+            .flat_map(|part| part.lines().skip(1))
+            .flat_map(str::split_whitespace)
+            .collect();
+        assert_eq!(words, roadmap.split_whitespace().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn long_code_block_keeps_fences_balanced() {
+        let code = (0..60)
+            .map(|line| format!("    print(\"step {line}\")"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let roadmap = format!("Try this:\n\n```python\n{code}\n```\n\nThen move on.");
+        let parts = split_for_discord_with_limit(&roadmap, 400);
+        assert!(parts.len() > 2);
+        assert_within_limit(&parts, 400);
+        for part in &parts {
+            assert_eq!(part.matches("```").count() % 2, 0, "{part}");
+        }
+        let code_lines: Vec<&str> = parts
+            .iter()
+            .flat_map(|part| part.lines())
+            .filter(|line| line.contains("print"))
+            .collect();
+        assert_eq!(code_lines, code.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn generated_roadmap_fits_discord() {
+        let paragraph = "Spend the first weeks getting comfortable with the language itself, \
+                         writing small scripts that read, clean and summarise real data. "
+            .repeat(30);
+        let code = (0..80)
+            .map(|line| format!("df{line} = pd.read_csv(\"data_{line}.csv\")"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let roadmap = format!(
+            "Data Science Roadmap\n\n{paragraph}\n\n1. Python\n```python\n{code}\n```\n\n\
+             2. Statistics\n{paragraph}"
+        );
+        let parts = split_for_discord(&roadmap);
+        assert!(parts.len() > 2);
+        assert_within_limit(&parts, 2_000);
+        for part in &parts {
+            assert_eq!(part.matches("```").count() % 2, 0, "{part}");
+        }
+        let words = |text: &str| {
+            text.split_whitespace()
+                .filter(|word| !word.starts_with("```"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let split_words: Vec<String> = parts
+            .iter()
+            .flat_map(|part| words(part.split_once('\n').unwrap().1))
+            .collect();
+        assert_eq!(split_words, words(&roadmap));
+    }
 }
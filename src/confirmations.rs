@@ -0,0 +1,410 @@
+use crate::roadmaps;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serenity::all::{
+    ButtonStyle, ChannelId, ComponentInteraction, Context, CreateActionRow, CreateAllowedMentions,
+    CreateButton, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+    EditMessage, Http, Mentionable, Message, MessageId, UserId,
+};
+use serenity::prelude::{TypeMap, TypeMapKey};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// How long the author has to answer before the offer is withdrawn.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often expired offers are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Custom id prefixes of the two buttons, followed by `:` and the asking message's id.
+const ACCEPT_PREFIX: &str = "roadmap-confirm";
+const DECLINE_PREFIX: &str = "roadmap-decline";
+
+/// How a confirmation prompt was answered.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Accepted,
+    Declined,
+    Expired,
+}
+
+/// A detected roadmap request waiting for its author to confirm.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingConfirmation {
+    pub(crate) message: Message,
+    pub(crate) prompt_id: MessageId,
+    pub(crate) asked: Instant,
+}
+
+/// What a button click amounts to.
+#[derive(Debug)]
+pub(crate) enum Claim {
+    /// Nothing is waiting on this message, it's been answered or swept already.
+    Missing,
+    /// Someone other than the author clicked.
+    NotAuthor(UserId),
+    /// The offer ran out before the click arrived.
+    Expired(PendingConfirmation),
+    Claimed(PendingConfirmation),
+}
+
+/// Confirmations waiting for an answer, by the message that asked for a roadmap.
+#[derive(Debug, Default)]
+pub(crate) struct PendingConfirmations {
+    pending: HashMap<MessageId, PendingConfirmation>,
+}
+
+impl PendingConfirmations {
+    pub(crate) fn add(&mut self, pending: PendingConfirmation) {
+        self.pending.insert(pending.message.id, pending);
+    }
+
+    /// Takes the confirmation for `message_id` if `user_id` is its author. Other people's
+    /// clicks leave it waiting.
+    pub(crate) fn claim(&mut self, message_id: MessageId, user_id: UserId, now: Instant) -> Claim {
+        let Some(pending) = self.pending.get(&message_id) else {
+            return Claim::Missing;
+        };
+        if pending.message.author.id != user_id {
+            return Claim::NotAuthor(pending.message.author.id);
+        }
+        let pending = self.pending.remove(&message_id).expect("checked above");
+        if now.saturating_duration_since(pending.asked) >= CONFIRMATION_TIMEOUT {
+            Claim::Expired(pending)
+        } else {
+            Claim::Claimed(pending)
+        }
+    }
+
+    /// Removes and returns every confirmation nobody answered in time.
+    pub(crate) fn take_expired(&mut self, now: Instant) -> Vec<PendingConfirmation> {
+        let expired: Vec<MessageId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| {
+                now.saturating_duration_since(pending.asked) >= CONFIRMATION_TIMEOUT
+            })
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        expired
+            .iter()
+            .filter_map(|message_id| self.pending.remove(message_id))
+            .collect()
+    }
+}
+
+pub(crate) struct RoadmapConfirmations;
+
+impl TypeMapKey for RoadmapConfirmations {
+    type Value = Arc<RwLock<PendingConfirmations>>;
+}
+
+async fn pending_confirmations(data: &RwLock<TypeMap>) -> Arc<RwLock<PendingConfirmations>> {
+    let data_read = data.read().await;
+    data_read
+        .get::<RoadmapConfirmations>()
+        .expect("Expected RoadmapConfirmations in TypeMap.")
+        .clone()
+}
+
+/// One line of the confirmations record.
+#[derive(Serialize, Debug)]
+struct Record {
+    at: DateTime<Utc>,
+    outcome: Outcome,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    author_id: UserId,
+}
+
+/// Appends `outcome` for `pending` to the record at `path`, so the share of detections
+/// people turn down can be worked out later.
+fn record_to(path: &Path, outcome: Outcome, pending: &PendingConfirmation) -> anyhow::Result<()> {
+    let record = Record {
+        at: Utc::now(),
+        outcome,
+        channel_id: pending.message.channel_id,
+        message_id: pending.message.id,
+        author_id: pending.message.author.id,
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(&record)?)?;
+    Ok(())
+}
+
+fn record(outcome: Outcome, pending: &PendingConfirmation) {
+    info!(
+        "Roadmap confirmation for {} - {outcome:?}",
+        pending.message.author.name
+    );
+    let path = roadmaps::confirmations_path();
+    if let Err(e) = record_to(&path, outcome, pending) {
+        warn!("Failed to record confirmation to {}: {e}", path.display());
+    }
+}
+
+fn buttons(message_id: MessageId) -> Vec<CreateActionRow> {
+    vec![CreateActionRow::Buttons(vec![
+        CreateButton::new(format!("{ACCEPT_PREFIX}:{message_id}"))
+            .label("Yes, make me a roadmap")
+            .style(ButtonStyle::Primary),
+        CreateButton::new(format!("{DECLINE_PREFIX}:{message_id}"))
+            .label("No thanks")
+            .style(ButtonStyle::Secondary),
+    ])]
+}
+
+/// Which button `custom_id` belongs to, and the message it was offered for. `None` for
+/// buttons this module didn't create.
+fn parse_custom_id(custom_id: &str) -> Option<(bool, MessageId)> {
+    let (prefix, message_id) = custom_id.split_once(':')?;
+    let accepted = match prefix {
+        ACCEPT_PREFIX => true,
+        DECLINE_PREFIX => false,
+        _ => return None,
+    };
+    let message_id = message_id.parse::<u64>().ok().filter(|id| *id != 0)?;
+    Some((accepted, MessageId::new(message_id)))
+}
+
+/// Offers to make a roadmap for `message` instead of making one straight away, saying
+/// why detection took it as a request with `explanation`. Only the author is pinged,
+/// whatever the explanation says.
+pub(crate) async fn ask(ctx: &Context, message: &Message, explanation: &str) -> anyhow::Result<()> {
+    let prompt = message
+        .channel_id
+        .send_message(
+            &ctx.http,
+            CreateMessage::new()
+                .content(format!(
+                    "Hi {}! {explanation}\nWant me to make you a learning roadmap?",
+                    message.author.mention()
+                ))
+                .allowed_mentions(CreateAllowedMentions::new().users([message.author.id]))
+                .components(buttons(message.id)),
+        )
+        .await?;
+    pending_confirmations(&ctx.data)
+        .await
+        .write()
+        .await
+        .add(PendingConfirmation {
+            message: message.clone(),
+            prompt_id: prompt.id,
+            asked: Instant::now(),
+        });
+    Ok(())
+}
+
+async fn respond(
+    ctx: &Context,
+    interaction: &ComponentInteraction,
+    response: CreateInteractionResponse,
+) -> anyhow::Result<()> {
+    interaction.create_response(&ctx.http, response).await?;
+    Ok(())
+}
+
+/// Replaces the prompt's text and takes its buttons away.
+fn close_prompt(content: &str) -> CreateInteractionResponse {
+    CreateInteractionResponse::UpdateMessage(
+        CreateInteractionResponseMessage::new()
+            .content(content)
+            .components(vec![]),
+    )
+}
+
+/// Answers a click on a confirmation button. Returns the message to make a roadmap for
+/// when its author accepted.
+pub(crate) async fn handle_component(
+    ctx: &Context,
+    interaction: &ComponentInteraction,
+) -> anyhow::Result<Option<Message>> {
+    let Some((accepted, message_id)) = parse_custom_id(interaction.data.custom_id.as_str()) else {
+        return Ok(None);
+    };
+    let claim = pending_confirmations(&ctx.data).await.write().await.claim(
+        message_id,
+        interaction.user.id,
+        Instant::now(),
+    );
+    match claim {
+        Claim::Missing => {
+            respond(ctx, interaction, close_prompt("This offer has expired.")).await?;
+            Ok(None)
+        }
+        Claim::NotAuthor(author_id) => {
+            respond(
+                ctx,
+                interaction,
+                CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new()
+                        .content(format!("Only {} can answer this.", author_id.mention()))
+                        .ephemeral(true),
+                ),
+            )
+            .await?;
+            Ok(None)
+        }
+        Claim::Expired(pending) => {
+            record(Outcome::Expired, &pending);
+            respond(ctx, interaction, close_prompt("This offer has expired.")).await?;
+            Ok(None)
+        }
+        Claim::Claimed(pending) if accepted => {
+            record(Outcome::Accepted, &pending);
+            respond(
+                ctx,
+                interaction,
+                close_prompt("On it, making your roadmap."),
+            )
+            .await?;
+            Ok(Some(pending.message))
+        }
+        Claim::Claimed(pending) => {
+            record(Outcome::Declined, &pending);
+            respond(ctx, interaction, close_prompt("No problem!")).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Withdraws every offer nobody answered in time, recording each as expired.
+async fn expire_pending(data: &RwLock<TypeMap>, http: &Http) {
+    let expired = pending_confirmations(data)
+        .await
+        .write()
+        .await
+        .take_expired(Instant::now());
+    for pending in expired {
+        record(Outcome::Expired, &pending);
+        let closed = EditMessage::new()
+            .content("This offer has expired.")
+            .components(vec![]);
+        if let Err(e) = pending
+            .message
+            .channel_id
+            .edit_message(http, pending.prompt_id, closed)
+            .await
+        {
+            warn!("Failed to close expired confirmation due to {e}");
+        }
+    }
+}
+
+/// Sweeps expired offers for as long as the bot runs.
+pub(crate) async fn expire_pending_forever(data: Arc<RwLock<TypeMap>>, http: Arc<Http>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        expire_pending(&data, &http).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+
+    fn pending(message_id: u64, asked: Instant) -> PendingConfirmation {
+        let mut message = Message::default();
+        message.id = MessageId::new(message_id);
+        message.channel_id = ChannelId::new(10);
+        message.author.id = ADA;
+        PendingConfirmation {
+            message,
+            prompt_id: MessageId::new(message_id + 1),
+            asked,
+        }
+    }
+
+    #[test]
+    fn only_the_author_can_claim() {
+        let now = Instant::now();
+        let mut confirmations = PendingConfirmations::default();
+        confirmations.add(pending(100, now));
+        assert!(matches!(
+            confirmations.claim(MessageId::new(100), BOB, now),
+            Claim::NotAuthor(ADA)
+        ));
+        assert!(matches!(
+            confirmations.claim(MessageId::new(100), ADA, now),
+            Claim::Claimed(_)
+        ));
+        assert!(matches!(
+            confirmations.claim(MessageId::new(100), ADA, now),
+            Claim::Missing
+        ));
+    }
+
+    #[test]
+    fn late_clicks_are_expired() {
+        let now = Instant::now();
+        let mut confirmations = PendingConfirmations::default();
+        confirmations.add(pending(100, now));
+        assert!(matches!(
+            confirmations.claim(MessageId::new(100), ADA, now + CONFIRMATION_TIMEOUT),
+            Claim::Expired(_)
+        ));
+    }
+
+    #[test]
+    fn sweeping_takes_only_expired_confirmations() {
+        let now = Instant::now();
+        let mut confirmations = PendingConfirmations::default();
+        confirmations.add(pending(100, now));
+        confirmations.add(pending(200, now + Duration::from_secs(60)));
+        assert!(confirmations.take_expired(now).is_empty());
+        let expired = confirmations.take_expired(now + CONFIRMATION_TIMEOUT);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].message.id, MessageId::new(100));
+        assert_eq!(confirmations.pending.len(), 1);
+    }
+
+    #[test]
+    fn custom_ids_round_trip() {
+        assert_eq!(
+            parse_custom_id("roadmap-confirm:100"),
+            Some((true, MessageId::new(100)))
+        );
+        assert_eq!(
+            parse_custom_id("roadmap-decline:100"),
+            Some((false, MessageId::new(100)))
+        );
+        assert_eq!(parse_custom_id("roadmap-confirm:0"), None);
+        assert_eq!(parse_custom_id("something-else:100"), None);
+    }
+
+    #[test]
+    fn outcomes_are_appended_as_json_lines() {
+        let path = env::temp_dir().join("outcomes_are_appended_as_json_lines.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let now = Instant::now();
+        record_to(&path, Outcome::Declined, &pending(100, now)).unwrap();
+        record_to(&path, Outcome::Expired, &pending(200, now)).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let outcomes: Vec<String> = saved
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                format!("{} {}", record["message_id"], record["outcome"])
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec!["\"100\" \"declined\"", "\"200\" \"expired\""]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::roadmaps::PreviousRoadmap;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serenity::all::{Context, Message, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+lazy_static! {
+    /// Cheap signs a message is about a roadmap the user already has, checked before
+    /// paying for detection: a step by number, the roadmap itself, or a change asked of
+    /// one of those or of "it".
+    static ref FOLLOWUP_REGEX: Regex = Regex::new(concat!(
+        r"(?i)\bstep\s*#?\d+\b",
+        r"|\b(?:the|your|that|this|my)\s+roadmap\b",
+        r"|\b(?:revise|rewrite|redo|update|change|adjust|tweak|simplify|shorten|expand)",
+        r"\s+(?:it|that|this|the\s+(?:plan|steps?))\b",
+        r"|\bmake\s+(?:it|that|this)\s+(?:more|less|shorter|longer|simpler|easier|harder)\b",
+    ))
+    .unwrap();
+}
+
+/// Whether `message` looks like it's asking about a roadmap it follows up on.
+pub(crate) fn looks_like_followup(message: &str) -> bool {
+    FOLLOWUP_REGEX.is_match(message)
+}
+
+/// Each user's last roadmap, forgotten once it's `ttl` old.
+#[derive(Debug)]
+pub(crate) struct LastRoadmaps {
+    ttl: Duration,
+    roadmaps: HashMap<UserId, (PreviousRoadmap, Instant)>,
+}
+
+impl LastRoadmaps {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        LastRoadmaps {
+            ttl,
+            roadmaps: HashMap::new(),
+        }
+    }
+
+    /// Makes `roadmap` `user_id`'s last, replacing any earlier one.
+    pub(crate) fn remember(&mut self, user_id: UserId, roadmap: PreviousRoadmap, now: Instant) {
+        self.evict_expired(now);
+        self.roadmaps.insert(user_id, (roadmap, now));
+    }
+
+    /// `user_id`'s last roadmap, unless it's expired.
+    pub(crate) fn get(&self, user_id: UserId, now: Instant) -> Option<&PreviousRoadmap> {
+        self.roadmaps
+            .get(&user_id)
+            .filter(|(_, remembered)| now.saturating_duration_since(*remembered) < self.ttl)
+            .map(|(roadmap, _)| roadmap)
+    }
+
+    fn evict_expired(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.roadmaps
+            .retain(|_, (_, remembered)| now.saturating_duration_since(*remembered) < ttl);
+    }
+}
+
+pub(crate) struct RoadmapConversations;
+
+impl TypeMapKey for RoadmapConversations {
+    type Value = Arc<RwLock<LastRoadmaps>>;
+}
+
+async fn last_roadmaps(ctx: &Context) -> Arc<RwLock<LastRoadmaps>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<RoadmapConversations>()
+        .expect("Expected RoadmapConversations in TypeMap.")
+        .clone()
+}
+
+/// `user_id`'s last roadmap, if they had one recently enough to follow up on.
+pub(crate) async fn last_roadmap(ctx: &Context, user_id: UserId) -> Option<PreviousRoadmap> {
+    last_roadmaps(ctx)
+        .await
+        .read()
+        .await
+        .get(user_id, Instant::now())
+        .cloned()
+}
+
+/// Whether `message` might be its author asking to change a roadmap they recently got,
+/// worth running detection on even without the usual roadmap keywords.
+pub(crate) async fn may_follow_up(ctx: &Context, message: &Message) -> bool {
+    looks_like_followup(message.content.as_str())
+        && last_roadmap(ctx, message.author.id).await.is_some()
+}
+
+/// Makes `roadmap` `user_id`'s last, replacing any earlier one.
+pub(crate) async fn remember(ctx: &Context, user_id: UserId, roadmap: PreviousRoadmap) {
+    last_roadmaps(ctx)
+        .await
+        .write()
+        .await
+        .remember(user_id, roadmap, Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn roadmap(text: &str) -> PreviousRoadmap {
+        PreviousRoadmap {
+            request: "roadmap for data science please".to_string(),
+            roadmap: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn remembered_roadmap_is_kept_per_user() {
+        let now = Instant::now();
+        let mut last_roadmaps = LastRoadmaps::new(HOUR);
+        assert_eq!(last_roadmaps.get(ADA, now), None);
+        last_roadmaps.remember(ADA, roadmap("1. Python"), now);
+        assert_eq!(last_roadmaps.get(ADA, now), Some(&roadmap("1. Python")));
+        assert_eq!(last_roadmaps.get(BOB, now), None);
+    }
+
+    #[test]
+    fn revision_replaces_the_last_roadmap() {
+        let now = Instant::now();
+        let mut last_roadmaps = LastRoadmaps::new(HOUR);
+        last_roadmaps.remember(ADA, roadmap("1. Python"), now);
+        let later = now + Duration::from_secs(60);
+        last_roadmaps.remember(ADA, roadmap("1. Python for beginners"), later);
+        assert_eq!(
+            last_roadmaps.get(ADA, later),
+            Some(&roadmap("1. Python for beginners"))
+        );
+        // The revision restarts the clock
+        assert!(last_roadmaps.get(ADA, now + HOUR).is_some());
+    }
+
+    #[test]
+    fn roadmaps_expire_and_are_evicted() {
+        let now = Instant::now();
+        let mut last_roadmaps = LastRoadmaps::new(HOUR);
+        last_roadmaps.remember(ADA, roadmap("1. Python"), now);
+        assert_eq!(last_roadmaps.get(ADA, now + HOUR), None);
+        last_roadmaps.remember(BOB, roadmap("1. SQL"), now + HOUR);
+        assert_eq!(last_roadmaps.roadmaps.len(), 1);
+    }
+
+    #[test]
+    fn followups_are_spotted() {
+        assert!(looks_like_followup(
+            "can you make step 3 more beginner friendly?"
+        ));
+        assert!(looks_like_followup("Could you shorten the roadmap?"));
+        assert!(looks_like_followup("can you simplify it a bit?"));
+        assert!(looks_like_followup("make it more advanced please"));
+        assert!(!looks_like_followup("anyone up for games later?"));
+        assert!(!looks_like_followup("I took the first steps today"));
+        assert!(!looks_like_followup("I need to update my drivers"));
+        assert!(!looks_like_followup("my plan is to sleep early"));
+        assert!(!looks_like_followup("this is more advanced than I thought"));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::roadmaps::{RequestingRoadmap, RoadmapError};
+use crate::utilities::Role;
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// Remembers recent detection results so repeated messages don't cost another OpenAI call.
+/// Shared by every event handler task, so all state sits behind a mutex or atomics.
+pub(crate) struct DetectionCache {
+    entries: Option<Mutex<LruCache<u64, (Instant, RequestingRoadmap)>>>,
+    /// Detections currently running, which identical concurrent calls wait on.
+    in_flight: Mutex<HashMap<u64, Arc<OnceCell<RequestingRoadmap>>>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DetectionCache {
+    /// A `capacity` of 0 disables the cache.
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        DetectionCache {
+            entries: NonZeroUsize::new(capacity)
+                .map(|capacity| Mutex::new(LruCache::new(capacity))),
+            in_flight: Default::default(),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Hashes the model, message and context after trimming, lowercasing and collapsing
+    /// whitespace, so trivially different copies of a message share an entry.
+    pub(crate) fn key(model: &str, message: &str, context: &[(Role, String)]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        for (role, text) in context {
+            format!("{role:?}").hash(&mut hasher);
+            normalize(text).hash(&mut hasher);
+        }
+        normalize(message).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub(crate) fn get(&self, key: u64) -> Option<RequestingRoadmap> {
+        let entries = self.entries.as_ref()?;
+        let mut entries = entries.lock().unwrap();
+        let cached = match entries.get(&key) {
+            Some((stored_at, _)) if stored_at.elapsed() > self.ttl => {
+                entries.pop(&key);
+                None
+            }
+            Some((_, roadmap_request)) => Some(roadmap_request.clone()),
+            None => None,
+        };
+        let counter = if cached.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    pub(crate) fn insert(&self, key: u64, roadmap_request: RequestingRoadmap) {
+        if let Some(entries) = &self.entries {
+            entries
+                .lock()
+                .unwrap()
+                .put(key, (Instant::now(), roadmap_request));
+        }
+    }
+
+    /// Runs `detect` unless a call with the same key is already running, in which case it
+    /// waits for and returns that call's result, without its usage since that was only
+    /// spent once. Failures aren't shared, so a waiter whose leader failed or was cancelled
+    /// runs its own `detect` rather than inheriting the error.
+    /// Works whether or not the cache itself is enabled.
+    pub(crate) async fn single_flight(
+        &self,
+        key: u64,
+        detect: impl Future<Output = Result<RequestingRoadmap, RoadmapError>>,
+    ) -> Result<RequestingRoadmap, RoadmapError> {
+        let flight = Flight {
+            cache: self,
+            key,
+            cell: self
+                .in_flight
+                .lock()
+                .unwrap()
+                .entry(key)
+                .or_default()
+                .clone(),
+        };
+        let mut led = false;
+        let result = flight
+            .cell
+            .get_or_try_init(|| {
+                led = true;
+                detect
+            })
+            .await
+            .cloned();
+        result.map(|roadmap_request| {
+            if led {
+                roadmap_request
+            } else {
+                RequestingRoadmap {
+                    usage: None,
+                    ..roadmap_request
+                }
+            }
+        })
+    }
+
+    /// Hits and misses since startup.
+    pub(crate) fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A `single_flight` call's hold on its in-flight entry, let go however the call ends,
+/// cancellation included.
+struct Flight<'a> {
+    cache: &'a DetectionCache,
+    key: u64,
+    cell: Arc<OnceCell<RequestingRoadmap>>,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        // Done with the entry once it holds a result, or once nobody else is waiting on
+        // it to retry a failure
+        let mut in_flight = self.cache.in_flight.lock().unwrap();
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|running| Arc::ptr_eq(running, &self.cell))
+            && (self.cell.initialized() || Arc::strong_count(&self.cell) == 2)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roadmap_request(reason: &str) -> RequestingRoadmap {
+        RequestingRoadmap {
+            reason: reason.to_string(),
+            is_roadmap: true,
+            confidence: 0.9,
+            is_followup: false,
+            usage: None,
+            model: "gpt-4o-mini".to_string(),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn key_ignores_case_and_whitespace() {
+        let key = DetectionCache::key("gpt-4o-mini", "Can someone give me a roadmap?", &[]);
+        assert_eq!(
+            key,
+            DetectionCache::key("gpt-4o-mini", "  can someone  give me\na ROADMAP? ", &[])
+        );
+        assert_ne!(
+            key,
+            DetectionCache::key("gpt-4o", "Can someone give me a roadmap?", &[])
+        );
+        assert_ne!(
+            key,
+            DetectionCache::key(
+                "gpt-4o-mini",
+                "Can someone give me a roadmap?",
+                &[(Role::User, "I know Python".to_string())]
+            )
+        );
+    }
+
+    #[test]
+    fn cache_counts_hits_and_misses() {
+        let cache = DetectionCache::new(2, Duration::from_secs(60));
+        assert!(cache.get(1).is_none());
+        cache.insert(1, roadmap_request("Asking for a roadmap"));
+        assert_eq!(cache.get(1).unwrap().reason, "Asking for a roadmap");
+        assert_eq!(cache.stats(), (1, 1));
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let cache = DetectionCache::new(2, Duration::from_secs(60));
+        cache.insert(1, roadmap_request("first"));
+        cache.insert(2, roadmap_request("second"));
+        cache.get(1);
+        cache.insert(3, roadmap_request("third"));
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(3).is_some());
+    }
+
+    #[test]
+    fn cache_expires_entries_after_ttl() {
+        let cache = DetectionCache::new(2, Duration::ZERO);
+        cache.insert(1, roadmap_request("Asking for a roadmap"));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(cache.get(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_flight_is_cleaned_up() {
+        let cache = DetectionCache::new(0, Duration::from_secs(60));
+        let result = cache
+            .single_flight(1, async {
+                Err(RoadmapError::ApiError(anyhow::anyhow!("OpenAI is down")))
+            })
+            .await;
+        assert!(result.is_err());
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+        let result = cache
+            .single_flight(1, async { Ok(roadmap_request("Asking for a roadmap")) })
+            .await;
+        assert_eq!(result.unwrap().reason, "Asking for a roadmap");
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_flight_is_cleaned_up() {
+        let cache = DetectionCache::new(0, Duration::from_secs(60));
+        let stalled = cache.single_flight(1, std::future::pending());
+        assert!(tokio::time::timeout(Duration::from_millis(10), stalled)
+            .await
+            .is_err());
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_leader_reports_usage() {
+        let cache = DetectionCache::new(0, Duration::from_secs(60));
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let leader = cache.single_flight(1, async {
+            released.await.unwrap();
+            Ok(RequestingRoadmap {
+                usage: Some(openai::Usage {
+                    prompt_tokens: 100,
+                    completion_tokens: 10,
+                    total_tokens: 110,
+                }),
+                ..roadmap_request("Asking for a roadmap")
+            })
+        });
+        let waiter = cache.single_flight(1, async { panic!("the leader is already running") });
+        let (leader, waiter, _) = tokio::join!(leader, waiter, async {
+            release.send(()).unwrap();
+        });
+        assert_eq!(leader.unwrap().usage.unwrap().total_tokens, 110);
+        let waiter = waiter.unwrap();
+        assert_eq!(waiter.reason, "Asking for a roadmap");
+        assert!(waiter.usage.is_none());
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_disables_cache() {
+        let cache = DetectionCache::new(0, Duration::from_secs(60));
+        cache.insert(1, roadmap_request("Asking for a roadmap"));
+        assert!(cache.get(1).is_none());
+    }
+}
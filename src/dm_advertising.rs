@@ -0,0 +1,100 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// Why messages luring people into DMs are removed, and the strike reason that tells
+/// whether their author has been warned before.
+pub(crate) const REASON: &str = "advertising in DMs";
+
+lazy_static! {
+    /// Amounts with a currency, like `$500`, `500$`, `£1k` or `300 usdt`.
+    static ref MONEY_REGEX: Regex = Regex::new(
+        r"[$€£]\s?\d[\d,.]*\s?k?\b|\b\d[\d,.]*\s?k?\s?(?:[$€£]|usdt?\b|dollars?\b|euros?\b|bucks\b|btc\b)"
+    )
+    .unwrap();
+    /// Asking to be messaged privately.
+    static ref DM_REGEX: Regex = Regex::new(
+        r"\b(?:dm|pm|inbox|message|text|contact)\s+me\b|\bhit\s+me\s+up\b|\bsend\s+(?:me\s+)?an?\s+(?:dm|pm|message)\b|\bcheck\s+(?:your|ur)\s+(?:dms?|inbox)\b|\b(?:in|into|to)\s+(?:my|your|ur)\s+dms?\b"
+    )
+    .unwrap();
+    /// Contact details off Discord: messenger apps, their links and phone numbers.
+    static ref CONTACT_REGEX: Regex = Regex::new(
+        r"\b(?:telegram|whatsapp|wickr|tg)\b|\bt\.me/|\bwa\.me/|\+\d[\d\s-]{7,}\d"
+    )
+    .unwrap();
+}
+
+/// `reason` for removing a message, with DM advertising among the reasons if it isn't
+/// already.
+pub(crate) fn with_reason(reason: String) -> String {
+    if reason.contains(REASON) {
+        reason
+    } else {
+        format!("{reason}, and {REASON}")
+    }
+}
+
+/// How much `text`, already normalized for matching, looks like bait to take a scam to
+/// DMs, from 0.0 to 0.6. Any one of money, DM phrasing or an outside contact is common in
+/// normal chat, so it takes two of them to score at all.
+pub(crate) fn dm_advertising_score(text: &str) -> f32 {
+    let cues = [&*MONEY_REGEX, &*DM_REGEX, &*CONTACT_REGEX]
+        .iter()
+        .filter(|regex| regex.is_match(text))
+        .count();
+    match cues {
+        0 | 1 => 0.0,
+        2 => 0.4,
+        _ => 0.6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_normalization::normalize_for_matching;
+
+    fn score(text: &str) -> f32 {
+        dm_advertising_score(&normalize_for_matching(text))
+    }
+
+    #[test]
+    fn dm_bait_scores() {
+        for (text, expected) in [
+            ("dm me for a method to make $500/day", 0.4),
+            ("Check your DMs, I sent you the 200$ job offer", 0.4),
+            ("I made 5000$ in a week trading, add me on telegram", 0.4),
+            (
+                "Earn 300 USDT daily, contact me on WhatsApp +1 555 123 4567",
+                0.6,
+            ),
+            ("making £1k weekly? hit me up on tg", 0.6),
+            ("Ｄ\u{200B}Ｍ ｍｅ for $５００ a day, t.me/richguy", 0.6),
+        ] {
+            assert_eq!(score(text), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn other_reasons_are_kept() {
+        assert_eq!(
+            with_reason("matched scam rule \"crypto\"".to_string()),
+            "matched scam rule \"crypto\", and advertising in DMs"
+        );
+        let signals = "spam signals: new account, advertising in DMs".to_string();
+        assert_eq!(with_reason(signals.clone()), signals);
+    }
+
+    #[test]
+    fn everyday_messages_score_nothing() {
+        for text in [
+            "DM me your address for the giveaway",
+            "Message me if you need help with Python",
+            "The course costs $20 and is worth it",
+            "Our study group is on Telegram",
+            "I spent 3 days on this bug, it was 1 character",
+            "",
+        ] {
+            assert_eq!(score(text), 0.0, "{text}");
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::chunking::split_for_discord;
+use crate::in_flight;
+use crate::roadmaps;
+use crate::roadmaps::{RoadmapError, RoadmapProvided, RoadmapRequest};
+use futures::StreamExt;
+use serenity::all::{ChannelId, Context, CreateMessage, EditMessage, Mentionable, Message};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Shown until the first words of the roadmap arrive.
+const DRAFT_PLACEHOLDER: &str = "✍️ drafting your roadmap...";
+
+/// Ends a roadmap whose stream dropped partway, in place of the steps that never came.
+const CUT_SHORT_NOTE: &str =
+    "*The rest of this roadmap was lost to a connection problem, ask again for all of it.*";
+
+/// How often the draft is edited with new text, well inside Discord's edit rate limit.
+const EDIT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The reply as posted so far in `channel_id`, one Discord message per page.
+struct Draft {
+    channel_id: ChannelId,
+    pages: Vec<String>,
+    posted: Vec<Message>,
+}
+
+impl Draft {
+    async fn start(
+        ctx: &Context,
+        message: &Message,
+        channel_id: ChannelId,
+    ) -> anyhow::Result<Draft> {
+        let page = format!("Hi {}, \n {}", message.author.mention(), DRAFT_PLACEHOLDER);
+        let posted = channel_id
+            .send_message(&ctx.http, CreateMessage::new().content(page.clone()))
+            .await?;
+        Ok(Draft {
+            channel_id,
+            pages: vec![page],
+            posted: vec![posted],
+        })
+    }
+
+    /// Brings the posted messages up to date with `text`, editing pages that changed and
+    /// continuing in new messages once a page is full.
+    async fn show(&mut self, ctx: &Context, message: &Message, text: &str) -> anyhow::Result<()> {
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        let formatted = format!("Hi {}, \n {}", message.author.mention(), text);
+        for (index, page) in split_for_discord(formatted.as_str())
+            .into_iter()
+            .enumerate()
+        {
+            match self.posted.get_mut(index) {
+                Some(_) if self.pages[index] == page => {}
+                Some(posted) => {
+                    posted
+                        .edit(&ctx.http, EditMessage::new().content(page.clone()))
+                        .await?;
+                    self.pages[index] = page;
+                }
+                None => {
+                    let posted = self
+                        .channel_id
+                        .send_message(&ctx.http, CreateMessage::new().content(page.clone()))
+                        .await?;
+                    self.posted.push(posted);
+                    self.pages.push(page);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn discard(self, ctx: &Context) {
+        for posted in self.posted {
+            if let Err(e) = posted.delete(&ctx.http).await {
+                warn!("Failed to delete roadmap draft due to {e}");
+            }
+        }
+    }
+}
+
+/// Writes the roadmap `request` asks for `message` in `channel_id`, posting a placeholder
+/// straight away and editing it as the roadmap streams in. If the stream fails, what already
+/// arrived is kept and marked as cut short, and only a stream that failed before writing
+/// anything is retried as a regular completion. Returns `None`, taking the draft down, once
+/// `cancelled` is notified.
+pub async fn draft_roadmap(
+    ctx: &Context,
+    message: &Message,
+    channel_id: ChannelId,
+    request: RoadmapRequest,
+    cancelled: &Notify,
+) -> anyhow::Result<Option<RoadmapProvided>> {
+    let started = Instant::now();
+    let mut draft = Draft::start(ctx, message, channel_id).await?;
+    let mut roadmap = roadmaps::create_roadmap_stream(request.clone());
+    let mut edits = tokio::time::interval(EDIT_INTERVAL);
+    let mut text = String::new();
+    let streamed = loop {
+        tokio::select! {
+            chunk = roadmap.next() => match chunk {
+                Some(Ok(chunk)) => text.push_str(chunk.as_str()),
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            },
+            _ = edits.tick() => {
+                if let Err(e) = draft.show(ctx, message, text.as_str()).await {
+                    warn!("Failed to update roadmap draft due to {e}");
+                }
+            }
+            _ = cancelled.notified() => {
+                info!("Roadmap request {} was deleted, dropping its draft", message.id);
+                draft.discard(ctx).await;
+                return Ok(None);
+            }
+        }
+    };
+    let created = match streamed {
+        Ok(()) => roadmap.collect_roadmap().await,
+        Err(e) => Err(e),
+    };
+    let created = match created {
+        Ok(created) => Ok(created),
+        // Text that made it is already paid for, so it's kept rather than written again
+        Err(e) if is_api_error(&e) && !text.trim().is_empty() => {
+            warn!("Streaming roadmap failed due to {e:#}, keeping what arrived");
+            Ok(RoadmapProvided {
+                roadmap: format!("{}\n\n{CUT_SHORT_NOTE}", text.trim_end()),
+                structured: None,
+                usage: None,
+                model: request.creation_model(),
+                elapsed: started.elapsed(),
+                thread_id: None,
+            })
+        }
+        // Only a failed stream is worth retrying, the rest would fail the same way again
+        Err(e) if is_api_error(&e) => {
+            warn!("Streaming roadmap failed due to {e:#}, retrying without streaming");
+            match in_flight::unless_cancelled(cancelled, request.create()).await {
+                Some(created) => created.map_err(Into::into),
+                None => {
+                    draft.discard(ctx).await;
+                    return Ok(None);
+                }
+            }
+        }
+        Err(e) => Err(e),
+    };
+    match created {
+        Ok(created) => {
+            draft.show(ctx, message, created.roadmap.as_str()).await?;
+            Ok(Some(created))
+        }
+        Err(e) => {
+            draft.discard(ctx).await;
+            Err(e)
+        }
+    }
+}
+
+/// Whether `e` is the API call itself failing, rather than what came back being unusable.
+fn is_api_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(RoadmapError::ApiError(_)))
+}
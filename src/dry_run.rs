@@ -0,0 +1,101 @@
+use crate::llm::{ChatBackend, ChatParams, ChatReply};
+use crate::roadmaps::{DETECTION_FUNCTION, ROADMAP_FUNCTION};
+use crate::spam_detection::SPAM_FUNCTION;
+use openai::chat::ChatCompletionMessage;
+use serde_json::json;
+use serenity::async_trait;
+use tracing::info;
+
+/// Written in place of every free-text roadmap in dry-run mode.
+pub(crate) const PLACEHOLDER_ROADMAP: &str = "**Dry run roadmap**\n\
+1. This is a placeholder, no model was asked.\n\
+2. Turn off `dry_run` in the roadmap config for real roadmaps.";
+
+/// Answers every completion with a fixed reply instead of calling the API, so detection
+/// and creation run as normal without spending tokens.
+pub(crate) struct DryRunBackend;
+
+impl DryRunBackend {
+    /// The canned reply for a completion with `params`: a confident detection, a one-step
+    /// structured roadmap, a message that isn't spam, or the placeholder roadmap.
+    fn reply(params: &ChatParams) -> String {
+        match params
+            .function
+            .as_ref()
+            .map(|function| function.name.as_str())
+        {
+            Some(DETECTION_FUNCTION) => json!({
+                "reason": "Dry run, the message wasn't classified",
+                "is_roadmap": true,
+                "confidence": 1.0,
+                "is_followup": false,
+            })
+            .to_string(),
+            Some(ROADMAP_FUNCTION) => json!({
+                "title": "Dry run roadmap",
+                "intro": "This is a placeholder, no model was asked.",
+                "steps": [{
+                    "name": "Turn off dry run",
+                    "description": "Set `dry_run = false` in the roadmap config for real roadmaps.",
+                    "duration": "1 minute",
+                    "resources": [],
+                }],
+            })
+            .to_string(),
+            Some(SPAM_FUNCTION) => json!({
+                "reason": "Dry run, the message wasn't classified",
+                "is_spam": false,
+                "confidence": 1.0,
+            })
+            .to_string(),
+            _ => PLACEHOLDER_ROADMAP.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatBackend for DryRunBackend {
+    async fn complete(
+        &self,
+        _messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+    ) -> anyhow::Result<ChatReply> {
+        info!("Dry run, skipping {} completion", params.model);
+        Ok(ChatReply {
+            content: DryRunBackend::reply(params),
+            usage: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roadmaps::{RoadmapDecision, RoadmapRequest};
+    use std::sync::Arc;
+
+    fn dry_run(message: &str) -> RoadmapRequest {
+        RoadmapRequest::new(message).backend(Arc::new(DryRunBackend))
+    }
+
+    #[tokio::test]
+    async fn dry_run_detection_is_deterministic() {
+        let detected = dry_run("how do I learn rust?").detect().await.unwrap();
+        assert!(detected.is_roadmap);
+        assert!(!detected.is_followup);
+        assert_eq!(detected.confidence, 1.0);
+        assert_eq!(detected.decision(), RoadmapDecision::Create);
+        assert!(detected.usage.is_none());
+    }
+
+    #[tokio::test]
+    async fn dry_run_creation_returns_placeholders() {
+        let created = dry_run("how do I learn rust?").create().await.unwrap();
+        assert_eq!(created.roadmap, PLACEHOLDER_ROADMAP);
+        let structured = dry_run("how do I learn rust?")
+            .create_structured()
+            .await
+            .unwrap();
+        assert_eq!(structured.structured.unwrap().title, "Dry run roadmap");
+    }
+}
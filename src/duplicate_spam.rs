@@ -0,0 +1,465 @@
+use crate::messaging;
+use crate::spam_detection;
+use crate::text_normalization::normalize_for_matching;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serenity::all::{ChannelId, Context, Message, MessageId, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, instrument, warn};
+
+/// Only this much of each message is compared, keeping comparisons cheap however long
+/// the messages are.
+const MAX_COMPARED_CHARS: usize = 500;
+
+lazy_static! {
+    /// Links and mentions, which spammers vary between copies.
+    static ref VARYING_REGEX: Regex =
+        Regex::new(r"https?://\S+|<(?:@[!&]?|#)\d+>|@(?:everyone|here)").unwrap();
+}
+
+/// `content` without links, mentions, punctuation or emoji, normalized for matching with
+/// whitespace collapsed and cut to `MAX_COMPARED_CHARS`, so copies differing only in
+/// those or in how they're obfuscated match.
+fn normalize(content: &str) -> Vec<char> {
+    let content = normalize_for_matching(content);
+    VARYING_REGEX
+        .replace_all(content.as_str(), " ")
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(MAX_COMPARED_CHARS)
+        .collect()
+}
+
+/// Whether `a` can be turned into `b` with at most `max_distance` single-char edits.
+/// Only the band of the Levenshtein table that could stay within `max_distance` is
+/// filled in, and it gives up as soon as a whole row is over.
+fn within_distance(a: &[char], b: &[char], max_distance: usize) -> bool {
+    if a.len().abs_diff(b.len()) > max_distance {
+        return false;
+    }
+    let over = max_distance + 1;
+    let mut previous: Vec<usize> = (0..=b.len()).map(|j| j.min(over)).collect();
+    let mut current = vec![over; b.len() + 1];
+    for i in 1..=a.len() {
+        let start = i.saturating_sub(max_distance).max(1);
+        let end = (i + max_distance).min(b.len());
+        current[start - 1] = if start == 1 { i.min(over) } else { over };
+        let mut row_min = current[start - 1];
+        for j in start..=end {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            current[j] = substitution
+                .min(previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(over);
+            row_min = row_min.min(current[j]);
+        }
+        if let Some(past_band) = current.get_mut(end + 1) {
+            *past_band = over;
+        }
+        if row_min > max_distance {
+            return false;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()] <= max_distance
+}
+
+/// One message a user posted, by its normalized content.
+#[derive(Debug, Clone)]
+struct Sighting {
+    text: Vec<char>,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    at: Instant,
+}
+
+/// Each user's messages over the last `window`, to spot the same message, or one at least
+/// `min_similarity` alike, being pasted into `channels` or more channels.
+#[derive(Debug)]
+pub(crate) struct DuplicateMessages {
+    channels: usize,
+    window: Duration,
+    min_chars: usize,
+    min_similarity: f64,
+    sightings: HashMap<UserId, VecDeque<Sighting>>,
+}
+
+impl DuplicateMessages {
+    pub(crate) fn new(
+        channels: usize,
+        window: Duration,
+        min_chars: usize,
+        min_similarity: f64,
+    ) -> Self {
+        DuplicateMessages {
+            channels,
+            window,
+            min_chars,
+            min_similarity,
+            sightings: HashMap::new(),
+        }
+    }
+
+    /// Whether normalized `text` is too short to compare. Link-only and mention-only
+    /// messages normalize to nothing and are never copies, whatever `min_chars` is.
+    fn too_short(&self, text: &[char]) -> bool {
+        text.is_empty() || text.len() < self.min_chars
+    }
+
+    /// Whether `a` and `b` are at least `min_similarity` alike, as one minus their
+    /// Levenshtein distance over the longer length.
+    fn is_copy(&self, a: &[char], b: &[char]) -> bool {
+        let longest = a.len().max(b.len()) as f64;
+        // Nudged so exactly min_similarity alike isn't lost to rounding
+        let max_distance = ((1.0 - self.min_similarity) * longest + 1e-9).floor() as usize;
+        within_distance(a, b, max_distance)
+    }
+
+    /// Remembers `user_id` posting `content` as `message_id` in `channel_id`. When that
+    /// makes `channels` channels with copies of it inside the window, returns every copy,
+    /// which are then forgotten so they're only reported once.
+    pub(crate) fn record(
+        &mut self,
+        user_id: UserId,
+        content: &str,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        now: Instant,
+    ) -> Option<Vec<(ChannelId, MessageId)>> {
+        self.evict_expired(now);
+        let text = normalize(content);
+        if self.too_short(&text) {
+            return None;
+        }
+        let mut sightings = self.sightings.remove(&user_id).unwrap_or_default();
+        // An edited message is seen again, but it's still the one copy
+        sightings.retain(|sighting| sighting.message_id != message_id);
+        sightings.push_back(Sighting {
+            text,
+            channel_id,
+            message_id,
+            at: now,
+        });
+        let text = &sightings[sightings.len() - 1].text;
+        let is_copy: Vec<bool> = sightings
+            .iter()
+            .map(|sighting| self.is_copy(&sighting.text, text))
+            .collect();
+        let copies: Vec<(ChannelId, MessageId)> = sightings
+            .iter()
+            .zip(&is_copy)
+            .filter(|(_, is_copy)| **is_copy)
+            .map(|(sighting, _)| (sighting.channel_id, sighting.message_id))
+            .collect();
+        let channels: HashSet<ChannelId> =
+            copies.iter().map(|(channel_id, _)| *channel_id).collect();
+        if channels.len() < self.channels {
+            self.sightings.insert(user_id, sightings);
+            return None;
+        }
+        let mut is_copy = is_copy.into_iter();
+        sightings.retain(|_| !is_copy.next().unwrap_or(false));
+        if !sightings.is_empty() {
+            self.sightings.insert(user_id, sightings);
+        }
+        Some(copies)
+    }
+
+    /// Channels `user_id` has posted copies of `content` in within the window, counting
+    /// the one it was just recorded in.
+    pub(crate) fn channels_with_copies(&self, user_id: UserId, content: &str) -> usize {
+        let text = normalize(content);
+        if self.too_short(&text) {
+            return 0;
+        }
+        let Some(sightings) = self.sightings.get(&user_id) else {
+            return 0;
+        };
+        sightings
+            .iter()
+            .filter(|sighting| self.is_copy(&sighting.text, &text))
+            .map(|sighting| sighting.channel_id)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    fn evict_expired(&mut self, now: Instant) {
+        let window = self.window;
+        self.sightings.retain(|_, sightings| {
+            while sightings
+                .front()
+                .is_some_and(|sighting| now.saturating_duration_since(sighting.at) >= window)
+            {
+                sightings.pop_front();
+            }
+            !sightings.is_empty()
+        });
+    }
+}
+
+pub(crate) struct RecentMessages;
+
+impl TypeMapKey for RecentMessages {
+    type Value = Arc<RwLock<DuplicateMessages>>;
+}
+
+/// Tracker for the configured duplicate limits, to go in the `TypeMap`.
+pub(crate) fn from_config() -> DuplicateMessages {
+    let (channels, window, min_chars, min_similarity) = spam_detection::duplicate_limits();
+    DuplicateMessages::new(channels, window, min_chars, min_similarity)
+}
+
+/// Channels the author of `message` has recently posted copies of it in.
+pub(crate) async fn channels_with_copies(ctx: &Context, message: &Message) -> usize {
+    let recent_messages = {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<RecentMessages>()
+            .expect("Expected RecentMessages in TypeMap.")
+            .clone()
+    };
+    let recent_messages = recent_messages.read().await;
+    recent_messages.channels_with_copies(message.author.id, message.content.as_str())
+}
+
+/// Records `message` and, if it completes a cross-channel spam run, deletes every copy,
+/// takes the configured action against the author and reports it to the bot channel.
+/// Returns whether `message` was spam. Members with a trusted role are never checked.
+#[instrument(skip_all, ret(level = "debug"))]
+pub(crate) async fn check_duplicates(ctx: &Context, message: &Message) -> bool {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if spam_detection::is_trusted(roles) {
+        return false;
+    }
+    let recent_messages = {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<RecentMessages>()
+            .expect("Expected RecentMessages in TypeMap.")
+            .clone()
+    };
+    let copies = recent_messages.write().await.record(
+        message.author.id,
+        message.content.as_str(),
+        message.channel_id,
+        message.id,
+        Instant::now(),
+    );
+    let Some(copies) = copies else {
+        return false;
+    };
+    let action = spam_detection::duplicate_action();
+    info!(
+        "{} posted the same message in {} channels, taking action {action:?}",
+        message.author.name,
+        copies.len()
+    );
+    if let Err(e) = messaging::remove_duplicates_and_log(ctx, message, &copies, action).await {
+        warn!("Failed to act on duplicate spam due to {e:#}");
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+    const SCAM: &str = "Free Nitro for everyone, claim it here!";
+
+    fn tracker() -> DuplicateMessages {
+        DuplicateMessages::new(3, Duration::from_secs(60), 10, 0.85)
+    }
+
+    /// Records message `id` by `user_id` in `channel`.
+    fn post(
+        tracker: &mut DuplicateMessages,
+        user_id: UserId,
+        content: &str,
+        (channel, id): (u64, u64),
+        at: Instant,
+    ) -> Option<Vec<(ChannelId, MessageId)>> {
+        tracker.record(
+            user_id,
+            content,
+            ChannelId::new(channel),
+            MessageId::new(id),
+            at,
+        )
+    }
+
+    #[test]
+    fn same_message_in_enough_channels_is_caught() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        assert!(post(&mut tracker, ADA, SCAM, (1, 1), now).is_none());
+        let reworded = "free nitro for everyone,   claim it here!";
+        assert!(post(&mut tracker, ADA, reworded, (2, 2), now).is_none());
+        let copies = post(&mut tracker, ADA, SCAM, (3, 3), now).unwrap();
+        assert_eq!(
+            copies,
+            [1, 2, 3].map(|id| (ChannelId::new(id), MessageId::new(id)))
+        );
+        // Reported copies are forgotten
+        assert!(post(&mut tracker, ADA, SCAM, (4, 4), now).is_none());
+    }
+
+    #[test]
+    fn repeats_in_one_channel_by_other_users_or_too_short_are_not_caught() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        for id in 1..=5 {
+            assert!(post(&mut tracker, ADA, SCAM, (1, id), now).is_none());
+        }
+        // Editing a message sees it again
+        assert!(post(&mut tracker, ADA, SCAM, (2, 6), now).is_none());
+        assert!(post(&mut tracker, ADA, SCAM, (2, 6), now).is_none());
+        assert!(post(&mut tracker, BOB, SCAM, (3, 7), now).is_none());
+        for channel in 2..=4 {
+            assert!(post(&mut tracker, ADA, "thanks!", (channel, channel + 10), now).is_none());
+        }
+    }
+
+    #[test]
+    fn link_only_messages_are_never_copies() {
+        let mut tracker = DuplicateMessages::new(3, Duration::from_secs(60), 0, 0.85);
+        let now = Instant::now();
+        for channel in 1..=4 {
+            let link = format!("https://example.com/{channel} <@42>");
+            assert!(post(&mut tracker, ADA, &link, (channel, channel), now).is_none());
+        }
+        assert_eq!(tracker.channels_with_copies(ADA, "https://example.com"), 0);
+    }
+
+    #[test]
+    fn copies_below_the_limit_are_counted() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        assert_eq!(tracker.channels_with_copies(ADA, SCAM), 0);
+        post(&mut tracker, ADA, SCAM, (1, 1), now);
+        post(&mut tracker, ADA, SCAM, (1, 2), now);
+        assert_eq!(tracker.channels_with_copies(ADA, SCAM), 1);
+        post(&mut tracker, ADA, SCAM, (2, 3), now);
+        assert_eq!(tracker.channels_with_copies(ADA, SCAM), 2);
+        assert_eq!(tracker.channels_with_copies(BOB, SCAM), 0);
+        assert_eq!(tracker.channels_with_copies(ADA, "thanks!"), 0);
+    }
+
+    #[test]
+    fn old_messages_slide_out_of_the_window() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        assert!(post(&mut tracker, ADA, SCAM, (1, 1), now).is_none());
+        let later = |secs| now + Duration::from_secs(secs);
+        assert!(post(&mut tracker, ADA, SCAM, (2, 2), later(30)).is_none());
+        // The first copy is a minute old by now, so only two channels count
+        assert!(post(&mut tracker, ADA, SCAM, (3, 3), later(60)).is_none());
+        let copies = post(&mut tracker, ADA, SCAM, (4, 4), later(61)).unwrap();
+        assert_eq!(copies.len(), 3);
+    }
+
+    #[test]
+    fn mutated_copies_are_caught() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        let copies = [
+            "Free Nitro for everyone 🎁 claim it here https://nitro.example/a <@123>",
+            "FREE nitro for everyone! claim it here: https://nitro.example/b",
+            "Free Nitro for everybody, claim it here 🎉 @everyone",
+        ];
+        assert!(post(&mut tracker, ADA, copies[0], (1, 1), now).is_none());
+        assert!(post(
+            &mut tracker,
+            ADA,
+            "Anyone know a good Rust book?",
+            (2, 2),
+            now
+        )
+        .is_none());
+        assert!(post(&mut tracker, ADA, copies[1], (2, 3), now).is_none());
+        let caught = post(&mut tracker, ADA, copies[2], (3, 4), now).unwrap();
+        let ids: Vec<MessageId> = caught.iter().map(|(_, id)| *id).collect();
+        assert_eq!(ids, [1, 3, 4].map(MessageId::new));
+        // The unrelated message is still remembered
+        assert_eq!(tracker.sightings[&ADA].len(), 1);
+    }
+
+    #[test]
+    fn normalizing_strips_links_mentions_spacing_and_case() {
+        let normalized: String =
+            normalize("Hey  <@!42> <#7> Check\nTHIS https://x.example/y?z=1 @here")
+                .into_iter()
+                .collect();
+        assert_eq!(normalized, "hey check this");
+        let obfuscated: String = normalize("ＨＥＹ c\u{200B}hеck  t̷h̸i̵s̶")
+            .into_iter()
+            .collect();
+        assert_eq!(obfuscated, "hey check this");
+    }
+
+    #[test]
+    fn distance_is_bounded_levenshtein() {
+        let chars = |text: &str| text.chars().collect::<Vec<_>>();
+        let (kitten, sitting) = (chars("kitten"), chars("sitting"));
+        assert!(within_distance(&kitten, &sitting, 3));
+        assert!(!within_distance(&kitten, &sitting, 2));
+        assert!(within_distance(&chars(""), &chars("ab"), 2));
+        assert!(!within_distance(&chars("abcdef"), &chars("fedcba"), 4));
+        assert!(within_distance(&chars("abcdef"), &chars("abcdef"), 0));
+    }
+
+    #[test]
+    fn long_messages_stay_cheap() {
+        let mut tracker = DuplicateMessages::new(50, Duration::from_secs(60), 10, 0.85);
+        let now = Instant::now();
+        let long = "free nitro claim now ".repeat(100);
+        for id in 1..=20 {
+            post(
+                &mut tracker,
+                ADA,
+                format!("{long} {id}").as_str(),
+                (id, id),
+                now,
+            );
+        }
+        // A 2000+ char message compared against a full window of them
+        let started = Instant::now();
+        post(
+            &mut tracker,
+            ADA,
+            format!("{long} 21").as_str(),
+            (21, 21),
+            now,
+        );
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn users_without_recent_messages_are_evicted() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        post(&mut tracker, ADA, SCAM, (1, 1), now);
+        post(
+            &mut tracker,
+            BOB,
+            SCAM,
+            (1, 2),
+            now + Duration::from_secs(90),
+        );
+        assert_eq!(tracker.sightings.len(), 1);
+        assert!(tracker.sightings.contains_key(&BOB));
+    }
+}
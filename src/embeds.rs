@@ -0,0 +1,211 @@
+use crate::chunking::PART_DELAY;
+use crate::roadmaps::StructuredRoadmap;
+use serenity::all::{ChannelId, Context, CreateEmbed, CreateMessage, Mentionable, User};
+
+/// Discord's limits on a single embed.
+const MAX_FIELDS: usize = 25;
+const TITLE_LIMIT: usize = 256;
+const DESCRIPTION_LIMIT: usize = 4096;
+const FIELD_NAME_LIMIT: usize = 256;
+const FIELD_VALUE_LIMIT: usize = 1024;
+const EMBED_TOTAL_LIMIT: usize = 6000;
+
+/// One embed's worth of a roadmap, kept apart from serenity's builder so the layout can
+/// be checked in tests.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RoadmapEmbed {
+    pub(crate) title: String,
+    pub(crate) description: String,
+    pub(crate) fields: Vec<(String, String)>,
+}
+
+impl RoadmapEmbed {
+    fn new(title: String, description: String) -> Self {
+        RoadmapEmbed {
+            title,
+            description,
+            fields: vec![],
+        }
+    }
+
+    /// Chars counted towards `EMBED_TOTAL_LIMIT`.
+    fn len(&self) -> usize {
+        self.title.chars().count()
+            + self.description.chars().count()
+            + self
+                .fields
+                .iter()
+                .map(|(name, value)| name.chars().count() + value.chars().count())
+                .sum::<usize>()
+    }
+
+    pub(crate) fn to_embed(&self) -> CreateEmbed {
+        let mut embed = CreateEmbed::new().title(self.title.as_str());
+        if !self.description.is_empty() {
+            embed = embed.description(self.description.as_str());
+        }
+        embed.fields(
+            self.fields
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str(), false)),
+        )
+    }
+}
+
+/// Cuts `text` down to `limit` chars, marking the cut with an ellipsis.
+fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(limit - 1).collect();
+    format!("{}…", kept.trim_end())
+}
+
+/// Lays `roadmap` out as embeds with one field per step, starting another embed once one
+/// is full. Text too long for its slot is truncated.
+pub(crate) fn roadmap_embeds(roadmap: &StructuredRoadmap) -> Vec<RoadmapEmbed> {
+    let title = truncate(roadmap.title.as_str(), TITLE_LIMIT);
+    let mut embeds = vec![];
+    let mut current = RoadmapEmbed::new(
+        title.clone(),
+        truncate(roadmap.intro.as_str(), DESCRIPTION_LIMIT),
+    );
+    for (index, step) in roadmap.steps.iter().enumerate() {
+        let name = truncate(
+            format!("{}. {}", index + 1, step.name).as_str(),
+            FIELD_NAME_LIMIT,
+        );
+        let mut value = match step.duration.trim() {
+            "" => step.description.clone(),
+            duration => format!("{}\n⏱️ {duration}", step.description),
+        };
+        for resource in &step.resources {
+            value.push_str(format!("\n• {resource}").as_str());
+        }
+        // Discord rejects empty field values
+        let value = match truncate(value.trim(), FIELD_VALUE_LIMIT) {
+            value if value.is_empty() => "\u{200b}".to_string(),
+            value => value,
+        };
+        let field_len = name.chars().count() + value.chars().count();
+        if current.fields.len() == MAX_FIELDS || current.len() + field_len > EMBED_TOTAL_LIMIT {
+            embeds.push(current);
+            current = RoadmapEmbed::new(
+                truncate(format!("{title} (continued)").as_str(), TITLE_LIMIT),
+                String::new(),
+            );
+        }
+        current.fields.push((name, value));
+    }
+    embeds.push(current);
+    embeds
+}
+
+/// Sends `roadmap` to `author` in `channel_id`, one embed per message since Discord's
+/// total size limit covers every embed in a message.
+pub(crate) async fn send_roadmap_embeds(
+    ctx: &Context,
+    channel_id: ChannelId,
+    author: &User,
+    roadmap: &StructuredRoadmap,
+) -> anyhow::Result<()> {
+    for (index, embed) in roadmap_embeds(roadmap).iter().enumerate() {
+        let mut reply = CreateMessage::new().embed(embed.to_embed());
+        if index == 0 {
+            reply = reply.content(format!("Hi {},", author.mention()));
+        } else {
+            tokio::time::sleep(PART_DELAY).await;
+        }
+        channel_id.send_message(&ctx.http, reply).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roadmaps::{parse_structured_roadmap, RoadmapStep};
+
+    fn roadmap(steps: usize, description: &str) -> StructuredRoadmap {
+        StructuredRoadmap {
+            title: "Data Science Roadmap".to_string(),
+            intro: "Start with the foundations.".to_string(),
+            steps: (1..=steps)
+                .map(|step| RoadmapStep {
+                    name: format!("Step {step}"),
+                    description: description.to_string(),
+                    duration: "2 weeks".to_string(),
+                    resources: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn json_maps_to_one_field_per_step() {
+        let roadmap = parse_structured_roadmap(
+            r#"{"title": "Python to ML", "intro": "A three month plan.", "steps": [
+                {"name": "Python", "description": "Learn the basics.", "duration": "1 month",
+                 "resources": ["https://docs.python.org/3/tutorial/"]},
+                {"name": "Statistics", "description": "Probability and inference.", "duration": ""}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            roadmap_embeds(&roadmap),
+            vec![RoadmapEmbed {
+                title: "Python to ML".to_string(),
+                description: "A three month plan.".to_string(),
+                fields: vec![
+                    (
+                        "1. Python".to_string(),
+                        "Learn the basics.\n⏱️ 1 month\n• https://docs.python.org/3/tutorial/"
+                            .to_string()
+                    ),
+                    (
+                        "2. Statistics".to_string(),
+                        "Probability and inference.".to_string()
+                    ),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn more_than_25_steps_overflow_into_another_embed() {
+        let embeds = roadmap_embeds(&roadmap(30, "Practice."));
+        assert_eq!(embeds.len(), 2);
+        assert_eq!(embeds[0].fields.len(), 25);
+        assert_eq!(embeds[1].fields.len(), 5);
+        assert_eq!(embeds[1].fields[0].0, "26. Step 26");
+        assert_eq!(embeds[1].title, "Data Science Roadmap (continued)");
+        assert_eq!(roadmap_embeds(&roadmap(25, "Practice.")).len(), 1);
+    }
+
+    #[test]
+    fn long_descriptions_are_truncated_to_field_limit() {
+        let embeds = roadmap_embeds(&roadmap(1, "word ".repeat(500).as_str()));
+        let value = &embeds[0].fields[0].1;
+        assert_eq!(value.chars().count(), FIELD_VALUE_LIMIT);
+        assert!(value.ends_with('…'));
+    }
+
+    #[test]
+    fn embeds_stay_under_total_limit() {
+        let embeds = roadmap_embeds(&roadmap(20, "x".repeat(1_000).as_str()));
+        assert!(embeds.len() > 1);
+        for embed in &embeds {
+            assert!(embed.len() <= EMBED_TOTAL_LIMIT);
+            assert!(embed.fields.len() <= MAX_FIELDS);
+        }
+        let fields: usize = embeds.iter().map(|embed| embed.fields.len()).sum();
+        assert_eq!(fields, 20);
+    }
+
+    #[test]
+    fn empty_step_gets_placeholder_value() {
+        let mut roadmap = roadmap(1, "");
+        roadmap.steps[0].duration.clear();
+        assert_eq!(roadmap_embeds(&roadmap)[0].fields[0].1, "\u{200b}");
+    }
+}
@@ -0,0 +1,414 @@
+use crate::image_spam;
+use crate::spam_detection;
+use anyhow::bail;
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::all::{
+    CommandInteraction, CommandOptionType, Context, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Message, Permissions,
+};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Name of the slash command that shows an archived message.
+pub(crate) const COMMAND_NAME: &str = "evidence";
+
+/// How often records past the retention period are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Longest reply Discord accepts.
+const REPLY_LIMIT: usize = 2_000;
+
+lazy_static! {
+    static ref EVIDENCE_ARCHIVE: EvidenceArchive =
+        EvidenceArchive::open(&spam_detection::evidence_path())
+            .expect("Failed to open evidence archive");
+}
+
+/// A file attached to a deleted message, with the SHA-256 of its contents when it could
+/// be downloaded.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ArchivedAttachment {
+    pub(crate) url: String,
+    pub(crate) sha256: Option<String>,
+}
+
+/// A message as it was just before the bot deleted it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct EvidenceRecord {
+    pub(crate) author_id: u64,
+    pub(crate) author_name: String,
+    pub(crate) channel_id: u64,
+    pub(crate) message_id: u64,
+    pub(crate) content: String,
+    pub(crate) attachments: Vec<ArchivedAttachment>,
+    /// The rule or classifier verdict it was deleted for.
+    pub(crate) reason: String,
+    /// Unix times it was sent and archived.
+    pub(crate) sent_at: i64,
+    pub(crate) archived_at: i64,
+}
+
+impl EvidenceRecord {
+    /// What `/evidence` replies with, cut down to fit a message.
+    fn describe(&self, id: i64) -> String {
+        let attachments: String = self
+            .attachments
+            .iter()
+            .map(|attachment| {
+                let sha256 = attachment.sha256.as_deref().unwrap_or("not downloaded");
+                format!("\n- {} (sha256 {sha256})", attachment.url)
+            })
+            .collect();
+        let header = format!(
+            "**Evidence #{id}**\nAuthor: <@{}> ({}, {})\nChannel: <#{}>, message {}\n\
+             Sent <t:{}:f>, archived <t:{}:f>\nReason: {}\nAttachments:{}\n",
+            self.author_id,
+            self.author_name,
+            self.author_id,
+            self.channel_id,
+            self.message_id,
+            self.sent_at,
+            self.archived_at,
+            self.reason,
+            if attachments.is_empty() {
+                " none"
+            } else {
+                attachments.as_str()
+            },
+        );
+        // Keep the original intact without letting it close the code block
+        let content = self.content.replace("```", "`\u{200B}``");
+        let room = REPLY_LIMIT.saturating_sub(header.chars().count() + "```\n\n```".len());
+        let content = if content.chars().count() > room {
+            let kept: String = content.chars().take(room.saturating_sub(1)).collect();
+            format!("{kept}…")
+        } else {
+            content
+        };
+        format!("{header}```\n{content}\n```")
+    }
+}
+
+/// Deleted messages kept in a SQLite file, so appeals can be checked against what was
+/// actually posted.
+pub(crate) struct EvidenceArchive {
+    connection: Mutex<Connection>,
+}
+
+impl EvidenceArchive {
+    /// Opens the archive at `path`, creating the file and tables if needed.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS evidence (
+                id INTEGER PRIMARY KEY,
+                author_id INTEGER NOT NULL,
+                author_name TEXT NOT NULL,
+                channel_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                content TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                sent_at INTEGER NOT NULL,
+                archived_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS evidence_attachments (
+                evidence_id INTEGER NOT NULL REFERENCES evidence (id) ON DELETE CASCADE,
+                url TEXT NOT NULL,
+                sha256 TEXT
+            );
+            CREATE INDEX IF NOT EXISTS evidence_by_age ON evidence (archived_at);
+            PRAGMA foreign_keys = ON;",
+        )?;
+        Ok(EvidenceArchive {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Saves `record`, returning the id it can be looked up by.
+    pub(crate) fn archive(&self, record: &EvidenceRecord) -> anyhow::Result<i64> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO evidence
+             (author_id, author_name, channel_id, message_id, content, reason, sent_at, archived_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                record.author_id as i64,
+                record.author_name,
+                record.channel_id as i64,
+                record.message_id as i64,
+                record.content,
+                record.reason,
+                record.sent_at,
+                record.archived_at,
+            ],
+        )?;
+        let id = transaction.last_insert_rowid();
+        for attachment in &record.attachments {
+            transaction.execute(
+                "INSERT INTO evidence_attachments (evidence_id, url, sha256) VALUES (?1, ?2, ?3)",
+                params![id, attachment.url, attachment.sha256],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    /// The record archived as `id`, if it hasn't been purged.
+    pub(crate) fn get(&self, id: i64) -> anyhow::Result<Option<EvidenceRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let record = connection
+            .query_row(
+                "SELECT author_id, author_name, channel_id, message_id, content, reason,
+                        sent_at, archived_at
+                 FROM evidence WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok(EvidenceRecord {
+                        author_id: row.get::<_, i64>(0)? as u64,
+                        author_name: row.get(1)?,
+                        channel_id: row.get::<_, i64>(2)? as u64,
+                        message_id: row.get::<_, i64>(3)? as u64,
+                        content: row.get(4)?,
+                        attachments: vec![],
+                        reason: row.get(5)?,
+                        sent_at: row.get(6)?,
+                        archived_at: row.get(7)?,
+                    })
+                },
+            )
+            .optional()?;
+        let Some(mut record) = record else {
+            return Ok(None);
+        };
+        let mut attachments = connection.prepare(
+            "SELECT url, sha256 FROM evidence_attachments WHERE evidence_id = ?1 ORDER BY rowid",
+        )?;
+        record.attachments = attachments
+            .query_map(params![id], |row| {
+                Ok(ArchivedAttachment {
+                    url: row.get(0)?,
+                    sha256: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(Some(record))
+    }
+
+    /// Deletes every record archived before unix time `cutoff`, returning how many.
+    pub(crate) fn purge(&self, cutoff: i64) -> anyhow::Result<usize> {
+        let purged = self.connection.lock().unwrap().execute(
+            "DELETE FROM evidence WHERE archived_at < ?1",
+            params![cutoff],
+        )?;
+        Ok(purged)
+    }
+}
+
+/// Opens the archive now so a bad path stops the bot at startup.
+pub(crate) fn init_archive() {
+    lazy_static::initialize(&EVIDENCE_ARCHIVE);
+}
+
+/// Downloads the file at `url` and hashes it, giving up on anything over `max_bytes`.
+async fn hash_attachment(url: &str, max_bytes: usize, timeout: Duration) -> anyhow::Result<String> {
+    let bytes = image_spam::download(url, max_bytes, timeout).await?;
+    Ok(format!("{:x}", Sha256::digest(&bytes)))
+}
+
+/// Starts archiving `message` as it is, just before it's deleted for `reason`, so the
+/// deletion never waits on downloads. The task gives the id of the record. Attachments
+/// are hashed if they're small enough and download in time, and archived by address
+/// alone if not. Failing to archive is logged.
+pub(crate) fn archive(message: &Message, reason: &str) -> JoinHandle<Option<i64>> {
+    let message = message.clone();
+    let reason = reason.to_string();
+    tokio::spawn(async move { archive_now(&message, reason).await })
+}
+
+async fn archive_now(message: &Message, reason: String) -> Option<i64> {
+    let (max_bytes, timeout) = spam_detection::evidence_attachment_settings();
+    let hashes = futures::future::join_all(message.attachments.iter().map(|attachment| async {
+        if attachment.size as usize > max_bytes {
+            return None;
+        }
+        hash_attachment(attachment.url.as_str(), max_bytes, timeout)
+            .await
+            .inspect_err(|e| warn!("Failed to hash {} due to {e:#}", attachment.filename))
+            .ok()
+    }))
+    .await;
+    let record = EvidenceRecord {
+        author_id: message.author.id.get(),
+        author_name: message.author.name.clone(),
+        channel_id: message.channel_id.get(),
+        message_id: message.id.get(),
+        content: message.content.clone(),
+        attachments: message
+            .attachments
+            .iter()
+            .zip(hashes)
+            .map(|(attachment, sha256)| ArchivedAttachment {
+                url: attachment.url.clone(),
+                sha256,
+            })
+            .collect(),
+        reason,
+        sent_at: message.timestamp.unix_timestamp(),
+        archived_at: chrono::Utc::now().timestamp(),
+    };
+    tokio::task::spawn_blocking(move || EVIDENCE_ARCHIVE.archive(&record))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|archived| archived)
+        .inspect_err(|e| warn!("Failed to archive message {} due to {e:#}", message.id))
+        .ok()
+}
+
+/// Purges records older than `evidence_retention_days` for as long as the bot runs.
+pub(crate) async fn purge_forever() {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let cutoff =
+            chrono::Utc::now().timestamp() - spam_detection::evidence_retention().as_secs() as i64;
+        let purged = tokio::task::spawn_blocking(move || EVIDENCE_ARCHIVE.purge(cutoff))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|purged| purged);
+        match purged {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {purged} archived message(s)"),
+            Err(e) => warn!("Failed to purge archived messages due to {e:#}"),
+        }
+    }
+}
+
+/// `/evidence id:<id>`, for members who can manage the server.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("Show a message the bot deleted")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Integer,
+                "id",
+                "The evidence number from the mod log",
+            )
+            .min_int_value(1)
+            .required(true),
+        )
+}
+
+/// Replies privately with the archived message a `/evidence` command asks for.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let Some(id) = command
+        .data
+        .options
+        .iter()
+        .find(|option| option.name == "id")
+        .and_then(|option| option.value.as_i64())
+    else {
+        bail!("/{COMMAND_NAME} was sent without an id");
+    };
+    let record = tokio::task::spawn_blocking(move || EVIDENCE_ARCHIVE.get(id)).await??;
+    let reply = match record {
+        Some(record) => record.describe(id),
+        None => format!("There's no evidence #{id}, it may have been purged."),
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn record(archived_at: i64) -> EvidenceRecord {
+        EvidenceRecord {
+            author_id: 42,
+            author_name: "spammer".to_string(),
+            channel_id: 7,
+            message_id: 100,
+            content: "Claim your free nitro".to_string(),
+            attachments: vec![
+                ArchivedAttachment {
+                    url: "https://cdn.example/nitro.png".to_string(),
+                    sha256: Some("ab12".to_string()),
+                },
+                ArchivedAttachment {
+                    url: "https://cdn.example/huge.mp4".to_string(),
+                    sha256: None,
+                },
+            ],
+            reason: "matched scam rule \"nitro\"".to_string(),
+            sent_at: 1_700_000_000,
+            archived_at,
+        }
+    }
+
+    fn archive(name: &str) -> EvidenceArchive {
+        let path = env::temp_dir().join(format!("{name}.db"));
+        let _ = std::fs::remove_file(&path);
+        EvidenceArchive::open(&path).unwrap()
+    }
+
+    #[test]
+    fn archived_messages_come_back_whole() {
+        let archive = archive("archived_messages_come_back_whole");
+        let id = archive.archive(&record(1_700_000_001)).unwrap();
+        assert_eq!(archive.get(id).unwrap(), Some(record(1_700_000_001)));
+        assert_eq!(archive.get(id + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn only_old_records_are_purged() {
+        let archive = archive("only_old_records_are_purged");
+        let old = archive.archive(&record(1_000)).unwrap();
+        let new = archive.archive(&record(5_000)).unwrap();
+        assert_eq!(archive.purge(2_000).unwrap(), 1);
+        assert_eq!(archive.get(old).unwrap(), None);
+        assert!(archive.get(new).unwrap().is_some());
+        let orphans: i64 = archive
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM evidence_attachments WHERE evidence_id = ?1",
+                params![old],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(orphans, 0);
+    }
+
+    #[test]
+    fn long_evidence_fits_a_reply() {
+        let mut long = record(1_700_000_001);
+        long.content = "spam ".repeat(1_000);
+        let reply = long.describe(3);
+        assert!(reply.starts_with("**Evidence #3**"));
+        assert!(reply.contains("huge.mp4 (sha256 not downloaded)"));
+        assert!(reply.chars().count() <= REPLY_LIMIT);
+        assert!(reply.ends_with("…\n```"));
+    }
+}
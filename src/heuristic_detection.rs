@@ -0,0 +1,224 @@
+use crate::roadmaps::{RequestingRoadmap, RoadmapError};
+use crate::utilities::Role;
+use serde::Deserialize;
+use serenity::async_trait;
+use std::time::Instant;
+
+/// Name recorded as the "model" of heuristic detections.
+pub(crate) const HEURISTIC_MODEL: &str = "heuristic";
+
+/// How roadmap requests are detected.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DetectionMode {
+    /// Ask the detection model about every message.
+    Llm,
+    /// Only match `heuristic_keywords`, never calling OpenAI.
+    Heuristic,
+    /// Match `heuristic_keywords` first, only asking the model about messages that don't.
+    HeuristicFirst,
+}
+
+pub(crate) fn default_keywords() -> Vec<String> {
+    [
+        "can i get a roadmap",
+        "can i have a roadmap",
+        "roadmap for",
+        "learning path",
+        "where do i start",
+        "where should i start",
+        "how do i get started",
+        "how do i get into",
+        "what should i learn",
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+/// Anything that can decide whether a message asks for a roadmap.
+#[async_trait]
+pub(crate) trait RoadmapDetector: Send + Sync {
+    async fn detect(
+        &self,
+        message: String,
+        context: Vec<(Role, String)>,
+    ) -> Result<RequestingRoadmap, RoadmapError>;
+}
+
+/// Detects roadmap requests by phrases in the message alone, for free and offline.
+#[derive(Debug, Clone)]
+pub(crate) struct HeuristicDetector {
+    keywords: Vec<String>,
+}
+
+/// Lowercased with runs of whitespace collapsed, so phrases match however they're typed.
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl HeuristicDetector {
+    pub(crate) fn new(keywords: &[String]) -> Self {
+        HeuristicDetector {
+            keywords: keywords
+                .iter()
+                .map(|keyword| normalize(keyword))
+                .filter(|keyword| !keyword.is_empty())
+                .collect(),
+        }
+    }
+
+    /// The first keyword `message` contains, if any.
+    pub(crate) fn matching_keyword(&self, message: &str) -> Option<&str> {
+        let message = normalize(message);
+        self.keywords
+            .iter()
+            .find(|keyword| message.contains(keyword.as_str()))
+            .map(String::as_str)
+    }
+}
+
+#[async_trait]
+impl RoadmapDetector for HeuristicDetector {
+    async fn detect(
+        &self,
+        message: String,
+        _context: Vec<(Role, String)>,
+    ) -> Result<RequestingRoadmap, RoadmapError> {
+        let started = Instant::now();
+        let (is_roadmap, confidence, reason) = match self.matching_keyword(message.as_str()) {
+            Some(keyword) => (true, 1.0, format!("Mentions \"{keyword}\"")),
+            None => (false, 0.0, "Mentions no roadmap keywords".to_string()),
+        };
+        Ok(RequestingRoadmap {
+            reason,
+            is_roadmap,
+            confidence,
+            is_followup: false,
+            usage: None,
+            model: HEURISTIC_MODEL.to_string(),
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Detects with `heuristic`, `llm` or both, as `mode` says. In `HeuristicFirst` a keyword
+/// match is taken as is and only messages without one are sent to `llm`.
+pub(crate) async fn detect_with(
+    mode: DetectionMode,
+    heuristic: &dyn RoadmapDetector,
+    llm: &dyn RoadmapDetector,
+    message: String,
+    context: Vec<(Role, String)>,
+) -> Result<RequestingRoadmap, RoadmapError> {
+    match mode {
+        DetectionMode::Llm => llm.detect(message, context).await,
+        DetectionMode::Heuristic => heuristic.detect(message, context).await,
+        DetectionMode::HeuristicFirst => {
+            let detection = heuristic.detect(message.clone(), context.clone()).await?;
+            if detection.is_roadmap {
+                Ok(detection)
+            } else {
+                llm.detect(message, context).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Says every message is a roadmap request, as a stand-in for the model.
+    struct AlwaysRoadmap;
+
+    #[async_trait]
+    impl RoadmapDetector for AlwaysRoadmap {
+        async fn detect(
+            &self,
+            _message: String,
+            _context: Vec<(Role, String)>,
+        ) -> Result<RequestingRoadmap, RoadmapError> {
+            Ok(RequestingRoadmap {
+                reason: "Asked the model".to_string(),
+                is_roadmap: true,
+                confidence: 0.9,
+                is_followup: false,
+                usage: None,
+                model: "gpt-4o-mini".to_string(),
+                elapsed: Duration::ZERO,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn detects_requests_by_keyword() {
+        let detector = HeuristicDetector::new(&default_keywords());
+        for message in [
+            "Can I get a roadmap for data science?",
+            "Is there a learning path for Rust?",
+            "I want to do ML but where do I   start",
+            "How do I get into backend development?",
+            "WHAT SHOULD I LEARN after Python",
+        ] {
+            let detection = detector.detect(message.to_string(), vec![]).await.unwrap();
+            assert!(detection.is_roadmap, "{message}");
+            assert_eq!(detection.confidence, 1.0);
+            assert_eq!(detection.model, HEURISTIC_MODEL);
+        }
+        for message in [
+            "Thanks, that roadmap helped a lot",
+            "Where do I find the meeting link?",
+            "Learning Rust has been fun",
+            "",
+        ] {
+            let detection = detector.detect(message.to_string(), vec![]).await.unwrap();
+            assert!(!detection.is_roadmap, "{message}");
+            assert_eq!(detection.confidence, 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn mode_picks_the_detector() {
+        let heuristic = HeuristicDetector::new(&default_keywords());
+        for (mode, message, model) in [
+            (DetectionMode::Llm, "Can I get a roadmap?", "gpt-4o-mini"),
+            (
+                DetectionMode::Heuristic,
+                "Can I get a roadmap?",
+                HEURISTIC_MODEL,
+            ),
+            (DetectionMode::Heuristic, "Hello there", HEURISTIC_MODEL),
+            (
+                DetectionMode::HeuristicFirst,
+                "Can I get a roadmap?",
+                HEURISTIC_MODEL,
+            ),
+            (DetectionMode::HeuristicFirst, "Hello there", "gpt-4o-mini"),
+        ] {
+            let detection = detect_with(
+                mode,
+                &heuristic,
+                &AlwaysRoadmap,
+                message.to_string(),
+                vec![],
+            )
+            .await
+            .unwrap();
+            assert_eq!(detection.model, model, "{mode:?} {message}");
+        }
+    }
+
+    #[test]
+    fn keywords_are_configurable() {
+        let detector = HeuristicDetector::new(&["Study  Plan".to_string(), " ".to_string()]);
+        assert_eq!(
+            detector.matching_keyword("any study plan for SQL?"),
+            Some("study plan")
+        );
+        assert_eq!(detector.matching_keyword("can I get a roadmap?"), None);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::evidence;
+use crate::messaging;
+use crate::metrics;
+use crate::mod_log::{self, ModLogEntry};
+use crate::spam_detection;
+use crate::HONEY_POT_CHANNEL;
+use anyhow::Context as _;
+use serde::Deserialize;
+use serenity::all::{ChannelId, Context, Message, RoleId, UserId};
+use tracing::{error, info, instrument};
+
+/// Why honeypot messages are deleted, for the evidence archive and mod log.
+const HONEYPOT_REASON: &str = "posted in a honeypot channel";
+
+/// What's done to someone who posts in a honeypot.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HoneypotAction {
+    Ban,
+    /// Ban and unban straight away, removing their recent messages without keeping them
+    /// out for good.
+    Softban,
+}
+
+/// Channels only spam bots post in, and who's safe from them.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct HoneypotConfig {
+    pub(crate) channels: Vec<u64>,
+    pub(crate) action: HoneypotAction,
+    /// Roles and users never banned for posting in a honeypot, on top of trusted roles.
+    pub(crate) exempt_roles: Vec<u64>,
+    pub(crate) exempt_users: Vec<u64>,
+    /// Only log what would have been done.
+    pub(crate) dry_run: bool,
+}
+
+impl Default for HoneypotConfig {
+    fn default() -> Self {
+        HoneypotConfig {
+            channels: vec![HONEY_POT_CHANNEL],
+            action: HoneypotAction::Ban,
+            exempt_roles: vec![],
+            exempt_users: vec![],
+            dry_run: false,
+        }
+    }
+}
+
+/// What to do about a message, from where it was posted and who posted it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HoneypotResponse {
+    /// Not posted in a honeypot.
+    Ignore,
+    /// Posted in a honeypot by someone on the allowlist.
+    Exempt,
+    Act(HoneypotAction),
+    /// What would have been done outside of dry-run mode.
+    DryRun(HoneypotAction),
+}
+
+pub(crate) fn respond(
+    honeypot: &HoneypotConfig,
+    channel_id: ChannelId,
+    user_id: UserId,
+    roles: &[RoleId],
+    trusted: bool,
+) -> HoneypotResponse {
+    if !honeypot.channels.contains(&channel_id.get()) {
+        HoneypotResponse::Ignore
+    } else if trusted
+        || honeypot.exempt_users.contains(&user_id.get())
+        || roles
+            .iter()
+            .any(|role_id| honeypot.exempt_roles.contains(&role_id.get()))
+    {
+        HoneypotResponse::Exempt
+    } else if honeypot.dry_run {
+        HoneypotResponse::DryRun(honeypot.action)
+    } else {
+        HoneypotResponse::Act(honeypot.action)
+    }
+}
+
+async fn take_action(
+    ctx: &Context,
+    message: &Message,
+    action: HoneypotAction,
+) -> anyhow::Result<()> {
+    let guild_id = message
+        .guild_id
+        .context("Honeypot message outside a guild")?;
+    messaging::delete_message(ctx, message).await?;
+    messaging::ban_user(ctx, &guild_id, &message.author.id).await?;
+    if action == HoneypotAction::Softban {
+        guild_id.unban(&ctx.http, message.author.id).await?;
+    }
+    Ok(())
+}
+
+/// Deletes a message posted in a honeypot and bans its author, or only logs it in
+/// dry-run mode. Returns whether it was dealt with, so nothing else needs to look at it.
+/// Messages from exempt members go through the usual checks.
+#[instrument(skip_all, ret(level = "debug"))]
+pub(crate) async fn check_honeypot(ctx: &Context, message: &Message) -> bool {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    let response = respond(
+        spam_detection::honeypot(),
+        message.channel_id,
+        message.author.id,
+        roles,
+        spam_detection::is_trusted(roles),
+    );
+    let (action, dry_run) = match response {
+        HoneypotResponse::Ignore => return false,
+        HoneypotResponse::Exempt => {
+            info!(
+                "{} posted in a honeypot channel but is exempt",
+                message.author.name
+            );
+            return false;
+        }
+        HoneypotResponse::Act(action) => (action, false),
+        HoneypotResponse::DryRun(action) => (action, true),
+    };
+    info!("Received message in Honeypot channel - {action:?}, dry run: {dry_run}");
+    if !dry_run {
+        let archiving = evidence::archive(message, HONEYPOT_REASON);
+        match take_action(ctx, message, action).await {
+            Ok(()) => {
+                let evidence_id = archiving.await.ok().flatten();
+                let name = match action {
+                    HoneypotAction::Ban => "ban",
+                    HoneypotAction::Softban => "softban",
+                };
+                metrics::increment(metrics::SPAM_ACTIONS, &[("action", name)]);
+                let taken = match action {
+                    HoneypotAction::Ban => "Deleted message and banned them",
+                    HoneypotAction::Softban => "Deleted message and softbanned them",
+                };
+                mod_log::record(
+                    &ctx.http,
+                    &ModLogEntry::for_message(message, taken, HONEYPOT_REASON)
+                        .evidence(evidence_id),
+                )
+                .await;
+            }
+            Err(e) => error!("Failed to {action:?} honeypot poster due to {e:#}"),
+        }
+    }
+    if let Err(e) = messaging::log_honeypot(ctx, message, action, dry_run).await {
+        error!("Failed to log honeypot message due to {e}");
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HONEYPOT: ChannelId = ChannelId::new(10);
+    const ADA: UserId = UserId::new(1);
+
+    fn honeypot() -> HoneypotConfig {
+        HoneypotConfig {
+            channels: vec![HONEYPOT.get()],
+            action: HoneypotAction::Softban,
+            exempt_roles: vec![7],
+            exempt_users: vec![2],
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn only_honeypot_channels_count() {
+        assert_eq!(
+            respond(&honeypot(), ChannelId::new(11), ADA, &[], false),
+            HoneypotResponse::Ignore
+        );
+        assert_eq!(
+            respond(&honeypot(), HONEYPOT, ADA, &[RoleId::new(8)], false),
+            HoneypotResponse::Act(HoneypotAction::Softban)
+        );
+    }
+
+    #[test]
+    fn allowlisted_posters_are_never_banned() {
+        let honeypot = honeypot();
+        for (user_id, roles, trusted) in [
+            (UserId::new(2), vec![], false),
+            (ADA, vec![RoleId::new(7)], false),
+            (ADA, vec![], true),
+        ] {
+            assert_eq!(
+                respond(&honeypot, HONEYPOT, user_id, &roles, trusted),
+                HoneypotResponse::Exempt
+            );
+        }
+    }
+
+    #[test]
+    fn dry_run_only_says_what_it_would_do() {
+        let honeypot = HoneypotConfig {
+            dry_run: true,
+            ..honeypot()
+        };
+        assert_eq!(
+            respond(&honeypot, HONEYPOT, ADA, &[], false),
+            HoneypotResponse::DryRun(HoneypotAction::Softban)
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use crate::messaging;
+use crate::spam_detection;
+use crate::strikes::Severity;
+use anyhow::{bail, ensure};
+use image::imageops::FilterType;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    Attachment, CommandInteraction, CommandType, Context, CreateCommand, EditInteractionResponse,
+    Message, Permissions, ResolvedTarget,
+};
+use serenity::prelude::TypeMapKey;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{info, instrument, warn};
+
+lazy_static! {
+    /// Shared by every download, so connections to Discord's CDN are reused.
+    static ref DOWNLOAD_CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+/// Name of the message context-menu command that marks a message's images as spam.
+pub(crate) const COMMAND_NAME: &str = "Mark image as spam";
+
+/// Difference hash of an image: shrunk to 9x8 greyscale, each bit says whether a pixel is
+/// brighter than the one to its right. Resizing, recompressing and small edits barely
+/// change it.
+pub(crate) fn dhash(bytes: &[u8]) -> anyhow::Result<u64> {
+    let image = image::load_from_memory(bytes)?
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = image.get_pixel(x, y)[0] > image.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(brighter);
+        }
+    }
+    Ok(hash)
+}
+
+fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Hashes of images marked as spam, saved so they're still caught after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct SpamImages {
+    pub(crate) hashes: BTreeSet<u64>,
+}
+
+impl SpamImages {
+    /// Loads the hashes last saved to `path`, or none if nothing has been saved yet.
+    pub(crate) fn load(path: &Path) -> SpamImages {
+        match std::fs::read_to_string(path) {
+            Ok(saved) => serde_json::from_str(&saved)
+                .inspect_err(|e| warn!("Ignoring unreadable spam images {}: {e}", path.display()))
+                .unwrap_or_default(),
+            Err(_) => SpamImages::default(),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// The distance to the closest known spam image, if one is within `max_distance`.
+    pub(crate) fn closest(&self, hash: u64, max_distance: u32) -> Option<u32> {
+        self.hashes
+            .iter()
+            .map(|&known| hamming_distance(known, hash))
+            .filter(|&distance| distance <= max_distance)
+            .min()
+    }
+}
+
+pub(crate) struct KnownSpamImages;
+
+impl TypeMapKey for KnownSpamImages {
+    type Value = Arc<RwLock<SpamImages>>;
+}
+
+pub(crate) fn saved_images() -> SpamImages {
+    SpamImages::load(&spam_detection::spam_images_path())
+}
+
+async fn spam_images(ctx: &Context) -> Arc<RwLock<SpamImages>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<KnownSpamImages>()
+        .expect("Expected KnownSpamImages in TypeMap.")
+        .clone()
+}
+
+fn is_image(attachment: &Attachment) -> bool {
+    attachment
+        .content_type
+        .as_deref()
+        .is_some_and(|content_type| content_type.starts_with("image/"))
+        || attachment.width.is_some()
+}
+
+/// Downloads the file at `url` within `timeout`, giving up as soon as it's over
+/// `max_bytes` whether or not the server said how big it is.
+pub(crate) async fn download(
+    url: &str,
+    max_bytes: usize,
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let mut response = DOWNLOAD_CLIENT
+        .get(url)
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    if let Some(length) = response.content_length() {
+        ensure!(length as usize <= max_bytes, "file is {length} bytes");
+    }
+    let mut bytes = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        ensure!(bytes.len() <= max_bytes, "file is over {max_bytes} bytes");
+    }
+    Ok(bytes)
+}
+
+/// Downloads an image of at most `max_bytes` within `timeout` and hashes it on a blocking
+/// thread, keeping decoding off the event loop.
+pub(crate) async fn hash_image(
+    url: &str,
+    max_bytes: usize,
+    timeout: Duration,
+) -> anyhow::Result<u64> {
+    let bytes = download(url, max_bytes, timeout).await?;
+    tokio::task::spawn_blocking(move || dhash(&bytes)).await?
+}
+
+/// Hashes of the image attachments on `message` that aren't too big, skipping any that
+/// fail to download or decode.
+async fn attachment_hashes(message: &Message) -> Vec<u64> {
+    let (max_bytes, timeout, _) = spam_detection::image_settings();
+    let mut hashes = vec![];
+    for attachment in message
+        .attachments
+        .iter()
+        .filter(|attachment| is_image(attachment))
+    {
+        if attachment.size as usize > max_bytes {
+            continue;
+        }
+        match hash_image(attachment.url.as_str(), max_bytes, timeout).await {
+            Ok(hash) => hashes.push(hash),
+            Err(e) => warn!("Failed to hash {} due to {e:#}", attachment.filename),
+        }
+    }
+    hashes
+}
+
+/// Deletes `message` and escalates against its author if one of its images is a known scam
+/// image. Meant to be spawned, so slow downloads never hold up other checks. Members
+/// with a trusted role are never checked.
+#[instrument(skip_all)]
+pub(crate) async fn check_images(ctx: Context, message: Message) {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if spam_detection::is_trusted(roles) || !message.attachments.iter().any(is_image) {
+        return;
+    }
+    let (_, _, max_distance) = spam_detection::image_settings();
+    let hashes = attachment_hashes(&message).await;
+    let closest = {
+        let spam_images = spam_images(&ctx).await;
+        let spam_images = spam_images.read().await;
+        hashes
+            .iter()
+            .filter_map(|&hash| spam_images.closest(hash, max_distance))
+            .min()
+    };
+    let Some(distance) = closest else {
+        return;
+    };
+    info!(
+        "Removing message - {} posted a known scam image ({distance} bits off)",
+        message.author.name
+    );
+    if let Err(e) =
+        messaging::remove_and_escalate(&ctx, &message, "posting a known scam image", Severity::High)
+            .await
+    {
+        warn!("Failed to remove scam image due to {e:#}");
+    }
+}
+
+/// "Mark image as spam" on a message, for members who can manage messages.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .kind(CommandType::Message)
+        .default_member_permissions(Permissions::MANAGE_MESSAGES)
+        .dm_permission(false)
+}
+
+/// Adds the hashes of the chosen message's images to the known spam images, saves them
+/// and replies privately.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let Some(ResolvedTarget::Message(message)) = command.data.target() else {
+        bail!("{COMMAND_NAME} was used without a message");
+    };
+    // Downloading the images can take longer than Discord waits for a reply
+    command.defer_ephemeral(&ctx.http).await?;
+    let hashes = attachment_hashes(message).await;
+    let reply = if hashes.is_empty() {
+        "That message has no images I could read.".to_string()
+    } else {
+        let spam_images = spam_images(ctx).await;
+        let mut spam_images = spam_images.write().await;
+        spam_images.hashes.extend(&hashes);
+        spam_images.save(&spam_detection::spam_images_path())?;
+        format!(
+            "Marked {} image(s) as spam, {} known in all.",
+            hashes.len(),
+            spam_images.hashes.len()
+        )
+    };
+    command
+        .edit_response(&ctx.http, EditInteractionResponse::new().content(reply))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+    use std::env;
+    use std::io::Cursor;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A diagonal gradient with a bright block, encoded as `format`.
+    fn screenshot(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            let shade = ((x * 255 / width + y * 128 / height) % 256) as u8;
+            if x > width / 2 && y < height / 3 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([shade, shade / 2, 255 - shade])
+            }
+        });
+        let mut bytes = Cursor::new(vec![]);
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut bytes, format)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn resized_and_recompressed_copies_hash_alike() {
+        let original = dhash(&screenshot(400, 300, ImageFormat::Png)).unwrap();
+        let resized = dhash(&screenshot(200, 150, ImageFormat::Jpeg)).unwrap();
+        assert!(hamming_distance(original, resized) <= 6);
+        let flipped = {
+            let image = image::load_from_memory(&screenshot(400, 300, ImageFormat::Png))
+                .unwrap()
+                .fliph();
+            let mut bytes = Cursor::new(vec![]);
+            image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+            dhash(bytes.get_ref()).unwrap()
+        };
+        assert!(hamming_distance(original, flipped) > 6);
+    }
+
+    #[tokio::test]
+    async fn downloads_stop_at_the_limit_without_a_length() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = [0; 1024];
+                let _ = socket.read(&mut buffer).await;
+                // No Content-Length, so only the body's end says how big it is
+                let _ = socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n")
+                    .await;
+                let _ = socket.write_all(&[0; 4096]).await;
+            }
+        });
+        let url = format!("http://{address}/image.png");
+        let timeout = Duration::from_secs(5);
+        assert_eq!(download(&url, 4096, timeout).await.unwrap().len(), 4096);
+        assert!(download(&url, 1024, timeout).await.is_err());
+    }
+
+    #[test]
+    fn non_images_fail_to_hash() {
+        assert!(dhash(b"not an image").is_err());
+    }
+
+    #[test]
+    fn closest_match_within_distance() {
+        let spam_images = SpamImages {
+            hashes: BTreeSet::from([0b1111, u64::MAX]),
+        };
+        assert_eq!(spam_images.closest(0b0111, 2), Some(1));
+        assert_eq!(spam_images.closest(0, 3), None);
+        assert_eq!(SpamImages::default().closest(0, 64), None);
+    }
+
+    #[test]
+    fn spam_images_survive_restart() {
+        let path = env::temp_dir().join("spam_images_survive_restart.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(SpamImages::load(&path), SpamImages::default());
+        let spam_images = SpamImages {
+            hashes: BTreeSet::from([1, u64::MAX]),
+        };
+        spam_images.save(&path).unwrap();
+        assert_eq!(SpamImages::load(&path), spam_images);
+    }
+}
@@ -0,0 +1,238 @@
+use serenity::all::{Context, MessageId, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing::info;
+
+/// Roadmaps being written, by the message that asked for them, so deleting that message
+/// can stop the roadmap.
+pub(crate) struct InFlightRoadmaps;
+
+impl TypeMapKey for InFlightRoadmaps {
+    type Value = Arc<Mutex<HashMap<MessageId, Arc<Notify>>>>;
+}
+
+async fn in_flight(ctx: &Context) -> Arc<Mutex<HashMap<MessageId, Arc<Notify>>>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<InFlightRoadmaps>()
+        .expect("Expected InFlightRoadmaps in TypeMap.")
+        .clone()
+}
+
+/// A roadmap being written, tracked in `InFlightRoadmaps` until dropped however it ended.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    requests: Arc<Mutex<HashMap<MessageId, Arc<Notify>>>>,
+    message_id: MessageId,
+    cancelled: Arc<Notify>,
+}
+
+impl InFlight {
+    /// Notified if the request is deleted.
+    pub(crate) fn cancelled(&self) -> &Notify {
+        &self.cancelled
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().remove(&self.message_id);
+    }
+}
+
+/// Starts tracking the roadmap `message_id` asked for, until the returned `InFlight` is
+/// dropped.
+pub(crate) async fn start(ctx: &Context, message_id: MessageId) -> InFlight {
+    let cancelled = Arc::new(Notify::new());
+    let requests = in_flight(ctx).await;
+    requests
+        .lock()
+        .unwrap()
+        .insert(message_id, cancelled.clone());
+    InFlight {
+        requests,
+        message_id,
+        cancelled,
+    }
+}
+
+/// Stops the roadmap `message_id` asked for, if one is underway.
+pub(crate) async fn cancel(ctx: &Context, message_id: MessageId) {
+    if let Some(cancelled) = in_flight(ctx).await.lock().unwrap().get(&message_id) {
+        info!("Roadmap request {message_id} was deleted, cancelling its roadmap");
+        cancelled.notify_one();
+    }
+}
+
+/// Runs `generation` to completion, or drops it as soon as `cancelled` is notified, even
+/// if that was before it started, and returns `None`.
+pub(crate) async fn unless_cancelled<T>(
+    cancelled: &Notify,
+    generation: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        biased;
+        _ = cancelled.notified() => None,
+        generated = generation => Some(generated),
+    }
+}
+
+/// Users with a roadmap being created, so a second request can't start another at the
+/// same time. Clones share the same set.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UserLocks {
+    users: Arc<Mutex<HashSet<UserId>>>,
+}
+
+impl UserLocks {
+    /// Marks `user_id` busy until the returned lock is dropped, or returns `None` if
+    /// they already are.
+    pub(crate) fn try_lock(&self, user_id: UserId) -> Option<UserLock> {
+        if !self.users.lock().unwrap().insert(user_id) {
+            return None;
+        }
+        Some(UserLock {
+            users: self.users.clone(),
+            user_id,
+        })
+    }
+}
+
+/// Holds a user busy in `UserLocks`, freeing them when dropped however creation ended.
+#[derive(Debug)]
+pub(crate) struct UserLock {
+    users: Arc<Mutex<HashSet<UserId>>>,
+    user_id: UserId,
+}
+
+impl Drop for UserLock {
+    fn drop(&mut self) {
+        self.users.lock().unwrap().remove(&self.user_id);
+    }
+}
+
+/// Users with a roadmap being created, whether they asked in chat or with `/roadmap`.
+pub(crate) struct CreatingRoadmaps;
+
+impl TypeMapKey for CreatingRoadmaps {
+    type Value = UserLocks;
+}
+
+/// Marks `user_id` as having a roadmap on the way, or returns `None` if one already is.
+pub(crate) async fn lock_user(ctx: &Context, user_id: UserId) -> Option<UserLock> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<CreatingRoadmaps>()
+        .expect("Expected CreatingRoadmaps in TypeMap.")
+        .try_lock(user_id)
+}
+
+/// Reply for someone who asks again while their last roadmap is still being written.
+pub(crate) const STILL_WORKING: &str =
+    "I'm still working on your last roadmap, it'll be with you shortly.";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ChatBackend, ChatParams, ChatReply};
+    use crate::roadmaps::RoadmapRequest;
+    use openai::chat::ChatCompletionMessage;
+    use serenity::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    /// Sets its flag when dropped, to see the completion was abandoned.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Never finishes a completion within the test.
+    struct SlowBackend {
+        dropped: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl ChatBackend for SlowBackend {
+        async fn complete(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            let _flag = DropFlag(self.dropped.clone());
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            anyhow::bail!("SlowBackend finished")
+        }
+    }
+
+    #[tokio::test]
+    async fn cancellation_drops_generation_before_sending() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let sent = AtomicBool::new(false);
+        let request = RoadmapRequest::new("how do I learn rust?").backend(Arc::new(SlowBackend {
+            dropped: dropped.clone(),
+        }));
+        let cancelled = Arc::new(Notify::new());
+        let deleter = cancelled.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            deleter.notify_one();
+        });
+        let generated = tokio::time::timeout(
+            Duration::from_secs(5),
+            unless_cancelled(&cancelled, async {
+                let created = request.create().await;
+                sent.store(true, Ordering::SeqCst);
+                created
+            }),
+        )
+        .await
+        .expect("Cancelled generation should stop promptly");
+        assert!(generated.is_none());
+        assert!(!sent.load(Ordering::SeqCst));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cancellation_before_start_still_counts() {
+        let cancelled = Notify::new();
+        cancelled.notify_one();
+        assert_eq!(unless_cancelled(&cancelled, async { 1 }).await, None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_from_one_user_only_run_once() {
+        let user_locks = UserLocks::default();
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let first = {
+            let (user_locks, started, release) =
+                (user_locks.clone(), started.clone(), release.clone());
+            tokio::spawn(async move {
+                let _lock = user_locks.try_lock(UserId::new(1)).unwrap();
+                started.notify_one();
+                release.notified().await;
+                Err::<(), _>(anyhow::anyhow!("creation failed"))
+            })
+        };
+        started.notified().await;
+        assert!(user_locks.try_lock(UserId::new(1)).is_none());
+        assert!(user_locks.try_lock(UserId::new(2)).is_some());
+        release.notify_one();
+        assert!(first.await.unwrap().is_err());
+        // Failing still frees the user for their next request
+        assert!(user_locks.try_lock(UserId::new(1)).is_some());
+    }
+
+    #[tokio::test]
+    async fn uncancelled_generation_completes() {
+        let cancelled = Notify::new();
+        assert_eq!(unless_cancelled(&cancelled, async { 1 }).await, Some(1));
+    }
+}
@@ -0,0 +1,385 @@
+use crate::messaging;
+use crate::spam_detection;
+use crate::text_normalization::{fold_lookalike, is_invisible};
+use crate::user_info;
+use anyhow::bail;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    CommandInteraction, CommandOptionType, Context, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Message, Permissions,
+    ResolvedValue, UserId,
+};
+use serenity::prelude::TypeMapKey;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, instrument, warn};
+
+/// Name of the slash command that edits the invite allowlist.
+pub(crate) const COMMAND_NAME: &str = "invite-allowlist";
+
+lazy_static! {
+    /// `discord.gg/<code>` and `discord.com/invite/<code>` (or `discordapp.com`), with or
+    /// without a scheme, capturing the code.
+    static ref INVITE_REGEX: Regex = Regex::new(
+        r"(?i)\b(?:https?://)?(?:www\.)?(?:discord\.gg|discord(?:app)?\.com/invite)/([a-z0-9-]{2,32})(?:[^a-z0-9-]|$)"
+    )
+    .unwrap();
+}
+
+/// The invite codes in `content`, wherever they appear, markdown links included, after
+/// removing invisible characters and folding lookalike letters back to ASCII.
+pub(crate) fn extract_invite_codes(content: &str) -> Vec<String> {
+    let cleaned: String = content
+        .chars()
+        .filter(|c| !is_invisible(*c))
+        .map(fold_lookalike)
+        .collect();
+    INVITE_REGEX
+        .captures_iter(cleaned.as_str())
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// Invites to our own and partner servers, which anyone may post. Codes are
+/// case-sensitive, like Discord's.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct InviteAllowlist {
+    pub(crate) codes: BTreeSet<String>,
+}
+
+impl InviteAllowlist {
+    /// Loads the allowlist last saved to `path`, or `default` if nothing has been saved yet.
+    pub(crate) fn load(path: &Path, default: InviteAllowlist) -> InviteAllowlist {
+        match std::fs::read_to_string(path) {
+            Ok(saved) => serde_json::from_str(&saved)
+                .inspect_err(|e| {
+                    warn!(
+                        "Ignoring unreadable invite allowlist {}: {e}",
+                        path.display()
+                    )
+                })
+                .unwrap_or(default),
+            Err(_) => default,
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// The invites in `content` that aren't allowed.
+    pub(crate) fn unknown_invites(&self, content: &str) -> Vec<String> {
+        extract_invite_codes(content)
+            .into_iter()
+            .filter(|code| !self.codes.contains(code))
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        if self.codes.is_empty() {
+            "No invites are allowed.".to_string()
+        } else {
+            let codes: Vec<String> = self.codes.iter().map(|code| format!("`{code}`")).collect();
+            format!("Allowed invites: {}.", codes.join(", "))
+        }
+    }
+}
+
+/// When each user last posted an invite that wasn't allowed, so a repeat within `window`
+/// is punished harder.
+#[derive(Debug)]
+pub(crate) struct InviteOffenses {
+    window: Duration,
+    last_offense: HashMap<UserId, Instant>,
+}
+
+impl InviteOffenses {
+    pub(crate) fn new(window: Duration) -> Self {
+        InviteOffenses {
+            window,
+            last_offense: HashMap::new(),
+        }
+    }
+
+    /// Records an offense by `user_id`, returning whether they'd offended recently.
+    pub(crate) fn offend(&mut self, user_id: UserId, now: Instant) -> bool {
+        let window = self.window;
+        self.last_offense
+            .retain(|_, offended| now.saturating_duration_since(*offended) < window);
+        self.last_offense.insert(user_id, now).is_some()
+    }
+}
+
+/// The allowlist in use, changed at runtime by the slash command.
+pub(crate) struct InviteAllowlists;
+
+impl TypeMapKey for InviteAllowlists {
+    type Value = Arc<RwLock<InviteAllowlist>>;
+}
+
+pub(crate) struct InviteOffenders;
+
+impl TypeMapKey for InviteOffenders {
+    type Value = Arc<RwLock<InviteOffenses>>;
+}
+
+/// The saved allowlist, or the one in the config if it's never been changed.
+pub(crate) fn saved_allowlist() -> InviteAllowlist {
+    InviteAllowlist::load(
+        &spam_detection::invite_allowlist_path(),
+        InviteAllowlist {
+            codes: spam_detection::allowed_invites().iter().cloned().collect(),
+        },
+    )
+}
+
+pub(crate) fn offenses_from_config() -> InviteOffenses {
+    InviteOffenses::new(spam_detection::invite_offense_window())
+}
+
+async fn invite_allowlist(ctx: &Context) -> Arc<RwLock<InviteAllowlist>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<InviteAllowlists>()
+        .expect("Expected InviteAllowlists in TypeMap.")
+        .clone()
+}
+
+/// Deletes `message` and warns its author if it has an invite that isn't allowed, timing
+/// them out if they did it recently too. Returns whether it did. Members with a trusted
+/// role and staff who can manage the server or its messages can post any invite.
+#[instrument(skip_all, ret(level = "debug"))]
+pub(crate) async fn check_invites(ctx: &Context, message: &Message) -> bool {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if spam_detection::is_trusted(roles) {
+        return false;
+    }
+    let unknown_invites = invite_allowlist(ctx)
+        .await
+        .read()
+        .await
+        .unknown_invites(message.content.as_str());
+    if unknown_invites.is_empty() {
+        return false;
+    }
+    let staff = Permissions::MANAGE_GUILD | Permissions::MANAGE_MESSAGES;
+    if user_info::has_any_permission(ctx, message, staff).await {
+        return false;
+    }
+    let offenders = {
+        let data_read = ctx.data.read().await;
+        data_read
+            .get::<InviteOffenders>()
+            .expect("Expected InviteOffenders in TypeMap.")
+            .clone()
+    };
+    let repeat = offenders
+        .write()
+        .await
+        .offend(message.author.id, Instant::now());
+    info!(
+        "Removing invites {unknown_invites:?} from {} (repeat offense: {repeat})",
+        message.author.name
+    );
+    if let Err(e) = messaging::remove_invite_and_log(ctx, message, repeat).await {
+        warn!("Failed to remove invite spam due to {e:#}");
+    }
+    true
+}
+
+/// `/invite-allowlist add|remove|list`, for members who can manage the server.
+pub(crate) fn command() -> CreateCommand {
+    let invite_option = || {
+        CreateCommandOption::new(
+            CommandOptionType::String,
+            "invite",
+            "The invite link or code",
+        )
+        .required(true)
+    };
+    CreateCommand::new(COMMAND_NAME)
+        .description("Choose which Discord invites members may post")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::SubCommand, "add", "Allow an invite")
+                .add_sub_option(invite_option()),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "remove",
+                "Stop allowing an invite",
+            )
+            .add_sub_option(invite_option()),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "list",
+            "Show the allowed invites",
+        ))
+}
+
+/// The code from an invite given to the command, which may be a link or a bare code.
+fn invite_code(invite: &str) -> String {
+    extract_invite_codes(invite)
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| invite.trim().to_string())
+}
+
+/// Applies an `/invite-allowlist` command, saves the result and replies privately.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let invite_allowlist = invite_allowlist(ctx).await;
+    let reply = {
+        let mut invite_allowlist = invite_allowlist.write().await;
+        let options = command.data.options();
+        let Some(subcommand) = options.first() else {
+            bail!("/{COMMAND_NAME} was sent without a subcommand");
+        };
+        let code = match &subcommand.value {
+            ResolvedValue::SubCommand(options) => {
+                options.iter().find_map(|option| match option.value {
+                    ResolvedValue::String(invite) => Some(invite_code(invite)),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        match (subcommand.name, code) {
+            ("list", _) => invite_allowlist.describe(),
+            ("add", Some(code)) => {
+                invite_allowlist.codes.insert(code.clone());
+                invite_allowlist.save(&spam_detection::invite_allowlist_path())?;
+                format!("Allowed `{code}`. {}", invite_allowlist.describe())
+            }
+            ("remove", Some(code)) => {
+                invite_allowlist.codes.remove(&code);
+                invite_allowlist.save(&spam_detection::invite_allowlist_path())?;
+                format!("Stopped allowing `{code}`. {}", invite_allowlist.describe())
+            }
+            (name, _) => bail!("Unknown /{COMMAND_NAME} subcommand {name}"),
+        }
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const ADA: UserId = UserId::new(1);
+
+    #[test]
+    fn extracts_embedded_invites() {
+        assert_eq!(
+            extract_invite_codes("join us at discord.gg/abc123 for free nitro!!"),
+            ["abc123"]
+        );
+        assert_eq!(
+            extract_invite_codes(
+                "https://discord.com/invite/Xy-Z9 and https://discordapp.com/invite/other"
+            ),
+            ["Xy-Z9", "other"]
+        );
+        assert!(extract_invite_codes("I use discord a lot, gg everyone").is_empty());
+    }
+
+    #[test]
+    fn only_invite_links_are_invites() {
+        assert!(extract_invite_codes("notdiscord.gg/abc123").is_empty());
+        assert!(extract_invite_codes("discord.com/channels/123/456").is_empty());
+        assert!(extract_invite_codes("discord.me/abc123 and dsc.gg/abc123").is_empty());
+        assert!(extract_invite_codes("discord . gg / spaced").is_empty());
+        assert!(extract_invite_codes(&format!("discord.gg/{}", "a".repeat(40))).is_empty());
+        assert_eq!(
+            extract_invite_codes("www.discord.gg/one discord.gg/two"),
+            ["one", "two"]
+        );
+    }
+
+    #[test]
+    fn extracts_invites_from_markdown_links() {
+        assert_eq!(
+            extract_invite_codes("[our study group](https://discord.gg/study) is open"),
+            ["study"]
+        );
+        assert_eq!(
+            extract_invite_codes("[https://github.com/rust](<https://discord.gg/scam>)"),
+            ["scam"]
+        );
+    }
+
+    #[test]
+    fn extracts_obfuscated_invites() {
+        assert_eq!(
+            extract_invite_codes("disc\u{200B}ord.g\u{200D}g/hidden"),
+            ["hidden"]
+        );
+        // Cyrillic о and fullwidth letters and dot
+        assert_eq!(extract_invite_codes("discоrd.gg/cyrillic"), ["cyrillic"]);
+        assert_eq!(extract_invite_codes("ｄｉｓｃｏｒｄ．ｇｇ/wide"), ["wide"]);
+    }
+
+    #[test]
+    fn allowlisted_invites_pass() {
+        let invite_allowlist = InviteAllowlist {
+            codes: BTreeSet::from(["ours".to_string()]),
+        };
+        assert!(invite_allowlist
+            .unknown_invites("come to discord.gg/ours")
+            .is_empty());
+        assert_eq!(
+            invite_allowlist.unknown_invites("discord.gg/ours or discord.gg/theirs"),
+            ["theirs"]
+        );
+        assert_eq!(invite_code("https://discord.gg/ours"), "ours");
+        assert_eq!(invite_code(" ours "), "ours");
+    }
+
+    #[test]
+    fn repeat_offenses_are_remembered_for_the_window() {
+        let mut offenses = InviteOffenses::new(Duration::from_secs(3600));
+        let now = Instant::now();
+        assert!(!offenses.offend(ADA, now));
+        assert!(offenses.offend(ADA, now + Duration::from_secs(60)));
+        assert!(!offenses.offend(ADA, now + Duration::from_secs(3660)));
+    }
+
+    #[test]
+    fn invite_allowlist_survives_restart() {
+        let path = env::temp_dir().join("invite_allowlist_survives_restart.json");
+        let _ = std::fs::remove_file(&path);
+        let invite_allowlist = InviteAllowlist {
+            codes: BTreeSet::from(["ours".to_string()]),
+        };
+        invite_allowlist.save(&path).unwrap();
+        assert_eq!(
+            InviteAllowlist::load(&path, InviteAllowlist::default()),
+            invite_allowlist
+        );
+    }
+}
@@ -0,0 +1,561 @@
+use crate::spam_detection;
+use anyhow::{bail, Context as _};
+use hyper::client::connect::dns::Name;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use serde::Deserialize;
+use serenity::all::{
+    CommandInteraction, CommandOptionType, Context, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Message, Permissions,
+};
+use serenity::prelude::TypeMapKey;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, info, instrument};
+use url::Url;
+
+/// Name of the slash command that reloads the domain lists.
+pub(crate) const COMMAND_NAME: &str = "domain-lists";
+
+lazy_static! {
+    /// `http(s)://` links, ending at whitespace or the brackets markdown wraps them in.
+    static ref URL_REGEX: Regex = Regex::new(r"(?i)https?://[^\s<>()\[\]`]+").unwrap();
+    /// Follows redirects by hand, so each hop can be checked and counted, and only
+    /// connects to public addresses.
+    static ref REDIRECT_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("Failed to build redirect client");
+}
+
+/// Whether `ip` is on the public internet, rather than loopback, a private or link-local
+/// network (cloud metadata services included), or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public(IpAddr::V4(mapped)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link-local, fe80::/10
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves hosts like the system does, but fails for any host with an address that
+/// isn't public, so a redirect can't point the bot at itself or its network. Checking the
+/// addresses actually connected to also rules out a host changing them between lookups.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(private) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(format!("{} resolves to {}", name.as_str(), private.ip()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Every link in `content`, masked markdown links included. Links broken over lines to dodge
+/// filters are also read with the line breaks taken out.
+pub(crate) fn extract_urls(content: &str) -> Vec<Url> {
+    let joined = content.replace(['\r', '\n'], "");
+    let mut urls: Vec<Url> = vec![];
+    for text in [content, joined.as_str()] {
+        for found in URL_REGEX.find_iter(text) {
+            let link = found
+                .as_str()
+                .trim_end_matches(['.', ',', '!', '?', ';', ':', '\'', '"', '*', '_', '~']);
+            if let Ok(url) = Url::parse(link) {
+                if !urls.contains(&url) {
+                    urls.push(url);
+                }
+            }
+        }
+    }
+    urls
+}
+
+/// The part of `url`'s host that's registered, e.g. `example.co.uk` for
+/// `www.example.co.uk`. Hosts are compared in punycode, so lookalike letters never match
+/// the ASCII domain they imitate.
+pub(crate) fn registrable_domain(url: &Url) -> Option<String> {
+    let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+    let domain = psl::domain_str(host.as_str()).unwrap_or(host.as_str());
+    Some(domain.to_string())
+}
+
+/// A domain as written in the lists, which may be Unicode or a full host, as it's compared.
+fn normalize_domain(domain: &str) -> Option<String> {
+    registrable_domain(&Url::parse(format!("http://{}/", domain.trim()).as_str()).ok()?)
+}
+
+/// What a message's links add up to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LinkVerdict {
+    NoLinks,
+    /// A link goes to this blocked domain.
+    Blocked(String),
+    /// Every link goes to an allowed domain, so link heuristics can be skipped.
+    Allowed,
+    Unknown,
+}
+
+/// Domains whose links are deleted on sight, and domains trusted enough to skip link
+/// heuristics, by registrable domain.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub(crate) struct DomainLists {
+    pub(crate) blocked: BTreeSet<String>,
+    pub(crate) allowed: BTreeSet<String>,
+}
+
+impl DomainLists {
+    /// Reads a TOML or JSON file of `blocked` and `allowed` domains, or empty lists if the
+    /// file doesn't exist.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<DomainLists> {
+        let domain_lists: DomainLists = config::Config::builder()
+            .add_source(config::File::from(path).required(false))
+            .build()
+            .and_then(|loaded| loaded.try_deserialize())
+            .with_context(|| format!("Failed to parse domain lists {}", path.display()))?;
+        let normalize = |domains: BTreeSet<String>| -> anyhow::Result<BTreeSet<String>> {
+            domains
+                .iter()
+                .map(|domain| {
+                    normalize_domain(domain)
+                        .with_context(|| format!("{domain} in {} isn't a domain", path.display()))
+                })
+                .collect()
+        };
+        Ok(DomainLists {
+            blocked: normalize(domain_lists.blocked)?,
+            allowed: normalize(domain_lists.allowed)?,
+        })
+    }
+
+    fn is_listed(&self, domain: &str) -> bool {
+        self.blocked.contains(domain) || self.allowed.contains(domain)
+    }
+
+    pub(crate) fn verdict(&self, domains: &[String]) -> LinkVerdict {
+        if domains.is_empty() {
+            LinkVerdict::NoLinks
+        } else if let Some(blocked) = domains.iter().find(|domain| self.blocked.contains(*domain)) {
+            LinkVerdict::Blocked(blocked.clone())
+        } else if domains.iter().all(|domain| self.allowed.contains(domain)) {
+            LinkVerdict::Allowed
+        } else {
+            LinkVerdict::Unknown
+        }
+    }
+
+    fn describe(&self) -> String {
+        let list = |domains: &BTreeSet<String>| match domains.len() {
+            0 => "none".to_string(),
+            _ => domains.iter().cloned().collect::<Vec<_>>().join(", "),
+        };
+        format!(
+            "Blocked: {}. Allowed: {}.",
+            list(&self.blocked),
+            list(&self.allowed)
+        )
+    }
+}
+
+/// Every hop `url` takes to where it ends up, itself first, following at most
+/// `max_redirects` redirects, each given `timeout`. Stops at a hop to a blocked domain,
+/// or one that can't be followed any further, such as one to a private address.
+pub(crate) async fn expand(
+    url: Url,
+    domain_lists: &DomainLists,
+    max_redirects: usize,
+    timeout: Duration,
+) -> Vec<Url> {
+    expand_with(
+        &REDIRECT_CLIENT,
+        is_public,
+        url,
+        domain_lists,
+        max_redirects,
+        timeout,
+    )
+    .await
+}
+
+/// `expand` with `client`, which must resolve hosts only to addresses `allowed` takes,
+/// as it's only checked for hosts given as IP addresses.
+async fn expand_with(
+    client: &reqwest::Client,
+    allowed: fn(IpAddr) -> bool,
+    mut url: Url,
+    domain_lists: &DomainLists,
+    max_redirects: usize,
+    timeout: Duration,
+) -> Vec<Url> {
+    let mut hops = vec![url.clone()];
+    for _ in 0..max_redirects {
+        let blocked =
+            registrable_domain(&url).is_some_and(|domain| domain_lists.blocked.contains(&domain));
+        let private_ip = match url.host() {
+            Some(url::Host::Ipv4(ip)) => !allowed(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => !allowed(IpAddr::V6(ip)),
+            _ => false,
+        };
+        if blocked || private_ip {
+            debug!("Stopped expanding at {url}, it's blocked or private");
+            break;
+        }
+        let response = match client.head(url.clone()).timeout(timeout).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Stopped expanding {url} due to {e}");
+                break;
+            }
+        };
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok());
+        match location.map(|location| url.join(location)) {
+            Some(Ok(next)) if response.status().is_redirection() => {
+                url = next;
+                hops.push(url.clone());
+            }
+            _ => break,
+        }
+    }
+    hops
+}
+
+/// The registrable domains `urls` go to, expanding links from shorteners that aren't on
+/// either list when that's turned on.
+async fn link_domains(domain_lists: &DomainLists, urls: Vec<Url>) -> Vec<String> {
+    let (expand_shorteners, shorteners, max_redirects, timeout) =
+        spam_detection::shortener_settings();
+    let mut domains = vec![];
+    for url in urls {
+        let Some(domain) = registrable_domain(&url) else {
+            continue;
+        };
+        let is_shortener = shorteners.contains(&domain);
+        if expand_shorteners && is_shortener && !domain_lists.is_listed(domain.as_str()) {
+            let hops = expand(url, domain_lists, max_redirects, timeout).await;
+            debug!("Expanded {domain} link to {:?}", hops.last());
+            // Every hop after the shortener is checked, or the shortener itself when it
+            // couldn't be followed
+            let skip = usize::from(hops.len() > 1);
+            domains.extend(hops.iter().skip(skip).filter_map(registrable_domain));
+        } else {
+            domains.push(domain);
+        }
+    }
+    domains
+}
+
+/// The domain lists in use, replaced by `/domain-lists reload`.
+pub(crate) struct LinkDomains;
+
+impl TypeMapKey for LinkDomains {
+    type Value = Arc<RwLock<DomainLists>>;
+}
+
+/// The domain lists on disk, stopping the bot at startup if they're malformed.
+pub(crate) fn load_domain_lists() -> DomainLists {
+    DomainLists::load(&spam_detection::domain_lists_path()).expect("Invalid domain lists")
+}
+
+async fn link_domain_lists(ctx: &Context) -> Arc<RwLock<DomainLists>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<LinkDomains>()
+        .expect("Expected LinkDomains in TypeMap.")
+        .clone()
+}
+
+/// Checks where `message`'s links go against the domain lists. Members with a trusted role
+/// aren't checked.
+#[instrument(skip_all, ret(level = "debug"))]
+pub(crate) async fn check_links(ctx: &Context, message: &Message) -> LinkVerdict {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if spam_detection::is_trusted(roles) {
+        return LinkVerdict::Unknown;
+    }
+    let urls = extract_urls(message.content.as_str());
+    if urls.is_empty() {
+        return LinkVerdict::NoLinks;
+    }
+    let domain_lists = link_domain_lists(ctx).await.read().await.clone();
+    let domains = link_domains(&domain_lists, urls).await;
+    domain_lists.verdict(&domains)
+}
+
+/// `/domain-lists reload|show`, for members who can manage the server.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("Reload or show the blocked and allowed link domains")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "reload",
+            "Read the domain lists file again",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "show",
+            "Show the domain lists in use",
+        ))
+}
+
+/// Applies a `/domain-lists` command and replies privately. A file that fails to load
+/// leaves the lists in use unchanged.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let domain_lists = link_domain_lists(ctx).await;
+    let options = command.data.options();
+    let Some(subcommand) = options.first() else {
+        bail!("/{COMMAND_NAME} was sent without a subcommand");
+    };
+    let reply = match subcommand.name {
+        "show" => domain_lists.read().await.describe(),
+        "reload" => match DomainLists::load(&spam_detection::domain_lists_path()) {
+            Ok(reloaded) => {
+                info!("Reloaded domain lists {reloaded:?}");
+                let reply = format!("Reloaded. {}", reloaded.describe());
+                *domain_lists.write().await = reloaded;
+                reply
+            }
+            Err(e) => format!("Kept the current lists, the file didn't load: {e:#}"),
+        },
+        name => bail!("Unknown /{COMMAND_NAME} subcommand {name}"),
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn domains(content: &str) -> Vec<String> {
+        extract_urls(content)
+            .iter()
+            .filter_map(registrable_domain)
+            .collect()
+    }
+
+    fn domain_lists() -> DomainLists {
+        DomainLists {
+            blocked: BTreeSet::from(["bad.example".to_string(), "xn--pple-43d.com".to_string()]),
+            allowed: BTreeSet::from(["github.com".to_string(), "rust-lang.org".to_string()]),
+        }
+    }
+
+    #[test]
+    fn extracts_masked_links() {
+        assert_eq!(
+            domains("[free nitro](http://bad.example/claim) and <https://docs.rust-lang.org>"),
+            ["bad.example", "rust-lang.org"]
+        );
+        assert_eq!(
+            domains("see https://github.com/rust-lang/rust, it's great."),
+            ["github.com"]
+        );
+        assert!(domains("no links, just www.words").is_empty());
+    }
+
+    #[test]
+    fn registrable_domain_ignores_subdomains() {
+        assert_eq!(domains("https://a.b.bad.co.uk/x"), ["bad.co.uk"]);
+        assert_eq!(domains("https://WWW.Bad.Example./"), ["bad.example"]);
+    }
+
+    #[test]
+    fn lookalike_domains_are_compared_in_punycode() {
+        // Cyrillic а in place of the Latin a
+        assert_eq!(domains("https://аpple.com/login"), ["xn--pple-43d.com"]);
+        assert_eq!(
+            domain_lists().verdict(&domains("https://аpple.com/login")),
+            LinkVerdict::Blocked("xn--pple-43d.com".to_string())
+        );
+        assert_eq!(
+            domain_lists().verdict(&domains("https://gіthub.com")),
+            LinkVerdict::Unknown
+        );
+        assert_eq!(normalize_domain("аpple.com").unwrap(), "xn--pple-43d.com");
+    }
+
+    #[test]
+    fn extracts_links_split_across_lines() {
+        let verdict = domain_lists().verdict(&domains("claim at https://bad.\nexample/nitro"));
+        assert_eq!(verdict, LinkVerdict::Blocked("bad.example".to_string()));
+        assert!(domains("https://bad.ex\r\nample").contains(&"bad.example".to_string()));
+    }
+
+    #[test]
+    fn verdict_needs_every_link_allowed() {
+        let domain_lists = domain_lists();
+        assert_eq!(domain_lists.verdict(&[]), LinkVerdict::NoLinks);
+        assert_eq!(
+            domain_lists.verdict(&domains("https://github.com https://rust-lang.org")),
+            LinkVerdict::Allowed
+        );
+        assert_eq!(
+            domain_lists.verdict(&domains("https://github.com https://unknown.example")),
+            LinkVerdict::Unknown
+        );
+    }
+
+    #[test]
+    fn domain_lists_load_from_file() {
+        let path = env::temp_dir().join("domain_lists_load_from_file.toml");
+        std::fs::write(
+            &path,
+            "blocked = [\"www.Bad.example\", \"аpple.com\"]\nallowed = [\"github.com\"]\n",
+        )
+        .unwrap();
+        let loaded = DomainLists::load(&path).unwrap();
+        assert_eq!(
+            loaded.blocked,
+            BTreeSet::from(["bad.example".to_string(), "xn--pple-43d.com".to_string()])
+        );
+        assert_eq!(
+            DomainLists::load(&env::temp_dir().join("no_domain_lists.toml")).unwrap(),
+            DomainLists::default()
+        );
+    }
+
+    /// Answers every request with a redirect to `/` plus one more hop on the same server.
+    async fn redirecting_server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut hops = 0;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = [0; 1024];
+                let _ = socket.read(&mut buffer).await;
+                hops += 1;
+                let location = if hops < 3 {
+                    format!("/hop{hops}")
+                } else {
+                    "https://bad.example/nitro".to_string()
+                };
+                let response = format!(
+                    "HTTP/1.1 301 Moved Permanently\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        Url::parse(format!("http://{address}/short").as_str()).unwrap()
+    }
+
+    async fn expand_locally(short: Url, max_redirects: usize) -> Vec<Url> {
+        expand_with(
+            &reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+            |_| true,
+            short,
+            &domain_lists(),
+            max_redirects,
+            Duration::from_secs(5),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn expand_follows_redirects_up_to_the_limit() {
+        let short = redirecting_server().await;
+        let hops = expand_locally(short, 5).await;
+        let paths: Vec<&str> = hops.iter().map(Url::path).collect();
+        assert_eq!(paths, ["/short", "/hop1", "/hop2", "/nitro"]);
+        // Stopped at the blocked domain rather than asking it where it goes
+        assert_eq!(hops[3].as_str(), "https://bad.example/nitro");
+
+        let short = redirecting_server().await;
+        let hops = expand_locally(short, 1).await;
+        assert_eq!(hops.last().unwrap().path(), "/hop1");
+    }
+
+    #[tokio::test]
+    async fn expand_never_requests_private_addresses() {
+        let short = redirecting_server().await;
+        assert_eq!(
+            expand(short.clone(), &domain_lists(), 3, Duration::from_secs(5)).await,
+            [short]
+        );
+        let by_name = Url::parse("http://localhost:9/short").unwrap();
+        let error = REDIRECT_CLIENT.head(by_name).send().await.unwrap_err();
+        assert!(format!("{error:?}").contains("resolves to 127.0.0.1"));
+    }
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{private}");
+        }
+        for public in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"] {
+            assert!(is_public(public.parse().unwrap()), "{public}");
+        }
+    }
+}
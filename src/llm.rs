@@ -0,0 +1,388 @@
+use crate::metrics;
+use crate::roadmaps::RoadmapError;
+use crate::utilities;
+use crate::utilities::RetryPolicy;
+use anyhow::ensure;
+use openai::chat::{
+    ChatCompletion, ChatCompletionBuilder, ChatCompletionDelta, ChatCompletionFunctionDefinition,
+    ChatCompletionMessage,
+};
+use openai::Usage;
+use serde_json::json;
+use serenity::async_trait;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Anything that can turn a chat prompt into a reply, so callers aren't tied to OpenAI.
+#[async_trait]
+pub(crate) trait ChatBackend: Send + Sync {
+    async fn complete(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+    ) -> anyhow::Result<ChatReply>;
+
+    /// Sends the reply through `chunks` as it's written, then returns all of it. Backends
+    /// that can't stream send the whole reply as one chunk.
+    async fn complete_stream(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+        chunks: mpsc::Sender<String>,
+    ) -> anyhow::Result<ChatReply> {
+        let reply = self.complete(messages, params).await?;
+        let _ = chunks.send(reply.content.clone()).await;
+        Ok(reply)
+    }
+}
+
+/// A completion's text, plus the tokens it cost when the backend reports them.
+#[derive(Clone, Debug)]
+pub(crate) struct ChatReply {
+    pub(crate) content: String,
+    pub(crate) usage: Option<Usage>,
+}
+
+/// Totals the usage of two completions, treating a missing report as no tokens.
+pub(crate) fn add_usage(first: Option<Usage>, second: Option<Usage>) -> Option<Usage> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(Usage {
+            prompt_tokens: first.prompt_tokens + second.prompt_tokens,
+            completion_tokens: first.completion_tokens + second.completion_tokens,
+            total_tokens: first.total_tokens + second.total_tokens,
+        }),
+        (first, second) => first.or(second),
+    }
+}
+
+/// One-line summary of a completion's cost for logs, e.g. `gpt-4o-mini 120+30 tokens in 850ms`.
+pub(crate) fn describe_completion(model: &str, usage: Option<Usage>, elapsed: Duration) -> String {
+    match usage {
+        Some(usage) => format!(
+            "{model} {}+{} tokens in {}ms",
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            elapsed.as_millis()
+        ),
+        None => format!("{model} in {}ms", elapsed.as_millis()),
+    }
+}
+
+/// What a single completion asks of the backend.
+#[derive(Clone, Debug)]
+pub(crate) struct ChatParams {
+    pub(crate) model: String,
+    pub(crate) max_tokens: Option<u64>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) function: Option<ChatCompletionFunctionDefinition>,
+}
+
+impl ChatParams {
+    pub(crate) fn new(model: &str) -> Self {
+        ChatParams {
+            model: model.to_string(),
+            max_tokens: None,
+            temperature: None,
+            function: None,
+        }
+    }
+
+    pub(crate) fn model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    pub(crate) fn max_tokens(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub(crate) fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Makes the model always call `function`, so the reply is the call's JSON arguments.
+    pub(crate) fn force_function(mut self, function: ChatCompletionFunctionDefinition) -> Self {
+        self.function = Some(function);
+        self
+    }
+}
+
+/// Whether `set_api_key` has been given a key, since the `openai` crate can't say.
+static API_KEY_SET: AtomicBool = AtomicBool::new(false);
+
+/// Hands `key` to the `openai` crate, refusing a blank one so a missing `OPENAI_KEY` stops
+/// the bot at startup instead of failing every roadmap.
+pub(crate) fn set_api_key(key: &str) -> Result<(), RoadmapError> {
+    if key.trim().is_empty() {
+        return Err(RoadmapError::Unauthenticated);
+    }
+    openai::set_key(key.trim().to_string());
+    API_KEY_SET.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+fn check_api_key() -> Result<(), RoadmapError> {
+    if API_KEY_SET.load(Ordering::Relaxed) {
+        Ok(())
+    } else {
+        Err(RoadmapError::Unauthenticated)
+    }
+}
+
+/// Sends completions through the `openai` crate, to OpenAI or whichever compatible
+/// endpoint `OPENAI_BASE_URL` points at.
+pub(crate) struct OpenAiBackend {
+    retry_policy: RetryPolicy,
+    request_timeout: Duration,
+}
+
+impl OpenAiBackend {
+    pub(crate) fn new() -> Self {
+        OpenAiBackend {
+            retry_policy: RetryPolicy::default(),
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub(crate) fn max_retries(mut self, max_retries: usize) -> Self {
+        self.retry_policy.max_retries = max_retries;
+        self
+    }
+
+    /// Stops retrying once another attempt would start after `retry_deadline`.
+    pub(crate) fn retry_deadline(mut self, retry_deadline: Duration) -> Self {
+        self.retry_policy.deadline = retry_deadline;
+        self
+    }
+
+    pub(crate) fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+}
+
+fn request_builder(
+    messages: Vec<ChatCompletionMessage>,
+    params: &ChatParams,
+) -> ChatCompletionBuilder {
+    let mut builder = ChatCompletion::builder(params.model.as_str(), messages);
+    if let Some(max_tokens) = params.max_tokens {
+        builder = builder.max_tokens(max_tokens);
+    }
+    if let Some(temperature) = params.temperature {
+        builder = builder.temperature(temperature);
+    }
+    if let Some(function) = &params.function {
+        builder = builder
+            .function_call(json!({ "name": function.name }))
+            .functions(vec![function.clone()]);
+    }
+    builder
+}
+
+#[async_trait]
+impl ChatBackend for OpenAiBackend {
+    async fn complete(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+    ) -> anyhow::Result<ChatReply> {
+        check_api_key()?;
+        let request = request_builder(messages, params).build()?;
+        let started = Instant::now();
+        let chat_completion =
+            utilities::create_completion(&request, &self.retry_policy, self.request_timeout).await;
+        record_call(
+            params,
+            started,
+            chat_completion.as_ref().map(|completion| completion.usage),
+        );
+        let chat_completion = chat_completion?;
+        let usage = chat_completion.usage;
+        Ok(ChatReply {
+            content: utilities::reply_content(chat_completion)?,
+            usage,
+        })
+    }
+
+    /// Streams without retries, since chunks already sent can't be taken back. The
+    /// `openai` crate drops stream errors, so a stream that stops before a finish reason
+    /// arrives is reported as failed. Streams don't report usage, so it's counted locally.
+    async fn complete_stream(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+        chunks: mpsc::Sender<String>,
+    ) -> anyhow::Result<ChatReply> {
+        check_api_key()?;
+        let request = request_builder(messages.clone(), params)
+            .stream(true)
+            .build()?;
+        let started = Instant::now();
+        let reply: anyhow::Result<ChatReply> = async {
+            let mut deltas = utilities::with_timeout(
+                self.request_timeout,
+                ChatCompletionDelta::create(&request),
+            )
+            .await
+            .map_err(utilities::flag_auth_error)?;
+            let mut content = String::new();
+            let mut finished = false;
+            while let Some(delta) = utilities::with_timeout(self.request_timeout, async {
+                anyhow::Ok(deltas.recv().await)
+            })
+            .await?
+            {
+                for choice in delta.choices {
+                    if let Some(text) = choice.delta.content {
+                        content.push_str(text.as_str());
+                        // Nobody is listening any more, so stop paying for tokens
+                        ensure!(
+                            chunks.send(text).await.is_ok(),
+                            "Roadmap stream was dropped"
+                        );
+                    }
+                    if choice.finish_reason.as_deref() == Some(utilities::CONTENT_FILTER) {
+                        return Err(RoadmapError::Refused {
+                            model: params.model.clone(),
+                        }
+                        .into());
+                    }
+                    finished |= choice.finish_reason.is_some();
+                }
+            }
+            ensure!(
+                finished,
+                "OpenAI stream from {} ended before the reply was finished",
+                params.model
+            );
+            let prompt_tokens =
+                utilities::count_prompt_tokens(params.model.as_str(), &messages) as u32;
+            let completion_tokens =
+                utilities::count_tokens(params.model.as_str(), content.as_str()) as u32;
+            Ok(ChatReply {
+                content,
+                usage: Some(Usage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                }),
+            })
+        }
+        .await;
+        record_call(params, started, reply.as_ref().map(|reply| reply.usage));
+        reply
+    }
+}
+
+/// Counts a call to the model, its latency and, when it reported them, its tokens.
+fn record_call<E>(params: &ChatParams, started: Instant, usage: Result<Option<Usage>, E>) {
+    let model = params.model.as_str();
+    metrics::observe(
+        metrics::OPENAI_LATENCY,
+        &[("model", model)],
+        started.elapsed(),
+    );
+    let outcome = if usage.is_ok() { "ok" } else { "error" };
+    metrics::increment(
+        metrics::OPENAI_CALLS,
+        &[("model", model), ("outcome", outcome)],
+    );
+    if let Ok(Some(usage)) = usage {
+        for (kind, tokens) in [
+            ("prompt", usage.prompt_tokens),
+            ("completion", usage.completion_tokens),
+        ] {
+            metrics::increment_by(
+                metrics::OPENAI_TOKENS,
+                &[("model", model), ("kind", kind)],
+                tokens as u64,
+            );
+        }
+    }
+}
+
+/// Replays canned replies in order and records every prompt and its params, for offline tests.
+#[cfg(test)]
+pub(crate) struct MockChatBackend {
+    replies: std::sync::Mutex<std::collections::VecDeque<String>>,
+    requests: std::sync::Mutex<Vec<(Vec<ChatCompletionMessage>, ChatParams)>>,
+    usage: Option<Usage>,
+}
+
+#[cfg(test)]
+impl MockChatBackend {
+    pub(crate) fn new(replies: &[&str]) -> Self {
+        MockChatBackend {
+            replies: std::sync::Mutex::new(replies.iter().map(|reply| reply.to_string()).collect()),
+            requests: Default::default(),
+            usage: None,
+        }
+    }
+
+    /// Reports `usage` with every reply.
+    pub(crate) fn usage(mut self, usage: Usage) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    pub(crate) fn prompts(&self) -> Vec<Vec<ChatCompletionMessage>> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|(prompt, _)| prompt.clone()).collect()
+    }
+
+    pub(crate) fn params(&self) -> Vec<ChatParams> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|(_, params)| params.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ChatBackend for MockChatBackend {
+    async fn complete(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        params: &ChatParams,
+    ) -> anyhow::Result<ChatReply> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push((messages, params.clone()));
+        match self.replies.lock().unwrap().pop_front() {
+            Some(content) => Ok(ChatReply {
+                content,
+                usage: self.usage,
+            }),
+            None => anyhow::bail!("MockChatBackend ran out of replies"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::user_message;
+
+    #[tokio::test]
+    async fn blank_api_key_is_refused() {
+        assert!(matches!(
+            set_api_key("  "),
+            Err(RoadmapError::Unauthenticated)
+        ));
+        let error = OpenAiBackend::new()
+            .complete(
+                vec![user_message("Hi".to_string())],
+                &ChatParams::new("gpt-4o-mini"),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RoadmapError>(),
+            Some(RoadmapError::Unauthenticated)
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+use std::env;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Chooses how logs are written: `text` (the default), `pretty` or `json`.
+const LOG_FORMAT_ENV: &str = "LOG_FORMAT";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    /// One line per event, with the spans it's in.
+    Text,
+    /// Several indented lines per event, for reading in a terminal.
+    Pretty,
+    /// One JSON object per event, for log shippers like Promtail.
+    Json,
+}
+
+impl LogFormat {
+    fn from_env() -> LogFormat {
+        match env::var(LOG_FORMAT_ENV).as_deref() {
+            Err(_) | Ok("text") => LogFormat::Text,
+            Ok("pretty") => LogFormat::Pretty,
+            Ok("json") => LogFormat::Json,
+            Ok(other) => {
+                eprintln!("Unknown {LOG_FORMAT_ENV} {other:?}, logging as text");
+                LogFormat::Text
+            }
+        }
+    }
+}
+
+/// Levels from `RUST_LOG`, like `info,spam_blocker=debug`, or `info` for everything when
+/// it's unset. Directives that can't be read are skipped.
+fn filter_from_env() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
+/// Sends `tracing` events and `log` records to stdout in the format `LOG_FORMAT` names, at
+/// the levels `RUST_LOG` sets.
+pub(crate) fn init() {
+    let layer = match LogFormat::from_env() {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+        LogFormat::Json => json_layer(std::io::stdout).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter_from_env()))
+        .init();
+}
+
+/// One JSON object per event written to `writer`, with its fields and every span it's in,
+/// outermost first.
+fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .with_writer(writer)
+        .with_current_span(false)
+        .with_span_list(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::{field, info, info_span};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_carry_event_and_span_fields() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(json_layer(move || writer.clone()).with_filter(LevelFilter::INFO));
+        tracing::subscriber::with_default(subscriber, || {
+            let event = info_span!("message", channel_id = 10u64, latency_ms = field::Empty);
+            let _entered = event.enter();
+            let detection = info_span!("detection", model = "gpt-4o-mini");
+            let _entered = detection.enter();
+            event.record("latency_ms", 12u64);
+            info!(is_roadmap = true, "Detected roadmap request");
+            tracing::debug!("Hidden at info");
+        });
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Detected roadmap request");
+        assert_eq!(line["fields"]["is_roadmap"], true);
+        assert_eq!(
+            line["spans"],
+            serde_json::json!([
+                {"name": "message", "channel_id": 10, "latency_ms": 12},
+                {"name": "detection", "model": "gpt-4o-mini"},
+            ])
+        );
+    }
+}
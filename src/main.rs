@@ -1,34 +1,98 @@
 use std::collections::HashMap;
 use std::env;
 use std::sync::Arc;
+use std::time::Instant;
 
-use crate::chunking::chunk_string;
-use crate::clean_messages::clean_message;
+use crate::chunking::{split_for_discord, PART_DELAY};
+use crate::confirmations::RoadmapConfirmations;
+use crate::conversation_state::{LastRoadmaps, RoadmapConversations};
+use crate::drafting::draft_roadmap;
+use crate::duplicate_spam::RecentMessages;
+use crate::image_spam::KnownSpamImages;
+use crate::in_flight::{CreatingRoadmaps, InFlightRoadmaps};
+use crate::invite_spam::{InviteAllowlists, InviteOffenders};
+use crate::link_screening::{LinkDomains, LinkVerdict};
+use crate::llm::describe_completion;
+use crate::member_risk::NewMembers;
+use crate::mod_log::ModLogEntry;
+use crate::progress::ProgressIndicator;
+use crate::raid::RaidMode;
 use crate::request::answer_request;
-use crate::roadmaps::{create_roadmap, is_message_roadmap_request};
-use crate::spam_detection::classify_message_spam;
+use crate::review_queue::SpamReviews;
+use crate::roadmap_channels::RoadmapChannels;
+use crate::roadmap_command::RoadmapCooldowns;
+use crate::roadmaps::{
+    PreviousRoadmap, RequestingRoadmap, RoadmapDecision, RoadmapProvided, RoadmapRequest,
+};
+use crate::scam_rules::ScamRuleSet;
+use crate::spam_pipeline::{SpamBands, SpamVerdict};
+use crate::strikes::{Severity, StrikeRecords, Strikes};
+use crate::threads::RoadmapThreads;
 use crate::user_info::retrieve_user_context;
+use crate::utilities::Role;
 use dotenv::dotenv;
-use openai::set_key;
-use serenity::all::Mention;
+use openai::set_base_url;
+use serenity::all::{Command, GetMessages, Interaction, Member, Mention, Reaction, Timestamp};
 use serenity::async_trait;
 use serenity::builder::CreateMessage;
 use serenity::model::channel::Message;
 use serenity::model::event::MessageUpdateEvent;
 use serenity::model::gateway::Ready;
-use serenity::model::id::{ChannelId, UserId};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
 use serenity::prelude::*;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tracing::{error, info};
+use tokio::sync::Notify;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 use user_info::{UserContext, UserJoinDate};
 
+mod budget;
+mod channel_context;
 mod chunking;
 mod clean_messages;
+mod confirmations;
+mod conversation_state;
+mod detection_cache;
+mod dm_advertising;
+mod drafting;
+mod dry_run;
+mod duplicate_spam;
+mod embeds;
+mod evidence;
+mod heuristic_detection;
+mod honeypot;
+mod image_spam;
+mod in_flight;
+mod invite_spam;
+mod link_screening;
+mod llm;
+mod logging;
+mod member_risk;
+mod mention_spam;
 mod messaging;
+mod metrics;
+mod metrics_server;
+mod mod_log;
+mod progress;
+mod quota;
+mod raid;
+mod rate_limit;
+mod reports;
 mod request;
+mod review_queue;
+mod roadmap_channels;
+mod roadmap_command;
+mod roadmap_history;
+mod roadmap_store;
 mod roadmaps;
+mod scam_rules;
+mod scrubbing;
 mod spam_detection;
+mod spam_pipeline;
+mod storage;
+mod strikes;
+mod text_normalization;
+mod threads;
 mod user_info;
 mod utilities;
 
@@ -51,35 +115,52 @@ const SPAM_EATER_ID: u64 = 1091478027264868422;
 #[derive(Debug)]
 enum MessageClassification {
     Normal,
-    MaybeSpam,
+    MaybeSpam(String),
     DefinitelySpam(String),
+    /// Spam only by the tighter checks used during a raid, removed without a timeout.
+    RaidSpam(String),
+    /// Spam luring people into DMs, whose author is only warned the first time. The reason
+    /// gives DM advertising alongside anything else the message was caught for.
+    DmAdvertising(String),
+    /// In the grey zone, but the model couldn't be asked, so it's only ever reviewed.
+    Unclassified,
 }
 
+#[instrument(skip_all, ret(level = "debug"))]
 async fn is_message_suspicious(
+    ctx: &Context,
     message: &Message,
-    user_join_date: Option<i64>,
+    links_allowed: bool,
+    on_probation: bool,
 ) -> MessageClassification {
-    if (messaging::is_suspicious_url(message.content.as_str()) | message.mention_everyone)
-        && messaging::is_new_user(user_join_date)
+    let signals = spam_pipeline::gather_signals(ctx, message, links_allowed, on_probation).await;
+    let raid = match message.guild_id {
+        Some(guild_id) => raid::is_tightened(ctx, guild_id).await,
+        None => false,
+    };
+    let bands = if raid {
+        SpamBands::during_raid()
+    } else {
+        SpamBands::from_config()
+    };
+    // TODO: Track the context of user messages
+    match spam_pipeline::classify(
+        &*roadmaps::default_backend(),
+        bands,
+        &signals,
+        message.content.clone(),
+        vec![],
+    )
+    .await
     {
-        // TODO: Track the context of user messages
-        match classify_message_spam(message.content.clone(), vec![]).await {
-            Ok(classification) => {
-                if classification.is_spam {
-                    MessageClassification::DefinitelySpam(classification.reason)
-                } else {
-                    info!(
-                        "Message ({}) hit filter, not considered suspicious due to {}",
-                        clean_message(message.content.as_str()),
-                        classification.reason
-                    );
-                    MessageClassification::Normal
-                }
-            }
-            Err(_) => MessageClassification::MaybeSpam,
+        SpamVerdict::Spam(reason) if signals.dm_advertising > 0.0 => {
+            MessageClassification::DmAdvertising(dm_advertising::with_reason(reason))
         }
-    } else {
-        MessageClassification::Normal
+        SpamVerdict::Spam(reason) => MessageClassification::DefinitelySpam(reason),
+        SpamVerdict::RaidSpam(reason) => MessageClassification::RaidSpam(reason),
+        SpamVerdict::Clean => MessageClassification::Normal,
+        SpamVerdict::Review(reason) => MessageClassification::MaybeSpam(reason),
+        SpamVerdict::Unsure => MessageClassification::Unclassified,
     }
 }
 
@@ -90,9 +171,15 @@ async fn reply_chunked(
     content: String,
 ) -> anyhow::Result<()> {
     let formatted_message = format!("Hi {}, \n {}", user, content);
-    for chunk in chunk_string(formatted_message.as_str(), 1_950) {
+    for (index, part) in split_for_discord(formatted_message.as_str())
+        .into_iter()
+        .enumerate()
+    {
+        if index > 0 {
+            tokio::time::sleep(PART_DELAY).await;
+        }
         channel_id
-            .send_message(&ctx.http, CreateMessage::new().content(chunk))
+            .send_message(&ctx.http, CreateMessage::new().content(part))
             .await?;
     }
     Ok(())
@@ -122,94 +209,522 @@ async fn handle_request(ctx: &Context, message: &Message) -> anyhow::Result<()>
     Ok(())
 }
 
+/// Posts the roadmap `request` asks for in `channel_id` as embeds, or as plain text if it
+/// couldn't be structured. Returns `None` without posting once `cancelled` is notified.
+async fn post_structured_roadmap(
+    ctx: &Context,
+    message: &Message,
+    channel_id: ChannelId,
+    request: RoadmapRequest,
+    cancelled: &Notify,
+) -> anyhow::Result<Option<RoadmapProvided>> {
+    let Some(created_roadmap) =
+        in_flight::unless_cancelled(cancelled, request.create_structured()).await
+    else {
+        return Ok(None);
+    };
+    let created_roadmap = created_roadmap?;
+    match &created_roadmap.structured {
+        Some(structured) => {
+            embeds::send_roadmap_embeds(ctx, channel_id, &message.author, structured).await?
+        }
+        None => {
+            reply_chunked(
+                ctx,
+                message.author.mention(),
+                channel_id,
+                created_roadmap.roadmap.clone(),
+            )
+            .await?
+        }
+    }
+    Ok(Some(created_roadmap))
+}
+
+#[instrument(name = "roadmap", skip_all)]
 async fn handle_roadmap(ctx: &Context, message: &Message) -> anyhow::Result<()> {
-    if is_message_roadmap_request(message.content.clone(), vec![])
-        .await?
-        .is_roadmap
-    {
-        let user_context = retrieve_user_context(ctx, message).await;
-        let created_roadmap = create_roadmap(message.content.clone(), user_context).await?;
+    let previous = conversation_state::last_roadmap(ctx, message.author.id).await;
+    // Lets detection tell a follow-up from a fresh request
+    let context = previous
+        .iter()
+        .map(|previous| (Role::User, previous.request.clone()))
+        .collect();
+    let followup_to = previous.clone();
+    let (roadmap_request, answered) =
+        roadmaps::detect_then_create(message.content.clone(), context, |roadmap_request| {
+            answer_roadmap_request(ctx, message, followup_to, roadmap_request)
+        })
+        .await?;
+    if let Some(answered) = answered {
+        return answered;
+    }
+    log_detection(message, &roadmap_request);
+    if let Some(previous) = previous.filter(|_| roadmap_request.is_followup) {
+        // Asking for changes is explicit enough to not need the usual confidence
+        return create_roadmap(ctx, message, Some(previous), &roadmap_request.reason).await;
+    }
+    if roadmap_request.decision() == RoadmapDecision::Unsure {
+        message.react(&ctx.http, '❓').await?;
+    }
+    Ok(())
+}
+
+fn log_detection(message: &Message, roadmap_request: &RequestingRoadmap) {
+    info!(
+        "Roadmap detection for {} - {}",
+        message.author.name,
+        describe_completion(
+            &roadmap_request.model,
+            roadmap_request.usage,
+            roadmap_request.elapsed
+        )
+    );
+}
+
+/// Answers a message detection was confident asks for a roadmap: a follow-up on the
+/// author's `previous` roadmap revises it, anything else gets a new roadmap, or a
+/// confirmation first with `confirm_roadmaps`.
+async fn answer_roadmap_request(
+    ctx: &Context,
+    message: &Message,
+    previous: Option<PreviousRoadmap>,
+    roadmap_request: RequestingRoadmap,
+) -> anyhow::Result<()> {
+    log_detection(message, &roadmap_request);
+    if let Some(previous) = previous.filter(|_| roadmap_request.is_followup) {
+        // Asking for changes is explicit enough to not need confirming
+        return create_roadmap(ctx, message, Some(previous), &roadmap_request.reason).await;
+    }
+    if roadmaps::confirm_roadmaps() {
+        return confirmations::ask(ctx, message, roadmap_request.explanation().as_str()).await;
+    }
+    create_roadmap(ctx, message, None, &roadmap_request.reason).await
+}
+
+/// Recent messages per channel looked through for requests missed while offline.
+const BACKLOG_MESSAGES: u8 = 50;
+/// How old a missed request can be and still get answered, in seconds.
+const BACKLOG_MAX_AGE_SECS: i64 = 60 * 60;
+
+/// Answers roadmap requests posted while the bot was offline. Only channels enabled by
+/// name are scanned, and messages the bot has reacted to were already answered.
+async fn answer_roadmap_backlog(ctx: &Context) -> anyhow::Result<()> {
+    let cutoff = Timestamp::now().unix_timestamp() - BACKLOG_MAX_AGE_SECS;
+    let mut backlog = vec![];
+    for channel_id in roadmap_channels::listed_channels(ctx).await {
+        let messages = channel_id
+            .messages(&ctx.http, GetMessages::new().limit(BACKLOG_MESSAGES))
+            .await?;
+        backlog.extend(messages.into_iter().filter(|message| {
+            !message.author.bot
+                && message.timestamp.unix_timestamp() >= cutoff
+                && !message.reactions.iter().any(|reaction| reaction.me)
+                && !messaging::is_message_request(message)
+                && messaging::message_discusses_roadmaps(message)
+        }));
+    }
+    let detections = roadmaps::detect_batch(
+        backlog
+            .iter()
+            .map(|message| (message.content.clone(), vec![]))
+            .collect(),
+    )
+    .await;
+    info!("Checked {} missed roadmap requests", backlog.len());
+    for (message, detection) in backlog.iter().zip(detections) {
+        let answered = match detection {
+            Ok(roadmap_request) => match roadmap_request.decision() {
+                RoadmapDecision::Create => {
+                    answer_roadmap_request(ctx, message, None, roadmap_request).await
+                }
+                RoadmapDecision::Unsure => message
+                    .react(&ctx.http, '❓')
+                    .await
+                    .map(|_| ())
+                    .map_err(Into::into),
+                RoadmapDecision::Ignore => Ok(()),
+            },
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = answered {
+            error!("Failed to answer missed roadmap request due to {e:#}");
+        }
+    }
+    Ok(())
+}
+
+/// Makes and posts the roadmap `message` asked for, with the conversation before it as
+/// context, in a thread off `message` where threads are enabled. With `revising`, the
+/// author's last roadmap is rewritten with the changes `message` asks for instead.
+/// `reason` is why it was taken as a request, for the mod log.
+async fn create_roadmap(
+    ctx: &Context,
+    message: &Message,
+    revising: Option<PreviousRoadmap>,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let Some(_creating) = in_flight::lock_user(ctx, message.author.id).await else {
+        info!("Already creating a roadmap for {}", message.author.name);
         reply_chunked(
             ctx,
             message.author.mention(),
             message.channel_id,
-            created_roadmap.roadmap,
+            in_flight::STILL_WORKING.to_string(),
         )
         .await?;
+        return Ok(());
+    };
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if let Err(limit) = roadmaps::check_limits(message.author.id, roles).await {
+        info!("Limiting roadmaps for {} - {limit:?}", message.author.name);
+        reply_chunked(
+            ctx,
+            message.author.mention(),
+            message.channel_id,
+            limit.reply(),
+        )
+        .await?;
+        return Ok(());
     }
-    Ok(())
-}
-
-async fn handle_message(ctx: Context, message: Message) {
-    match is_message_suspicious(
-        &message,
-        user_info::get_user_join_date(&ctx, &message.author).await,
+    let mut progress = ProgressIndicator::start(ctx, message).await;
+    // Deleting the request from here on drops its roadmap instead of posting it
+    let in_flight = in_flight::start(ctx, message.id).await;
+    let context_budget = roadmaps::channel_context_budget(message.channel_id);
+    let user_context = match roadmaps::fetch_channel_context(
+        &*ctx.http,
+        message.channel_id,
+        message.id,
+        message.author.id,
+        message.content.as_str(),
+        UserId::from(SPAM_EATER_ID),
+        context_budget,
     )
     .await
     {
-        MessageClassification::Normal => {}
-        MessageClassification::MaybeSpam => {
-            info!(
-                "Removing message - likely spam - {}",
-                message.content.as_str()
-            );
-            messaging::remove_message_and_log(&ctx, message.clone())
+        Ok(channel_context) => channel_context,
+        Err(e) => {
+            warn!("Failed to fetch channel context due to {e}, using recent messages");
+            retrieve_user_context(ctx, message)
                 .await
-                .unwrap()
+                .into_iter()
+                .map(|line| (Role::User, line))
+                .collect()
         }
+    };
+    let thread_id = threads::roadmap_thread(
+        ctx,
+        message.guild_id,
+        message.channel_id,
+        message.id,
+        message.author.name.as_str(),
+    )
+    .await;
+    let channel_id = thread_id.unwrap_or(message.channel_id);
+    // A revision still answers the original request, so that is what gets kept
+    let request = revising
+        .as_ref()
+        .map_or(message.content.clone(), |previous| previous.request.clone());
+    let mut roadmap_request = RoadmapRequest::new(message.content.clone())
+        .conversation(user_context)
+        .attachments(messaging::referenced_material(message));
+    if let Some(previous) = revising {
+        roadmap_request = roadmap_request.revising(previous);
+    }
+    if let Some(context_budget) = context_budget {
+        roadmap_request = roadmap_request.context_budget(context_budget);
+    }
+    let (prompt_tokens, max_cost_usd) = roadmap_request.estimate();
+    debug!("Roadmap prompt is about {prompt_tokens} tokens, costing at most ${max_cost_usd:.4}");
+    let created_roadmap = if roadmaps::structured_roadmaps() {
+        post_structured_roadmap(
+            ctx,
+            message,
+            channel_id,
+            roadmap_request,
+            in_flight.cancelled(),
+        )
+        .await
+    } else {
+        draft_roadmap(
+            ctx,
+            message,
+            channel_id,
+            roadmap_request,
+            in_flight.cancelled(),
+        )
+        .await
+    };
+    drop(in_flight);
+    let created_roadmap = created_roadmap?;
+    if let Some(mut created_roadmap) = created_roadmap {
+        progress.succeeded();
+        created_roadmap.thread_id = thread_id;
+        roadmaps::store_roadmap(message.author.id, request.as_str(), &created_roadmap).await;
+        conversation_state::remember(
+            ctx,
+            message.author.id,
+            PreviousRoadmap {
+                request,
+                roadmap: created_roadmap.roadmap.clone(),
+            },
+        )
+        .await;
+        mod_log::record_roadmap(
+            &ctx.http,
+            &ModLogEntry::for_message(message, "Wrote a roadmap", reason),
+        )
+        .await;
+        info!(
+            thread_id = ?created_roadmap.thread_id,
+            "Roadmap creation for {} - {}",
+            message.author.name,
+            describe_completion(
+                &created_roadmap.model,
+                created_roadmap.usage,
+                created_roadmap.elapsed
+            )
+        );
+    } else {
+        info!(
+            "Roadmap for {} cancelled, its request was deleted",
+            message.author.name
+        );
+    }
+    Ok(())
+}
+
+/// Tells the author their roadmap couldn't be made, if it's something they can act on.
+async fn apologise(ctx: &Context, message: &Message, e: &anyhow::Error) {
+    if let Some(apology) = roadmaps::apology(e) {
+        let _ = reply_chunked(
+            ctx,
+            message.author.mention(),
+            message.channel_id,
+            apology.to_string(),
+        )
+        .await;
+    }
+}
+
+async fn handle_message(ctx: Context, message: Message) {
+    // Downloading and hashing images is slow, so it runs alongside everything else
+    if !message.attachments.is_empty() {
+        tokio::spawn(image_spam::check_images(ctx.clone(), message.clone()).in_current_span());
+    }
+    // Before anything that calls OpenAI, so obvious spam costs nothing to catch
+    if mention_spam::check_mentions(&ctx, &message).await {
+        return;
+    }
+    if duplicate_spam::check_duplicates(&ctx, &message).await {
+        return;
+    }
+    if invite_spam::check_invites(&ctx, &message).await {
+        return;
+    }
+    let links = link_screening::check_links(&ctx, &message).await;
+    if let LinkVerdict::Blocked(domain) = &links {
+        info!("Removing message - links to blocked {domain}");
+        let reason = format!("linking to {domain}");
+        if let Err(e) =
+            messaging::remove_and_escalate(&ctx, &message, reason.as_str(), Severity::High).await
+        {
+            error!("Failed to remove blocked link due to {e:#}");
+        }
+        return;
+    }
+    let on_probation = member_risk::on_probation(&ctx, &message).await;
+    if on_probation {
+        let duplicate_channels = duplicate_spam::channels_with_copies(&ctx, &message).await;
+        if let Some(reason) =
+            member_risk::probation_reason(links == LinkVerdict::Unknown, duplicate_channels)
+        {
+            info!("Removing message - {reason}");
+            if let Err(e) =
+                messaging::remove_and_escalate(&ctx, &message, reason.as_str(), Severity::Medium)
+                    .await
+            {
+                error!("Failed to remove message from new member due to {e:#}");
+            }
+            return;
+        }
+    }
+    match is_message_suspicious(&ctx, &message, links == LinkVerdict::Allowed, on_probation).await {
+        MessageClassification::Normal => {
+            debug!("Message looks clean");
+            member_risk::record_clean(&ctx, &message).await
+        }
+        MessageClassification::MaybeSpam(reason) => match spam_detection::review_channel() {
+            Some(review_channel) => {
+                info!("Sending message for review - {reason}");
+                debug!(
+                    "Message under review is {}",
+                    roadmaps::loggable(message.content.as_str())
+                );
+                if let Err(e) = review_queue::submit(
+                    &ctx,
+                    ChannelId::new(review_channel),
+                    &message,
+                    reason.as_str(),
+                )
+                .await
+                {
+                    error!("Failed to send message for review due to {e:#}");
+                }
+            }
+            None => {
+                info!("Removing message - likely spam - {reason}");
+                debug!(
+                    "Removed message was {}",
+                    roadmaps::loggable(message.content.as_str())
+                );
+                if let Err(e) =
+                    messaging::remove_and_escalate(&ctx, &message, reason.as_str(), Severity::Low)
+                        .await
+                {
+                    error!("Failed to remove likely spam due to {e:#}");
+                }
+            }
+        },
+        MessageClassification::Unclassified => match spam_detection::review_channel() {
+            Some(review_channel) => {
+                info!("Sending message for review - it couldn't be classified");
+                if let Err(e) = review_queue::submit(
+                    &ctx,
+                    ChannelId::new(review_channel),
+                    &message,
+                    "couldn't be classified",
+                )
+                .await
+                {
+                    error!("Failed to send message for review due to {e:#}");
+                }
+            }
+            None => info!("Leaving grey-zone message up, it couldn't be classified"),
+        },
         MessageClassification::DefinitelySpam(reason) => {
-            info!(
-                "Removing message - definitely spam - {}",
-                message.content.as_str()
+            info!("Removing message - definitely spam - {reason}");
+            debug!(
+                "Removed message was {}",
+                roadmaps::loggable(message.content.as_str())
             );
-            messaging::remove_warn_timeout_and_log(&ctx, message.clone(), reason.as_str())
-                .await
-                .unwrap()
+            if let Err(e) =
+                messaging::remove_and_escalate(&ctx, &message, reason.as_str(), Severity::Medium)
+                    .await
+            {
+                error!("Failed to remove spam due to {e:#}");
+            }
+        }
+        MessageClassification::RaidSpam(reason) => {
+            info!("Removing message - spam by raid checks - {reason}");
+            debug!(
+                "Removed message was {}",
+                roadmaps::loggable(message.content.as_str())
+            );
+            if let Err(e) =
+                messaging::remove_without_escalating(&ctx, &message, reason.as_str(), Severity::Low)
+                    .await
+            {
+                error!("Failed to remove spam during a raid due to {e:#}");
+            }
+        }
+        MessageClassification::DmAdvertising(reason) => {
+            info!("Removing message - {reason}");
+            debug!(
+                "Removed message was {}",
+                roadmaps::loggable(message.content.as_str())
+            );
+            if let Err(e) = messaging::remove_and_escalate_warning_once(
+                &ctx,
+                &message,
+                reason.as_str(),
+                dm_advertising::REASON,
+                Severity::Medium,
+            )
+            .await
+            {
+                error!("Failed to remove DM advertising due to {e:#}");
+            }
         }
     }
     if messaging::is_message_request(&message) {
         if let Err(e) = handle_request(&ctx, &message).await {
             error!("Failed to create reply due to {e}")
         }
-    } else if messaging::message_discusses_roadmaps(&message) {
+    } else if (messaging::message_discusses_roadmaps(&message)
+        || conversation_state::may_follow_up(&ctx, &message).await)
+        && roadmap_channels::roadmaps_enabled(&ctx, &message).await
+    {
         if let Err(e) = handle_roadmap(&ctx, &message).await {
-            error!("Failed to create Roadmap due to {e}")
+            error!("Failed to create Roadmap due to {e:#}");
+            apologise(&ctx, &message, &e).await;
         }
     }
 }
 
+/// A span for handling an event about `message`, so everything logged for it can be
+/// found by its IDs.
+fn message_span(kind: &'static str, message: &Message) -> Span {
+    info_span!(
+        "event",
+        kind,
+        guild_id = message.guild_id.map(|id| id.get()),
+        channel_id = message.channel_id.get(),
+        message_id = message.id.get(),
+        author_id = message.author.id.get()
+    )
+}
+
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
-        if msg.channel_id != ChannelId::from(BOT_CHANNEL)
-            && msg.author.id != UserId::from(SPAM_EATER_ID)
-        {
-            if msg.channel_id == ChannelId::from(HONEY_POT_CHANNEL) {
-                info!("Received message in Honeypot channel - removing");
-                messaging::delete_message(&ctx, &msg).await.unwrap();
-                messaging::log_ban(&ctx, msg.author.name.as_str())
-                    .await
-                    .unwrap();
-                messaging::ban_user(&ctx, &msg.guild_id.unwrap(), &msg.author.id)
-                    .await
-                    .unwrap();
-            }
-            user_info::update_user_context(&ctx, &msg).await;
-            match msg.member {
-                None => {
-                    error!("Couldn't find MemberInfo for {:?}", msg.author);
+        let started = Instant::now();
+        let span = message_span("message", &msg);
+        async move {
+            if msg.channel_id != ChannelId::from(BOT_CHANNEL)
+                && msg.author.id != UserId::from(SPAM_EATER_ID)
+            {
+                if honeypot::check_honeypot(&ctx, &msg).await {
+                    return;
                 }
-                Some(ref member_info) => {
-                    user_info::update_user_join_date(
-                        &ctx,
-                        &msg.author,
-                        member_info.joined_at.unwrap().unix_timestamp(),
-                    )
-                    .await;
-                    handle_message(ctx, msg).await;
+                user_info::update_user_context(&ctx, &msg).await;
+                match msg.member {
+                    None => {
+                        error!("Couldn't find MemberInfo for {:?}", msg.author);
+                    }
+                    Some(ref member_info) => {
+                        user_info::update_user_join_date(
+                            &ctx,
+                            &msg.author,
+                            member_info.joined_at.unwrap().unix_timestamp(),
+                        )
+                        .await;
+                        handle_message(ctx, msg).await;
+                        metrics::increment(metrics::MESSAGES_PROCESSED, &[]);
+                        metrics::observe(metrics::MESSAGE_HANDLING, &[], started.elapsed());
+                    }
                 }
             }
         }
+        .instrument(span)
+        .await
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
+        let span = info_span!(
+            "event",
+            kind = "member_join",
+            guild_id = new_member.guild_id.get(),
+            user_id = new_member.user.id.get()
+        );
+        async {
+            member_risk::admit_member(&ctx, &new_member).await;
+            raid::record_join(&ctx, new_member.guild_id).await;
+        }
+        .instrument(span)
+        .await
     }
 
     async fn message_update(
@@ -220,12 +735,131 @@ impl EventHandler for Handler {
         _event: MessageUpdateEvent,
     ) {
         if let Some(updated_message) = new {
-            handle_message(ctx, updated_message).await;
+            let span = message_span("message_update", &updated_message);
+            handle_message(ctx, updated_message).instrument(span).await;
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        let span = info_span!(
+            "event",
+            kind = "message_delete",
+            guild_id = guild_id.map(|id| id.get()),
+            channel_id = channel_id.get(),
+            message_id = deleted_message_id.get()
+        );
+        in_flight::cancel(&ctx, deleted_message_id)
+            .instrument(span)
+            .await;
+    }
+
+    async fn reaction_add(&self, ctx: Context, add_reaction: Reaction) {
+        let span = info_span!(
+            "event",
+            kind = "reaction_add",
+            guild_id = add_reaction.guild_id.map(|id| id.get()),
+            channel_id = add_reaction.channel_id.get(),
+            message_id = add_reaction.message_id.get()
+        );
+        if let Err(e) = reports::check_reaction(&ctx, &add_reaction)
+            .instrument(span)
+            .await
+        {
+            error!("Failed to handle report reaction due to {e:#}");
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let span = info_span!(
+            "event",
+            kind = "interaction",
+            interaction_id = interaction.id().get()
+        );
+        async move {
+            if let Interaction::Component(component) = &interaction {
+                if let Err(e) = review_queue::handle_component(&ctx, component).await {
+                    error!("Failed to handle spam review due to {e:#}");
+                }
+                match confirmations::handle_component(&ctx, component).await {
+                    Ok(Some(message)) => {
+                        if let Err(e) =
+                            create_roadmap(&ctx, &message, None, "confirmed by its author").await
+                        {
+                            error!("Failed to create Roadmap due to {e:#}");
+                            apologise(&ctx, &message, &e).await;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to handle confirmation due to {e}"),
+                }
+            }
+            if let Interaction::Command(command) = interaction {
+                let handled = match command.data.name.as_str() {
+                    roadmap_channels::COMMAND_NAME => {
+                        roadmap_channels::handle_command(&ctx, &command).await
+                    }
+                    invite_spam::COMMAND_NAME => invite_spam::handle_command(&ctx, &command).await,
+                    link_screening::COMMAND_NAME => {
+                        link_screening::handle_command(&ctx, &command).await
+                    }
+                    raid::COMMAND_NAME => raid::handle_command(&ctx, &command).await,
+                    scam_rules::COMMAND_NAME => scam_rules::handle_command(&ctx, &command).await,
+                    evidence::COMMAND_NAME => evidence::handle_command(&ctx, &command).await,
+                    image_spam::COMMAND_NAME => image_spam::handle_command(&ctx, &command).await,
+                    reports::COMMAND_NAME => reports::handle_command(&ctx, &command).await,
+                    roadmap_command::COMMAND_NAME => {
+                        roadmap_command::handle_command(&ctx, &command).await
+                    }
+                    roadmap_command::MY_ROADMAP_COMMAND_NAME => {
+                        roadmap_command::handle_my_roadmap(&ctx, &command).await
+                    }
+                    roadmap_history::COMMAND_NAME => {
+                        roadmap_history::handle_command(&ctx, &command).await
+                    }
+                    roadmap_history::ADMIN_COMMAND_NAME => {
+                        roadmap_history::handle_admin_command(&ctx, &command).await
+                    }
+                    _ => Ok(()),
+                };
+                if let Err(e) = handled {
+                    error!("Failed to handle /{} due to {e}", command.data.name);
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+        let commands = vec![
+            roadmap_channels::command(),
+            invite_spam::command(),
+            link_screening::command(),
+            raid::command(),
+            scam_rules::command(),
+            evidence::command(),
+            image_spam::command(),
+            reports::command(),
+            roadmap_command::command(),
+            roadmap_command::my_roadmap_command(),
+            roadmap_history::command(),
+            roadmap_history::admin_command(),
+        ];
+        if let Err(e) = Command::set_global_commands(&ctx.http, commands).await {
+            error!("Failed to register slash commands due to {e}");
+        }
+        tokio::spawn(async move {
+            if let Err(e) = answer_roadmap_backlog(&ctx).await {
+                error!("Failed to look for missed roadmap requests due to {e:#}");
+            }
+        });
     }
 }
 
@@ -256,14 +890,36 @@ async fn start_health_check() -> Result<(), Box<dyn std::error::Error>> {
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    tracing_subscriber::fmt::init();
+    logging::init();
+    roadmaps::init_config();
+    spam_detection::init_config();
+    evidence::init_archive();
+    storage::init_database();
+    roadmaps::restore_usage().await;
     // Configure the client with your Discord bot token in the environment.
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
-    let openai_key = env::var("OPENAI_KEY").expect("Expected an OpenAI Key in the environment");
-    set_key(openai_key);
+    llm::set_api_key(&env::var("OPENAI_KEY").unwrap_or_default())
+        .expect("Expected an OpenAI Key in the environment");
+    // Point at any OpenAI-compatible endpoint (e.g. Ollama) instead of api.openai.com,
+    // api_base_url in the roadmap config taking precedence over the environment
+    set_base_url(
+        roadmaps::api_base_url()
+            .or_else(|| env::var("OPENAI_BASE_URL").ok())
+            .unwrap_or_default(),
+    );
+    // Spam checks that don't need OpenAI keep working, so only warn if it's unreachable
+    if let Err(e) = roadmaps::check_llm_health().await {
+        warn!("OpenAI API unreachable, roadmaps and spam classification will fail until it's back: {e:#}");
+    }
     // Set gateway intents, which decides what events the bot will be notified about
-    let intents =
+    let mut intents =
         GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT | GatewayIntents::GUILDS;
+    if spam_detection::watch_joins() {
+        intents |= GatewayIntents::GUILD_MEMBERS;
+    }
+    if spam_detection::report_reaction_threshold().is_some() {
+        intents |= GatewayIntents::GUILD_MESSAGE_REACTIONS;
+    }
 
     let mut client = Client::builder(&token, intents)
         .event_handler(Handler)
@@ -274,15 +930,76 @@ async fn main() {
         let mut data = client.data.write().await;
         data.insert::<UserJoinDate>(Arc::new(RwLock::new(HashMap::default())));
         data.insert::<UserContext>(Arc::new(RwLock::new(HashMap::default())));
+        data.insert::<RecentMessages>(Arc::new(RwLock::new(duplicate_spam::from_config())));
+        data.insert::<LinkDomains>(Arc::new(RwLock::new(link_screening::load_domain_lists())));
+        data.insert::<ScamRuleSet>(Arc::new(RwLock::new(scam_rules::load_scam_rules())));
+        data.insert::<NewMembers>(Arc::new(RwLock::new(member_risk::from_config())));
+        data.insert::<RaidMode>(Arc::new(RwLock::new(HashMap::new())));
+        data.insert::<KnownSpamImages>(Arc::new(RwLock::new(image_spam::saved_images())));
+        data.insert::<Strikes>(Arc::new(RwLock::new(
+            StrikeRecords::load(&storage::shared()).await,
+        )));
+        data.insert::<SpamReviews>(Arc::new(RwLock::new(review_queue::saved_queue())));
+        data.insert::<InviteAllowlists>(Arc::new(RwLock::new(invite_spam::saved_allowlist())));
+        data.insert::<InviteOffenders>(Arc::new(RwLock::new(invite_spam::offenses_from_config())));
+        data.insert::<CreatingRoadmaps>(Default::default());
+        data.insert::<InFlightRoadmaps>(Default::default());
+        data.insert::<RoadmapChannels>(Arc::new(RwLock::new(roadmaps::channel_list())));
+        data.insert::<RoadmapCooldowns>(Arc::new(RwLock::new(Default::default())));
+        data.insert::<RoadmapConfirmations>(Arc::new(RwLock::new(Default::default())));
+        data.insert::<RoadmapThreads>(Arc::new(RwLock::new(threads::remembered_threads())));
+        data.insert::<RoadmapConversations>(Arc::new(RwLock::new(LastRoadmaps::new(
+            roadmaps::followup_ttl(),
+        ))));
     }
 
+    tokio::spawn(confirmations::expire_pending_forever(
+        client.data.clone(),
+        client.http.clone(),
+    ));
+
+    tokio::spawn(raid::tick_forever(client.data.clone(), client.http.clone()));
+
+    tokio::spawn(evidence::purge_forever());
+
+    tokio::spawn(roadmaps::watch_prompts_forever());
+
+    tokio::spawn(review_queue::expire_reviews_forever(
+        client.data.clone(),
+        client.http.clone(),
+    ));
+
     tokio::spawn(async {
         if let Err(e) = start_health_check().await {
             eprintln!("Health check service failed: {}", e);
         }
     });
 
+    let (stop_metrics, metrics_stopped) = tokio::sync::oneshot::channel::<()>();
+    let metrics_server = spam_detection::metrics_address().map(|address| {
+        tokio::spawn(async move {
+            let stopped = async {
+                let _ = metrics_stopped.await;
+            };
+            if let Err(e) = metrics_server::run(address, stopped).await {
+                error!("Metrics server failed due to {e:#}");
+            }
+        })
+    });
+
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Shutting down");
+            shard_manager.shutdown_all().await;
+        }
+    });
+
     if let Err(why) = client.start().await {
         println!("Client error: {:?}", why);
     }
+    let _ = stop_metrics.send(());
+    if let Some(metrics_server) = metrics_server {
+        let _ = metrics_server.await;
+    }
 }
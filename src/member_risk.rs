@@ -0,0 +1,355 @@
+use crate::spam_detection;
+use crate::text_normalization::normalize_for_matching;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serenity::all::{Context, Member, Message, MessageId, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{info, instrument};
+
+lazy_static! {
+    /// Names pretending to be staff or a prize, or ending in a run of digits like the
+    /// ones generated for bulk accounts.
+    static ref SUSPICIOUS_NAME_REGEX: Regex = Regex::new(
+        r"(?i)(admin|moderator|\bmod\b|support|nitro|giveaway|airdrop|crypto|gift)|\d{4,}$"
+    )
+    .unwrap();
+}
+
+/// How much each trait of a new member adds to their risk, and how young an account or
+/// membership must be to count at all.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct RiskWeights {
+    /// Added in full for a brand new account, falling to nothing at `young_account_hours`.
+    pub(crate) account_age: f32,
+    pub(crate) young_account_hours: u64,
+    /// Added in full for posting straight after joining, falling to nothing at
+    /// `fresh_join_minutes`.
+    pub(crate) join_age: f32,
+    pub(crate) fresh_join_minutes: u64,
+    pub(crate) default_avatar: f32,
+    pub(crate) suspicious_name: f32,
+}
+
+impl Default for RiskWeights {
+    fn default() -> Self {
+        RiskWeights {
+            account_age: 0.5,
+            young_account_hours: 48,
+            join_age: 0.2,
+            fresh_join_minutes: 10,
+            default_avatar: 0.15,
+            suspicious_name: 0.15,
+        }
+    }
+}
+
+/// What's known about a member when they post.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MemberProfile {
+    pub(crate) account_age: Duration,
+    /// Time since they joined the server.
+    pub(crate) join_age: Duration,
+    pub(crate) default_avatar: bool,
+    pub(crate) username: String,
+    pub(crate) display_name: Option<String>,
+}
+
+/// `weight` scaled by how far `age` is from `limit`, so the youngest get all of it.
+fn fading(weight: f32, age: Duration, limit: Duration) -> f32 {
+    if limit.is_zero() {
+        return 0.0;
+    }
+    weight * (1.0 - (age.as_secs_f32() / limit.as_secs_f32()).min(1.0))
+}
+
+/// How likely `profile` is to be a spam account, from 0.0 to 1.0.
+pub(crate) fn risk_score(profile: &MemberProfile, weights: &RiskWeights) -> f32 {
+    let mut score = fading(
+        weights.account_age,
+        profile.account_age,
+        Duration::from_secs(weights.young_account_hours * 60 * 60),
+    );
+    score += fading(
+        weights.join_age,
+        profile.join_age,
+        Duration::from_secs(weights.fresh_join_minutes * 60),
+    );
+    if profile.default_avatar {
+        score += weights.default_avatar;
+    }
+    let suspicious_name = std::iter::once(profile.username.as_str())
+        .chain(profile.display_name.as_deref())
+        .any(|name| SUSPICIOUS_NAME_REGEX.is_match(&normalize_for_matching(name)));
+    if suspicious_name {
+        score += weights.suspicious_name;
+    }
+    score.clamp(0.0, 1.0)
+}
+
+/// Why a message from someone on probation breaks its stricter rules, if it does.
+pub(crate) fn probation_reason(unknown_link: bool, duplicate_channels: usize) -> Option<String> {
+    if unknown_link {
+        Some("posting a link while new to the server".to_string())
+    } else if duplicate_channels >= 2 {
+        Some(format!(
+            "posting the same message in {duplicate_channels} channels while new to the server"
+        ))
+    } else {
+        None
+    }
+}
+
+/// A member who joined while the bot was watching, until they've posted enough clean
+/// messages.
+#[derive(Debug)]
+struct Newcomer {
+    created_at: i64,
+    joined_at: i64,
+    default_avatar: bool,
+    username: String,
+    display_name: Option<String>,
+    clean_messages: HashSet<MessageId>,
+}
+
+/// New members whose first `messages` messages are held to stricter rules, with their
+/// risk falling as clean ones build up. Anyone still on probation `young_account_hours`
+/// after joining is let off.
+#[derive(Debug)]
+pub(crate) struct Probation {
+    messages: usize,
+    weights: RiskWeights,
+    newcomers: HashMap<UserId, Newcomer>,
+}
+
+impl Probation {
+    pub(crate) fn new(messages: usize, weights: RiskWeights) -> Self {
+        Probation {
+            messages,
+            weights,
+            newcomers: HashMap::new(),
+        }
+    }
+
+    /// Puts `user_id` on probation, created and joined at the given unix timestamps.
+    pub(crate) fn admit(
+        &mut self,
+        user_id: UserId,
+        created_at: i64,
+        joined_at: i64,
+        default_avatar: bool,
+        username: String,
+        display_name: Option<String>,
+    ) {
+        if self.messages == 0 {
+            return;
+        }
+        let lasts = self.lasts();
+        self.newcomers
+            .retain(|_, newcomer| joined_at.saturating_sub(newcomer.joined_at) < lasts);
+        self.newcomers.insert(
+            user_id,
+            Newcomer {
+                created_at,
+                joined_at,
+                default_avatar,
+                username,
+                display_name,
+                clean_messages: HashSet::new(),
+            },
+        );
+    }
+
+    /// Risk of `user_id` at unix time `now`, scaled down by the clean messages they've
+    /// posted. Zero for anyone not on probation.
+    pub(crate) fn risk(&self, user_id: UserId, now: i64) -> f32 {
+        let Some(newcomer) = self.newcomers.get(&user_id) else {
+            return 0.0;
+        };
+        if now.saturating_sub(newcomer.joined_at) >= self.lasts() {
+            return 0.0;
+        }
+        let since =
+            |timestamp: i64| Duration::from_secs(now.saturating_sub(timestamp).max(0) as u64);
+        let profile = MemberProfile {
+            account_age: since(newcomer.created_at),
+            join_age: since(newcomer.joined_at),
+            default_avatar: newcomer.default_avatar,
+            username: newcomer.username.clone(),
+            display_name: newcomer.display_name.clone(),
+        };
+        let remaining = 1.0 - newcomer.clean_messages.len() as f32 / self.messages as f32;
+        risk_score(&profile, &self.weights) * remaining
+    }
+
+    /// Seconds after joining that probation ends, however few messages were posted.
+    fn lasts(&self) -> i64 {
+        (self.weights.young_account_hours * 60 * 60) as i64
+    }
+
+    /// Counts `message_id` as a clean message from `user_id`, ending their probation once
+    /// there are enough. Edits of a counted message don't count again.
+    pub(crate) fn record_clean(&mut self, user_id: UserId, message_id: MessageId) {
+        let Some(newcomer) = self.newcomers.get_mut(&user_id) else {
+            return;
+        };
+        newcomer.clean_messages.insert(message_id);
+        if newcomer.clean_messages.len() >= self.messages {
+            info!("{} finished probation", newcomer.username);
+            self.newcomers.remove(&user_id);
+        }
+    }
+}
+
+pub(crate) struct NewMembers;
+
+impl TypeMapKey for NewMembers {
+    type Value = Arc<RwLock<Probation>>;
+}
+
+/// Probation for the configured number of messages, to go in the `TypeMap`.
+pub(crate) fn from_config() -> Probation {
+    let (messages, _) = spam_detection::probation_settings();
+    Probation::new(messages, spam_detection::risk_weights().clone())
+}
+
+async fn new_members(ctx: &Context) -> Arc<RwLock<Probation>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<NewMembers>()
+        .expect("Expected NewMembers in TypeMap.")
+        .clone()
+}
+
+/// Puts a member who just joined on probation.
+pub(crate) async fn admit_member(ctx: &Context, member: &Member) {
+    let joined_at = member.joined_at.map_or_else(
+        || chrono::Utc::now().timestamp(),
+        |joined| joined.unix_timestamp(),
+    );
+    new_members(ctx).await.write().await.admit(
+        member.user.id,
+        member.user.id.created_at().unix_timestamp(),
+        joined_at,
+        member.user.avatar.is_none(),
+        member.user.name.clone(),
+        member.user.global_name.clone(),
+    );
+}
+
+/// Whether the author of `message` is risky enough to be held to probation's rules.
+#[instrument(skip_all, ret(level = "debug"))]
+pub(crate) async fn on_probation(ctx: &Context, message: &Message) -> bool {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if spam_detection::is_trusted(roles) {
+        return false;
+    }
+    let (_, min_risk) = spam_detection::probation_settings();
+    let risk = new_members(ctx)
+        .await
+        .read()
+        .await
+        .risk(message.author.id, message.timestamp.unix_timestamp());
+    risk > 0.0 && risk >= min_risk
+}
+
+/// Counts `message` towards its author finishing probation.
+pub(crate) async fn record_clean(ctx: &Context, message: &Message) {
+    new_members(ctx)
+        .await
+        .write()
+        .await
+        .record_clean(message.author.id, message.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    fn profile(account_hours: u64, join_minutes: u64) -> MemberProfile {
+        MemberProfile {
+            account_age: Duration::from_secs(account_hours * HOUR),
+            join_age: Duration::from_secs(join_minutes * 60),
+            default_avatar: false,
+            username: "ada".to_string(),
+            display_name: None,
+        }
+    }
+
+    #[test]
+    fn young_accounts_and_fresh_joins_are_riskier() {
+        let weights = RiskWeights::default();
+        assert_eq!(risk_score(&profile(24 * 365, 60), &weights), 0.0);
+        assert!((risk_score(&profile(0, 60), &weights) - 0.5).abs() < 1e-6);
+        assert!((risk_score(&profile(24, 60), &weights) - 0.25).abs() < 1e-6);
+        assert!((risk_score(&profile(24 * 365, 0), &weights) - 0.2).abs() < 1e-6);
+        assert!(risk_score(&profile(1, 1), &weights) > risk_score(&profile(40, 9), &weights));
+    }
+
+    #[test]
+    fn avatars_and_names_add_their_weights() {
+        let weights = RiskWeights::default();
+        let mut bot_like = profile(24 * 365, 60);
+        bot_like.default_avatar = true;
+        assert!((risk_score(&bot_like, &weights) - 0.15).abs() < 1e-6);
+        bot_like.username = "ada48213".to_string();
+        assert!((risk_score(&bot_like, &weights) - 0.3).abs() < 1e-6);
+        let mut staff = profile(24 * 365, 60);
+        staff.display_name = Some("Discord Support".to_string());
+        assert!((risk_score(&staff, &weights) - 0.15).abs() < 1e-6);
+        staff.display_name = Some("Ｄiscоrd Ѕuрроrt".to_string());
+        assert!((risk_score(&staff, &weights) - 0.15).abs() < 1e-6);
+        let everything = MemberProfile {
+            default_avatar: true,
+            username: "free_nitro".to_string(),
+            ..profile(0, 0)
+        };
+        assert_eq!(risk_score(&everything, &weights), 1.0);
+    }
+
+    #[test]
+    fn risk_decays_with_clean_messages() {
+        let mut probation = Probation::new(4, RiskWeights::default());
+        let user_id = UserId::new(1);
+        probation.admit(user_id, 0, 0, true, "ada".to_string(), None);
+        let start = probation.risk(user_id, 0);
+        assert!((start - 0.85).abs() < 1e-6);
+        probation.record_clean(user_id, MessageId::new(1));
+        probation.record_clean(user_id, MessageId::new(1));
+        assert!((probation.risk(user_id, 0) - start * 0.75).abs() < 1e-6);
+        for id in 2..=4 {
+            probation.record_clean(user_id, MessageId::new(id));
+        }
+        assert_eq!(probation.risk(user_id, 0), 0.0);
+        assert_eq!(probation.risk(UserId::new(2), 0), 0.0);
+    }
+
+    #[test]
+    fn probation_expires_after_young_account_hours() {
+        let mut probation = Probation::new(4, RiskWeights::default());
+        let lasts = (48 * HOUR) as i64;
+        probation.admit(UserId::new(1), 0, 0, true, "ada".to_string(), None);
+        assert!(probation.risk(UserId::new(1), lasts - 1) > 0.0);
+        assert_eq!(probation.risk(UserId::new(1), lasts), 0.0);
+        probation.admit(UserId::new(2), 0, lasts, true, "bob".to_string(), None);
+        assert_eq!(probation.newcomers.len(), 1);
+        assert!(probation.newcomers.contains_key(&UserId::new(2)));
+    }
+
+    #[test]
+    fn probation_allows_no_links_or_copies() {
+        assert!(probation_reason(true, 1).is_some());
+        assert!(probation_reason(false, 2).is_some());
+        assert_eq!(probation_reason(false, 1), None);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::messaging;
+use crate::spam_detection;
+use crate::user_info;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serenity::all::{Context, Message, Permissions};
+use std::collections::HashSet;
+use tracing::{info, instrument, warn};
+
+lazy_static! {
+    /// `<@id>`, `<@!id>` and `<@&id>`, the way user and role mentions are written.
+    static ref MENTION_REGEX: Regex = Regex::new(r"<@([!&]?)(\d+)>").unwrap();
+    static ref EVERYONE_REGEX: Regex = Regex::new(r"@(everyone|here)\b").unwrap();
+}
+
+/// Mentions written out in a message's text. Mentions in code blocks don't ping but still
+/// count, and reply pings aren't written in the text so never do.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Mentions {
+    pub(crate) everyone: bool,
+    /// Distinct users and roles mentioned.
+    pub(crate) distinct: usize,
+}
+
+pub(crate) fn count_mentions(content: &str) -> Mentions {
+    let distinct: HashSet<(bool, &str)> = MENTION_REGEX
+        .captures_iter(content)
+        .map(|captures| {
+            let is_role = &captures[1] == "&";
+            (is_role, captures.get(2).unwrap().as_str())
+        })
+        .collect();
+    Mentions {
+        everyone: EVERYONE_REGEX.is_match(content),
+        distinct: distinct.len(),
+    }
+}
+
+/// Why `content` is a mass mention, if it is.
+pub(crate) fn mass_mention_reason(content: &str, max_mentions: usize) -> Option<String> {
+    let mentions = count_mentions(content);
+    if mentions.everyone {
+        Some("pinging @everyone or @here".to_string())
+    } else if mentions.distinct > max_mentions {
+        Some(format!("mentioning {} people or roles", mentions.distinct))
+    } else {
+        None
+    }
+}
+
+/// Deletes `message`, times its author out and tells the bot team if it pings everyone or
+/// more than `max_mentions` users and roles. Returns whether it did. Members with a
+/// trusted role, or allowed to mention everyone, can mention as many as they like.
+#[instrument(skip_all, ret(level = "debug"))]
+pub(crate) async fn check_mentions(ctx: &Context, message: &Message) -> bool {
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if spam_detection::is_trusted(roles) {
+        return false;
+    }
+    let Some(reason) =
+        mass_mention_reason(message.content.as_str(), spam_detection::max_mentions())
+    else {
+        return false;
+    };
+    if user_info::has_any_permission(ctx, message, Permissions::MENTION_EVERYONE).await {
+        return false;
+    }
+    info!(
+        "Removing mass mention from {} ({reason})",
+        message.author.name
+    );
+    if let Err(e) = messaging::remove_mass_mention_and_log(ctx, message, reason.as_str()).await {
+        warn!("Failed to remove mass mention due to {e:#}");
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_distinct_users_and_roles() {
+        assert_eq!(
+            count_mentions("<@1> <@!1> <@2> <@&1> <@&3> hi"),
+            Mentions {
+                everyone: false,
+                distinct: 4
+            }
+        );
+        assert_eq!(count_mentions("no pings here").distinct, 0);
+    }
+
+    #[test]
+    fn mentions_in_code_blocks_count() {
+        let content = "```\n<@1> <@2> <@3> @everyone\n```";
+        assert_eq!(
+            count_mentions(content),
+            Mentions {
+                everyone: true,
+                distinct: 3
+            }
+        );
+        assert!(mass_mention_reason("`@here` free nitro", 5).is_some());
+    }
+
+    #[test]
+    fn only_floods_and_everyone_are_mass_mentions() {
+        assert_eq!(mass_mention_reason("<@1> <@2> <@3>", 3), None);
+        assert_eq!(
+            mass_mention_reason("<@1> <@2> <@3> <@&4>", 3).as_deref(),
+            Some("mentioning 4 people or roles")
+        );
+        assert_eq!(
+            mass_mention_reason("@everyone free nitro", 3).as_deref(),
+            Some("pinging @everyone or @here")
+        );
+        // An email address isn't a ping
+        assert_eq!(mass_mention_reason("mail me at me@hereford.ac.uk", 3), None);
+    }
+}
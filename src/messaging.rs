@@ -1,9 +1,26 @@
+use crate::chunking::DISCORD_MESSAGE_LIMIT;
 use crate::clean_messages::clean_message;
+use crate::evidence;
+use crate::honeypot::HoneypotAction;
+use crate::link_screening;
+use crate::metrics;
+use crate::mod_log::{self, ModLogEntry};
+use crate::spam_detection;
+use crate::spam_detection::SpamAction;
+use crate::strikes;
+use crate::strikes::{Escalation, Severity};
 use crate::{BOT_CHANNEL, VAGUELY_OKAY_WEBSITES};
+use anyhow::Context as _;
 use chrono::{Duration, TimeZone, Utc};
 use serenity::all::{
-    ChannelId, Context, CreateMessage, GuildId, Mentionable, Message, Timestamp, User, UserId,
+    ChannelId, Context, CreateAllowedMentions, CreateAttachment, CreateMessage, Embed, GuildId,
+    Http, Mentionable, Message, MessageId, RoleId, Timestamp, User, UserId,
 };
+use std::ops::RangeInclusive;
+use url::Url;
+
+/// Latest strikes listed when someone's escalated.
+const STRIKE_HISTORY_SHOWN: usize = 5;
 
 pub fn is_suspicious_url(path: &str) -> bool {
     path.contains("http")
@@ -23,18 +40,27 @@ pub fn is_new_user(timestamp: Option<i64>) -> bool {
     }
 }
 
-async fn warn_user_generic(
-    ctx: &Context,
-    channel_id: ChannelId,
-    user: &User,
-) -> serenity::Result<Message> {
-    warn_user_with_message(
-        ctx,
-        channel_id,
-        user,
-        "please wait a while after joining before sharing links or mentioning people.".to_string(),
-    )
-    .await
+/// `intro`, `content` exactly as written in a code block, then `outro`, or `None` if
+/// that's too long for one message.
+fn quoted(intro: &str, content: &str, outro: &str) -> Option<String> {
+    // Keep the original intact without letting it close the code block
+    let quoted = format!(
+        "{intro}\n```\n{}\n```{outro}",
+        content.replace("```", "`\u{200B}``")
+    );
+    (quoted.chars().count() <= DISCORD_MESSAGE_LIMIT).then_some(quoted)
+}
+
+/// A message showing the bot team `content` without pinging anyone, quoted after `intro`
+/// or attached as a text file when it's too long to quote.
+fn quote_for_bot_team(intro: &str, content: &str, outro: &str) -> CreateMessage {
+    let message = match quoted(intro, content, outro) {
+        Some(quoted) => CreateMessage::new().content(quoted),
+        None => CreateMessage::new()
+            .content(format!("{intro} (attached){outro}"))
+            .add_file(CreateAttachment::bytes(content.as_bytes(), "message.txt")),
+    };
+    message.allowed_mentions(CreateAllowedMentions::new())
 }
 
 async fn warn_user_with_reason(
@@ -69,45 +95,42 @@ async fn warn_user_with_message(
         .await
 }
 
-async fn log_actions(
+/// Tells the bot team someone posted in a honeypot, keeping the whole message as
+/// evidence. In `dry_run` it says what would have been done instead.
+pub(crate) async fn log_honeypot(
     ctx: &Context,
-    content: &str,
-    author_name: &str,
-    reason: Option<&str>,
-    timeout: bool,
+    message: &Message,
+    action: HoneypotAction,
+    dry_run: bool,
 ) -> serenity::Result<Message> {
-    let formatted_reason = match reason {
-        None => "".to_string(),
-        Some(reason) => format!(" because `{}`", reason),
+    let action = match action {
+        HoneypotAction::Ban => "banned them",
+        HoneypotAction::Softban => "softbanned them",
     };
-    let actions_taken = if timeout {
-        " and timed them out until tomorrow"
+    let taken = if dry_run {
+        format!("I would have deleted it and {action}, but I'm in dry-run mode")
     } else {
-        ""
-    }
-    .to_string();
-    ChannelId::from(BOT_CHANNEL)
-        .send_message(
-            &ctx.http,
-            CreateMessage::new().content(format!(
-                "Hey bot team! I found '{}' from {} suspicious{}, so I deleted it{}. :)",
-                clean_message(content),
-                author_name,
-                formatted_reason,
-                actions_taken
-            )),
-        )
-        .await
-}
-
-pub async fn log_ban(ctx: &Context, author_name: &str) -> serenity::Result<Message> {
+        format!("so I deleted it and {action}")
+    };
+    let attachments: String = message
+        .attachments
+        .iter()
+        .map(|attachment| format!("\n{}", attachment.url))
+        .collect();
+    let intro = format!(
+        "Hey bot team! {} ({}) posted this in {}, {taken}:",
+        message.author.name,
+        message.author.id,
+        message.channel_id.mention(),
+    );
     ChannelId::from(BOT_CHANNEL)
         .send_message(
             &ctx.http,
-            CreateMessage::new().content(format!(
-                "Hey bot team! '{}' posted in THE CHANNEL, so I deleted them :)",
-                author_name
-            )),
+            quote_for_bot_team(
+                intro.as_str(),
+                message.content.as_str(),
+                attachments.as_str(),
+            ),
         )
         .await
 }
@@ -122,66 +145,291 @@ pub async fn ban_user(ctx: &Context, guild_id: &GuildId, user: &UserId) -> seren
         .await
 }
 
-async fn timeout_user(ctx: &Context, guild_id: &GuildId, user: &UserId) -> serenity::Result<()> {
+async fn timeout_user_for(
+    ctx: &Context,
+    guild_id: &GuildId,
+    user: &UserId,
+    duration: std::time::Duration,
+) -> serenity::Result<()> {
     guild_id
         .member(ctx, user)
         .await?
         .disable_communication_until_datetime(
             ctx,
             Timestamp::from_unix_timestamp(
-                Timestamp::now().unix_timestamp() + Duration::days(1).num_seconds(),
+                Timestamp::now().unix_timestamp() + duration.as_secs() as i64,
             )
             .unwrap(),
         )
         .await
 }
 
-pub async fn remove_message_and_log(ctx: &Context, message: Message) -> anyhow::Result<()> {
-    warn_user_generic(ctx, message.channel_id, &message.author)
-        .await
-        .unwrap();
-    ctx.http
-        .delete_message(
-            message.channel_id,
-            message.id,
-            Some("Updated message with banned content"),
-        )
-        .await
-        .unwrap();
-    log_actions(
+/// Deletes a spam message and warns its author, gives them strikes for `severity` and
+/// takes whatever action the strike ladder says, then tells the bot team along with
+/// their strikes so far.
+pub(crate) async fn remove_and_escalate(
+    ctx: &Context,
+    message: &Message,
+    reason: &str,
+    severity: Severity,
+) -> anyhow::Result<()> {
+    escalate(
+        ctx,
+        message,
+        reason,
+        severity,
+        Escalation::Delete..=Escalation::Ban,
+        None,
+    )
+    .await
+}
+
+/// `remove_and_escalate` for messages that are only spam by the tighter checks used
+/// during a raid: they still give strikes, but never time anyone out by themselves, so
+/// timeouts stay at the usual thresholds.
+pub(crate) async fn remove_without_escalating(
+    ctx: &Context,
+    message: &Message,
+    reason: &str,
+    severity: Severity,
+) -> anyhow::Result<()> {
+    escalate(
         ctx,
-        message.content.as_str(),
-        message.author.name.as_str(),
+        message,
+        reason,
+        severity,
+        Escalation::Delete..=Escalation::Delete,
         None,
-        false,
+    )
+    .await
+}
+
+/// `remove_and_escalate`, only warning the author publicly if none of the strikes they
+/// still have mention `warned_for`, which `reason` may give alongside other reasons.
+pub(crate) async fn remove_and_escalate_warning_once(
+    ctx: &Context,
+    message: &Message,
+    reason: &str,
+    warned_for: &str,
+    severity: Severity,
+) -> anyhow::Result<()> {
+    escalate(
+        ctx,
+        message,
+        reason,
+        severity,
+        Escalation::Delete..=Escalation::Ban,
+        Some(warned_for),
+    )
+    .await
+}
+
+/// `remove_and_escalate`, keeping what the ladder says within `escalations`, and skipping
+/// the public warning if an earlier strike mentions `warn_once`. The mod log and the bot
+/// channel hear about it even if acting fails, with what went wrong.
+async fn escalate(
+    ctx: &Context,
+    message: &Message,
+    reason: &str,
+    severity: Severity,
+    escalations: RangeInclusive<Escalation>,
+    warn_once: Option<&str>,
+) -> anyhow::Result<()> {
+    let guild_id = message.guild_id.context("Spam outside a guild")?;
+    let (total, escalation, history) = strikes::record(
+        ctx,
+        message.author.id,
+        severity,
+        reason,
+        message.timestamp.unix_timestamp(),
     )
     .await?;
+    let escalation = escalation.clamp(*escalations.start(), *escalations.end());
+    let warned_before = warn_once.is_some_and(|warned_for| {
+        history.split_last().is_some_and(|(_, earlier)| {
+            earlier
+                .iter()
+                .any(|strike| strike.reason.contains(warned_for))
+        })
+    });
+    let archiving = evidence::archive(message, reason);
+    let acted: anyhow::Result<()> = async {
+        if !warned_before {
+            warn_user_with_reason(ctx, message.channel_id, &message.author, reason).await?;
+        }
+        ctx.http
+            .delete_message(
+                message.channel_id,
+                message.id,
+                Some("Message with banned content"),
+            )
+            .await?;
+        match escalation {
+            Escalation::Delete => {}
+            Escalation::ShortTimeout | Escalation::LongTimeout => {
+                let duration = escalation.timeout().unwrap_or_default();
+                timeout_user_for(ctx, &guild_id, &message.author.id, duration).await?;
+            }
+            Escalation::Kick => {
+                guild_id
+                    .kick_with_reason(&ctx.http, message.author.id, reason)
+                    .await?
+            }
+            Escalation::Ban => {
+                guild_id
+                    .ban_with_reason(&ctx.http, message.author.id, 0, reason)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+    .await;
+    let evidence_id = archiving.await.ok().flatten();
+    let action = match &acted {
+        Ok(()) => format!("Deleted message{}", escalation.describe()),
+        Err(e) => format!(
+            "Tried to delete message{}, but failed: {e:#}",
+            escalation.describe()
+        ),
+    };
+    mod_log::record(
+        &ctx.http,
+        &ModLogEntry::for_message(message, action, reason).evidence(evidence_id),
+    )
+    .await;
+    let history: Vec<String> = history
+        .iter()
+        .rev()
+        .take(STRIKE_HISTORY_SHOWN)
+        .map(|strike| {
+            format!(
+                "- <t:{}:R> `{}` (+{})",
+                strike.at, strike.reason, strike.strikes
+            )
+        })
+        .collect();
+    let done = match &acted {
+        Ok(()) => format!("so I deleted it{}", escalation.describe()),
+        Err(e) => format!(
+            "so I tried to delete it{}, but failed: {e:#}",
+            escalation.describe()
+        ),
+    };
+    let intro = format!(
+        "Hey bot team! {} posted this in {}, {reason}, {done}:",
+        message.author.name,
+        message.channel_id.mention(),
+    );
+    let outro = format!("\nThey're on {total:.1} strikes:\n{}", history.join("\n"));
+    let posted = ChannelId::from(BOT_CHANNEL)
+        .send_message(
+            &ctx.http,
+            quote_for_bot_team(
+                intro.as_str(),
+                clean_message(message.content.as_str()).as_str(),
+                outro.as_str(),
+            ),
+        )
+        .await;
+    acted?;
+    metrics::increment(metrics::SPAM_ACTIONS, &[("action", escalation.name())]);
+    posted?;
     Ok(())
 }
 
-pub async fn remove_warn_timeout_and_log(
+/// Deletes every copy of a message pasted across channels and escalates its author like
+/// any spammer, doing at least `action` to them.
+pub(crate) async fn remove_duplicates_and_log(
+    ctx: &Context,
+    message: &Message,
+    copies: &[(ChannelId, MessageId)],
+    action: SpamAction,
+) -> anyhow::Result<()> {
+    for (channel_id, message_id) in copies {
+        if *message_id == message.id {
+            continue;
+        }
+        // Copies already deleted by their author or a moderator don't matter
+        let _ = ctx
+            .http
+            .delete_message(
+                *channel_id,
+                *message_id,
+                Some("Same message across channels"),
+            )
+            .await;
+    }
+    let reason = format!("the same message in {} channels", copies.len());
+    escalate(
+        ctx,
+        message,
+        reason.as_str(),
+        Severity::Medium,
+        action.least_escalation()..=Escalation::Ban,
+        None,
+    )
+    .await
+}
+
+/// Deletes a message with an invite that isn't allowed and escalates its author, timing
+/// them out for at least a day if it's a `repeat` offense.
+pub(crate) async fn remove_invite_and_log(
+    ctx: &Context,
+    message: &Message,
+    repeat: bool,
+) -> anyhow::Result<()> {
+    let at_least = if repeat {
+        Escalation::LongTimeout
+    } else {
+        Escalation::Delete
+    };
+    escalate(
+        ctx,
+        message,
+        "invite to another server",
+        Severity::Low,
+        at_least..=Escalation::Ban,
+        None,
+    )
+    .await
+}
+
+/// Deletes a message mentioning too many people and escalates its author, timing them
+/// out for at least a day. The bot team sees the message without anyone being pinged
+/// again.
+pub(crate) async fn remove_mass_mention_and_log(
     ctx: &Context,
-    message: Message,
+    message: &Message,
     reason: &str,
 ) -> anyhow::Result<()> {
-    warn_user_with_reason(ctx, message.channel_id, &message.author, reason).await?;
-    ctx.http
-        .delete_message(
-            message.channel_id,
-            message.id,
-            Some("Message with banned content"),
-        )
-        .await
-        .unwrap();
-    timeout_user(ctx, &message.guild_id.unwrap(), &message.author.id).await?;
-    log_actions(
+    escalate(
         ctx,
-        message.content.as_str(),
-        message.author.name.as_str(),
-        Some(reason),
-        true,
+        message,
+        reason,
+        Severity::Medium,
+        Escalation::LongTimeout..=Escalation::Ban,
+        None,
     )
-    .await?;
+    .await
+}
+
+/// Tells the bot team raid mode changed, pinging the configured mod role if `ping`.
+pub(crate) async fn log_raid(http: &Http, announcement: &str, ping: bool) -> serenity::Result<()> {
+    let role_id = spam_detection::raid_mod_role()
+        .filter(|_| ping)
+        .map(RoleId::new);
+    let content = match role_id {
+        Some(role_id) => format!("{} {announcement}!", role_id.mention()),
+        None => format!("Hey bot team! {announcement}."),
+    };
+    ChannelId::from(BOT_CHANNEL)
+        .send_message(
+            http,
+            CreateMessage::new()
+                .content(content)
+                .allowed_mentions(CreateAllowedMentions::new().roles(role_id)),
+        )
+        .await?;
     Ok(())
 }
 
@@ -190,6 +438,85 @@ pub fn message_discusses_roadmaps(message: &Message) -> bool {
         | message.content.to_lowercase().contains("road map")
 }
 
+/// What `message` links to or attaches, for grounding its roadmap: each link, and each
+/// file's name and address.
+pub(crate) fn referenced_material(message: &Message) -> Vec<String> {
+    let files = message
+        .attachments
+        .iter()
+        .map(|attachment| format!("{}: {}", attachment.filename, attachment.url));
+    linked_material(message.content.as_str(), &message.embeds)
+        .into_iter()
+        .chain(files)
+        .collect()
+}
+
+/// Each link in `content`, with its preview's title and description when `embeds` has
+/// one. Previews usually arrive in an edit after the message, so the links themselves are
+/// read from the content.
+fn linked_material(content: &str, embeds: &[Embed]) -> Vec<String> {
+    link_screening::extract_urls(content)
+        .into_iter()
+        .map(|url| {
+            let preview = embeds.iter().find(|embed| {
+                embed
+                    .url
+                    .as_deref()
+                    .and_then(|embed_url| Url::parse(embed_url).ok())
+                    .is_some_and(|embed_url| embed_url == url)
+            });
+            let Some(preview) = preview else {
+                return url.to_string();
+            };
+            [
+                preview.title.as_deref(),
+                Some(url.as_str()),
+                preview.description.as_deref(),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n")
+        })
+        .collect()
+}
+
 pub fn is_message_request(message: &Message) -> bool {
     message.content.to_lowercase().starts_with("!request")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_only_what_fits_in_a_message() {
+        assert_eq!(
+            quoted("Look:", "```rm -rf```", "\nmore").as_deref(),
+            Some("Look:\n```\n`\u{200B}``rm -rf`\u{200B}``\n```\nmore")
+        );
+        let pings = "<@123456789012345678> ".repeat(100);
+        assert_eq!(quoted("Look:", pings.as_str(), ""), None);
+    }
+
+    #[test]
+    fn links_are_read_from_the_content_with_any_preview() {
+        let preview: Embed = serde_json::from_value(serde_json::json!({
+            "url": "https://jobs.example.com/analyst",
+            "title": "Data Analyst",
+            "description": "SQL and Tableau",
+        }))
+        .unwrap();
+        assert_eq!(
+            linked_material(
+                "Roadmap for https://jobs.example.com/analyst and https://course.example.org/sql?",
+                &[preview]
+            ),
+            [
+                "Data Analyst\nhttps://jobs.example.com/analyst\nSQL and Tableau",
+                "https://course.example.org/sql"
+            ]
+        );
+        assert!(linked_material("Roadmap please", &[]).is_empty());
+    }
+}
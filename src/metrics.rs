@@ -0,0 +1,187 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub(crate) const MESSAGES_PROCESSED: &str = "spam_eater_messages_processed_total";
+pub(crate) const SPAM_ACTIONS: &str = "spam_eater_spam_actions_total";
+pub(crate) const ROADMAP_DETECTIONS: &str = "spam_eater_roadmap_detections_total";
+pub(crate) const ROADMAP_CREATIONS: &str = "spam_eater_roadmap_creations_total";
+pub(crate) const OPENAI_CALLS: &str = "spam_eater_openai_calls_total";
+pub(crate) const OPENAI_RETRIES: &str = "spam_eater_openai_retries_total";
+pub(crate) const OPENAI_TOKENS: &str = "spam_eater_openai_tokens_total";
+pub(crate) const OPENAI_LATENCY: &str = "spam_eater_openai_latency_seconds";
+pub(crate) const MESSAGE_HANDLING: &str = "spam_eater_message_handling_seconds";
+
+/// Upper bounds, in seconds, of the buckets every histogram counts into.
+pub(crate) const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    Counter,
+    Histogram,
+}
+
+/// Every metric with its kind and help text, so exporters can describe them before
+/// anything is recorded.
+pub(crate) const METRICS: [(&str, Kind, &str); 9] = [
+    (
+        MESSAGES_PROCESSED,
+        Kind::Counter,
+        "Messages checked for spam and roadmap requests",
+    ),
+    (
+        SPAM_ACTIONS,
+        Kind::Counter,
+        "Actions taken against spam, by action",
+    ),
+    (
+        ROADMAP_DETECTIONS,
+        Kind::Counter,
+        "Roadmap detections, by outcome",
+    ),
+    (
+        ROADMAP_CREATIONS,
+        Kind::Counter,
+        "Roadmap creations, by outcome",
+    ),
+    (
+        OPENAI_CALLS,
+        Kind::Counter,
+        "OpenAI chat completions, by model and outcome",
+    ),
+    (
+        OPENAI_RETRIES,
+        Kind::Counter,
+        "OpenAI requests retried after a transient failure",
+    ),
+    (
+        OPENAI_TOKENS,
+        Kind::Counter,
+        "OpenAI tokens used, by model and kind",
+    ),
+    (
+        OPENAI_LATENCY,
+        Kind::Histogram,
+        "Seconds each OpenAI chat completion took, retries included",
+    ),
+    (
+        MESSAGE_HANDLING,
+        Kind::Histogram,
+        "Seconds from receiving a message to being done with it",
+    ),
+];
+
+/// A metric's label pairs, sorted so the same labels always make the same series.
+pub(crate) type Labels = Vec<(&'static str, String)>;
+
+/// Counts per bucket of `BUCKETS`, not cumulative, and the sum and count of every value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Histogram {
+    pub(crate) buckets: [u64; BUCKETS.len()],
+    pub(crate) sum: f64,
+    pub(crate) count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+fn labels(labels: &[(&'static str, &str)]) -> Labels {
+    let mut labels: Labels = labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+    labels.sort();
+    labels
+}
+
+pub(crate) fn increment(name: &'static str, labels: &[(&'static str, &str)]) {
+    increment_by(name, labels, 1);
+}
+
+pub(crate) fn increment_by(name: &'static str, series: &[(&'static str, &str)], value: u64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.counters.entry((name, labels(series))).or_default() += value;
+}
+
+pub(crate) fn observe(name: &'static str, series: &[(&'static str, &str)], elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let mut registry = REGISTRY.lock().unwrap();
+    let histogram = registry
+        .histograms
+        .entry((name, labels(series)))
+        .or_default();
+    if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+        histogram.buckets[bucket] += 1;
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+/// Every counter and histogram series recorded so far.
+pub(crate) struct Snapshot {
+    pub(crate) counters: Vec<(&'static str, Labels, u64)>,
+    pub(crate) histograms: Vec<(&'static str, Labels, Histogram)>,
+}
+
+pub(crate) fn snapshot() -> Snapshot {
+    let registry = REGISTRY.lock().unwrap();
+    Snapshot {
+        counters: registry
+            .counters
+            .iter()
+            .map(|((name, labels), value)| (*name, labels.clone(), *value))
+            .collect(),
+        histograms: registry
+            .histograms
+            .iter()
+            .map(|((name, labels), histogram)| (*name, labels.clone(), histogram.clone()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_are_kept_apart_by_sorted_labels() {
+        let name = "test_series_total";
+        increment(name, &[("b", "2"), ("a", "1")]);
+        increment_by(name, &[("a", "1"), ("b", "2")], 2);
+        increment(name, &[("a", "other")]);
+        observe(name, &[], Duration::from_millis(200));
+        observe(name, &[], Duration::from_secs(120));
+        let Snapshot {
+            counters,
+            histograms,
+        } = snapshot();
+        let counters: Vec<_> = counters
+            .into_iter()
+            .filter(|(of, ..)| *of == name)
+            .collect();
+        assert_eq!(
+            counters,
+            [
+                (
+                    name,
+                    vec![("a", "1".to_string()), ("b", "2".to_string())],
+                    3
+                ),
+                (name, vec![("a", "other".to_string())], 1),
+            ]
+        );
+        let (_, _, histogram) = histograms.into_iter().find(|(of, ..)| *of == name).unwrap();
+        // 0.2s lands in the 0.25s bucket, and 120s is only in the total
+        assert_eq!(histogram.buckets[2], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 1);
+        assert_eq!(histogram.count, 2);
+    }
+}
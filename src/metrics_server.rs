@@ -0,0 +1,195 @@
+use crate::metrics;
+use crate::metrics::{Kind, Labels, BUCKETS};
+use crate::roadmaps;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Serves `/metrics` and `/budget` on `address` until `shutdown` resolves.
+pub(crate) async fn run(
+    address: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Serving metrics on http://{address}/metrics");
+    serve(listener, shutdown).await
+}
+
+/// Answers scrapes on `listener` until `shutdown` resolves, leaving scrapes already
+/// accepted to finish.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    tokio::pin!(shutdown);
+    loop {
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            () = &mut shutdown => {
+                info!("Metrics server stopped");
+                return Ok(());
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket).await {
+                warn!("Failed to answer metrics scrape due to {e}");
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let read = socket.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let response = if request.starts_with("GET /metrics ") {
+        ok("text/plain; version=0.0.4", render())
+    } else if request.starts_with("GET /budget ") {
+        // Today's OpenAI spend and what's left of the daily budget
+        ok(
+            "application/json",
+            serde_json::to_string(&roadmaps::budget_report()).unwrap_or_default(),
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+fn ok(content_type: &str, body: String) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// `labels` as `{name="value",...}`, with `extra` last, or nothing when there are none.
+fn series(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Every metric in the Prometheus text format, described even before it's recorded.
+pub(crate) fn render() -> String {
+    let snapshot = metrics::snapshot();
+    let mut text = String::new();
+    for (name, kind, help) in metrics::METRICS {
+        let kind_name = match kind {
+            Kind::Counter => "counter",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind_name}");
+        match kind {
+            Kind::Counter => {
+                for (_, labels, value) in snapshot.counters.iter().filter(|(of, ..)| *of == name) {
+                    let _ = writeln!(text, "{name}{} {value}", series(labels, None));
+                }
+            }
+            Kind::Histogram => {
+                for (_, labels, histogram) in
+                    snapshot.histograms.iter().filter(|(of, ..)| *of == name)
+                {
+                    let mut cumulative = 0;
+                    for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                        cumulative += count;
+                        let le = bound.to_string();
+                        let _ = writeln!(
+                            text,
+                            "{name}_bucket{} {cumulative}",
+                            series(labels, Some(("le", le.as_str())))
+                        );
+                    }
+                    let _ = writeln!(
+                        text,
+                        "{name}_bucket{} {}\n{name}_sum{} {}\n{name}_count{} {}",
+                        series(labels, Some(("le", "+Inf"))),
+                        histogram.count,
+                        series(labels, None),
+                        histogram.sum,
+                        series(labels, None),
+                        histogram.count
+                    );
+                }
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn scrape_lists_every_metric_and_server_stops() {
+        metrics::increment(metrics::SPAM_ACTIONS, &[("action", "ban")]);
+        metrics::observe(
+            metrics::OPENAI_LATENCY,
+            &[("model", "gpt-4o-mini")],
+            Duration::from_millis(300),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, async {
+            let _ = stopped.await;
+        }));
+
+        let response = reqwest::get(format!("http://{address}/metrics"))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = response.text().await.unwrap();
+        for (name, ..) in metrics::METRICS {
+            assert!(body.contains(&format!("# TYPE {name} ")), "{name} missing");
+        }
+        assert!(body.contains(r#"spam_eater_spam_actions_total{action="ban"} "#));
+        assert!(body.contains(
+            r#"spam_eater_openai_latency_seconds_bucket{model="gpt-4o-mini",le="0.5"} "#
+        ));
+        assert!(body.contains(r#"spam_eater_openai_latency_seconds_count{model="gpt-4o-mini"} "#));
+
+        let missing = reqwest::get(format!("http://{address}/health"))
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let labels = vec![("reason", "say \"hi\"\\\n".to_string())];
+        assert_eq!(
+            series(&labels, Some(("le", "1"))),
+            r#"{reason="say \"hi\"\\\n",le="1"}"#
+        );
+        assert_eq!(series(&vec![], None), "");
+    }
+}
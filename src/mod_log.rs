@@ -0,0 +1,241 @@
+use crate::roadmaps::loggable;
+use crate::spam_detection;
+use serenity::all::{
+    ChannelId, Colour, CreateAllowedMentions, CreateEmbed, CreateMessage, Http, Mentionable,
+    Message, Timestamp, User, UserId,
+};
+use tracing::{info, warn};
+
+/// Longest part of the original message shown in the mod log.
+const SNIPPET_LIMIT: usize = 1_000;
+
+/// Something the bot did on its own, as recorded in the mod log.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ModLogEntry {
+    pub(crate) user_id: UserId,
+    pub(crate) user_name: String,
+    pub(crate) channel_id: ChannelId,
+    /// What was done, like "Deleted message and banned them".
+    pub(crate) action: String,
+    /// The rule or classifier verdict it was done for.
+    pub(crate) reason: String,
+    pub(crate) content: String,
+    /// Unix times the message was sent and the action was taken.
+    pub(crate) sent_at: i64,
+    pub(crate) acted_at: i64,
+    /// Number of the message's record in the evidence archive.
+    pub(crate) evidence_id: Option<i64>,
+}
+
+impl ModLogEntry {
+    pub(crate) fn new(
+        user: &User,
+        channel_id: ChannelId,
+        content: &str,
+        sent_at: i64,
+        action: impl Into<String>,
+        reason: impl Into<String>,
+    ) -> Self {
+        ModLogEntry {
+            user_id: user.id,
+            user_name: user.name.clone(),
+            channel_id,
+            action: action.into(),
+            reason: reason.into(),
+            content: content.to_string(),
+            sent_at,
+            acted_at: chrono::Utc::now().timestamp(),
+            evidence_id: None,
+        }
+    }
+
+    /// The entry pointing at the message's record in the evidence archive, if it has one.
+    pub(crate) fn evidence(mut self, evidence_id: Option<i64>) -> Self {
+        self.evidence_id = evidence_id;
+        self
+    }
+
+    /// An entry for something done about `message`.
+    pub(crate) fn for_message(
+        message: &Message,
+        action: impl Into<String>,
+        reason: impl Into<String>,
+    ) -> Self {
+        ModLogEntry::new(
+            &message.author,
+            message.channel_id,
+            message.content.as_str(),
+            message.timestamp.unix_timestamp(),
+            action,
+            reason,
+        )
+    }
+
+    /// One line for the logs, with the content only as `loggable` allows.
+    fn summary(&self) -> String {
+        format!(
+            "{} - {} ({}) in {} because {} - {}",
+            self.action,
+            self.user_name,
+            self.user_id,
+            self.channel_id,
+            self.reason,
+            loggable(self.content.as_str())
+        )
+    }
+}
+
+/// A mod log embed, kept apart from serenity's builder so its layout can be checked in
+/// tests.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ModLogEmbed {
+    pub(crate) title: String,
+    pub(crate) description: String,
+    pub(crate) fields: Vec<(&'static str, String, bool)>,
+    pub(crate) timestamp: i64,
+}
+
+impl ModLogEmbed {
+    fn to_embed(&self) -> CreateEmbed {
+        CreateEmbed::new()
+            .title(self.title.as_str())
+            .description(self.description.as_str())
+            .fields(
+                self.fields
+                    .iter()
+                    .map(|(name, value, inline)| (*name, value.as_str(), *inline)),
+            )
+            .colour(Colour::DARK_ORANGE)
+            .timestamp(Timestamp::from_unix_timestamp(self.timestamp).unwrap_or_default())
+    }
+}
+
+/// How every automated action looks in the mod log: what was done as the title, the
+/// start of the message as a code block, then who, where, why and when.
+pub(crate) fn mod_log_embed(entry: &ModLogEntry) -> ModLogEmbed {
+    let snippet = if entry.content.chars().count() > SNIPPET_LIMIT {
+        let kept: String = entry.content.chars().take(SNIPPET_LIMIT - 1).collect();
+        format!("{}…", kept.trim_end())
+    } else {
+        entry.content.clone()
+    };
+    let description = match snippet.trim() {
+        "" => "*No text*".to_string(),
+        // Keep the original intact without letting it close the code block
+        snippet => format!("```\n{}\n```", snippet.replace("```", "`\u{200B}``")),
+    };
+    let mut fields = vec![
+        (
+            "User",
+            format!("{} ({})", entry.user_id.mention(), entry.user_name),
+            true,
+        ),
+        ("Channel", entry.channel_id.mention().to_string(), true),
+        ("Reason", entry.reason.clone(), false),
+        ("Sent", format!("<t:{}:f>", entry.sent_at), true),
+        ("Acted", format!("<t:{}:f>", entry.acted_at), true),
+    ];
+    if let Some(evidence_id) = entry.evidence_id {
+        fields.push(("Evidence", format!("#{evidence_id}"), true));
+    }
+    ModLogEmbed {
+        title: entry.action.clone(),
+        description,
+        fields,
+        timestamp: entry.acted_at,
+    }
+}
+
+/// Posts `entry` to the mod-log channel, or only logs it when there isn't one or the post
+/// fails. It never fails itself, so the action it records always stands.
+pub(crate) async fn record(http: &Http, entry: &ModLogEntry) {
+    let Some(mod_log_channel) = spam_detection::mod_log_channel() else {
+        info!("{}", entry.summary());
+        return;
+    };
+    let post = CreateMessage::new()
+        .embed(mod_log_embed(entry).to_embed())
+        .allowed_mentions(CreateAllowedMentions::new());
+    if let Err(e) = ChannelId::new(mod_log_channel)
+        .send_message(http, post)
+        .await
+    {
+        warn!(
+            "Failed to post to the mod log due to {e}: {}",
+            entry.summary()
+        );
+    }
+}
+
+/// `record`s a roadmap the bot wrote, if `mod_log_roadmaps` is on.
+pub(crate) async fn record_roadmap(http: &Http, entry: &ModLogEntry) {
+    if spam_detection::mod_log_roadmaps() {
+        record(http, entry).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(content: &str) -> ModLogEntry {
+        ModLogEntry {
+            user_id: UserId::new(42),
+            user_name: "spammer".to_string(),
+            channel_id: ChannelId::new(7),
+            action: "Deleted message and timed them out for 24 hours".to_string(),
+            reason: "matched scam rule \"nitro\"".to_string(),
+            content: content.to_string(),
+            sent_at: 1_700_000_000,
+            acted_at: 1_700_000_002,
+            evidence_id: None,
+        }
+    }
+
+    /// The embed as text, so any change to the layout shows up as a diff.
+    fn snapshot(embed: &ModLogEmbed) -> String {
+        let mut text = format!("# {}\n{}\n", embed.title, embed.description);
+        for (name, value, inline) in &embed.fields {
+            let inline = if *inline { " (inline)" } else { "" };
+            text.push_str(format!("{name}{inline}: {value}\n").as_str());
+        }
+        text.push_str(format!("@ {}\n", embed.timestamp).as_str());
+        text
+    }
+
+    #[test]
+    fn enforcement_embed_snapshot() {
+        assert_eq!(
+            snapshot(&mod_log_embed(
+                &entry("Claim your free nitro ```now```").evidence(Some(12))
+            )),
+            "# Deleted message and timed them out for 24 hours\n\
+             ```\nClaim your free nitro `\u{200B}``now`\u{200B}``\n```\n\
+             User (inline): <@42> (spammer)\n\
+             Channel (inline): <#7>\n\
+             Reason: matched scam rule \"nitro\"\n\
+             Sent (inline): <t:1700000000:f>\n\
+             Acted (inline): <t:1700000002:f>\n\
+             Evidence (inline): #12\n\
+             @ 1700000002\n"
+        );
+    }
+
+    #[test]
+    fn long_and_empty_messages_snapshot() {
+        let long = mod_log_embed(&entry(&"spam ".repeat(400)));
+        assert_eq!(long.description.chars().count(), SNIPPET_LIMIT + 8);
+        assert!(long.description.ends_with("spam…\n```"));
+        assert_eq!(
+            snapshot(&mod_log_embed(&entry("  "))),
+            "# Deleted message and timed them out for 24 hours\n\
+             *No text*\n\
+             User (inline): <@42> (spammer)\n\
+             Channel (inline): <#7>\n\
+             Reason: matched scam rule \"nitro\"\n\
+             Sent (inline): <t:1700000000:f>\n\
+             Acted (inline): <t:1700000002:f>\n\
+             @ 1700000002\n"
+        );
+    }
+}
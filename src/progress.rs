@@ -0,0 +1,78 @@
+use serenity::all::{ChannelId, Context, Http, Message, MessageId, ReactionType, Typing};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Left on the request while its roadmap is being written.
+const WORKING: char = '⏳';
+const SUCCEEDED: char = '✅';
+const FAILED: char = '❌';
+
+/// The reaction a finished request is left with.
+fn outcome_reaction(succeeded: bool) -> char {
+    if succeeded {
+        SUCCEEDED
+    } else {
+        FAILED
+    }
+}
+
+/// Shows a roadmap is on its way: ⏳ on the request and typing in its channel until
+/// dropped, when ⏳ is swapped for ✅ if `succeeded` was called or ❌ otherwise, so an
+/// early return on error still leaves the request marked. Without permission to react
+/// only the typing indicator is shown, and a request deleted meanwhile is left alone.
+pub(crate) struct ProgressIndicator {
+    http: Arc<Http>,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    reacted: bool,
+    succeeded: bool,
+    _typing: Typing,
+}
+
+impl ProgressIndicator {
+    pub(crate) async fn start(ctx: &Context, message: &Message) -> ProgressIndicator {
+        let reacted = match message.react(&ctx.http, WORKING).await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Failed to react to roadmap request due to {e}, only showing typing");
+                false
+            }
+        };
+        ProgressIndicator {
+            http: ctx.http.clone(),
+            channel_id: message.channel_id,
+            message_id: message.id,
+            reacted,
+            succeeded: false,
+            _typing: message.channel_id.start_typing(&ctx.http),
+        }
+    }
+
+    /// Marks the request as answered, for ✅ rather than ❌.
+    pub(crate) fn succeeded(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for ProgressIndicator {
+    fn drop(&mut self) {
+        if !self.reacted {
+            return;
+        }
+        let http = self.http.clone();
+        let (channel_id, message_id) = (self.channel_id, self.message_id);
+        let outcome = outcome_reaction(self.succeeded);
+        tokio::spawn(async move {
+            let swapped = async {
+                http.delete_reaction_me(channel_id, message_id, &ReactionType::from(WORKING))
+                    .await?;
+                http.create_reaction(channel_id, message_id, &ReactionType::from(outcome))
+                    .await
+            };
+            // Most likely the request was deleted while its roadmap was written
+            if let Err(e) = swapped.await {
+                debug!("Failed to mark roadmap request {message_id} as done due to {e}");
+            }
+        });
+    }
+}
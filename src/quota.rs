@@ -0,0 +1,287 @@
+use crate::rate_limit::describe_wait;
+use crate::storage::Storage;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use serde::Deserialize;
+use serenity::all::UserId;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Why a user can't have a roadmap right now, and how long until they can.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LimitReached {
+    RateLimited(Duration),
+    Cooldown(Duration),
+    DailyQuota(Duration),
+}
+
+impl LimitReached {
+    /// The reply explaining the limit, to follow a greeting.
+    pub(crate) fn reply(&self) -> String {
+        match self {
+            LimitReached::RateLimited(wait) => format!(
+                "you've asked for a lot of roadmaps lately, try again in {}.",
+                describe_wait(*wait)
+            ),
+            LimitReached::Cooldown(wait) => format!(
+                "you just got a roadmap, you can ask for another in {}.",
+                describe_wait(*wait)
+            ),
+            LimitReached::DailyQuota(wait) => format!(
+                "you've had all your roadmaps for today, you can ask for more in {}.",
+                describe_wait(*wait)
+            ),
+        }
+    }
+}
+
+/// A user's roadmaps on `day` (UTC), and when they last had one.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct UserUsage {
+    pub(crate) day: NaiveDate,
+    pub(crate) count: u32,
+    pub(crate) last: DateTime<Utc>,
+}
+
+/// Per-user cooldown between roadmaps and a cap on roadmaps per UTC day. Usage is saved
+/// to `storage` after every roadmap so restarts don't reset anyone's quota.
+pub(crate) struct RoadmapQuota {
+    cooldown: Duration,
+    daily_cap: Option<u32>,
+    storage: Option<Storage>,
+    usage: Mutex<HashMap<UserId, UserUsage>>,
+}
+
+impl RoadmapQuota {
+    /// A `daily_cap` of `None` lets users have any number of roadmaps a day, cooldown
+    /// permitting. Saved usage is only picked up by `restore`.
+    pub(crate) fn new(
+        cooldown: Duration,
+        daily_cap: Option<u32>,
+        storage: Option<Storage>,
+    ) -> Self {
+        RoadmapQuota {
+            cooldown,
+            daily_cap,
+            storage,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Picks up the usage saved in `storage` before a restart.
+    pub(crate) async fn restore(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        match storage.roadmap_usage().await {
+            Ok(saved) => {
+                let mut usage = self.usage.lock().await;
+                for (user_id, used) in saved {
+                    usage.entry(user_id).or_insert(used);
+                }
+            }
+            Err(e) => warn!("Ignoring unreadable quota: {e:#}"),
+        }
+    }
+
+    /// Counts a roadmap against `user_id`'s quota, or says which limit they're over.
+    /// `also` is only asked once the quota allows the roadmap, so limits it checks, like a
+    /// rate limiter's tokens, aren't spent on roadmaps the quota turns away, and the
+    /// roadmap isn't counted if `also` turns it away.
+    pub(crate) async fn take(
+        &self,
+        user_id: UserId,
+        also: impl FnOnce() -> Result<(), LimitReached>,
+    ) -> Result<(), LimitReached> {
+        self.take_at(user_id, Utc::now(), also).await
+    }
+
+    /// `take` at `now`. The usage is saved before the lock is let go, so saves land in the
+    /// order roadmaps were counted.
+    async fn take_at(
+        &self,
+        user_id: UserId,
+        now: DateTime<Utc>,
+        also: impl FnOnce() -> Result<(), LimitReached>,
+    ) -> Result<(), LimitReached> {
+        let mut usage = self.usage.lock().await;
+        self.check(&usage, user_id, now)?;
+        also()?;
+        let used = self.count(&mut usage, user_id, now);
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        let saved = async {
+            storage.set_roadmap_usage(user_id, used).await?;
+            // Matching what `count` forgets
+            storage
+                .forget_roadmap_usage(now.date_naive(), now - self.cooldown)
+                .await
+        };
+        if let Err(e) = saved.await {
+            warn!("Failed to save quota for {user_id}: {e:#}");
+        }
+        Ok(())
+    }
+
+    /// Which limit, if any, `user_id` is over at `now`.
+    fn check(
+        &self,
+        usage: &HashMap<UserId, UserUsage>,
+        user_id: UserId,
+        now: DateTime<Utc>,
+    ) -> Result<(), LimitReached> {
+        let Some(used) = usage.get(&user_id) else {
+            return Ok(());
+        };
+        let since_last = (now - used.last).to_std().unwrap_or_default();
+        if since_last < self.cooldown {
+            return Err(LimitReached::Cooldown(self.cooldown - since_last));
+        }
+        if used.day == now.date_naive() && self.daily_cap.is_some_and(|cap| used.count >= cap) {
+            return Err(LimitReached::DailyQuota(until_tomorrow(now)));
+        }
+        Ok(())
+    }
+
+    /// Counts a roadmap for `user_id` at `now`, returning their usage with it.
+    fn count(
+        &self,
+        usage: &mut HashMap<UserId, UserUsage>,
+        user_id: UserId,
+        now: DateTime<Utc>,
+    ) -> UserUsage {
+        let today = now.date_naive();
+        let count = match usage.get(&user_id) {
+            Some(used) if used.day == today => used.count + 1,
+            _ => 1,
+        };
+        let used = UserUsage {
+            day: today,
+            count,
+            last: now,
+        };
+        usage.insert(user_id, used);
+        // Yesterday's usage only matters while its cooldown is still running
+        let cooldown = self.cooldown;
+        usage.retain(|_, used| {
+            used.day == today || (now - used.last).to_std().unwrap_or_default() < cooldown
+        });
+        used
+    }
+}
+
+/// Time left until the next UTC midnight, when daily quotas reset.
+fn until_tomorrow(now: DateTime<Utc>) -> Duration {
+    let midnight = (now.date_naive() + Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight exists")
+        .and_utc();
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+    const TEN_MINUTES: Duration = Duration::from_secs(600);
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    fn allow() -> Result<(), LimitReached> {
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cooldown_reports_time_remaining() {
+        let quota = RoadmapQuota::new(TEN_MINUTES, Some(3), None);
+        assert_eq!(quota.take_at(ADA, at(12, 0), allow).await, Ok(()));
+        assert_eq!(
+            quota.take_at(ADA, at(12, 4), allow).await,
+            Err(LimitReached::Cooldown(Duration::from_secs(360)))
+        );
+        assert_eq!(quota.take_at(BOB, at(12, 4), allow).await, Ok(()));
+        assert_eq!(quota.take_at(ADA, at(12, 10), allow).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn daily_cap_lasts_until_midnight() {
+        let quota = RoadmapQuota::new(TEN_MINUTES, Some(2), None);
+        assert_eq!(quota.take_at(ADA, at(9, 0), allow).await, Ok(()));
+        assert_eq!(quota.take_at(ADA, at(10, 0), allow).await, Ok(()));
+        assert_eq!(
+            quota.take_at(ADA, at(22, 30), allow).await,
+            Err(LimitReached::DailyQuota(Duration::from_secs(90 * 60)))
+        );
+        let tomorrow = at(0, 0) + Days::new(1);
+        assert_eq!(quota.take_at(ADA, tomorrow, allow).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn cooldown_carries_across_midnight() {
+        let quota = RoadmapQuota::new(TEN_MINUTES, Some(1), None);
+        assert_eq!(quota.take_at(ADA, at(23, 55), allow).await, Ok(()));
+        let just_after_midnight = at(0, 1) + Days::new(1);
+        assert_eq!(
+            quota.take_at(ADA, just_after_midnight, allow).await,
+            Err(LimitReached::Cooldown(Duration::from_secs(240)))
+        );
+        assert_eq!(
+            quota.take_at(ADA, at(0, 5) + Days::new(1), allow).await,
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn other_limits_are_only_asked_once_the_quota_allows() {
+        let quota = RoadmapQuota::new(TEN_MINUTES, Some(3), None);
+        let rate_limited = || Err(LimitReached::RateLimited(TEN_MINUTES));
+        assert_eq!(
+            quota.take_at(ADA, at(12, 0), rate_limited).await,
+            Err(LimitReached::RateLimited(TEN_MINUTES))
+        );
+        // Turned away by the other limit, so not counted
+        assert_eq!(quota.take_at(ADA, at(12, 0), allow).await, Ok(()));
+        let asked = std::cell::Cell::new(false);
+        let result = quota
+            .take_at(ADA, at(12, 5), || {
+                asked.set(true);
+                Ok(())
+            })
+            .await;
+        assert_eq!(
+            result,
+            Err(LimitReached::Cooldown(Duration::from_secs(300)))
+        );
+        assert!(!asked.get());
+    }
+
+    #[test]
+    fn until_tomorrow_counts_to_utc_midnight() {
+        assert_eq!(until_tomorrow(at(0, 0)), Duration::from_secs(24 * 3600));
+        assert_eq!(until_tomorrow(at(23, 59)), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn quota_survives_restart() {
+        let path = env::temp_dir().join("quota_survives_restart.db");
+        let _ = std::fs::remove_file(&path);
+        let quota = RoadmapQuota::new(TEN_MINUTES, Some(1), Some(Storage::open(&path).unwrap()));
+        assert_eq!(quota.take_at(ADA, at(12, 0), allow).await, Ok(()));
+        let restarted =
+            RoadmapQuota::new(TEN_MINUTES, Some(1), Some(Storage::open(&path).unwrap()));
+        restarted.restore().await;
+        assert!(matches!(
+            restarted.take_at(ADA, at(13, 0), allow).await,
+            Err(LimitReached::DailyQuota(_))
+        ));
+        assert_eq!(restarted.take_at(BOB, at(13, 0), allow).await, Ok(()));
+    }
+}
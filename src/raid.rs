@@ -0,0 +1,478 @@
+use crate::messaging;
+use crate::spam_detection;
+use anyhow::bail;
+use serenity::all::{
+    Channel, ChannelId, CommandInteraction, CommandOptionType, Context, CreateCommand,
+    CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage, EditChannel,
+    GuildId, Http, Permissions,
+};
+use serenity::prelude::{TypeMap, TypeMapKey};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// Name of the slash command that turns raid mode on and off.
+pub(crate) const COMMAND_NAME: &str = "raid-mode";
+
+/// How often raid mode checks whether the raid has gone quiet.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RaidState {
+    Normal,
+    /// Slowmode is on and spam thresholds are tight until no one has joined for the
+    /// quiet period.
+    Raid {
+        last_join: Instant,
+    },
+    /// Slowmode is lifted but thresholds stay tight until the cooldown is over, and
+    /// another burst of joins goes straight back to `Raid`.
+    CoolingDown {
+        since: Instant,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RaidEvent {
+    Join,
+    Tick,
+    /// A moderator turned raid mode on.
+    Start,
+    /// A moderator turned raid mode off, skipping the cooldown.
+    Stop,
+}
+
+/// Guild joins over a sliding window, and the raid state they've put the server in.
+#[derive(Debug)]
+pub(crate) struct RaidDetector {
+    joins_threshold: usize,
+    window: Duration,
+    quiet_period: Duration,
+    cooldown: Duration,
+    joins: VecDeque<Instant>,
+    state: RaidState,
+}
+
+impl RaidDetector {
+    pub(crate) fn new(
+        joins_threshold: usize,
+        window: Duration,
+        quiet_period: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        RaidDetector {
+            joins_threshold,
+            window,
+            quiet_period,
+            cooldown,
+            joins: VecDeque::new(),
+            state: RaidState::Normal,
+        }
+    }
+
+    pub(crate) fn state(&self) -> RaidState {
+        self.state
+    }
+
+    /// Whether spam thresholds should be tightened, during a raid and while cooling down.
+    pub(crate) fn is_tightened(&self) -> bool {
+        self.state != RaidState::Normal
+    }
+
+    /// Moves the state machine on by `event` at `now`, returning the new state if it
+    /// changed.
+    pub(crate) fn handle(&mut self, event: RaidEvent, now: Instant) -> Option<RaidState> {
+        let window = self.window;
+        while self
+            .joins
+            .front()
+            .is_some_and(|&joined| now.saturating_duration_since(joined) >= window)
+        {
+            self.joins.pop_front();
+        }
+        let next = match (self.state, event) {
+            (RaidState::Raid { .. }, RaidEvent::Join) => {
+                self.joins.push_back(now);
+                self.state = RaidState::Raid { last_join: now };
+                return None;
+            }
+            (_, RaidEvent::Join) => {
+                self.joins.push_back(now);
+                if self.joins.len() < self.joins_threshold {
+                    return None;
+                }
+                RaidState::Raid { last_join: now }
+            }
+            (RaidState::Raid { last_join }, RaidEvent::Tick)
+                if now.saturating_duration_since(last_join) >= self.quiet_period =>
+            {
+                RaidState::CoolingDown { since: now }
+            }
+            (RaidState::CoolingDown { since }, RaidEvent::Tick)
+                if now.saturating_duration_since(since) >= self.cooldown =>
+            {
+                RaidState::Normal
+            }
+            (RaidState::Raid { .. }, RaidEvent::Start) | (RaidState::Normal, RaidEvent::Stop) => {
+                return None;
+            }
+            (_, RaidEvent::Start) => RaidState::Raid { last_join: now },
+            (_, RaidEvent::Stop) => RaidState::Normal,
+            (_, RaidEvent::Tick) => return None,
+        };
+        self.state = next;
+        Some(next)
+    }
+
+    fn describe(&self, now: Instant) -> String {
+        match self.state {
+            RaidState::Normal => format!(
+                "Raid mode is off, {} joins in the last {}s.",
+                self.joins.len(),
+                self.window.as_secs()
+            ),
+            RaidState::Raid { last_join } => format!(
+                "Raid mode is on, ending {}s after the last join ({}s ago).",
+                self.quiet_period.as_secs(),
+                now.saturating_duration_since(last_join).as_secs()
+            ),
+            RaidState::CoolingDown { since } => format!(
+                "Raid mode is cooling down, back to normal in {}s.",
+                self.cooldown
+                    .saturating_sub(now.saturating_duration_since(since))
+                    .as_secs()
+            ),
+        }
+    }
+}
+
+/// The raid detector, and the slowmode each raid channel had before the raid changed it.
+#[derive(Debug)]
+pub(crate) struct RaidWatch {
+    pub(crate) detector: RaidDetector,
+    saved_slowmode: HashMap<ChannelId, u16>,
+}
+
+/// Each guild's raid watch, so a raid on one server leaves the others alone.
+pub(crate) struct RaidMode;
+
+impl TypeMapKey for RaidMode {
+    type Value = Arc<RwLock<HashMap<GuildId, RaidWatch>>>;
+}
+
+/// Raid detection with the configured limits, for a guild seen for the first time.
+pub(crate) fn from_config() -> RaidWatch {
+    let (joins_threshold, window, quiet_period, cooldown) = spam_detection::raid_limits();
+    RaidWatch {
+        detector: RaidDetector::new(joins_threshold, window, quiet_period, cooldown),
+        saved_slowmode: HashMap::new(),
+    }
+}
+
+async fn raid_watches(data: &RwLock<TypeMap>) -> Arc<RwLock<HashMap<GuildId, RaidWatch>>> {
+    let data_read = data.read().await;
+    data_read
+        .get::<RaidMode>()
+        .expect("Expected RaidMode in TypeMap.")
+        .clone()
+}
+
+/// Slows `guild_id`'s raid channels down, remembering their slowmode from before.
+async fn start_slowmode(
+    http: &Http,
+    raid_watches: &RwLock<HashMap<GuildId, RaidWatch>>,
+    guild_id: GuildId,
+) {
+    let (channels, slowmode_secs) = spam_detection::raid_slowmode();
+    for &channel_id in channels {
+        let channel_id = ChannelId::new(channel_id);
+        let previous = match channel_id.to_channel(http).await {
+            Ok(Channel::Guild(channel)) if channel.guild_id == guild_id => {
+                channel.rate_limit_per_user.unwrap_or(0)
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Failed to look up raid channel {channel_id} due to {e}");
+                continue;
+            }
+        };
+        raid_watches
+            .write()
+            .await
+            .entry(guild_id)
+            .or_insert_with(from_config)
+            .saved_slowmode
+            .entry(channel_id)
+            .or_insert(previous);
+        let edit = EditChannel::new().rate_limit_per_user(slowmode_secs);
+        if let Err(e) = channel_id.edit(http, edit).await {
+            warn!("Failed to set slowmode in {channel_id} due to {e}");
+        }
+    }
+}
+
+/// Puts `guild_id`'s raid channels' slowmode back the way it was.
+async fn end_slowmode(
+    http: &Http,
+    raid_watches: &RwLock<HashMap<GuildId, RaidWatch>>,
+    guild_id: GuildId,
+) {
+    let saved_slowmode = match raid_watches.write().await.get_mut(&guild_id) {
+        Some(raid_watch) => std::mem::take(&mut raid_watch.saved_slowmode),
+        None => return,
+    };
+    for (channel_id, slowmode_secs) in saved_slowmode {
+        let edit = EditChannel::new().rate_limit_per_user(slowmode_secs);
+        if let Err(e) = channel_id.edit(http, edit).await {
+            warn!("Failed to restore slowmode in {channel_id} due to {e}");
+        }
+    }
+}
+
+/// Feeds `event` to `guild_id`'s raid detector, then changes slowmode and tells the
+/// moderators if the state changed.
+async fn handle_event(
+    data: &RwLock<TypeMap>,
+    http: &Http,
+    guild_id: GuildId,
+    event: RaidEvent,
+) -> RaidState {
+    let raid_watches = raid_watches(data).await;
+    let (previous, next) = {
+        let mut raid_watches = raid_watches.write().await;
+        let raid_watch = raid_watches.entry(guild_id).or_insert_with(from_config);
+        let previous = raid_watch.detector.state();
+        (previous, raid_watch.detector.handle(event, Instant::now()))
+    };
+    let Some(next) = next else {
+        return previous;
+    };
+    info!("Raid mode in {guild_id} went from {previous:?} to {next:?} on {event:?}");
+    let announcement = match (previous, next) {
+        (_, RaidState::Raid { .. }) => {
+            start_slowmode(http, &raid_watches, guild_id).await;
+            match event {
+                RaidEvent::Start => "raid mode was turned on",
+                _ => "lots of people are joining at once, so I've turned raid mode on",
+            }
+        }
+        (RaidState::Raid { .. }, RaidState::CoolingDown { .. }) => {
+            end_slowmode(http, &raid_watches, guild_id).await;
+            "the raid has gone quiet, so I've lifted slowmode and am cooling down"
+        }
+        (RaidState::Raid { .. }, RaidState::Normal) => {
+            end_slowmode(http, &raid_watches, guild_id).await;
+            "raid mode was turned off"
+        }
+        (_, RaidState::Normal) => "raid mode is over, spam thresholds are back to normal",
+        (_, RaidState::CoolingDown { .. }) => return next,
+    };
+    let ping = matches!(next, RaidState::Raid { .. }) && event != RaidEvent::Start;
+    if let Err(e) = messaging::log_raid(http, announcement, ping).await {
+        warn!("Failed to announce raid mode due to {e}");
+    }
+    next
+}
+
+/// Counts a member joining `guild_id` towards detecting a raid there.
+pub(crate) async fn record_join(ctx: &Context, guild_id: GuildId) {
+    handle_event(&ctx.data, &ctx.http, guild_id, RaidEvent::Join).await;
+}
+
+/// Whether spam thresholds are tightened for a raid on `guild_id`.
+pub(crate) async fn is_tightened(ctx: &Context, guild_id: GuildId) -> bool {
+    raid_watches(&ctx.data)
+        .await
+        .read()
+        .await
+        .get(&guild_id)
+        .is_some_and(|raid_watch| raid_watch.detector.is_tightened())
+}
+
+/// Lets raid mode expire once it's quiet, in every guild, for as long as the bot runs.
+pub(crate) async fn tick_forever(data: Arc<RwLock<TypeMap>>, http: Arc<Http>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let guild_ids: Vec<GuildId> = raid_watches(&data)
+            .await
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect();
+        for guild_id in guild_ids {
+            handle_event(&data, &http, guild_id, RaidEvent::Tick).await;
+        }
+    }
+}
+
+/// `/raid-mode on|off|status`, for members who can manage the server.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("Turn raid mode on or off")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "on",
+            "Slow channels down and tighten spam checks",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "off",
+            "Lift slowmode and go back to normal spam checks",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "status",
+            "Show whether raid mode is on",
+        ))
+}
+
+/// Applies a `/raid-mode` command and replies privately.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let Some(guild_id) = command.guild_id else {
+        bail!("/{COMMAND_NAME} was used outside a guild");
+    };
+    let options = command.data.options();
+    let Some(subcommand) = options.first() else {
+        bail!("/{COMMAND_NAME} was sent without a subcommand");
+    };
+    match subcommand.name {
+        "on" => {
+            handle_event(&ctx.data, &ctx.http, guild_id, RaidEvent::Start).await;
+        }
+        "off" => {
+            handle_event(&ctx.data, &ctx.http, guild_id, RaidEvent::Stop).await;
+        }
+        "status" => {}
+        name => bail!("Unknown /{COMMAND_NAME} subcommand {name}"),
+    }
+    let reply = match raid_watches(&ctx.data).await.read().await.get(&guild_id) {
+        Some(raid_watch) => raid_watch.detector.describe(Instant::now()),
+        None => from_config().detector.describe(Instant::now()),
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Raids are 3 joins in 10s, over after 30s without joins and a 60s cooldown.
+    fn detector() -> RaidDetector {
+        RaidDetector::new(3, 10 * SECOND, 30 * SECOND, 60 * SECOND)
+    }
+
+    #[test]
+    fn burst_of_joins_starts_a_raid() {
+        let start = Instant::now();
+        let mut detector = detector();
+        assert_eq!(detector.handle(RaidEvent::Join, start), None);
+        assert_eq!(detector.handle(RaidEvent::Join, start + SECOND), None);
+        assert_eq!(
+            detector.handle(RaidEvent::Join, start + 2 * SECOND),
+            Some(RaidState::Raid {
+                last_join: start + 2 * SECOND
+            })
+        );
+        assert!(detector.is_tightened());
+    }
+
+    #[test]
+    fn joins_spread_over_the_window_are_normal() {
+        let start = Instant::now();
+        let mut detector = detector();
+        for join in 0..10 {
+            assert_eq!(
+                detector.handle(RaidEvent::Join, start + join * 6 * SECOND),
+                None
+            );
+        }
+        assert_eq!(detector.state(), RaidState::Normal);
+    }
+
+    #[test]
+    fn raid_cools_down_then_ends_once_quiet() {
+        let start = Instant::now();
+        let mut detector = detector();
+        for _ in 0..3 {
+            detector.handle(RaidEvent::Join, start);
+        }
+        // Joins during the raid push the end back
+        detector.handle(RaidEvent::Join, start + 20 * SECOND);
+        assert_eq!(detector.handle(RaidEvent::Tick, start + 40 * SECOND), None);
+        assert_eq!(
+            detector.handle(RaidEvent::Tick, start + 50 * SECOND),
+            Some(RaidState::CoolingDown {
+                since: start + 50 * SECOND
+            })
+        );
+        assert!(detector.is_tightened());
+        assert_eq!(detector.handle(RaidEvent::Tick, start + 100 * SECOND), None);
+        assert_eq!(
+            detector.handle(RaidEvent::Tick, start + 110 * SECOND),
+            Some(RaidState::Normal)
+        );
+        assert!(!detector.is_tightened());
+    }
+
+    #[test]
+    fn another_burst_while_cooling_down_restarts_the_raid() {
+        let start = Instant::now();
+        let mut detector = detector();
+        for _ in 0..3 {
+            detector.handle(RaidEvent::Join, start);
+        }
+        detector.handle(RaidEvent::Tick, start + 30 * SECOND);
+        let later = start + 40 * SECOND;
+        detector.handle(RaidEvent::Join, later);
+        detector.handle(RaidEvent::Join, later);
+        assert_eq!(
+            detector.handle(RaidEvent::Join, later),
+            Some(RaidState::Raid { last_join: later })
+        );
+    }
+
+    #[test]
+    fn moderators_can_toggle_raid_mode() {
+        let start = Instant::now();
+        let mut detector = detector();
+        assert_eq!(detector.handle(RaidEvent::Stop, start), None);
+        assert_eq!(
+            detector.handle(RaidEvent::Start, start),
+            Some(RaidState::Raid { last_join: start })
+        );
+        assert_eq!(detector.handle(RaidEvent::Start, start + SECOND), None);
+        assert_eq!(
+            detector.handle(RaidEvent::Stop, start + SECOND),
+            Some(RaidState::Normal)
+        );
+        // A raid started by hand still expires once it's quiet
+        detector.handle(RaidEvent::Start, start);
+        assert_eq!(
+            detector.handle(RaidEvent::Tick, start + 30 * SECOND),
+            Some(RaidState::CoolingDown {
+                since: start + 30 * SECOND
+            })
+        );
+    }
+}
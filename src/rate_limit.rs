@@ -0,0 +1,154 @@
+use serenity::all::UserId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A user's tokens as of `updated`.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per user: each roadmap takes a token, and tokens come back at
+/// one per `refill_secs` up to `capacity`. Safe to share between handlers.
+pub(crate) struct RateLimiter {
+    capacity: f64,
+    refill_secs: f64,
+    buckets: Mutex<HashMap<UserId, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Lets each user make `capacity` roadmaps in a burst, then one every `refill`.
+    pub(crate) fn new(capacity: u32, refill: Duration) -> Self {
+        RateLimiter {
+            capacity: capacity as f64,
+            refill_secs: refill.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `user_id` if they have one, returning whether they may go ahead.
+    pub(crate) fn check(&self, user_id: UserId) -> bool {
+        self.check_at(user_id, Instant::now())
+    }
+
+    fn check_at(&self, user_id: UserId, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.refilled(buckets.get(&user_id), now);
+        let allowed = bucket.tokens >= 1.0;
+        let tokens = if allowed {
+            bucket.tokens - 1.0
+        } else {
+            bucket.tokens
+        };
+        buckets.insert(
+            user_id,
+            Bucket {
+                tokens,
+                updated: now,
+            },
+        );
+        // Full buckets are the same as no bucket, so don't keep them around
+        buckets.retain(|_, bucket| self.refilled(Some(bucket), now).tokens < self.capacity);
+        allowed
+    }
+
+    /// How long until `user_id` has a token again, zero if they have one now.
+    pub(crate) fn retry_after(&self, user_id: UserId) -> Duration {
+        self.retry_after_at(user_id, Instant::now())
+    }
+
+    fn retry_after_at(&self, user_id: UserId, now: Instant) -> Duration {
+        let buckets = self.buckets.lock().unwrap();
+        let missing = 1.0 - self.refilled(buckets.get(&user_id), now).tokens;
+        Duration::from_secs_f64(missing.max(0.0) * self.refill_secs)
+    }
+
+    /// `bucket` topped up for the time since it was last touched.
+    fn refilled(&self, bucket: Option<&Bucket>, now: Instant) -> Bucket {
+        match bucket {
+            None => Bucket {
+                tokens: self.capacity,
+                updated: now,
+            },
+            Some(bucket) => Bucket {
+                tokens: (bucket.tokens
+                    + now.saturating_duration_since(bucket.updated).as_secs_f64()
+                        / self.refill_secs)
+                    .min(self.capacity),
+                updated: now,
+            },
+        }
+    }
+}
+
+/// `wait` rounded up for a reply, like "2 minutes" or "30 seconds".
+pub(crate) fn describe_wait(wait: Duration) -> String {
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    match secs {
+        1 => "1 second".to_string(),
+        secs if secs < 60 => format!("{secs} seconds"),
+        60 => "1 minute".to_string(),
+        secs => format!("{} minutes", secs.div_ceil(60)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+
+    #[test]
+    fn bursts_up_to_capacity_then_refills() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(limiter.check_at(ADA, now));
+        assert!(limiter.check_at(ADA, now));
+        assert!(!limiter.check_at(ADA, now));
+        assert_eq!(limiter.retry_after_at(ADA, now), Duration::from_secs(60));
+        assert_eq!(
+            limiter.retry_after_at(ADA, now + Duration::from_secs(45)),
+            Duration::from_secs(15)
+        );
+        assert!(limiter.check_at(ADA, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn users_have_separate_buckets() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(limiter.check_at(ADA, now));
+        assert!(!limiter.check_at(ADA, now));
+        assert!(limiter.check_at(BOB, now));
+        assert_eq!(limiter.retry_after_at(BOB, now), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn waits_are_rounded_up() {
+        assert_eq!(describe_wait(Duration::ZERO), "1 second");
+        assert_eq!(describe_wait(Duration::from_millis(29_400)), "30 seconds");
+        assert_eq!(describe_wait(Duration::from_secs(60)), "1 minute");
+        assert_eq!(describe_wait(Duration::from_secs(61)), "2 minutes");
+    }
+
+    #[test]
+    fn concurrent_checks_never_exceed_capacity() {
+        let limiter = Arc::new(RateLimiter::new(5, Duration::from_secs(3600)));
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let limiter = limiter.clone();
+                std::thread::spawn(move || limiter.check(ADA))
+            })
+            .collect();
+        let allowed = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|allowed| *allowed)
+            .count();
+        assert_eq!(allowed, 5);
+    }
+}
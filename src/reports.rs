@@ -0,0 +1,225 @@
+use crate::rate_limit::{describe_wait, RateLimiter};
+use crate::review_queue::{self, ReportOutcome};
+use crate::spam_detection;
+use anyhow::bail;
+use lazy_static::lazy_static;
+use serenity::all::{
+    ChannelId, CommandInteraction, CommandType, Context, CreateCommand, CreateInteractionResponse,
+    CreateInteractionResponseMessage, Message, MessageId, Reaction, ReactionType, ResolvedTarget,
+    RoleId, UserId,
+};
+use tracing::{info, warn};
+
+/// Name of the message command members report messages with.
+pub(crate) const COMMAND_NAME: &str = "Report message";
+
+/// Reaction that reports a message once enough members add it.
+const FLAG: &str = "🚩";
+
+lazy_static! {
+    static ref REPORT_LIMITER: RateLimiter = {
+        let (limit, refill) = spam_detection::report_rate();
+        RateLimiter::new(limit, refill)
+    };
+}
+
+/// Whether someone with `roles` is staff: trusted, or able to decide on reviews.
+fn is_staff(roles: &[RoleId], mod_role: Option<u64>) -> bool {
+    spam_detection::is_trusted(roles)
+        || mod_role.is_some_and(|mod_role| roles.iter().any(|role_id| role_id.get() == mod_role))
+}
+
+/// Whether the author of `message` is staff, looking them up if the message doesn't say.
+async fn author_is_staff(ctx: &Context, message: &Message) -> bool {
+    let roles = match &message.member {
+        Some(member) => member.roles.clone(),
+        None => match message.member(ctx).await {
+            Ok(member) => member.roles,
+            Err(_) => return false,
+        },
+    };
+    is_staff(&roles, spam_detection::review_mod_role())
+}
+
+/// What the reporter is told about their report.
+fn acknowledgment(outcome: ReportOutcome) -> &'static str {
+    match outcome {
+        ReportOutcome::Filed | ReportOutcome::Added => "Thanks, the moderators will take a look.",
+        ReportOutcome::Repeated => "You've already reported that message.",
+    }
+}
+
+/// Why a report from `reporter` is turned away, if it is. Repeats are turned away first, so
+/// they never spend one of `limiter`'s tokens.
+fn refusal(limiter: &RateLimiter, reporter: UserId, repeated: bool) -> Option<String> {
+    if repeated {
+        return Some(acknowledgment(ReportOutcome::Repeated).to_string());
+    }
+    if !limiter.check(reporter) {
+        return Some(format!(
+            "You've reported a lot of messages recently, try again in {}.",
+            describe_wait(limiter.retry_after(reporter))
+        ));
+    }
+    None
+}
+
+/// Whether `flags` 🚩 reactions are enough to file a message, which bot messages never are.
+fn flagged_enough(flags: usize, threshold: usize, from_bot: bool) -> bool {
+    flags >= threshold && !from_bot
+}
+
+/// `refusal` against the shared limiter, looking up whether it's a repeat.
+async fn refuse(ctx: &Context, message_id: MessageId, reporter: UserId) -> Option<String> {
+    let repeated = review_queue::has_reported(ctx, message_id, reporter).await;
+    refusal(&REPORT_LIMITER, reporter, repeated)
+}
+
+async fn file(
+    ctx: &Context,
+    review_channel: u64,
+    message: &Message,
+    reporters: &[UserId],
+) -> anyhow::Result<ReportOutcome> {
+    let from_staff = author_is_staff(ctx, message).await;
+    let outcome = review_queue::report(
+        ctx,
+        ChannelId::new(review_channel),
+        message,
+        reporters,
+        from_staff,
+    )
+    .await?;
+    info!(
+        "Message from {} reported by {} member(s): {outcome:?}",
+        message.author.name,
+        reporters.len()
+    );
+    Ok(outcome)
+}
+
+/// The "Report message" command, for anyone.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .kind(CommandType::Message)
+        .dm_permission(false)
+}
+
+/// Files the chosen message for review and thanks the reporter privately. The reporter
+/// hears back even if filing fails.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let Some(ResolvedTarget::Message(message)) = command.data.target() else {
+        bail!("{COMMAND_NAME} was used without a message");
+    };
+    let reporter = command.user.id;
+    let mut failed = None;
+    let reply = match spam_detection::review_channel() {
+        None => "Reports aren't set up on this server.".to_string(),
+        Some(_) if message.author.bot => "Bot messages can't be reported.".to_string(),
+        Some(review_channel) => match refuse(ctx, message.id, reporter).await {
+            Some(refusal) => refusal,
+            None => match file(ctx, review_channel, message, &[reporter]).await {
+                Ok(outcome) => acknowledgment(outcome).to_string(),
+                Err(e) => {
+                    failed = Some(e);
+                    "Sorry, your report couldn't be filed, please try again later.".to_string()
+                }
+            },
+        },
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Files a message for review once `report_reaction_threshold` members flag it with 🚩,
+/// listing everyone who did as reporters.
+pub(crate) async fn check_reaction(ctx: &Context, reaction: &Reaction) -> anyhow::Result<()> {
+    let (Some(threshold), Some(review_channel)) = (
+        spam_detection::report_reaction_threshold(),
+        spam_detection::review_channel(),
+    ) else {
+        return Ok(());
+    };
+    let flag = ReactionType::Unicode(FLAG.to_string());
+    if reaction.emoji != flag || reaction.guild_id.is_none() {
+        return Ok(());
+    }
+    let Some(reporter) = reaction.user_id else {
+        return Ok(());
+    };
+    let message = reaction.message(&ctx.http).await?;
+    let flags = message
+        .reactions
+        .iter()
+        .find(|existing| existing.reaction_type == flag)
+        .map_or(0, |existing| existing.count as usize);
+    if !flagged_enough(flags, threshold, message.author.bot) {
+        return Ok(());
+    }
+    if let Some(refusal) = refuse(ctx, message.id, reporter).await {
+        warn!("Ignoring 🚩 from {reporter}: {refusal}");
+        return Ok(());
+    }
+    let reporters: Vec<UserId> = message
+        .reaction_users(&ctx.http, flag, Some(100), None)
+        .await?
+        .into_iter()
+        .filter(|user| !user.bot)
+        .map(|user| user.id)
+        .collect();
+    file(ctx, review_channel, &message, &reporters).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn repeats_are_refused_without_spending_a_token() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(3600));
+        let ada = UserId::new(1);
+        for _ in 0..3 {
+            assert_eq!(
+                refusal(&limiter, ada, true).as_deref(),
+                Some("You've already reported that message.")
+            );
+        }
+        assert_eq!(refusal(&limiter, ada, false), None);
+        assert!(refusal(&limiter, ada, false)
+            .unwrap()
+            .starts_with("You've reported a lot of messages recently"));
+    }
+
+    #[test]
+    fn messages_are_filed_from_the_threshold_on() {
+        assert!(!flagged_enough(2, 3, false));
+        assert!(flagged_enough(3, 3, false));
+        assert!(flagged_enough(4, 3, false));
+        assert!(!flagged_enough(5, 3, true));
+    }
+
+    #[test]
+    fn moderators_count_as_staff() {
+        assert!(is_staff(&[RoleId::new(7)], Some(7)));
+        assert!(!is_staff(&[RoleId::new(8)], Some(7)));
+        assert!(!is_staff(&[], None));
+    }
+}
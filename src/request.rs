@@ -1,8 +1,9 @@
 use crate::utilities;
+use crate::utilities::PromptBudget;
 use anyhow::bail;
 use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
 use serde::Deserialize;
-use tracing::info;
+use tracing::{debug, info};
 
 static REQUEST_PROMPT: &str = include_str!("../prompts/request.txt");
 static VERIFY_PROMPT: &str = include_str!("../prompts/verify.txt");
@@ -41,16 +42,17 @@ fn system_message_verify(question: String) -> anyhow::Result<ChatCompletionMessa
 async fn create_reply(message: String, context: Vec<String>) -> anyhow::Result<String> {
     let chat_completion = ChatCompletion::builder(
         "gpt-4o-mini",
-        utilities::build_message(message, context, system_message_request(), 0, 1024),
+        utilities::build_message(
+            message,
+            context,
+            system_message_request(),
+            0,
+            &[PromptBudget::chars(1024)],
+        ),
     )
     .create()
     .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        Ok(content)
-    } else {
-        bail!("No reply from ChatGPT")
-    }
+    Ok(utilities::reply_content(chat_completion)?)
 }
 
 async fn verify_request(request: String, reply: String) -> anyhow::Result<VerifyReply> {
@@ -61,27 +63,24 @@ async fn verify_request(request: String, reply: String) -> anyhow::Result<Verify
             vec![],
             system_message_verify(request.clone())?,
             0,
-            1024,
+            &[PromptBudget::chars(1024)],
         ),
     )
     .create()
     .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        info!("Generated Verification - {}", content.as_str());
-        Ok(serde_json::from_str(content.as_str())?)
-    } else {
-        bail!("No reply from ChatGPT")
-    }
+    let content = utilities::reply_content(chat_completion)?;
+    debug!("Generated Verification - {}", content.as_str());
+    Ok(serde_json::from_str(content.as_str())?)
 }
 
 pub(crate) async fn answer_request(request: String) -> anyhow::Result<Option<String>> {
     if request.trim().is_empty() {
         return Ok(None);
     }
-    info!("Generating reply for request {}", request.as_str(),);
+    info!("Generating reply for request");
+    debug!("Request is {}", request.as_str());
     let unverified_reply = create_reply(request.clone(), vec![]).await?;
-    info!("Generated unverified reply {}", unverified_reply.as_str(),);
+    debug!("Generated unverified reply {}", unverified_reply.as_str());
     let response_verification = verify_request(request, unverified_reply.clone()).await?;
     if response_verification.answers_correctly {
         info!(
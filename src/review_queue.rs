@@ -0,0 +1,698 @@
+use crate::metrics;
+use crate::spam_detection;
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    ButtonStyle, ChannelId, Colour, ComponentInteraction, Context, CreateActionRow, CreateButton,
+    CreateEmbed, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+    EditMessage, GuildId, Http, Mentionable, Message, MessageId, Permissions, RoleId, Timestamp,
+    UserId,
+};
+use serenity::prelude::{TypeMap, TypeMapKey};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// How often unreviewed messages are checked for expiry.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Custom ids of the review buttons. The review message they're on says which message
+/// is being reviewed.
+const APPROVE_ID: &str = "spam-review-approve";
+const DELETE_ID: &str = "spam-review-delete";
+const BAN_ID: &str = "spam-review-ban";
+
+/// Classifier reason of messages only under review because members reported them.
+const REPORT_REASON: &str = "None, reported by members";
+
+/// Longest message shown in a review, well inside an embed description's limit.
+const CONTENT_LIMIT: usize = 3_500;
+
+/// What a moderator decided about a message held for review.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Decision {
+    Approve,
+    Delete,
+    DeleteAndBan,
+}
+
+impl Decision {
+    /// The button `custom_id` belongs to, or `None` for buttons this module didn't create.
+    fn from_custom_id(custom_id: &str) -> Option<Decision> {
+        match custom_id {
+            APPROVE_ID => Some(Decision::Approve),
+            DELETE_ID => Some(Decision::Delete),
+            BAN_ID => Some(Decision::DeleteAndBan),
+            _ => None,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Decision::Approve => "Approved",
+            Decision::Delete => "Deleted",
+            Decision::DeleteAndBan => "Deleted and banned",
+        }
+    }
+}
+
+/// A message the classifier wasn't sure about, left up until a moderator decides.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ReviewItem {
+    pub(crate) guild_id: Option<GuildId>,
+    pub(crate) channel_id: ChannelId,
+    pub(crate) message_id: MessageId,
+    pub(crate) author_id: UserId,
+    pub(crate) content: String,
+    pub(crate) reason: String,
+    /// Unix time it was sent for review.
+    pub(crate) queued_at: i64,
+    /// Members who reported it, each counted once.
+    #[serde(default)]
+    pub(crate) reports: Vec<Report>,
+    /// Whether its author was staff when it was reported.
+    #[serde(default)]
+    pub(crate) from_staff: bool,
+}
+
+/// A member's report of a message, shown only in the review channel.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Report {
+    pub(crate) reporter: UserId,
+    /// Whether most of the reporter's earlier reports had been rejected when they made it.
+    pub(crate) unreliable: bool,
+}
+
+/// How a member's reports have turned out once moderators decided on them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ReporterRecord {
+    upheld: u32,
+    rejected: u32,
+}
+
+impl ReporterRecord {
+    /// Whether the reporter has had at least three reports rejected for every one upheld.
+    fn is_unreliable(&self) -> bool {
+        self.rejected >= 3 && self.rejected >= 3 * self.upheld
+    }
+}
+
+impl ReviewItem {
+    pub(crate) fn new(message: &Message, reason: &str, queued_at: i64) -> Self {
+        ReviewItem {
+            guild_id: message.guild_id,
+            channel_id: message.channel_id,
+            message_id: message.id,
+            author_id: message.author.id,
+            content: message.content.clone(),
+            reason: reason.to_string(),
+            queued_at,
+            reports: vec![],
+            from_staff: false,
+        }
+    }
+
+    /// The review embed, with a `resolution` field once it's been dealt with.
+    fn embed(&self, resolution: Option<(&str, String)>) -> CreateEmbed {
+        let content = if self.content.chars().count() > CONTENT_LIMIT {
+            let kept: String = self.content.chars().take(CONTENT_LIMIT - 1).collect();
+            format!("{kept}…")
+        } else {
+            self.content.clone()
+        };
+        let title = match (self.from_staff, self.reports.is_empty()) {
+            (true, _) => "Reported staff message",
+            (false, false) => "Reported message",
+            (false, true) => "Possible spam",
+        };
+        let mut embed = CreateEmbed::new()
+            .title(title)
+            .description(content)
+            .field("Author", self.author_id.mention().to_string(), true)
+            .field("Channel", self.channel_id.mention().to_string(), true)
+            .field(
+                "Message",
+                self.message_id.link(self.channel_id, self.guild_id),
+                false,
+            )
+            .field("Classifier reason", self.reason.as_str(), false)
+            .timestamp(Timestamp::from_unix_timestamp(self.queued_at).unwrap_or_default());
+        if self.from_staff {
+            embed = embed.field(
+                "Staff",
+                "The author is staff. Check the report before acting on it.",
+                false,
+            );
+        }
+        if !self.reports.is_empty() {
+            embed = embed.field(
+                format!("Reports ({})", self.reports.len()),
+                self.describe_reports(),
+                false,
+            );
+        }
+        match resolution {
+            None => embed.colour(Colour::ORANGE),
+            Some((name, value)) => embed.colour(Colour::DARK_GREY).field(name, value, false),
+        }
+    }
+
+    /// Who reported it, one per line, calling out reporters who are usually wrong.
+    fn describe_reports(&self) -> String {
+        self.reports
+            .iter()
+            .map(|report| {
+                let reporter = report.reporter.mention();
+                if report.unreliable {
+                    format!("{reporter} (most of their reports are rejected)")
+                } else {
+                    reporter.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Messages waiting for review, by the id of their review message, saved so the buttons
+/// still work after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct ReviewQueue {
+    items: HashMap<MessageId, ReviewItem>,
+    /// How each member's reports have turned out.
+    #[serde(default)]
+    reporters: HashMap<UserId, ReporterRecord>,
+}
+
+impl ReviewQueue {
+    /// Loads the queue last saved to `path`, or an empty one if nothing has been saved.
+    pub(crate) fn load(path: &Path) -> ReviewQueue {
+        match std::fs::read_to_string(path) {
+            Ok(saved) => serde_json::from_str(&saved)
+                .inspect_err(|e| warn!("Ignoring unreadable review queue {}: {e}", path.display()))
+                .unwrap_or_default(),
+            Err(_) => ReviewQueue::default(),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn add(&mut self, review_id: MessageId, item: ReviewItem) {
+        self.items.insert(review_id, item);
+    }
+
+    /// Whether `message_id` is already waiting for review, like when it's edited.
+    pub(crate) fn is_queued(&self, message_id: MessageId) -> bool {
+        self.items
+            .values()
+            .any(|item| item.message_id == message_id)
+    }
+
+    /// Whether `reporter` has already reported `message_id` in a review still open.
+    pub(crate) fn has_reported(&self, message_id: MessageId, reporter: UserId) -> bool {
+        self.items.values().any(|item| {
+            item.message_id == message_id
+                && item
+                    .reports
+                    .iter()
+                    .any(|report| report.reporter == reporter)
+        })
+    }
+
+    /// A report from `reporter`, marked if their reports are usually rejected.
+    pub(crate) fn new_report(&self, reporter: UserId) -> Report {
+        Report {
+            reporter,
+            unreliable: self
+                .reporters
+                .get(&reporter)
+                .is_some_and(ReporterRecord::is_unreliable),
+        }
+    }
+
+    /// Adds reports from `reporters` to the review of `message_id`, returning the id of
+    /// the review and how many of them hadn't reported it yet, or `None` if it isn't
+    /// queued.
+    pub(crate) fn add_reports(
+        &mut self,
+        message_id: MessageId,
+        reporters: &[UserId],
+    ) -> Option<(MessageId, usize)> {
+        let reports: Vec<Report> = reporters
+            .iter()
+            .map(|reporter| self.new_report(*reporter))
+            .collect();
+        let (review_id, item) = self
+            .items
+            .iter_mut()
+            .find(|(_, item)| item.message_id == message_id)?;
+        let before = item.reports.len();
+        for report in reports {
+            if !item
+                .reports
+                .iter()
+                .any(|known| known.reporter == report.reporter)
+            {
+                item.reports.push(report);
+            }
+        }
+        Some((*review_id, item.reports.len() - before))
+    }
+
+    /// Records whether moderators agreed with everyone who reported `item`.
+    pub(crate) fn record_outcome(&mut self, item: &ReviewItem, upheld: bool) {
+        for report in &item.reports {
+            let record = self.reporters.entry(report.reporter).or_default();
+            if upheld {
+                record.upheld += 1;
+            } else {
+                record.rejected += 1;
+            }
+        }
+    }
+
+    /// Takes the item reviewed by `review_id`, so only the first decision on it counts.
+    pub(crate) fn take(&mut self, review_id: MessageId) -> Option<ReviewItem> {
+        self.items.remove(&review_id)
+    }
+
+    /// Removes and returns every item queued at least `max_age` before unix time `now`.
+    pub(crate) fn take_expired(
+        &mut self,
+        now: i64,
+        max_age: Duration,
+    ) -> Vec<(MessageId, ReviewItem)> {
+        let expired: Vec<MessageId> = self
+            .items
+            .iter()
+            .filter(|(_, item)| now - item.queued_at >= max_age.as_secs() as i64)
+            .map(|(review_id, _)| *review_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|review_id| Some((review_id, self.items.remove(&review_id)?)))
+            .collect()
+    }
+}
+
+pub(crate) struct SpamReviews;
+
+impl TypeMapKey for SpamReviews {
+    type Value = Arc<RwLock<ReviewQueue>>;
+}
+
+pub(crate) fn saved_queue() -> ReviewQueue {
+    ReviewQueue::load(&spam_detection::review_queue_path())
+}
+
+async fn review_queue(data: &RwLock<TypeMap>) -> Arc<RwLock<ReviewQueue>> {
+    let data_read = data.read().await;
+    data_read
+        .get::<SpamReviews>()
+        .expect("Expected SpamReviews in TypeMap.")
+        .clone()
+}
+
+/// Whether someone with `roles` and `permissions` may decide on reviews: anyone with
+/// `mod_role`, or anyone who can manage messages when there isn't one.
+fn may_review(roles: &[RoleId], permissions: Option<Permissions>, mod_role: Option<u64>) -> bool {
+    match mod_role {
+        Some(mod_role) => roles.iter().any(|role_id| role_id.get() == mod_role),
+        None => permissions.is_some_and(|permissions| permissions.manage_messages()),
+    }
+}
+
+fn buttons() -> Vec<CreateActionRow> {
+    vec![CreateActionRow::Buttons(vec![
+        CreateButton::new(APPROVE_ID)
+            .label("Approve")
+            .style(ButtonStyle::Success),
+        CreateButton::new(DELETE_ID)
+            .label("Delete")
+            .style(ButtonStyle::Secondary),
+        CreateButton::new(BAN_ID)
+            .label("Delete + Ban")
+            .style(ButtonStyle::Danger),
+    ])]
+}
+
+/// Posts `message` to the review channel with buttons for moderators to decide on it,
+/// leaving it up and its author unwarned in the meantime.
+pub(crate) async fn submit(
+    ctx: &Context,
+    review_channel: ChannelId,
+    message: &Message,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let review_queue = review_queue(&ctx.data).await;
+    if review_queue.read().await.is_queued(message.id) {
+        return Ok(());
+    }
+    let item = ReviewItem::new(message, reason, chrono::Utc::now().timestamp());
+    let review = review_channel
+        .send_message(
+            &ctx.http,
+            CreateMessage::new()
+                .embed(item.embed(None))
+                .components(buttons()),
+        )
+        .await?;
+    let mut review_queue = review_queue.write().await;
+    review_queue.add(review.id, item);
+    review_queue.save(&spam_detection::review_queue_path())?;
+    Ok(())
+}
+
+/// Whether `reporter` has already reported `message_id`, so another report from them can
+/// be turned away before anything is spent on it.
+pub(crate) async fn has_reported(ctx: &Context, message_id: MessageId, reporter: UserId) -> bool {
+    review_queue(&ctx.data)
+        .await
+        .read()
+        .await
+        .has_reported(message_id, reporter)
+}
+
+/// What became of a member's report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ReportOutcome {
+    /// The message wasn't under review, so now it is.
+    Filed,
+    /// The message was already under review and the report was added to it.
+    Added,
+    /// Everyone reporting had already reported the message.
+    Repeated,
+}
+
+/// Files a report of `message` by `reporters`, adding it to the message's review if there
+/// is one so moderators see a single entry with a count.
+pub(crate) async fn report(
+    ctx: &Context,
+    review_channel: ChannelId,
+    message: &Message,
+    reporters: &[UserId],
+    from_staff: bool,
+) -> anyhow::Result<ReportOutcome> {
+    let review_queue = review_queue(&ctx.data).await;
+    // Held throughout, so reports arriving together still make one review
+    let mut review_queue = review_queue.write().await;
+    let outcome = match review_queue.add_reports(message.id, reporters) {
+        Some((_, 0)) => return Ok(ReportOutcome::Repeated),
+        Some((review_id, _)) => {
+            let item = &review_queue.items[&review_id];
+            review_channel
+                .edit_message(
+                    &ctx.http,
+                    review_id,
+                    EditMessage::new().embed(item.embed(None)),
+                )
+                .await?;
+            ReportOutcome::Added
+        }
+        None => {
+            let mut item = ReviewItem::new(message, REPORT_REASON, chrono::Utc::now().timestamp());
+            item.reports = reporters
+                .iter()
+                .map(|reporter| review_queue.new_report(*reporter))
+                .collect();
+            item.from_staff = from_staff;
+            let review = review_channel
+                .send_message(
+                    &ctx.http,
+                    CreateMessage::new()
+                        .embed(item.embed(None))
+                        .components(buttons()),
+                )
+                .await?;
+            review_queue.add(review.id, item);
+            ReportOutcome::Filed
+        }
+    };
+    review_queue.save(&spam_detection::review_queue_path())?;
+    Ok(outcome)
+}
+
+async fn reply_privately(
+    ctx: &Context,
+    interaction: &ComponentInteraction,
+    content: &str,
+) -> anyhow::Result<()> {
+    interaction
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(content)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Carries out `decision` on the reviewed message.
+async fn carry_out(ctx: &Context, item: &ReviewItem, decision: Decision) -> anyhow::Result<()> {
+    if decision == Decision::Approve {
+        return Ok(());
+    }
+    // The author or another moderator may have deleted it already
+    if let Err(e) = ctx
+        .http
+        .delete_message(item.channel_id, item.message_id, Some("Spam, after review"))
+        .await
+    {
+        warn!("Failed to delete reviewed message due to {e}");
+    }
+    if decision == Decision::DeleteAndBan {
+        let Some(guild_id) = item.guild_id else {
+            anyhow::bail!("Reviewed message wasn't in a guild");
+        };
+        guild_id
+            .ban_with_reason(&ctx.http, item.author_id, 0, "Spam, after review")
+            .await?;
+        metrics::increment(metrics::SPAM_ACTIONS, &[("action", "ban")]);
+    } else {
+        metrics::increment(metrics::SPAM_ACTIONS, &[("action", "delete")]);
+    }
+    Ok(())
+}
+
+/// Answers a click on a review button, carrying out the decision and showing who made
+/// it. Clicks on other buttons are left alone.
+pub(crate) async fn handle_component(
+    ctx: &Context,
+    interaction: &ComponentInteraction,
+) -> anyhow::Result<()> {
+    let Some(decision) = Decision::from_custom_id(interaction.data.custom_id.as_str()) else {
+        return Ok(());
+    };
+    let (roles, permissions) = interaction
+        .member
+        .as_ref()
+        .map_or((&[][..], None), |member| {
+            (member.roles.as_slice(), member.permissions)
+        });
+    if !may_review(roles, permissions, spam_detection::review_mod_role()) {
+        return reply_privately(ctx, interaction, "Only moderators can review spam.").await;
+    }
+    let review_queue = review_queue(&ctx.data).await;
+    let review_id = interaction.message.id;
+    let Some(item) = review_queue.write().await.take(review_id) else {
+        return reply_privately(ctx, interaction, "This has already been dealt with.").await;
+    };
+    if let Err(e) = carry_out(ctx, &item, decision).await {
+        review_queue.write().await.add(review_id, item);
+        return Err(e);
+    }
+    {
+        let mut review_queue = review_queue.write().await;
+        review_queue.record_outcome(&item, decision != Decision::Approve);
+        review_queue.save(&spam_detection::review_queue_path())?;
+    }
+    info!(
+        "{} reviewed a message from {}: {decision:?}",
+        interaction.user.name, item.author_id
+    );
+    let resolution = format!("{} by {}", decision.describe(), interaction.user.mention());
+    interaction
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .embed(item.embed(Some(("Resolved", resolution))))
+                    .components(vec![]),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Closes every review nobody decided on in time, leaving the messages up.
+async fn expire_reviews(data: &RwLock<TypeMap>, http: &Http) {
+    let Some(review_channel) = spam_detection::review_channel() else {
+        return;
+    };
+    let max_age = spam_detection::review_expiry();
+    let expired = {
+        let review_queue = review_queue(data).await;
+        let mut review_queue = review_queue.write().await;
+        let expired = review_queue.take_expired(chrono::Utc::now().timestamp(), max_age);
+        if !expired.is_empty() {
+            if let Err(e) = review_queue.save(&spam_detection::review_queue_path()) {
+                warn!("Failed to save review queue due to {e}");
+            }
+        }
+        expired
+    };
+    for (review_id, item) in expired {
+        let note = format!(
+            "Nobody reviewed this within {} hours, so it was left up.",
+            max_age.as_secs() / 3600
+        );
+        let closed = EditMessage::new()
+            .embed(item.embed(Some(("Expired", note))))
+            .components(vec![]);
+        if let Err(e) = ChannelId::new(review_channel)
+            .edit_message(http, review_id, closed)
+            .await
+        {
+            warn!("Failed to close expired review due to {e}");
+        }
+    }
+}
+
+/// Expires old reviews for as long as the bot runs.
+pub(crate) async fn expire_reviews_forever(data: Arc<RwLock<TypeMap>>, http: Arc<Http>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        expire_reviews(&data, &http).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const HOUR: i64 = 60 * 60;
+
+    fn item(message_id: u64, queued_at: i64) -> ReviewItem {
+        ReviewItem {
+            guild_id: Some(GuildId::new(1)),
+            channel_id: ChannelId::new(10),
+            message_id: MessageId::new(message_id),
+            author_id: UserId::new(2),
+            content: "Check out my course".to_string(),
+            reason: "Might be an ad".to_string(),
+            queued_at,
+            reports: vec![],
+            from_staff: false,
+        }
+    }
+
+    #[test]
+    fn only_the_first_decision_counts() {
+        let mut review_queue = ReviewQueue::default();
+        review_queue.add(MessageId::new(500), item(100, 0));
+        assert!(review_queue.is_queued(MessageId::new(100)));
+        assert_eq!(review_queue.take(MessageId::new(500)), Some(item(100, 0)));
+        assert!(!review_queue.is_queued(MessageId::new(100)));
+        assert_eq!(review_queue.take(MessageId::new(500)), None);
+        assert_eq!(review_queue.take(MessageId::new(100)), None);
+    }
+
+    #[test]
+    fn only_old_reviews_expire() {
+        let max_age = Duration::from_secs(24 * HOUR as u64);
+        let mut review_queue = ReviewQueue::default();
+        review_queue.add(MessageId::new(500), item(100, 0));
+        review_queue.add(MessageId::new(600), item(200, 2 * HOUR));
+        assert!(review_queue.take_expired(23 * HOUR, max_age).is_empty());
+        let expired = review_queue.take_expired(25 * HOUR, max_age);
+        assert_eq!(expired, vec![(MessageId::new(500), item(100, 0))]);
+        assert_eq!(review_queue.items.len(), 1);
+    }
+
+    #[test]
+    fn review_queue_survives_restart() {
+        let path = env::temp_dir().join("review_queue_survives_restart.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(ReviewQueue::load(&path), ReviewQueue::default());
+        let mut review_queue = ReviewQueue::default();
+        review_queue.add(MessageId::new(500), item(100, 0));
+        review_queue.save(&path).unwrap();
+        assert_eq!(ReviewQueue::load(&path), review_queue);
+    }
+
+    #[test]
+    fn reports_of_a_queued_message_are_counted_once() {
+        let (ada, bob) = (UserId::new(3), UserId::new(4));
+        let mut review_queue = ReviewQueue::default();
+        assert_eq!(review_queue.add_reports(MessageId::new(100), &[ada]), None);
+        review_queue.add(MessageId::new(500), item(100, 0));
+        assert_eq!(
+            review_queue.add_reports(MessageId::new(100), &[ada]),
+            Some((MessageId::new(500), 1))
+        );
+        assert_eq!(
+            review_queue.add_reports(MessageId::new(100), &[ada, bob]),
+            Some((MessageId::new(500), 1))
+        );
+        assert_eq!(
+            review_queue.add_reports(MessageId::new(100), &[bob]),
+            Some((MessageId::new(500), 0))
+        );
+        assert_eq!(review_queue.items[&MessageId::new(500)].reports.len(), 2);
+        assert!(review_queue.has_reported(MessageId::new(100), bob));
+        assert!(!review_queue.has_reported(MessageId::new(101), bob));
+    }
+
+    #[test]
+    fn reporters_who_are_usually_wrong_are_marked() {
+        let ada = UserId::new(3);
+        let mut review_queue = ReviewQueue::default();
+        let mut reported = item(100, 0);
+        reported.reports = vec![review_queue.new_report(ada)];
+        review_queue.record_outcome(&reported, true);
+        for _ in 0..2 {
+            review_queue.record_outcome(&reported, false);
+        }
+        assert!(!review_queue.new_report(ada).unreliable);
+        review_queue.record_outcome(&reported, false);
+        assert!(review_queue.new_report(ada).unreliable);
+        assert!(!review_queue.new_report(UserId::new(4)).unreliable);
+    }
+
+    #[test]
+    fn only_moderators_may_review() {
+        let moderator = [RoleId::new(7)];
+        assert!(may_review(&moderator, None, Some(7)));
+        assert!(!may_review(
+            &[RoleId::new(8)],
+            Some(Permissions::all()),
+            Some(7)
+        ));
+        assert!(may_review(&[], Some(Permissions::MANAGE_MESSAGES), None));
+        assert!(!may_review(&[], Some(Permissions::SEND_MESSAGES), None));
+        assert!(!may_review(&moderator, None, None));
+    }
+
+    #[test]
+    fn custom_ids_map_to_decisions() {
+        assert_eq!(
+            Decision::from_custom_id("spam-review-approve"),
+            Some(Decision::Approve)
+        );
+        assert_eq!(
+            Decision::from_custom_id("spam-review-ban"),
+            Some(Decision::DeleteAndBan)
+        );
+        assert_eq!(Decision::from_custom_id("roadmap-confirm:100"), None);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::roadmaps;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    Channel, ChannelId, CommandInteraction, CommandOptionType, Context, CreateCommand,
+    CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage, Message,
+    Permissions, ResolvedValue,
+};
+use serenity::prelude::TypeMapKey;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// Name of the slash command that edits the channel list.
+pub(crate) const COMMAND_NAME: &str = "roadmap-channels";
+
+/// Channels roadmap detection runs in. An `allowed` of `None` means every channel, and
+/// `denied` wins over `allowed`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct ChannelList {
+    pub(crate) allowed: Option<BTreeSet<ChannelId>>,
+    pub(crate) denied: BTreeSet<ChannelId>,
+}
+
+impl ChannelList {
+    /// Loads the list last saved to `path`, or `default` if nothing has been saved yet.
+    pub(crate) fn load(path: &Path, default: ChannelList) -> ChannelList {
+        match std::fs::read_to_string(path) {
+            Ok(saved) => serde_json::from_str(&saved)
+                .inspect_err(|e| warn!("Ignoring unreadable channel list {}: {e}", path.display()))
+                .unwrap_or(default),
+            Err(_) => default,
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Whether roadmaps are detected in `channel_id`. Threads pass their parent channel
+    /// as `parent_id` and follow its setting.
+    pub(crate) fn is_enabled(&self, channel_id: ChannelId, parent_id: Option<ChannelId>) -> bool {
+        let channel_id = parent_id.unwrap_or(channel_id);
+        !self.denied.contains(&channel_id)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&channel_id))
+    }
+
+    /// Channels enabled by name, none when it's every channel.
+    fn listed(&self) -> Vec<ChannelId> {
+        self.allowed.iter().flatten().copied().collect()
+    }
+
+    /// True when every channel is enabled, so there's no need to look a channel up.
+    fn allows_everything(&self) -> bool {
+        self.allowed.is_none() && self.denied.is_empty()
+    }
+
+    pub(crate) fn add(&mut self, channel_id: ChannelId) {
+        self.denied.remove(&channel_id);
+        if let Some(allowed) = &mut self.allowed {
+            allowed.insert(channel_id);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, channel_id: ChannelId) {
+        self.denied.insert(channel_id);
+        if let Some(allowed) = &mut self.allowed {
+            allowed.remove(&channel_id);
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mentions = |channels: &BTreeSet<ChannelId>| {
+            channels
+                .iter()
+                .map(|channel_id| format!("<#{channel_id}>"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let allowed = match &self.allowed {
+            None => "all channels".to_string(),
+            Some(allowed) if allowed.is_empty() => "no channels".to_string(),
+            Some(allowed) => mentions(allowed),
+        };
+        if self.denied.is_empty() {
+            format!("Roadmaps are enabled in {allowed}.")
+        } else {
+            format!(
+                "Roadmaps are enabled in {allowed}, except {}.",
+                mentions(&self.denied)
+            )
+        }
+    }
+}
+
+/// The channel list in use, changed at runtime by the slash command.
+pub(crate) struct RoadmapChannels;
+
+impl TypeMapKey for RoadmapChannels {
+    type Value = Arc<RwLock<ChannelList>>;
+}
+
+async fn channel_list(ctx: &Context) -> Arc<RwLock<ChannelList>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<RoadmapChannels>()
+        .expect("Expected RoadmapChannels in TypeMap.")
+        .clone()
+}
+
+/// The channels roadmaps are enabled in by name, for when they have to be listed.
+pub(crate) async fn listed_channels(ctx: &Context) -> Vec<ChannelId> {
+    channel_list(ctx).await.read().await.listed()
+}
+
+/// Whether roadmap detection should run for `message`, following the parent channel's
+/// setting for threads.
+pub(crate) async fn roadmaps_enabled(ctx: &Context, message: &Message) -> bool {
+    let channel_list = channel_list(ctx).await;
+    let channel_list = channel_list.read().await;
+    if channel_list.allows_everything() {
+        return true;
+    }
+    let parent_id = match message.channel(ctx).await {
+        Ok(Channel::Guild(channel)) if channel.thread_metadata.is_some() => channel.parent_id,
+        Ok(_) => None,
+        Err(e) => {
+            warn!(
+                "Failed to look up channel {} due to {e}",
+                message.channel_id
+            );
+            None
+        }
+    };
+    channel_list.is_enabled(message.channel_id, parent_id)
+}
+
+/// `/roadmap-channels add|remove|list`, for members who can manage the server.
+pub(crate) fn command() -> CreateCommand {
+    let channel_option = || {
+        CreateCommandOption::new(CommandOptionType::Channel, "channel", "The channel")
+            .required(true)
+    };
+    CreateCommand::new(COMMAND_NAME)
+        .description("Choose which channels the bot offers roadmaps in")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "add",
+                "Offer roadmaps in a channel",
+            )
+            .add_sub_option(channel_option()),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "remove",
+                "Stop offering roadmaps in a channel",
+            )
+            .add_sub_option(channel_option()),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "list",
+            "Show where roadmaps are offered",
+        ))
+}
+
+/// Applies a `/roadmap-channels` command, saves the result and replies privately.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let channel_list = channel_list(ctx).await;
+    let reply = {
+        let mut channel_list = channel_list.write().await;
+        let options = command.data.options();
+        let Some(subcommand) = options.first() else {
+            bail!("/{COMMAND_NAME} was sent without a subcommand");
+        };
+        let channel_id = match &subcommand.value {
+            ResolvedValue::SubCommand(options) => {
+                options.iter().find_map(|option| match option.value {
+                    ResolvedValue::Channel(channel) => Some(channel.id),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        match (subcommand.name, channel_id) {
+            ("list", _) => channel_list.describe(),
+            ("add", Some(channel_id)) => {
+                channel_list.add(channel_id);
+                channel_list.save(&roadmaps::channels_path())?;
+                format!(
+                    "Roadmaps enabled in <#{channel_id}>. {}",
+                    channel_list.describe()
+                )
+            }
+            ("remove", Some(channel_id)) => {
+                channel_list.remove(channel_id);
+                channel_list.save(&roadmaps::channels_path())?;
+                format!(
+                    "Roadmaps disabled in <#{channel_id}>. {}",
+                    channel_list.describe()
+                )
+            }
+            (name, _) => bail!("Unknown /{COMMAND_NAME} subcommand {name}"),
+        }
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const MEMES: ChannelId = ChannelId::new(1);
+    const DATA_SCIENCE: ChannelId = ChannelId::new(2);
+    const THREAD: ChannelId = ChannelId::new(3);
+
+    #[test]
+    fn all_channels_by_default() {
+        let channel_list = ChannelList::default();
+        assert!(channel_list.is_enabled(MEMES, None));
+        assert!(channel_list.allows_everything());
+    }
+
+    #[test]
+    fn denied_channels_are_skipped() {
+        let mut channel_list = ChannelList::default();
+        channel_list.remove(MEMES);
+        assert!(!channel_list.is_enabled(MEMES, None));
+        assert!(channel_list.is_enabled(DATA_SCIENCE, None));
+        channel_list.add(MEMES);
+        assert!(channel_list.is_enabled(MEMES, None));
+    }
+
+    #[test]
+    fn allow_list_limits_channels() {
+        let mut channel_list = ChannelList {
+            allowed: Some(BTreeSet::from([DATA_SCIENCE])),
+            denied: BTreeSet::new(),
+        };
+        assert!(channel_list.is_enabled(DATA_SCIENCE, None));
+        assert!(!channel_list.is_enabled(MEMES, None));
+        channel_list.add(MEMES);
+        assert!(channel_list.is_enabled(MEMES, None));
+        channel_list.remove(DATA_SCIENCE);
+        assert!(!channel_list.is_enabled(DATA_SCIENCE, None));
+    }
+
+    #[test]
+    fn threads_follow_their_parent() {
+        let mut channel_list = ChannelList::default();
+        channel_list.remove(MEMES);
+        assert!(!channel_list.is_enabled(THREAD, Some(MEMES)));
+        assert!(channel_list.is_enabled(THREAD, Some(DATA_SCIENCE)));
+    }
+
+    #[test]
+    fn channel_list_survives_restart() {
+        let path = env::temp_dir().join("channel_list_survives_restart.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            ChannelList::load(&path, ChannelList::default()),
+            ChannelList::default()
+        );
+        let mut channel_list = ChannelList::default();
+        channel_list.remove(MEMES);
+        channel_list.save(&path).unwrap();
+        assert_eq!(
+            ChannelList::load(&path, ChannelList::default()),
+            channel_list
+        );
+    }
+
+    #[test]
+    fn only_allowed_channels_are_listed() {
+        let mut channel_list = ChannelList::default();
+        channel_list.remove(MEMES);
+        assert!(channel_list.listed().is_empty());
+        channel_list.allowed = Some(BTreeSet::from([MEMES, DATA_SCIENCE]));
+        channel_list.remove(MEMES);
+        assert_eq!(channel_list.listed(), [DATA_SCIENCE]);
+    }
+
+    #[test]
+    fn describe_lists_channels() {
+        let mut channel_list = ChannelList::default();
+        assert_eq!(
+            channel_list.describe(),
+            "Roadmaps are enabled in all channels."
+        );
+        channel_list.remove(MEMES);
+        assert_eq!(
+            channel_list.describe(),
+            "Roadmaps are enabled in all channels, except <#1>."
+        );
+    }
+}
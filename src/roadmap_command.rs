@@ -0,0 +1,465 @@
+use crate::chunking::{split_for_discord, PART_DELAY};
+use crate::conversation_state;
+use crate::embeds::{self, roadmap_embeds};
+use crate::in_flight;
+use crate::llm::describe_completion;
+use crate::mod_log::{self, ModLogEntry};
+use crate::roadmaps::{self, Instructions, PreviousRoadmap, RoadmapProvided, RoadmapRequest};
+use crate::threads;
+use serenity::all::{
+    ChannelId, CommandInteraction, CommandOptionType, Context, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseFollowup, CreateInteractionResponseMessage,
+    CreateMessage, EditInteractionResponse, Mentionable, MessageId, ResolvedValue, UserId,
+};
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+/// Name of the slash command that asks for a roadmap directly.
+pub(crate) const COMMAND_NAME: &str = "roadmap";
+
+/// Name of the slash command that shows the user their last roadmap again.
+pub(crate) const MY_ROADMAP_COMMAND_NAME: &str = "my-roadmap";
+
+/// When each user last used the command, so nobody can ask for roadmaps back to back.
+#[derive(Debug, Default)]
+pub(crate) struct Cooldowns {
+    last_used: HashMap<UserId, Instant>,
+}
+
+impl Cooldowns {
+    /// Starts `user_id`'s cooldown at `now`, or returns how long is left if it's still
+    /// running.
+    pub(crate) fn try_start(
+        &mut self,
+        user_id: UserId,
+        now: Instant,
+        cooldown: Duration,
+    ) -> Result<(), Duration> {
+        if let Some(last_used) = self.last_used.get(&user_id) {
+            let elapsed = now.saturating_duration_since(*last_used);
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+        self.last_used
+            .retain(|_, last_used| now - *last_used < cooldown);
+        self.last_used.insert(user_id, now);
+        Ok(())
+    }
+}
+
+pub(crate) struct RoadmapCooldowns;
+
+impl TypeMapKey for RoadmapCooldowns {
+    type Value = Arc<RwLock<Cooldowns>>;
+}
+
+async fn cooldowns(ctx: &Context) -> Arc<RwLock<Cooldowns>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<RoadmapCooldowns>()
+        .expect("Expected RoadmapCooldowns in TypeMap.")
+        .clone()
+}
+
+/// `/roadmap topic:<what to learn> history:<bool> language:<language>`, which skips
+/// detection.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("Get a learning roadmap for a topic")
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "topic",
+                "What you want to learn, and anything about where you're starting from",
+            )
+            .required(true),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::Boolean,
+            "history",
+            "Use the recent conversation in this channel as context",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "language",
+            "The language to write the roadmap in, like Spanish",
+        ))
+}
+
+/// `/my-roadmap`, which posts the user's last roadmap without writing a new one.
+pub(crate) fn my_roadmap_command() -> CreateCommand {
+    CreateCommand::new(MY_ROADMAP_COMMAND_NAME).description("Show the last roadmap you got")
+}
+
+/// The `topic`, `history` and `language` options of a `/roadmap` command.
+fn options(command: &CommandInteraction) -> (Option<String>, bool, Option<String>) {
+    let mut topic = None;
+    let mut history = false;
+    let mut language = None;
+    for option in command.data.options() {
+        match (option.name, option.value) {
+            ("topic", ResolvedValue::String(value)) => topic = Some(value.trim().to_string()),
+            ("history", ResolvedValue::Boolean(value)) => history = value,
+            ("language", ResolvedValue::String(value)) => language = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    (
+        topic.filter(|topic| !topic.is_empty()),
+        history,
+        language.filter(|language| !language.is_empty()),
+    )
+}
+
+async fn reply_privately(
+    ctx: &Context,
+    command: &CommandInteraction,
+    reply: String,
+) -> anyhow::Result<()> {
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Answers a `/roadmap` command. The response is deferred straight away, since making a
+/// roadmap takes longer than the three seconds Discord waits, then edited with the result.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let (Some(topic), history, language) = options(command) else {
+        return reply_privately(
+            ctx,
+            command,
+            "Tell me what you'd like to learn.".to_string(),
+        )
+        .await;
+    };
+    if let Some(language) = language
+        .as_deref()
+        .filter(|language| !roadmaps::is_known_language(language))
+    {
+        return reply_privately(
+            ctx,
+            command,
+            format!(
+                "Sorry, I can't write in {language}. Try a language's English name, like Spanish."
+            ),
+        )
+        .await;
+    }
+    let Some(_creating) = in_flight::lock_user(ctx, command.user.id).await else {
+        return reply_privately(ctx, command, in_flight::STILL_WORKING.to_string()).await;
+    };
+    let started = cooldowns(ctx).await.write().await.try_start(
+        command.user.id,
+        Instant::now(),
+        roadmaps::command_cooldown(),
+    );
+    if let Err(remaining) = started {
+        return reply_privately(
+            ctx,
+            command,
+            format!(
+                "You just asked for a roadmap, try again in {} seconds.",
+                remaining.as_secs().max(1)
+            ),
+        )
+        .await;
+    }
+    let roles = command
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    if let Err(limit) = roadmaps::check_limits(command.user.id, roles).await {
+        return reply_privately(ctx, command, format!("Sorry, {}", limit.reply())).await;
+    }
+    command.defer(&ctx.http).await?;
+    match create_roadmap(ctx, command, topic.clone(), history, language).await {
+        Ok(mut created_roadmap) => {
+            created_roadmap.thread_id = command_thread(ctx, command).await;
+            roadmaps::store_roadmap(command.user.id, topic.as_str(), &created_roadmap).await;
+            conversation_state::remember(
+                ctx,
+                command.user.id,
+                PreviousRoadmap {
+                    request: topic.clone(),
+                    roadmap: created_roadmap.roadmap.clone(),
+                },
+            )
+            .await;
+            mod_log::record_roadmap(
+                &ctx.http,
+                &ModLogEntry::new(
+                    &command.user,
+                    command.channel_id,
+                    topic.as_str(),
+                    command.id.created_at().unix_timestamp(),
+                    "Wrote a roadmap",
+                    format!("asked for with /{COMMAND_NAME}"),
+                ),
+            )
+            .await;
+            info!(
+                thread_id = ?created_roadmap.thread_id,
+                "Roadmap creation for /{COMMAND_NAME} by {} - {}",
+                command.user.name,
+                describe_completion(
+                    &created_roadmap.model,
+                    created_roadmap.usage,
+                    created_roadmap.elapsed
+                )
+            );
+            match created_roadmap.thread_id {
+                Some(thread_id) => send_to_thread(ctx, command, thread_id, &created_roadmap).await,
+                None => send_roadmap(ctx, command, &created_roadmap).await,
+            }
+        }
+        Err(e) => {
+            error!("Failed to create Roadmap for /{COMMAND_NAME} due to {e:#}");
+            let reply = format!(
+                "Sorry, {}",
+                roadmaps::apology(&e).unwrap_or("I couldn't make that roadmap, try again later.")
+            );
+            command
+                .edit_response(&ctx.http, EditInteractionResponse::new().content(reply))
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Answers `/my-roadmap` with the user's stored roadmap, if they have one.
+pub(crate) async fn handle_my_roadmap(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    match roadmaps::stored_roadmap(command.user.id).await {
+        Ok(Some(roadmap)) => {
+            command.defer(&ctx.http).await?;
+            send_roadmap(ctx, command, &roadmap).await
+        }
+        Ok(None) => {
+            reply_privately(
+                ctx,
+                command,
+                format!("You haven't had a roadmap yet, ask for one with /{COMMAND_NAME}."),
+            )
+            .await
+        }
+        Err(e) => {
+            warn!(
+                "Failed to look up roadmap for {} due to {e:#}",
+                command.user.name
+            );
+            reply_privately(
+                ctx,
+                command,
+                "Sorry, I can't find your last roadmap.".to_string(),
+            )
+            .await
+        }
+    }
+}
+
+async fn create_roadmap(
+    ctx: &Context,
+    command: &CommandInteraction,
+    topic: String,
+    history: bool,
+    language: Option<String>,
+) -> anyhow::Result<RoadmapProvided> {
+    let context_budget = roadmaps::channel_context_budget(command.channel_id);
+    let context = if history {
+        // Interaction ids are snowflakes too, so this is everything sent before the command
+        roadmaps::fetch_channel_context(
+            &*ctx.http,
+            command.channel_id,
+            MessageId::new(command.id.get()),
+            command.user.id,
+            topic.as_str(),
+            UserId::new(command.application_id.get()),
+            context_budget,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to fetch channel context due to {e}, continuing without it");
+            vec![]
+        })
+    } else {
+        vec![]
+    };
+    let instructions = Instructions {
+        language,
+        context_budget,
+        ..Default::default()
+    };
+    let created = if roadmaps::structured_roadmaps() {
+        RoadmapRequest::new(topic)
+            .conversation(context)
+            .instructions(instructions)
+            .create_structured()
+            .await
+    } else {
+        roadmaps::create_roadmap(topic, context, &instructions).await
+    };
+    Ok(created?)
+}
+
+/// A thread off the deferred response to post the roadmap in, where threads are enabled.
+async fn command_thread(ctx: &Context, command: &CommandInteraction) -> Option<ChannelId> {
+    if !command.guild_id.is_some_and(roadmaps::threads_enabled) {
+        return None;
+    }
+    let response = command
+        .get_response(&ctx.http)
+        .await
+        .inspect_err(|e| warn!("Failed to fetch /{COMMAND_NAME} response due to {e}"))
+        .ok()?;
+    threads::roadmap_thread(
+        ctx,
+        command.guild_id,
+        command.channel_id,
+        response.id,
+        command.user.name.as_str(),
+    )
+    .await
+}
+
+/// Posts the roadmap in `thread_id` and points the deferred response at it.
+async fn send_to_thread(
+    ctx: &Context,
+    command: &CommandInteraction,
+    thread_id: ChannelId,
+    created_roadmap: &RoadmapProvided,
+) -> anyhow::Result<()> {
+    match &created_roadmap.structured {
+        Some(structured) => {
+            embeds::send_roadmap_embeds(ctx, thread_id, &command.user, structured).await?
+        }
+        None => {
+            let reply = format!(
+                "Hi {},\n{}",
+                command.user.mention(),
+                created_roadmap.roadmap
+            );
+            for (index, part) in split_for_discord(reply.as_str()).into_iter().enumerate() {
+                if index > 0 {
+                    tokio::time::sleep(PART_DELAY).await;
+                }
+                thread_id
+                    .send_message(&ctx.http, CreateMessage::new().content(part))
+                    .await?;
+            }
+        }
+    }
+    command
+        .edit_response(
+            &ctx.http,
+            EditInteractionResponse::new().content(format!("Your roadmap is in <#{thread_id}>.")),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Fills in the deferred response with the roadmap, following up with any parts or embeds
+/// that don't fit in it.
+async fn send_roadmap(
+    ctx: &Context,
+    command: &CommandInteraction,
+    created_roadmap: &RoadmapProvided,
+) -> anyhow::Result<()> {
+    if let Some(structured) = &created_roadmap.structured {
+        for (index, embed) in roadmap_embeds(structured).iter().enumerate() {
+            if index == 0 {
+                command
+                    .edit_response(
+                        &ctx.http,
+                        EditInteractionResponse::new().embed(embed.to_embed()),
+                    )
+                    .await?;
+            } else {
+                tokio::time::sleep(PART_DELAY).await;
+                command
+                    .create_followup(
+                        &ctx.http,
+                        CreateInteractionResponseFollowup::new().embed(embed.to_embed()),
+                    )
+                    .await?;
+            }
+        }
+        return Ok(());
+    }
+    for (index, part) in split_for_discord(created_roadmap.roadmap.as_str())
+        .into_iter()
+        .enumerate()
+    {
+        if index == 0 {
+            command
+                .edit_response(&ctx.http, EditInteractionResponse::new().content(part))
+                .await?;
+        } else {
+            tokio::time::sleep(PART_DELAY).await;
+            command
+                .create_followup(
+                    &ctx.http,
+                    CreateInteractionResponseFollowup::new().content(part),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn cooldown_blocks_repeat_requests() {
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        assert_eq!(cooldowns.try_start(ADA, now, COOLDOWN), Ok(()));
+        assert_eq!(
+            cooldowns.try_start(ADA, now + Duration::from_secs(20), COOLDOWN),
+            Err(Duration::from_secs(40))
+        );
+        assert_eq!(cooldowns.try_start(ADA, now + COOLDOWN, COOLDOWN), Ok(()));
+    }
+
+    #[test]
+    fn cooldown_is_per_user() {
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        assert_eq!(cooldowns.try_start(ADA, now, COOLDOWN), Ok(()));
+        assert_eq!(cooldowns.try_start(BOB, now, COOLDOWN), Ok(()));
+    }
+
+    #[test]
+    fn expired_cooldowns_are_forgotten() {
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        cooldowns.try_start(ADA, now, COOLDOWN).unwrap();
+        cooldowns
+            .try_start(BOB, now + Duration::from_secs(90), COOLDOWN)
+            .unwrap();
+        assert_eq!(cooldowns.last_used.len(), 1);
+    }
+}
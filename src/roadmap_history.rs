@@ -0,0 +1,374 @@
+use crate::roadmap_store::StoredRoadmap;
+use crate::roadmaps;
+use anyhow::bail;
+use serenity::all::{
+    CommandInteraction, CommandOptionType, Context, CreateAttachment, CreateCommand,
+    CreateCommandOption, CreateInteractionResponseFollowup, CreateMessage, EditInteractionResponse,
+    Permissions, ResolvedValue, User, UserId,
+};
+use tracing::warn;
+
+/// Name of the slash command members list and export their roadmaps with.
+pub(crate) const COMMAND_NAME: &str = "my-roadmaps";
+
+/// Name of the slash command moderators export anyone's roadmaps with.
+pub(crate) const ADMIN_COMMAND_NAME: &str = "roadmap-history";
+
+/// Longest reply Discord accepts.
+const REPLY_LIMIT: usize = 2_000;
+
+/// Most files Discord takes on one message.
+const FILES_PER_MESSAGE: usize = 10;
+
+/// Longest first line shown for each roadmap in the list.
+const FIRST_LINE_LIMIT: usize = 80;
+
+/// The first line of `roadmap` with any heading marks, cut to `FIRST_LINE_LIMIT`.
+fn first_line(roadmap: &str) -> String {
+    let line = roadmap
+        .lines()
+        .map(|line| line.trim().trim_start_matches('#').trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or("(empty)");
+    if line.chars().count() > FIRST_LINE_LIMIT {
+        let kept: String = line.chars().take(FIRST_LINE_LIMIT - 1).collect();
+        format!("{}…", kept.trim_end())
+    } else {
+        line.to_string()
+    }
+}
+
+/// `history` newest first, a line each with its date and first line, stopping before the
+/// reply gets too long.
+fn describe_history(history: &[StoredRoadmap]) -> String {
+    if history.is_empty() {
+        return "You haven't had a roadmap yet, ask for one with /roadmap.".to_string();
+    }
+    let mut reply = "Your roadmaps, newest first:".to_string();
+    for (shown, stored) in history.iter().rev().enumerate() {
+        let line = format!(
+            "\n- <t:{}:d> {}",
+            stored.created_at.timestamp(),
+            first_line(stored.roadmap.as_str())
+        );
+        let more = format!(
+            "\n…and {} more. Use `/{COMMAND_NAME} export` to get them all.",
+            history.len() - shown
+        );
+        if reply.chars().count() + line.chars().count() + more.chars().count() > REPLY_LIMIT {
+            reply.push_str(more.as_str());
+            break;
+        }
+        reply.push_str(line.as_str());
+    }
+    reply
+}
+
+/// `history` as one Markdown document, a section per roadmap, oldest first.
+fn markdown_sections(user_name: &str, history: &[StoredRoadmap]) -> Vec<String> {
+    let mut sections = vec![format!(
+        "# Roadmaps for {user_name}\n\n{} roadmap(s), oldest first.\n\n",
+        history.len()
+    )];
+    sections.extend(history.iter().map(|stored| {
+        let request: String = stored
+            .message
+            .lines()
+            .map(|line| format!("> {line}\n"))
+            .collect();
+        format!(
+            "## {}\n\n{request}\n{}\n\n",
+            stored.created_at.format("%Y-%m-%d %H:%M UTC"),
+            stored.roadmap.trim_end()
+        )
+    }));
+    sections
+}
+
+/// `text` cut into pieces of at most `max_bytes`, never splitting a code point.
+fn cut_to_bytes(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut piece = String::new();
+    for c in text.chars() {
+        if piece.len() + c.len_utf8() > max_bytes {
+            pieces.push(std::mem::take(&mut piece));
+        }
+        piece.push(c);
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+/// `sections` packed in order into files of at most `max_bytes`. A section too big for a
+/// file of its own is split between lines, and a line too big between chars.
+fn pack(sections: Vec<String>, max_bytes: usize) -> Vec<String> {
+    let units = sections.into_iter().flat_map(|section| {
+        if section.len() <= max_bytes {
+            return vec![section];
+        }
+        section
+            .split_inclusive('\n')
+            .flat_map(|line| cut_to_bytes(line, max_bytes))
+            .collect()
+    });
+    let mut files = vec![];
+    let mut file = String::new();
+    for unit in units {
+        if file.len() + unit.len() > max_bytes {
+            files.push(std::mem::take(&mut file));
+        }
+        file.push_str(unit.as_str());
+    }
+    if !file.is_empty() {
+        files.push(file);
+    }
+    files
+}
+
+/// `user`'s history as Markdown attachments of at most `max_bytes` each.
+fn export_files(user: &User, history: &[StoredRoadmap], max_bytes: usize) -> Vec<CreateAttachment> {
+    let name: String = user
+        .name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let files = pack(markdown_sections(user.name.as_str(), history), max_bytes);
+    let count = files.len();
+    files
+        .into_iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let file_name = if count == 1 {
+                format!("roadmaps-{name}.md")
+            } else {
+                format!("roadmaps-{name}-{}-of-{count}.md", index + 1)
+            };
+            CreateAttachment::bytes(file.into_bytes(), file_name)
+        })
+        .collect()
+}
+
+/// `/my-roadmaps list|export`, for anyone.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("See the roadmaps you've had")
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "list",
+            "List your roadmaps with when you got them",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "export",
+                "Get all your roadmaps as a Markdown file",
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                "dm",
+                "Send the file by DM instead of here",
+            )),
+        )
+}
+
+/// `/roadmap-history user:<member>`, for members who can manage the server.
+pub(crate) fn admin_command() -> CreateCommand {
+    CreateCommand::new(ADMIN_COMMAND_NAME)
+        .description("Export a member's roadmaps as Markdown")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::User, "user", "Whose roadmaps")
+                .required(true),
+        )
+}
+
+/// The history of `user_id`, or the reply explaining why there isn't one.
+async fn history(user_id: UserId) -> Result<Vec<StoredRoadmap>, String> {
+    if !roadmaps::keeps_roadmaps() {
+        return Err("Roadmaps aren't kept on this server, so there's no history.".to_string());
+    }
+    roadmaps::roadmap_history(user_id).await.map_err(|e| {
+        warn!("Failed to look up roadmaps for {user_id} due to {e:#}");
+        "Sorry, I can't find those roadmaps.".to_string()
+    })
+}
+
+/// Fills in the deferred private reply with `reply` and the first batch of `files`,
+/// following up with the rest.
+async fn reply_privately(
+    ctx: &Context,
+    command: &CommandInteraction,
+    reply: String,
+    files: Vec<CreateAttachment>,
+) -> anyhow::Result<()> {
+    let mut batches = files.chunks(FILES_PER_MESSAGE);
+    let first = batches.next().map(<[_]>::to_vec).unwrap_or_default();
+    let response = first.into_iter().fold(
+        EditInteractionResponse::new().content(reply),
+        EditInteractionResponse::new_attachment,
+    );
+    command.edit_response(&ctx.http, response).await?;
+    for batch in batches {
+        command
+            .create_followup(
+                &ctx.http,
+                CreateInteractionResponseFollowup::new()
+                    .add_files(batch.to_vec())
+                    .ephemeral(true),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Sends `files` to `user` by DM, saying whether it worked.
+async fn send_by_dm(ctx: &Context, user: &User, files: Vec<CreateAttachment>) -> String {
+    for batch in files.chunks(FILES_PER_MESSAGE) {
+        let sent = user
+            .direct_message(&ctx.http, CreateMessage::new().add_files(batch.to_vec()))
+            .await;
+        if let Err(e) = sent {
+            warn!("Failed to DM roadmaps to {} due to {e}", user.name);
+            return "I couldn't DM you, check that your DMs are open for this server.".to_string();
+        }
+    }
+    "Sent your roadmaps by DM.".to_string()
+}
+
+/// Answers `/my-roadmaps list` with the list and `/my-roadmaps export` with the file.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let options = command.data.options();
+    let Some(subcommand) = options.first() else {
+        bail!("/{COMMAND_NAME} was sent without a subcommand");
+    };
+    let dm = match &subcommand.value {
+        ResolvedValue::SubCommand(options) => options.iter().any(|option| {
+            option.name == "dm" && matches!(option.value, ResolvedValue::Boolean(true))
+        }),
+        _ => false,
+    };
+    // Looking up the history and DMing it can take longer than Discord waits for a reply
+    command.defer_ephemeral(&ctx.http).await?;
+    let history = match history(command.user.id).await {
+        Ok(history) => history,
+        Err(reply) => return reply_privately(ctx, command, reply, vec![]).await,
+    };
+    match subcommand.name {
+        "list" => reply_privately(ctx, command, describe_history(&history), vec![]).await,
+        "export" if history.is_empty() => {
+            reply_privately(ctx, command, describe_history(&history), vec![]).await
+        }
+        "export" => {
+            let files = export_files(&command.user, &history, roadmaps::export_max_bytes());
+            if dm {
+                let reply = send_by_dm(ctx, &command.user, files).await;
+                reply_privately(ctx, command, reply, vec![]).await
+            } else {
+                let reply = format!("Here are your {} roadmap(s).", history.len());
+                reply_privately(ctx, command, reply, files).await
+            }
+        }
+        name => bail!("Unknown /{COMMAND_NAME} subcommand {name}"),
+    }
+}
+
+/// Answers `/roadmap-history` privately with the member's roadmaps as Markdown.
+pub(crate) async fn handle_admin_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let Some(user) = command
+        .data
+        .options()
+        .into_iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::User(user, _) => Some(user.clone()),
+            _ => None,
+        })
+    else {
+        bail!("/{ADMIN_COMMAND_NAME} was sent without a user");
+    };
+    command.defer_ephemeral(&ctx.http).await?;
+    let (reply, files) = match history(user.id).await {
+        Ok(history) if history.is_empty() => {
+            (format!("{} hasn't had a roadmap.", user.name), vec![])
+        }
+        Ok(history) => (
+            format!("{} roadmap(s) for {}.", history.len(), user.name),
+            export_files(&user, &history, roadmaps::export_max_bytes()),
+        ),
+        Err(reply) => (reply, vec![]),
+    };
+    reply_privately(ctx, command, reply, files).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn stored(day: u32, roadmap: &str) -> StoredRoadmap {
+        StoredRoadmap {
+            created_at: Utc.with_ymd_and_hms(2024, 5, day, 12, 30, 0).unwrap(),
+            message: "roadmap please\nI know Python".to_string(),
+            roadmap: roadmap.to_string(),
+        }
+    }
+
+    #[test]
+    fn list_is_newest_first_and_fits_a_reply() {
+        let history = vec![
+            stored(1, "# Learn Rust\n1. Read the book"),
+            stored(2, "\n\nData science in 3 months"),
+        ];
+        assert_eq!(
+            describe_history(&history),
+            "Your roadmaps, newest first:\n\
+             - <t:1714653000:d> Data science in 3 months\n\
+             - <t:1714566600:d> Learn Rust"
+        );
+        let long: Vec<StoredRoadmap> = (0..100)
+            .map(|_| stored(1, "x".repeat(200).as_str()))
+            .collect();
+        let reply = describe_history(&long);
+        assert!(reply.chars().count() <= REPLY_LIMIT);
+        assert!(reply.ends_with("more. Use `/my-roadmaps export` to get them all."));
+    }
+
+    #[test]
+    fn export_is_markdown_with_a_section_per_roadmap() {
+        let sections = markdown_sections("ada", &[stored(1, "1. Read the book\n")]);
+        assert_eq!(
+            sections.concat(),
+            "# Roadmaps for ada\n\n1 roadmap(s), oldest first.\n\n\
+             ## 2024-05-01 12:30 UTC\n\n\
+             > roadmap please\n\
+             > I know Python\n\
+             \n\
+             1. Read the book\n\n"
+        );
+    }
+
+    #[test]
+    fn big_exports_are_split_under_the_cap() {
+        let history: Vec<StoredRoadmap> = (1..=5)
+            .map(|day| stored(day, "step\n".repeat(100).as_str()))
+            .collect();
+        let sections = markdown_sections("ada", &history);
+        let whole = sections.concat();
+        let files = pack(sections, 1024);
+        assert!(files.len() > 1);
+        assert!(files.iter().all(|file| file.len() <= 1024));
+        assert_eq!(files.concat(), whole);
+        // A single line over the cap is cut between chars, never inside one
+        let files = pack(vec!["é".repeat(1000)], 1024);
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|file| file.len() <= 1024));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::roadmaps::RoadmapProvided;
+use chrono::{DateTime, Utc};
+use serenity::all::UserId;
+use serenity::async_trait;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+
+/// A roadmap someone was given, as kept in the store.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StoredRoadmap {
+    pub(crate) created_at: DateTime<Utc>,
+    /// What they asked for it with.
+    pub(crate) message: String,
+    pub(crate) roadmap: String,
+}
+
+/// Somewhere to keep the roadmaps people were given, so they can see them again without
+/// paying for a new one.
+#[async_trait]
+pub(crate) trait RoadmapStore: Send + Sync {
+    /// Saves `roadmap`, written for `user_id` in answer to `message` at `created_at`, as
+    /// their latest.
+    async fn save(
+        &self,
+        user_id: UserId,
+        message: &str,
+        roadmap: &RoadmapProvided,
+        created_at: DateTime<Utc>,
+    ) -> anyhow::Result<()>;
+
+    /// The latest roadmap written for `user_id`. Usage and timing aren't kept.
+    async fn get_roadmap(&self, user_id: UserId) -> anyhow::Result<Option<RoadmapProvided>>;
+
+    /// Every roadmap written for `user_id`, oldest first.
+    async fn history(&self, user_id: UserId) -> anyhow::Result<Vec<StoredRoadmap>>;
+}
+
+/// Keeps roadmaps for as long as the process runs, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct InMemoryRoadmapStore {
+    roadmaps: Mutex<HashMap<UserId, Vec<(StoredRoadmap, RoadmapProvided)>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl RoadmapStore for InMemoryRoadmapStore {
+    async fn save(
+        &self,
+        user_id: UserId,
+        message: &str,
+        roadmap: &RoadmapProvided,
+        created_at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        self.roadmaps
+            .lock()
+            .unwrap()
+            .entry(user_id)
+            .or_default()
+            .push((
+                StoredRoadmap {
+                    created_at,
+                    message: message.to_string(),
+                    roadmap: roadmap.roadmap.clone(),
+                },
+                RoadmapProvided {
+                    usage: None,
+                    elapsed: Duration::ZERO,
+                    ..roadmap.clone()
+                },
+            ));
+        Ok(())
+    }
+
+    async fn get_roadmap(&self, user_id: UserId) -> anyhow::Result<Option<RoadmapProvided>> {
+        let roadmaps = self.roadmaps.lock().unwrap();
+        Ok(roadmaps
+            .get(&user_id)
+            .and_then(|roadmaps| roadmaps.last())
+            .map(|(_, roadmap)| roadmap.clone()))
+    }
+
+    async fn history(&self, user_id: UserId) -> anyhow::Result<Vec<StoredRoadmap>> {
+        let roadmaps = self.roadmaps.lock().unwrap();
+        Ok(roadmaps
+            .get(&user_id)
+            .map(|roadmaps| roadmaps.iter().map(|(stored, _)| stored.clone()).collect())
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use chrono::TimeZone;
+    use std::env;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+
+    fn roadmap(text: &str) -> RoadmapProvided {
+        RoadmapProvided {
+            roadmap: text.to_string(),
+            structured: None,
+            usage: None,
+            model: "gpt-4o".to_string(),
+            elapsed: Duration::from_secs(3),
+            thread_id: None,
+        }
+    }
+
+    /// Saving twice for one user and once for another, the latest of each comes back,
+    /// and each user's history holds only theirs.
+    async fn keeps_latest_roadmap_per_user(store: &dyn RoadmapStore) {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        assert!(store.get_roadmap(ADA).await.unwrap().is_none());
+        store
+            .save(ADA, "roadmap please", &roadmap("first"), now)
+            .await
+            .unwrap();
+        store
+            .save(ADA, "another one", &roadmap("second"), now)
+            .await
+            .unwrap();
+        store
+            .save(BOB, "me too", &roadmap("bob's"), now)
+            .await
+            .unwrap();
+        let latest = store.get_roadmap(ADA).await.unwrap().unwrap();
+        assert_eq!(latest.roadmap, "second");
+        assert_eq!(latest.model, "gpt-4o");
+        assert_eq!(latest.elapsed, Duration::ZERO);
+        assert_eq!(
+            store.get_roadmap(BOB).await.unwrap().unwrap().roadmap,
+            "bob's"
+        );
+        let history = store.history(ADA).await.unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|stored| (stored.message.as_str(), stored.roadmap.as_str()))
+                .collect::<Vec<_>>(),
+            [("roadmap please", "first"), ("another one", "second")]
+        );
+        assert_eq!(history[0].created_at, now);
+        assert!(store.history(UserId::new(3)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_keeps_latest_roadmap() {
+        keeps_latest_roadmap_per_user(&InMemoryRoadmapStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn database_keeps_latest_roadmap() {
+        let path = env::temp_dir().join("database_keeps_latest_roadmap.db");
+        let _ = std::fs::remove_file(&path);
+        keeps_latest_roadmap_per_user(&Storage::open(&path).unwrap()).await;
+    }
+}
@@ -1,151 +1,4336 @@
-use anyhow::bail;
+use crate::budget::{BudgetReport, BudgetedBackend, SpendBudget};
+use crate::channel_context;
+use crate::channel_context::MessageFetcher;
+use crate::detection_cache::DetectionCache;
+use crate::dry_run::DryRunBackend;
+use crate::heuristic_detection;
+use crate::heuristic_detection::{DetectionMode, HeuristicDetector, RoadmapDetector};
+use crate::llm;
+use crate::llm::{ChatBackend, ChatParams, OpenAiBackend};
+use crate::metrics;
+use crate::quota::{LimitReached, RoadmapQuota};
+use crate::rate_limit::RateLimiter;
+use crate::roadmap_channels::ChannelList;
+use crate::roadmap_store::{RoadmapStore, StoredRoadmap};
+use crate::scrubbing;
+use crate::scrubbing::Scrubber;
+use crate::storage;
+use crate::utilities;
+use crate::utilities::{PromptBudget, RetriesExhausted, Role, CURRENT_MESSAGE_LABEL};
+use anyhow::{bail, ensure, Context};
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt};
 use lazy_static::lazy_static;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
-use serde::Deserialize;
-use tracing::info;
+use notify::{RecursiveMode, Watcher};
+use openai::chat::{
+    ChatCompletionFunctionDefinition, ChatCompletionMessage, ChatCompletionMessageRole,
+};
+use openai::Usage;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serenity::all::{AutoArchiveDuration, ChannelId, GuildId, MessageId, RoleId, UserId};
+use std::env;
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, field, info, instrument, warn, Span};
+use whatlang::Lang;
 
 lazy_static! {
-    static ref ROADMAP_CONFIG: RoadmapConfig = RoadmapConfig::default();
+    static ref ROADMAP_CONFIG: RoadmapConfig =
+        RoadmapConfig::from_env().expect("Invalid roadmap configuration");
+    static ref SPEND_BUDGET: Arc<SpendBudget> = Arc::new(SpendBudget::new(
+        ROADMAP_CONFIG.daily_budget_usd,
+        ROADMAP_CONFIG.prompt_price_per_million,
+        ROADMAP_CONFIG.completion_price_per_million,
+        Some(storage::shared())
+    ));
+    static ref OPENAI_BACKEND: Arc<dyn ChatBackend> = if ROADMAP_CONFIG.dry_run {
+        Arc::new(DryRunBackend)
+    } else {
+        Arc::new(BudgetedBackend::new(openai_backend(), SPEND_BUDGET.clone()))
+    };
+    static ref ROADMAP_SERVICE: RoadmapService =
+        RoadmapService::new(ROADMAP_CONFIG.clone(), OPENAI_BACKEND.clone());
+    static ref RATE_LIMITER: RateLimiter = RateLimiter::new(
+        ROADMAP_CONFIG.rate_limit_capacity,
+        Duration::from_secs(ROADMAP_CONFIG.rate_limit_refill_secs)
+    );
+    static ref ROADMAP_QUOTA: RoadmapQuota = RoadmapQuota::new(
+        Duration::from_secs(ROADMAP_CONFIG.roadmap_cooldown_secs),
+        ROADMAP_CONFIG.daily_roadmap_quota,
+        Some(storage::shared())
+    );
+    static ref ROADMAP_STORE: Option<Box<dyn RoadmapStore>> = ROADMAP_CONFIG
+        .keep_roadmaps
+        .then(|| Box::new(storage::shared()) as Box<dyn RoadmapStore>);
+    static ref ROADMAP_PROMPTS: PromptCache =
+        PromptCache::load(&ROADMAP_CONFIG).expect("Invalid roadmap prompts");
 }
 
+/// Environment variable pointing at an alternative roadmap config file (TOML or JSON).
+const ROADMAP_CONFIG_ENV: &str = "ROADMAP_CONFIG_PATH";
+
+/// Config file looked for next to the binary when `ROADMAP_CONFIG_PATH` is unset.
+const ROADMAP_CONFIG_FILE: &str = "roadmaps.toml";
+
 static DETECT_ROADMAP_PROMPT: &str = include_str!("../prompts/detect_roadmap.txt");
 
 static CREATE_ROADMAP_PROMPT: &str = include_str!("../prompts/create_roadmap_for_user.txt");
 
-#[derive(Deserialize)]
-struct RoadmapConfig {
-    context_length: usize,
-    message_limit_chars: usize,
+/// Follow-up sent once when the detection reply couldn't be parsed.
+static JSON_REPAIR_PROMPT: &str =
+    "Please output only the JSON object with the fields \"reason\", \"is_roadmap\" and \
+     \"confidence\", with no other text.";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct RoadmapConfig {
+    pub(crate) context_length: usize,
+    /// Newest context messages always sent, cut down if they're over the context budget.
+    pub(crate) min_context_messages: usize,
+    pub(crate) message_limit_chars: usize,
+    /// An OpenAI-compatible endpoint, like a self-hosted vLLM or Ollama, to send both
+    /// detection and creation to instead of OpenAI.
+    pub(crate) api_base_url: Option<String>,
+    pub(crate) detection_model: String,
+    pub(crate) creation_model: String,
+    pub(crate) detection_max_tokens: u64,
+    pub(crate) creation_max_tokens: u64,
+    pub(crate) detection_temperature: f32,
+    pub(crate) creation_temperature: f32,
+    pub(crate) max_prompt_tokens: usize,
+    pub(crate) count_context_tokens: bool,
+    pub(crate) message_limit_tokens: usize,
+    /// Chars kept of each link or file sent with a request, and of all of them together.
+    pub(crate) attachment_limit_chars: usize,
+    pub(crate) max_attachment_chars: usize,
+    pub(crate) max_retries: usize,
+    pub(crate) retry_deadline_secs: u64,
+    pub(crate) request_timeout_secs: u64,
+    /// Seconds a whole detection or creation call may take, each leaving room for a last
+    /// attempt started just before `retry_deadline_secs`.
+    pub(crate) detection_timeout_secs: u64,
+    pub(crate) creation_timeout_secs: u64,
+    pub(crate) detection_threshold: f32,
+    pub(crate) uncertain_threshold: f32,
+    /// Whether requests are detected by the model, by `heuristic_keywords` or by the
+    /// keywords with the model as a fallback.
+    pub(crate) detection_mode: DetectionMode,
+    /// Phrases that mark a message as a roadmap request, matched ignoring case.
+    pub(crate) heuristic_keywords: Vec<String>,
+    pub(crate) detection_cache: bool,
+    pub(crate) detection_cache_capacity: usize,
+    pub(crate) detection_cache_ttl_secs: u64,
+    /// Detections `detect_batch` runs at once, kept low to stay clear of OpenAI rate limits.
+    pub(crate) batch_concurrency: usize,
+    pub(crate) daily_budget_usd: Option<f64>,
+    pub(crate) prompt_price_per_million: f64,
+    pub(crate) completion_price_per_million: f64,
+    /// Where spend was kept before the database, imported when it's first created.
+    pub(crate) budget_path: String,
+    /// Files replacing the embedded detection and creation prompts.
+    pub(crate) detect_prompt_path: Option<PathBuf>,
+    pub(crate) create_prompt_path: Option<PathBuf>,
+    /// Deployment-specific instructions put before and after both system prompts, so the
+    /// prompts themselves stay as versioned. Empty leaves them unchanged.
+    pub(crate) system_prompt_prefix: String,
+    pub(crate) system_prompt_suffix: String,
+    /// Ask for roadmaps as JSON steps and post them as embeds, rather than drafting text.
+    pub(crate) structured_roadmaps: bool,
+    /// Channels roadmap detection is limited to, every channel when left out.
+    pub(crate) allowed_channels: Option<Vec<u64>>,
+    /// Channels roadmap detection never runs in, threads in them included.
+    pub(crate) denied_channels: Vec<u64>,
+    /// Where `/roadmap-channels` saves its changes, which replace the two lists above.
+    pub(crate) channels_path: String,
+    /// Seconds each user must wait between `/roadmap` commands.
+    pub(crate) command_cooldown_secs: u64,
+    /// Roadmaps each user can have in a burst before being rate limited.
+    pub(crate) rate_limit_capacity: u32,
+    /// Seconds for a rate limited user to earn another roadmap.
+    pub(crate) rate_limit_refill_secs: u64,
+    /// Seconds each user must wait after getting a roadmap before getting another.
+    pub(crate) roadmap_cooldown_secs: u64,
+    /// Roadmaps each user can get per UTC day, `None` for no cap.
+    pub(crate) daily_roadmap_quota: Option<u32>,
+    /// Where usage was kept before the database, imported when it's first created.
+    pub(crate) quota_path: String,
+    /// Roles exempt from rate limits, cooldowns and quotas.
+    pub(crate) staff_roles: Vec<u64>,
+    /// Keep created roadmaps in the database, so people can see theirs again.
+    pub(crate) keep_roadmaps: bool,
+    /// SQLite file roadmaps were kept in before the database, imported when it's first
+    /// created.
+    pub(crate) roadmap_store_path: Option<String>,
+    /// Largest file `/my-roadmaps export` attaches, bigger histories are split across
+    /// several.
+    pub(crate) export_max_bytes: usize,
+    /// Ask the author to confirm with a button before creating a detected roadmap.
+    pub(crate) confirm_roadmaps: bool,
+    /// Where answers to confirmation prompts are appended, one JSON line each.
+    pub(crate) confirmations_path: String,
+    /// Guilds where roadmaps are posted in a thread off the request instead of inline.
+    pub(crate) thread_guilds: Vec<u64>,
+    /// Minutes of inactivity before a roadmap thread is archived. Discord only accepts
+    /// 60, 1440, 4320 or 10080.
+    pub(crate) thread_archive_minutes: u16,
+    /// Seconds a user's last roadmap can be followed up on and revised.
+    pub(crate) followup_ttl_secs: u64,
+    /// Answer detection and creation with canned replies instead of calling the API.
+    pub(crate) dry_run: bool,
+    /// Trim context, dropping blank entries and repeats, before it's budgeted. Off sends
+    /// context as it was written.
+    pub(crate) clean_context: bool,
+    /// Replace emails and phone numbers in the message, context and attachments with
+    /// placeholders before they're sent to the model.
+    pub(crate) scrub_pii: bool,
+    /// Mask `profanity_words` in the message, context and attachments with asterisks
+    /// before they're sent to the model.
+    pub(crate) mask_profanity: bool,
+    pub(crate) profanity_words: Vec<String>,
+    /// Write roadmaps in the language the request was written in, rather than English.
+    pub(crate) localize_roadmaps: bool,
+    /// Context limits for channels that warrant more or less context than the rest.
+    pub(crate) channel_context_budgets: Vec<ChannelContextBudget>,
 }
 
 impl Default for RoadmapConfig {
     fn default() -> Self {
         RoadmapConfig {
             context_length: 3,
+            min_context_messages: 0,
             message_limit_chars: 2048,
+            api_base_url: None,
+            detection_model: "gpt-4o-mini".to_string(),
+            creation_model: "gpt-4o-mini".to_string(),
+            detection_max_tokens: 256,
+            creation_max_tokens: 1024,
+            detection_temperature: 0.0,
+            creation_temperature: 0.7,
+            max_prompt_tokens: 4096,
+            count_context_tokens: false,
+            message_limit_tokens: 512,
+            attachment_limit_chars: 1000,
+            max_attachment_chars: 3000,
+            max_retries: 3,
+            retry_deadline_secs: 30,
+            request_timeout_secs: 30,
+            detection_timeout_secs: 60,
+            creation_timeout_secs: 90,
+            detection_threshold: 0.7,
+            uncertain_threshold: 0.4,
+            detection_mode: DetectionMode::Llm,
+            heuristic_keywords: heuristic_detection::default_keywords(),
+            detection_cache: false,
+            detection_cache_capacity: 512,
+            detection_cache_ttl_secs: 600,
+            batch_concurrency: 4,
+            daily_budget_usd: None,
+            prompt_price_per_million: 0.15,
+            completion_price_per_million: 0.6,
+            budget_path: "roadmap_budget.json".to_string(),
+            detect_prompt_path: None,
+            create_prompt_path: None,
+            system_prompt_prefix: String::new(),
+            system_prompt_suffix: String::new(),
+            structured_roadmaps: false,
+            allowed_channels: None,
+            denied_channels: vec![],
+            channels_path: "roadmap_channels.json".to_string(),
+            command_cooldown_secs: 60,
+            rate_limit_capacity: 3,
+            rate_limit_refill_secs: 600,
+            roadmap_cooldown_secs: 600,
+            daily_roadmap_quota: None,
+            quota_path: "roadmap_quota.json".to_string(),
+            staff_roles: vec![],
+            keep_roadmaps: false,
+            roadmap_store_path: None,
+            export_max_bytes: 8 * 1024 * 1024,
+            confirm_roadmaps: false,
+            confirmations_path: "roadmap_confirmations.jsonl".to_string(),
+            thread_guilds: vec![],
+            thread_archive_minutes: 1440,
+            followup_ttl_secs: 3600,
+            dry_run: false,
+            clean_context: false,
+            scrub_pii: false,
+            mask_profanity: false,
+            profanity_words: scrubbing::default_profanity_words(),
+            localize_roadmaps: false,
+            channel_context_budgets: vec![],
+        }
+    }
+}
+
+impl RoadmapConfig {
+    fn cost_usd(&self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        (prompt_tokens as f64 * self.prompt_price_per_million
+            + completion_tokens as f64 * self.completion_price_per_million)
+            / 1_000_000.0
+    }
+
+    /// Loads the config from `ROADMAP_CONFIG_PATH`, or `roadmaps.toml` next to the binary.
+    fn from_env() -> anyhow::Result<RoadmapConfig> {
+        let path = match env::var(ROADMAP_CONFIG_ENV) {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => env::current_exe()?
+                .parent()
+                .map(|directory| directory.join(ROADMAP_CONFIG_FILE))
+                .unwrap_or_else(|| PathBuf::from(ROADMAP_CONFIG_FILE)),
+        };
+        let roadmap_config = RoadmapConfig::load(&path)?;
+        info!(
+            "Loaded roadmap config {:?} from {}",
+            roadmap_config,
+            path.display()
+        );
+        Ok(roadmap_config)
+    }
+
+    /// Reads a TOML or JSON config file, picking the format from the extension.
+    /// A missing file, or any field missing from it, falls back to the defaults.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<RoadmapConfig> {
+        let loaded = config::Config::builder()
+            .add_source(config::File::from(path).required(false))
+            .build()
+            .with_context(|| format!("Failed to parse roadmap config {}", path.display()))?;
+        let mut roadmap_config: RoadmapConfig = loaded
+            .clone()
+            .try_deserialize()
+            .with_context(|| format!("Failed to parse roadmap config {}", path.display()))?;
+        roadmap_config.apply_split_keys(&loaded);
+        roadmap_config
+            .validate()
+            .with_context(|| format!("Invalid roadmap config {}", path.display()))?;
+        Ok(roadmap_config)
+    }
+
+    /// Fills in the detection and creation fields from the single keys that set both before
+    /// they were split, wherever the new key isn't set, warning that the old one is deprecated.
+    fn apply_split_keys(&mut self, loaded: &config::Config) {
+        let (detection, creation) =
+            split_key::<String>(loaded, "model", "detection_model", "creation_model");
+        if let Some(model) = detection {
+            self.detection_model = model;
+        }
+        if let Some(model) = creation {
+            self.creation_model = model;
+        }
+        let (detection, creation) = split_key::<u64>(
+            loaded,
+            "max_tokens",
+            "detection_max_tokens",
+            "creation_max_tokens",
+        );
+        if let Some(max_tokens) = detection {
+            self.detection_max_tokens = max_tokens;
+        }
+        if let Some(max_tokens) = creation {
+            self.creation_max_tokens = max_tokens;
+        }
+    }
+
+    fn channel_context_budget(&self, channel_id: ChannelId) -> Option<ContextBudget> {
+        self.channel_context_budgets
+            .iter()
+            .find(|budget| budget.channel_id == channel_id.get())
+            .map(|budget| ContextBudget {
+                context_length: budget.context_length,
+                message_limit_chars: budget.message_limit_chars,
+            })
+    }
+
+    /// The detection cache described by this config, which is a no-op unless enabled.
+    fn detection_cache(&self) -> DetectionCache {
+        let capacity = if self.detection_cache {
+            self.detection_cache_capacity
+        } else {
+            0
+        };
+        DetectionCache::new(capacity, Duration::from_secs(self.detection_cache_ttl_secs))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.min_context_messages <= self.context_length,
+            "min_context_messages must be at most context_length"
+        );
+        ensure!(
+            self.message_limit_chars > 0,
+            "message_limit_chars must be greater than 0"
+        );
+        ensure!(
+            self.detection_max_tokens > 0 && self.creation_max_tokens > 0,
+            "detection_max_tokens and creation_max_tokens must be greater than 0"
+        );
+        ensure!(
+            (0.0..=2.0).contains(&self.detection_temperature)
+                && (0.0..=2.0).contains(&self.creation_temperature),
+            "detection_temperature and creation_temperature must be between 0 and 2"
+        );
+        ensure!(
+            self.max_prompt_tokens > 0,
+            "max_prompt_tokens must be greater than 0"
+        );
+        ensure!(
+            self.message_limit_tokens > 0,
+            "message_limit_tokens must be greater than 0"
+        );
+        ensure!(
+            self.export_max_bytes >= 1024,
+            "export_max_bytes must be at least 1024"
+        );
+        ensure!(
+            self.attachment_limit_chars > 0
+                && self.attachment_limit_chars <= self.max_attachment_chars,
+            "attachment_limit_chars must be between 1 and max_attachment_chars"
+        );
+        ensure!(
+            self.request_timeout_secs > 0,
+            "request_timeout_secs must be greater than 0"
+        );
+        // Retries stop starting after retry_deadline_secs, but the last one can run for
+        // request_timeout_secs more
+        let retries_secs = self.retry_deadline_secs + self.request_timeout_secs;
+        ensure!(
+            self.detection_timeout_secs >= retries_secs,
+            "detection_timeout_secs must be at least retry_deadline_secs plus \
+             request_timeout_secs"
+        );
+        ensure!(
+            self.creation_timeout_secs >= retries_secs,
+            "creation_timeout_secs must be at least retry_deadline_secs plus \
+             request_timeout_secs"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.detection_threshold),
+            "detection_threshold must be between 0 and 1"
+        );
+        ensure!(
+            (0.0..=self.detection_threshold).contains(&self.uncertain_threshold),
+            "uncertain_threshold must be between 0 and detection_threshold"
+        );
+        ensure!(
+            self.api_base_url.as_deref().is_none_or(|api_base_url| {
+                url::Url::parse(api_base_url)
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+            }),
+            "api_base_url must be an http or https URL"
+        );
+        ensure!(
+            self.detection_mode == DetectionMode::Llm
+                || self
+                    .heuristic_keywords
+                    .iter()
+                    .any(|keyword| !keyword.trim().is_empty()),
+            "heuristic_keywords must not be empty unless detection_mode is llm"
+        );
+        ensure!(
+            !self.mask_profanity
+                || self
+                    .profanity_words
+                    .iter()
+                    .any(|word| !word.trim().is_empty()),
+            "profanity_words must not be empty when mask_profanity is set"
+        );
+        ensure!(
+            self.daily_budget_usd.is_none_or(|cap_usd| cap_usd >= 0.0),
+            "daily_budget_usd must not be negative"
+        );
+        ensure!(
+            self.prompt_price_per_million >= 0.0 && self.completion_price_per_million >= 0.0,
+            "prompt_price_per_million and completion_price_per_million must not be negative"
+        );
+        ensure!(
+            self.allowed_channels
+                .iter()
+                .flatten()
+                .chain(&self.denied_channels)
+                .all(|&channel_id| channel_id != 0),
+            "allowed_channels and denied_channels must be channel IDs"
+        );
+        ensure!(
+            self.rate_limit_refill_secs > 0,
+            "rate_limit_refill_secs must be greater than 0"
+        );
+        ensure!(
+            self.thread_guilds.iter().all(|&guild_id| guild_id != 0),
+            "thread_guilds must be guild IDs"
+        );
+        ensure!(
+            self.channel_context_budgets
+                .iter()
+                .all(|budget| budget.channel_id != 0),
+            "channel_context_budgets must be for channel IDs"
+        );
+        ensure!(
+            self.staff_roles.iter().all(|&role_id| role_id != 0),
+            "staff_roles must be role IDs"
+        );
+        ensure!(
+            self.batch_concurrency > 0,
+            "batch_concurrency must be greater than 0"
+        );
+        ensure!(
+            [60, 1440, 4320, 10080].contains(&self.thread_archive_minutes),
+            "thread_archive_minutes must be 60, 1440, 4320 or 10080"
+        );
+        ensure!(
+            !self.detection_model.trim().is_empty(),
+            "detection_model must not be empty"
+        );
+        ensure!(
+            !self.creation_model.trim().is_empty(),
+            "creation_model must not be empty"
+        );
+        Ok(())
+    }
+}
+
+/// Declares a setter on `RoadmapConfigBuilder` for each listed config field.
+#[cfg(test)]
+macro_rules! config_setters {
+    ($($field:ident: $type:ty,)*) => {
+        $(
+            pub(crate) fn $field(mut self, $field: $type) -> Self {
+                self.roadmap_config.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Builds a `RoadmapConfig` in code rather than from a file, starting from the defaults,
+/// e.g. `RoadmapConfigBuilder::new().detection_threshold(0.9).scrub_pii(true).build()?`.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RoadmapConfigBuilder {
+    roadmap_config: RoadmapConfig,
+}
+
+#[cfg(test)]
+impl RoadmapConfigBuilder {
+    pub(crate) fn new() -> Self {
+        RoadmapConfigBuilder::default()
+    }
+
+    config_setters! {
+        context_length: usize,
+        min_context_messages: usize,
+        message_limit_chars: usize,
+        api_base_url: Option<String>,
+        detection_model: String,
+        creation_model: String,
+        creation_temperature: f32,
+        max_prompt_tokens: usize,
+        count_context_tokens: bool,
+        message_limit_tokens: usize,
+        attachment_limit_chars: usize,
+        max_attachment_chars: usize,
+        detection_timeout_secs: u64,
+        detection_threshold: f32,
+        uncertain_threshold: f32,
+        detection_mode: DetectionMode,
+        detection_cache: bool,
+        batch_concurrency: usize,
+        daily_budget_usd: Option<f64>,
+        prompt_price_per_million: f64,
+        completion_price_per_million: f64,
+        detect_prompt_path: Option<PathBuf>,
+        create_prompt_path: Option<PathBuf>,
+        system_prompt_prefix: String,
+        system_prompt_suffix: String,
+        thread_archive_minutes: u16,
+        clean_context: bool,
+        scrub_pii: bool,
+        mask_profanity: bool,
+        localize_roadmaps: bool,
+    }
+
+    /// The config with every field that wasn't set left at its default, checked the same
+    /// way as a loaded one.
+    pub(crate) fn build(self) -> anyhow::Result<RoadmapConfig> {
+        self.roadmap_config.validate()?;
+        Ok(self.roadmap_config)
+    }
+}
+
+/// The value of the deprecated `old` key for each of `detection` and `creation` left unset.
+fn split_key<T: serde::de::DeserializeOwned + Clone>(
+    loaded: &config::Config,
+    old: &str,
+    detection: &str,
+    creation: &str,
+) -> (Option<T>, Option<T>) {
+    let Ok(value) = loaded.get::<T>(old) else {
+        return (None, None);
+    };
+    warn!("Roadmap config key {old} is deprecated, set {detection} and {creation} instead");
+    let unset = |key: &str| loaded.get::<T>(key).is_err();
+    (
+        unset(detection).then(|| value.clone()),
+        unset(creation).then_some(value),
+    )
+}
+
+/// How long to wait after a prompt file changes before rereading it, so the several events
+/// an editor's save fires end up as one reload.
+const PROMPT_SETTLE: Duration = Duration::from_millis(200);
+
+/// The system prompts for detection and creation.
+struct RoadmapPrompts {
+    detect: String,
+    create: String,
+}
+
+impl RoadmapPrompts {
+    /// Reads the prompt files `roadmap_config` names, keeping the embedded prompt for any
+    /// left unset.
+    fn load(roadmap_config: &RoadmapConfig) -> anyhow::Result<RoadmapPrompts> {
+        Ok(RoadmapPrompts {
+            detect: load_prompt(
+                roadmap_config.detect_prompt_path.as_deref(),
+                DETECT_ROADMAP_PROMPT,
+            )?,
+            create: load_prompt(
+                roadmap_config.create_prompt_path.as_deref(),
+                CREATE_ROADMAP_PROMPT,
+            )?,
+        })
+    }
+}
+
+/// The prompts in use, reread whenever their files change so wording can be tried out
+/// without a restart.
+struct PromptCache {
+    prompts: RwLock<Arc<RoadmapPrompts>>,
+}
+
+impl PromptCache {
+    fn load(roadmap_config: &RoadmapConfig) -> anyhow::Result<PromptCache> {
+        Ok(PromptCache {
+            prompts: RwLock::new(Arc::new(RoadmapPrompts::load(roadmap_config)?)),
+        })
+    }
+
+    fn current(&self) -> Arc<RoadmapPrompts> {
+        self.prompts.read().unwrap().clone()
+    }
+
+    /// Rereads the prompt files. A file that no longer loads keeps the prompts as they
+    /// were, until it changes again.
+    fn refresh(&self, roadmap_config: &RoadmapConfig) {
+        match RoadmapPrompts::load(roadmap_config) {
+            Ok(prompts) => {
+                *self.prompts.write().unwrap() = Arc::new(prompts);
+                info!("Reloaded roadmap prompts");
+            }
+            Err(e) => error!("Keeping the previous roadmap prompts: {e:#}"),
+        }
+    }
+}
+
+/// The prompt files `roadmap_config` names, leaving out the unset ones.
+fn prompt_paths(roadmap_config: &RoadmapConfig) -> Vec<&Path> {
+    [
+        &roadmap_config.detect_prompt_path,
+        &roadmap_config.create_prompt_path,
+    ]
+    .into_iter()
+    .flatten()
+    .map(PathBuf::as_path)
+    .collect()
+}
+
+/// Whether `event` touched one of `paths`. Matched by file name, since editors often save by
+/// replacing the file and events can name it by another path to the same directory.
+fn touches_prompt(event: &notify::Event, paths: &[&Path]) -> bool {
+    !event.kind.is_access()
+        && event.paths.iter().any(|changed| {
+            paths
+                .iter()
+                .any(|path| path.file_name().is_some() && changed.file_name() == path.file_name())
+        })
+}
+
+/// Reloads the prompt files whenever they change, for as long as the bot runs. Does
+/// nothing when the embedded prompts are in use. Watches the directories holding the files
+/// rather than the files, so a save that replaces the file is still seen.
+pub(crate) async fn watch_prompts_forever() {
+    let paths = prompt_paths(&ROADMAP_CONFIG);
+    if paths.is_empty() {
+        return;
+    }
+    let (changes, mut changed) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event| {
+        let _ = changes.send(event);
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Failed to watch the roadmap prompts, edits need a restart: {e}");
+            return;
+        }
+    };
+    for path in &paths {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if let Err(e) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+            error!(
+                "Failed to watch {}, edits need a restart: {e}",
+                path.display()
+            );
+        }
+    }
+    while let Some(event) = changed.recv().await {
+        match event {
+            Ok(event) if touches_prompt(&event, &paths) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Error watching the roadmap prompts: {e}");
+                continue;
+            }
+        }
+        tokio::time::sleep(PROMPT_SETTLE).await;
+        while changed.try_recv().is_ok() {}
+        ROADMAP_PROMPTS.refresh(&ROADMAP_CONFIG);
+    }
+}
+
+fn load_prompt(path: Option<&Path>, embedded: &str) -> anyhow::Result<String> {
+    let Some(path) = path else {
+        return Ok(embedded.to_string());
+    };
+    let prompt = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read prompt {}", path.display()))?;
+    ensure!(
+        !prompt.trim().is_empty(),
+        "Prompt {} is empty",
+        path.display()
+    );
+    info!("Loaded prompt from {}", path.display());
+    Ok(prompt)
+}
+
+/// Why detection or creation failed, so callers can retry, apologize or stay quiet
+/// depending on the kind. Wrapped in `anyhow::Error` it can be recovered with `downcast_ref`.
+#[derive(Debug)]
+pub(crate) enum RoadmapError {
+    /// The API answered without any choices.
+    NoChoices { model: String },
+    /// The first choice had neither content nor a function call.
+    EmptyContent {
+        model: String,
+        finish_reason: String,
+    },
+    /// The model's reply wasn't the JSON asked for. `reply` is kept for debugging prompts,
+    /// but only shown as `loggable` allows, since it can quote the member's message.
+    ParseFailed {
+        what: &'static str,
+        reply: String,
+        cause: anyhow::Error,
+    },
+    /// A detection or creation call, retries included, ran past its configured timeout.
+    Timeout { call: &'static str, after: Duration },
+    /// OpenAI rejected the API key, or none was ever set.
+    Unauthenticated,
+    /// Today's estimated OpenAI spend has reached `daily_budget_usd`.
+    BudgetExceeded { spent_usd: f64, cap_usd: f64 },
+    /// The model declined to answer, rather than failing to.
+    Refused { model: String },
+    /// The request itself failed: network, rate limits, a dropped stream and the like.
+    ApiError(anyhow::Error),
+}
+
+impl Display for RoadmapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoadmapError::NoChoices { model } => {
+                write!(f, "OpenAI returned no choices from {model}")
+            }
+            RoadmapError::EmptyContent {
+                model,
+                finish_reason,
+            } => write!(f, "No reply from {model} (finish reason `{finish_reason}`)"),
+            RoadmapError::ParseFailed { what, reply, .. } => {
+                write!(f, "failed to parse {what}: {}", loggable(reply))
+            }
+            RoadmapError::Timeout { call, after } => {
+                write!(f, "Roadmap {call} timed out after {after:?}")
+            }
+            RoadmapError::Unauthenticated => {
+                write!(f, "OpenAI API key is missing or invalid, check OPENAI_KEY")
+            }
+            RoadmapError::BudgetExceeded { spent_usd, cap_usd } => {
+                write!(
+                    f,
+                    "Daily OpenAI budget of ${cap_usd:.2} used up (spent ${spent_usd:.2})"
+                )
+            }
+            RoadmapError::Refused { model } => {
+                write!(
+                    f,
+                    "{model} refused to answer (finish reason `content_filter`)"
+                )
+            }
+            RoadmapError::ApiError(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl std::error::Error for RoadmapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RoadmapError::ParseFailed { cause, .. } => Some(cause.as_ref()),
+            RoadmapError::ApiError(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Backends report failures as `anyhow::Error`, which keeps any `RoadmapError` they
+/// raised, picks out rejected API keys and counts everything else as an `ApiError`.
+impl From<anyhow::Error> for RoadmapError {
+    fn from(e: anyhow::Error) -> Self {
+        utilities::flag_auth_error(e)
+            .downcast()
+            .unwrap_or_else(RoadmapError::ApiError)
+    }
+}
+
+/// Gives up on `future` after `after`, so a stalled API can't hang the handler.
+async fn with_call_timeout<T>(
+    call: &'static str,
+    after: Duration,
+    future: impl Future<Output = Result<T, RoadmapError>>,
+) -> Result<T, RoadmapError> {
+    match tokio::time::timeout(after, future).await {
+        Ok(result) => result,
+        Err(_) => Err(RoadmapError::Timeout { call, after }),
+    }
+}
+
+/// Today's OpenAI spend against `daily_budget_usd`.
+pub(crate) fn budget_report() -> BudgetReport {
+    SPEND_BUDGET.report()
+}
+
+/// Whether roadmaps should be created with `create_structured` and posted as embeds.
+pub(crate) fn structured_roadmaps() -> bool {
+    ROADMAP_CONFIG.structured_roadmaps
+}
+
+/// Counts a roadmap for `user_id` against the rate limit, cooldown and daily quota, or
+/// says which one they're over. A rate limit token is only spent once the cooldown and
+/// quota allow the roadmap. Members with any of `staff_roles` among `roles` are exempt.
+pub(crate) async fn check_limits(user_id: UserId, roles: &[RoleId]) -> Result<(), LimitReached> {
+    if roles
+        .iter()
+        .any(|role_id| ROADMAP_CONFIG.staff_roles.contains(&role_id.get()))
+    {
+        return Ok(());
+    }
+    ROADMAP_QUOTA
+        .take(user_id, || {
+            if RATE_LIMITER.check(user_id) {
+                Ok(())
+            } else {
+                Err(LimitReached::RateLimited(RATE_LIMITER.retry_after(user_id)))
+            }
+        })
+        .await
+}
+
+/// How long each user must wait between `/roadmap` commands.
+pub(crate) fn command_cooldown() -> Duration {
+    Duration::from_secs(ROADMAP_CONFIG.command_cooldown_secs)
+}
+
+/// Whether detected roadmaps wait for the author to confirm before being created.
+pub(crate) fn confirm_roadmaps() -> bool {
+    ROADMAP_CONFIG.confirm_roadmaps
+}
+
+/// The context limits for roadmaps asked for in `channel_id`, if it has its own.
+pub(crate) fn channel_context_budget(channel_id: ChannelId) -> Option<ContextBudget> {
+    ROADMAP_CONFIG.channel_context_budget(channel_id)
+}
+
+/// Whether roadmaps are posted in threads in `guild_id`.
+pub(crate) fn threads_enabled(guild_id: GuildId) -> bool {
+    ROADMAP_CONFIG.thread_guilds.contains(&guild_id.get())
+}
+
+/// How long a roadmap thread stays open without activity.
+pub(crate) fn thread_archive_duration() -> AutoArchiveDuration {
+    AutoArchiveDuration::from(ROADMAP_CONFIG.thread_archive_minutes)
+}
+
+/// How long a user's last roadmap can be followed up on.
+pub(crate) fn followup_ttl() -> Duration {
+    Duration::from_secs(ROADMAP_CONFIG.followup_ttl_secs)
+}
+
+/// Where confirmation outcomes are recorded.
+pub(crate) fn confirmations_path() -> PathBuf {
+    PathBuf::from(&ROADMAP_CONFIG.confirmations_path)
+}
+
+/// What to tell the user when making their roadmap failed with `e`, if it's something
+/// they can do anything about.
+pub(crate) fn apology(e: &anyhow::Error) -> Option<&'static str> {
+    match e.downcast_ref::<RoadmapError>() {
+        Some(roadmap_error) => roadmap_error.apology(),
+        None => busy_apology(e),
+    }
+}
+
+fn busy_apology(e: &anyhow::Error) -> Option<&'static str> {
+    e.downcast_ref::<RetriesExhausted>()
+        .map(|_| "the AI is busy right now, try again in a minute.")
+}
+
+impl RoadmapError {
+    /// What to tell the user, if anything. Malformed or empty replies and one-off API
+    /// failures are left unexplained.
+    pub(crate) fn apology(&self) -> Option<&'static str> {
+        match self {
+            RoadmapError::Timeout { .. } => {
+                Some("the AI took too long to answer, try asking again.")
+            }
+            RoadmapError::BudgetExceeded { .. } => {
+                Some("AI features are resting for today, try again tomorrow.")
+            }
+            RoadmapError::Refused { .. } => Some("I can't help with that."),
+            RoadmapError::Unauthenticated => {
+                Some("AI features aren't set up right now, please let a moderator know.")
+            }
+            RoadmapError::ApiError(e) => busy_apology(e),
+            RoadmapError::NoChoices { .. }
+            | RoadmapError::EmptyContent { .. }
+            | RoadmapError::ParseFailed { .. } => None,
+        }
+    }
+}
+
+/// The backend roadmaps are made with, spending from the same daily budget.
+pub(crate) fn default_backend() -> Arc<dyn ChatBackend> {
+    OPENAI_BACKEND.clone()
+}
+
+/// OpenAI with the configured retries and timeouts, before any spend budget.
+fn openai_backend() -> OpenAiBackend {
+    OpenAiBackend::new()
+        .max_retries(ROADMAP_CONFIG.max_retries)
+        .retry_deadline(Duration::from_secs(ROADMAP_CONFIG.retry_deadline_secs))
+        .request_timeout(Duration::from_secs(ROADMAP_CONFIG.request_timeout_secs))
+}
+
+/// Sends a one-token completion to the detection model, so a wrong key or unreachable
+/// endpoint shows up at startup rather than as failed roadmaps. Goes around the spend
+/// budget, which may well be used up for the day, and never runs in dry-run mode.
+pub(crate) async fn check_llm_health() -> anyhow::Result<()> {
+    if ROADMAP_CONFIG.dry_run {
+        return Ok(());
+    }
+    probe_llm(&openai_backend(), ROADMAP_CONFIG.detection_model.as_str()).await
+}
+
+async fn probe_llm(backend: &dyn ChatBackend, model: &str) -> anyhow::Result<()> {
+    let probed = backend
+        .complete(
+            vec![utilities::user_message("ping".to_string())],
+            &ChatParams::new(model).max_tokens(1),
+        )
+        .await;
+    match probed {
+        // The endpoint works, there's just nothing left to spend on it today
+        Err(e) if matches!(e.downcast_ref(), Some(RoadmapError::BudgetExceeded { .. })) => Ok(()),
+        probed => probed
+            .map(|_| ())
+            .with_context(|| format!("Health check completion with {model} failed")),
+    }
+}
+
+/// Roughly how many tokens `messages` will take as a creation prompt, chat overhead included.
+pub(crate) fn estimate_prompt_tokens(messages: &[ChatCompletionMessage]) -> usize {
+    utilities::count_prompt_tokens(ROADMAP_CONFIG.creation_model.as_str(), messages)
+}
+
+/// Estimated USD cost of a completion at the configured token prices.
+pub(crate) fn estimate_cost_usd(prompt_tokens: usize, completion_tokens: usize) -> f64 {
+    ROADMAP_CONFIG.cost_usd(prompt_tokens, completion_tokens)
+}
+
+/// Where the channel list is saved once changed by `/roadmap-channels`.
+pub(crate) fn channels_path() -> PathBuf {
+    PathBuf::from(&ROADMAP_CONFIG.channels_path)
+}
+
+/// The endpoint completions are sent to instead of OpenAI's, if one is configured.
+pub(crate) fn api_base_url() -> Option<String> {
+    ROADMAP_CONFIG.api_base_url.clone()
+}
+
+/// Where spend was kept before the database.
+pub(crate) fn budget_path() -> PathBuf {
+    PathBuf::from(&ROADMAP_CONFIG.budget_path)
+}
+
+/// Where roadmap usage was kept before the database.
+pub(crate) fn quota_path() -> PathBuf {
+    PathBuf::from(&ROADMAP_CONFIG.quota_path)
+}
+
+/// Where roadmaps were kept before the database, if anywhere.
+pub(crate) fn roadmap_store_path() -> Option<PathBuf> {
+    ROADMAP_CONFIG
+        .roadmap_store_path
+        .as_ref()
+        .map(PathBuf::from)
+}
+
+/// The saved channel list, or the one in the config if it's never been changed.
+pub(crate) fn channel_list() -> ChannelList {
+    let to_ids = |channels: &[u64]| channels.iter().copied().map(ChannelId::new).collect();
+    ChannelList::load(
+        &channels_path(),
+        ChannelList {
+            allowed: ROADMAP_CONFIG.allowed_channels.as_deref().map(to_ids),
+            denied: to_ids(&ROADMAP_CONFIG.denied_channels),
+        },
+    )
+}
+
+/// Loads the roadmap config and prompts now so a broken file stops the bot at startup.
+pub(crate) fn init_config() {
+    lazy_static::initialize(&ROADMAP_CONFIG);
+    lazy_static::initialize(&ROADMAP_PROMPTS);
+    lazy_static::initialize(&ROADMAP_STORE);
+    if ROADMAP_CONFIG.dry_run {
+        warn!(
+            "Roadmap DRY RUN is on: detection and creation return canned replies without \
+             calling the API. Turn off dry_run before deploying."
+        );
+    }
+}
+
+/// Picks up the spend and roadmap usage saved before a restart.
+pub(crate) async fn restore_usage() {
+    SPEND_BUDGET.restore().await;
+    ROADMAP_QUOTA.restore().await;
+}
+
+/// Keeps `roadmap` as `user_id`'s latest, if roadmaps are being stored.
+pub(crate) async fn store_roadmap(user_id: UserId, message: &str, roadmap: &RoadmapProvided) {
+    if let Some(store) = ROADMAP_STORE.as_ref() {
+        if let Err(e) = store.save(user_id, message, roadmap, Utc::now()).await {
+            warn!("Failed to store roadmap for {user_id} due to {e:#}");
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// `user_id`'s latest roadmap. `Ok(None)` if they haven't had one, and an error if
+/// roadmaps aren't being stored.
+pub(crate) async fn stored_roadmap(user_id: UserId) -> anyhow::Result<Option<RoadmapProvided>> {
+    match ROADMAP_STORE.as_ref() {
+        Some(store) => store.get_roadmap(user_id).await,
+        None => bail!("roadmaps aren't being stored"),
+    }
+}
+
+/// Whether created roadmaps are kept, so there's a history to look up.
+pub(crate) fn keeps_roadmaps() -> bool {
+    ROADMAP_STORE.is_some()
+}
+
+/// Every roadmap `user_id` has had, oldest first, and an error if roadmaps aren't being
+/// stored.
+pub(crate) async fn roadmap_history(user_id: UserId) -> anyhow::Result<Vec<StoredRoadmap>> {
+    match ROADMAP_STORE.as_ref() {
+        Some(store) => store.history(user_id).await,
+        None => bail!("roadmaps aren't being stored"),
+    }
+}
+
+/// Largest file a roadmap history export is split into.
+pub(crate) fn export_max_bytes() -> usize {
+    ROADMAP_CONFIG.export_max_bytes
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub(crate) struct RequestingRoadmap {
     pub reason: String,
-    #[allow(dead_code)]
     pub is_roadmap: bool,
+    #[serde(default = "default_confidence")]
+    pub confidence: f32,
+    /// Whether the message asks for changes to a roadmap the bot already wrote.
+    #[serde(default)]
+    pub is_followup: bool,
+    /// Tokens spent deciding, including any JSON repair. `None` for cached results and
+    /// backends that don't report usage.
+    #[serde(skip)]
+    pub usage: Option<Usage>,
+    /// Model that made the decision.
+    #[serde(skip)]
+    pub model: String,
+    /// Time taken, cache lookups and waits on identical detections included.
+    #[serde(skip)]
+    pub elapsed: Duration,
 }
 
-#[derive(Deserialize, Debug)]
-pub(crate) struct RoadmapProvided {
-    pub roadmap: String,
+/// Confidence assumed when the model leaves the field out.
+fn default_confidence() -> f32 {
+    0.5
 }
 
-fn system_message_detection() -> ChatCompletionMessage {
-    ChatCompletionMessage {
-        role: ChatCompletionMessageRole::System,
-        content: Some(DETECT_ROADMAP_PROMPT.to_string()),
-        name: None,
-        function_call: None,
+/// What to do with a message once detection has run.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RoadmapDecision {
+    /// Confident enough to spend a creation call on it.
+    Create,
+    /// Might be a roadmap request, but not confidently enough to reply.
+    Unsure,
+    Ignore,
+}
+
+impl RequestingRoadmap {
+    pub(crate) fn decision(&self) -> RoadmapDecision {
+        self.decide(&ROADMAP_CONFIG)
+    }
+
+    /// Whether a roadmap should be written: the message asks for one with at least
+    /// `detection_threshold` confidence.
+    pub(crate) fn should_create(&self) -> bool {
+        self.should_create_with(&ROADMAP_CONFIG)
+    }
+
+    fn should_create_with(&self, roadmap_config: &RoadmapConfig) -> bool {
+        self.is_roadmap && self.confidence >= roadmap_config.detection_threshold
+    }
+
+    /// The decision in a sentence fit for a Discord reply, e.g. "I think you're asking for
+    /// a roadmap because: <reason>". Whitespace in the reason is collapsed and long
+    /// reasons are cut short.
+    pub(crate) fn explanation(&self) -> String {
+        let verdict = if self.is_roadmap {
+            "I think you're asking for a roadmap"
+        } else {
+            "I don't think you're asking for a roadmap"
+        };
+        let reason = self.reason.split_whitespace().collect::<Vec<_>>().join(" ");
+        if reason.is_empty() {
+            return format!("{verdict}.");
+        }
+        if reason.chars().count() <= EXPLANATION_REASON_LIMIT {
+            return format!("{verdict} because: {reason}");
+        }
+        let kept: String = reason.chars().take(EXPLANATION_REASON_LIMIT - 1).collect();
+        format!("{verdict} because: {}…", kept.trim_end())
+    }
+
+    /// `Create` exactly when `should_create` would, with `roadmap_config` in place of the
+    /// loaded config.
+    fn decide(&self, roadmap_config: &RoadmapConfig) -> RoadmapDecision {
+        if self.should_create_with(roadmap_config) {
+            RoadmapDecision::Create
+        } else if self.is_roadmap && self.confidence >= roadmap_config.uncertain_threshold {
+            RoadmapDecision::Unsure
+        } else {
+            RoadmapDecision::Ignore
+        }
     }
 }
 
-fn system_message_creation() -> ChatCompletionMessage {
-    ChatCompletionMessage {
-        role: ChatCompletionMessageRole::System,
-        content: Some(CREATE_ROADMAP_PROMPT.to_string()),
-        name: None,
-        function_call: None,
+/// Chars of the detection reason shown in an explanation.
+const EXPLANATION_REASON_LIMIT: usize = 300;
+
+/// Name of the function the detection model is made to call.
+pub(crate) const DETECTION_FUNCTION: &str = "classify_roadmap_request";
+
+/// Function whose parameters mirror `RequestingRoadmap`, so detection replies arrive as
+/// JSON arguments rather than free text.
+fn detection_function() -> ChatCompletionFunctionDefinition {
+    ChatCompletionFunctionDefinition {
+        name: DETECTION_FUNCTION.to_string(),
+        description: Some("Record whether a message is asking for a roadmap".to_string()),
+        parameters: Some(json!({
+            "type": "object",
+            "properties": {
+                "reason": {
+                    "type": "string",
+                    "description": "A short reason for the classification",
+                },
+                "is_roadmap": {
+                    "type": "boolean",
+                    "description": "Whether the message asks for a roadmap",
+                },
+                "confidence": {
+                    "type": "number",
+                    "minimum": 0.0,
+                    "maximum": 1.0,
+                    "description": "How sure the classification is, from 0.0 to 1.0",
+                },
+                "is_followup": {
+                    "type": "boolean",
+                    "description": "Whether the message asks to change the user's last roadmap",
+                },
+            },
+            "required": ["reason", "is_roadmap", "confidence", "is_followup"],
+        })),
     }
 }
 
-fn user_message(message: String) -> ChatCompletionMessage {
-    ChatCompletionMessage {
-        role: ChatCompletionMessageRole::User,
-        content: Some(message),
-        name: None,
-        function_call: None,
+/// Name of the function the creation model is made to call for a structured roadmap.
+pub(crate) const ROADMAP_FUNCTION: &str = "write_roadmap";
+
+/// Function whose parameters mirror `StructuredRoadmap`.
+fn roadmap_function() -> ChatCompletionFunctionDefinition {
+    ChatCompletionFunctionDefinition {
+        name: ROADMAP_FUNCTION.to_string(),
+        description: Some("Record a roadmap as an ordered list of steps".to_string()),
+        parameters: Some(json!({
+            "type": "object",
+            "properties": {
+                "title": {
+                    "type": "string",
+                    "description": "A short title for the roadmap",
+                },
+                "intro": {
+                    "type": "string",
+                    "description": "One or two sentences introducing the roadmap",
+                },
+                "steps": {
+                    "type": "array",
+                    "description": "The steps to follow, in order",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": {
+                                "type": "string",
+                                "description": "What the step covers",
+                            },
+                            "description": {
+                                "type": "string",
+                                "description": "What to do and learn, with links where helpful",
+                            },
+                            "duration": {
+                                "type": "string",
+                                "description": "Rough time the step takes, e.g. \"2 weeks\"",
+                            },
+                            "resources": {
+                                "type": "array",
+                                "description": "Links, books or courses for the step",
+                                "items": {"type": "string"},
+                            },
+                        },
+                        "required": ["name", "description", "duration", "resources"],
+                    },
+                },
+            },
+            "required": ["title", "intro", "steps"],
+        })),
     }
 }
 
-fn build_message(
-    message: String,
-    context: Vec<String>,
-    system_message: ChatCompletionMessage,
-) -> Vec<ChatCompletionMessage> {
-    let mut messages: Vec<ChatCompletionMessage> = vec![system_message];
-    let mut message_length: usize = message.len();
-    let mut message_buffer: String = message;
-    for contextual_message in context.into_iter().take(ROADMAP_CONFIG.context_length) {
-        if message_length + contextual_message.len() > ROADMAP_CONFIG.message_limit_chars {
-            break;
+/// A roadmap as a title, an intro and ordered steps, for posting as embeds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct StructuredRoadmap {
+    pub title: String,
+    #[serde(default)]
+    pub intro: String,
+    pub steps: Vec<RoadmapStep>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RoadmapStep {
+    #[serde(alias = "title")]
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub duration: String,
+    #[serde(default)]
+    pub resources: Vec<String>,
+}
+
+impl StructuredRoadmap {
+    /// The roadmap written out as plain text, for logs and `RoadmapProvided::roadmap`.
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = format!("{}\n\n{}\n", self.title, self.intro);
+        for (index, step) in self.steps.iter().enumerate() {
+            text.push_str(format!("\n{}. {}", index + 1, step.name).as_str());
+            if !step.duration.is_empty() {
+                text.push_str(format!(" ({})", step.duration).as_str());
+            }
+            text.push_str(format!("\n{}\n", step.description).as_str());
+            for resource in &step.resources {
+                text.push_str(format!("- {resource}\n").as_str());
+            }
         }
-        message_length += contextual_message.len();
-        message_buffer.insert_str(0, contextual_message.as_str());
+        text
     }
-    messages.push(user_message(message_buffer));
-    messages
 }
 
-pub(crate) async fn is_message_roadmap_request(
+/// Parses a structured roadmap reply, keeping the raw model output in the error.
+pub(crate) fn parse_structured_roadmap(raw: &str) -> Result<StructuredRoadmap, RoadmapError> {
+    extract_json_object(extract_json(raw))
+        .context("no JSON object found")
+        .and_then(|json| Ok(serde_json::from_str::<StructuredRoadmap>(json)?))
+        .and_then(|roadmap| {
+            ensure!(!roadmap.steps.is_empty(), "roadmap has no steps");
+            Ok(roadmap)
+        })
+        .map_err(|cause| RoadmapError::ParseFailed {
+            what: "structured roadmap",
+            reply: raw.to_string(),
+            cause,
+        })
+}
+
+/// Makes what it can of a structured roadmap reply `parse_structured_roadmap` turned
+/// down, so it needn't be asked for again. JSON with steps as plain strings or missing
+/// fields is filled out, and a reply without any JSON is taken as a free-text roadmap.
+/// Returns the roadmap's text and structure, or `None` if nothing is usable, like JSON
+/// cut off partway.
+fn repair_structured_roadmap(raw: &str) -> Option<(String, Option<StructuredRoadmap>)> {
+    let raw = extract_json(raw);
+    let Some(json) = extract_json_object(raw) else {
+        let looks_like_json = raw.starts_with('{') || raw.starts_with('[');
+        return (!raw.is_empty() && !looks_like_json).then(|| (raw.to_string(), None));
+    };
+    let value: serde_json::Value = serde_json::from_str(json).ok()?;
+    let text = |value: &serde_json::Value, keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| value.get(key).and_then(|field| field.as_str()))
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    let steps: Vec<RoadmapStep> = value
+        .get("steps")?
+        .as_array()?
+        .iter()
+        .map(|step| match step.as_str() {
+            Some(name) => RoadmapStep {
+                name: name.trim().to_string(),
+                description: String::new(),
+                duration: String::new(),
+                resources: vec![],
+            },
+            None => RoadmapStep {
+                name: text(step, &["name", "title"]),
+                description: text(step, &["description"]),
+                duration: text(step, &["duration"]),
+                resources: step
+                    .get("resources")
+                    .and_then(|resources| resources.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|resource| resource.as_str().map(String::from))
+                    .collect(),
+            },
+        })
+        .filter(|step| !step.name.is_empty() || !step.description.is_empty())
+        .collect();
+    if steps.is_empty() {
+        return None;
+    }
+    let structured = StructuredRoadmap {
+        title: text(&value, &["title"]),
+        intro: text(&value, &["intro"]),
+        steps,
+    };
+    Some((structured.to_text(), Some(structured)))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct RoadmapProvided {
+    pub roadmap: String,
+    /// The roadmap's steps, when it was created with `create_structured`.
+    #[serde(default)]
+    pub structured: Option<StructuredRoadmap>,
+    /// Tokens spent writing the roadmap, when the backend reports them.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Model that wrote the roadmap.
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub elapsed: Duration,
+    /// The thread the roadmap was posted in, if it got one.
+    #[serde(skip)]
+    pub thread_id: Option<ChannelId>,
+}
+
+/// `prompt` between the configured `system_prompt_prefix` and `system_prompt_suffix`.
+fn customize_prompt(roadmap_config: &RoadmapConfig, prompt: &str) -> String {
+    [
+        roadmap_config.system_prompt_prefix.trim(),
+        prompt,
+        roadmap_config.system_prompt_suffix.trim(),
+    ]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join("\n\n")
+}
+
+fn system_message_detection() -> ChatCompletionMessage {
+    utilities::system_message(customize_prompt(
+        &ROADMAP_CONFIG,
+        ROADMAP_PROMPTS.current().detect.as_str(),
+    ))
+}
+
+/// A roadmap the bot already wrote, and the request it answered.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PreviousRoadmap {
+    pub(crate) request: String,
+    pub(crate) roadmap: String,
+}
+
+/// What the creation prompt asks for beyond the request itself.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Instructions {
+    /// Language to write in (an ISO 639-3 code like `spa`, or a language's English name)
+    /// instead of the one the request is written in. Languages whatlang doesn't know are
+    /// ignored.
+    pub(crate) language: Option<String>,
+    /// A roadmap to revise following the request, rather than starting over.
+    pub(crate) revising: Option<PreviousRoadmap>,
+    /// Context limits to use instead of the config's.
+    pub(crate) context_budget: Option<ContextBudget>,
+    /// Links or text the request refers to, like a job posting, to ground the roadmap in.
+    pub(crate) attachments: Vec<String>,
+}
+
+/// The creation prompt, asking for the roadmap in `language` when it isn't English and
+/// for a revision when `revising`. The roadmap being revised is sent by `revision_message`.
+fn system_message_creation(language: Option<&str>, revising: bool) -> ChatCompletionMessage {
+    let mut prompt = String::new();
+    if let Some(language) = language {
+        prompt.push_str(
+            format!("Write the roadmap in {language}, the language the user asked in.\n\n")
+                .as_str(),
+        );
+    }
+    if revising {
+        prompt.push_str(
+            "The user quotes a roadmap you wrote for them earlier, and their latest message \
+             asks for changes to it: apply them and write out the whole revised roadmap.\n\n",
+        );
+    }
+    prompt.push_str(
+        customize_prompt(&ROADMAP_CONFIG, ROADMAP_PROMPTS.current().create.as_str()).as_str(),
+    );
+    utilities::system_message(prompt)
+}
+
+/// The user turn quoting the roadmap being revised and the request it was written for.
+fn revision_message(previous: &PreviousRoadmap) -> ChatCompletionMessage {
+    utilities::user_message(format!(
+        "You wrote this roadmap for my earlier request \"{}\":\n\n{}",
+        previous.request, previous.roadmap
+    ))
+}
+
+/// The creation prompt for `message`, with `instructions` applied.
+fn build_creation_message(
+    roadmap_config: &RoadmapConfig,
+    model: &str,
     message: String,
-    context: Vec<String>,
-) -> anyhow::Result<RequestingRoadmap> {
-    let chat_completion = ChatCompletion::builder(
-        "gpt-4o-mini",
-        build_message(message.clone(), context, system_message_detection()),
+    context: Vec<(Role, String)>,
+    instructions: &Instructions,
+) -> Vec<ChatCompletionMessage> {
+    let language = reply_language(
+        roadmap_config,
+        message.as_str(),
+        instructions.language.as_deref(),
+    );
+    let messages = build_message_with_stats(
+        roadmap_config,
+        model,
+        message,
+        context,
+        system_message_creation(language.as_deref(), instructions.revising.is_some()),
+        instructions.revising.as_ref().map(revision_message),
+        instructions.context_budget,
     )
-    .create()
-    .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        let roadmap_request: RequestingRoadmap = serde_json::from_str(content.as_str())?;
-        if roadmap_request.is_roadmap {
-            info!(
-                "Generating roadmap for request {} due to {}",
-                message.as_str(),
-                roadmap_request.reason.as_str()
-            );
-        } else {
-            info!(
-                "Ignoring roadmap request {} due to {}",
-                message.as_str(),
-                roadmap_request.reason.as_str()
-            );
+    .0;
+    add_attachments(roadmap_config, model, messages, &instructions.attachments)
+}
+
+/// The language `language` names, by ISO 639-3 code or English name, if whatlang knows it.
+fn known_language(language: &str) -> Option<Lang> {
+    let language = language.trim();
+    Lang::from_code(language.to_lowercase()).or_else(|| {
+        Lang::all()
+            .iter()
+            .copied()
+            .find(|lang| lang.eng_name().eq_ignore_ascii_case(language))
+    })
+}
+
+/// Whether `language` names a language roadmaps can be asked for in.
+pub(crate) fn is_known_language(language: &str) -> bool {
+    known_language(language).is_some()
+}
+
+/// The language to write the roadmap in: `language` if given and known, otherwise the
+/// language of `message` when `localize_roadmaps` is on and it can be told reliably.
+/// `None` means English, the prompt's default.
+fn reply_language(
+    roadmap_config: &RoadmapConfig,
+    message: &str,
+    language: Option<&str>,
+) -> Option<String> {
+    if let Some(language) = language {
+        match known_language(language) {
+            Some(Lang::Eng) => return None,
+            Some(lang) => return Some(lang.eng_name().to_string()),
+            None => warn!("Ignoring unknown roadmap language {language:?}"),
         }
-        Ok(roadmap_request)
+    }
+    if !roadmap_config.localize_roadmaps {
+        return None;
+    }
+    whatlang::detect(message)
+        .filter(|info| info.is_reliable() && info.lang() != Lang::Eng)
+        .map(|info| info.lang().eng_name().to_string())
+}
+
+/// Detection by the model, cached and under `detection_timeout_secs`.
+struct LlmDetector<'a> {
+    service: &'a RoadmapService,
+    backend: Arc<dyn ChatBackend>,
+    params: ChatParams,
+    context_budget: Option<ContextBudget>,
+}
+
+#[serenity::async_trait]
+impl RoadmapDetector for LlmDetector<'_> {
+    async fn detect(
+        &self,
+        message: String,
+        context: Vec<(Role, String)>,
+    ) -> Result<RequestingRoadmap, RoadmapError> {
+        with_call_timeout(
+            "detection",
+            Duration::from_secs(self.service.config.detection_timeout_secs),
+            detect_cached(
+                &self.service.config,
+                &*self.backend,
+                &self.params,
+                &self.service.detection_cache,
+                message,
+                context,
+                self.context_budget,
+            ),
+        )
+        .await
+    }
+}
+
+/// Context limits for a single call, taking precedence over `context_length` and the
+/// context budget in `RoadmapConfig`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ContextBudget {
+    pub(crate) context_length: usize,
+    pub(crate) message_limit_chars: usize,
+}
+
+/// `context_length` and `message_limit_chars` for roadmaps asked for in one channel.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ChannelContextBudget {
+    pub(crate) channel_id: u64,
+    pub(crate) context_length: usize,
+    pub(crate) message_limit_chars: usize,
+}
+
+/// The most context messages to send, from `budget` when there is one.
+fn context_length(roadmap_config: &RoadmapConfig, budget: Option<ContextBudget>) -> usize {
+    budget.map_or(roadmap_config.context_length, |budget| {
+        budget.context_length
+    })
+}
+
+/// The budget for the message and its context: `budget`'s chars when there is one,
+/// otherwise tokens if `count_context_tokens` is set and chars if not.
+fn context_budget<'a>(
+    roadmap_config: &RoadmapConfig,
+    model: &'a str,
+    budget: Option<ContextBudget>,
+) -> PromptBudget<'a> {
+    if let Some(budget) = budget {
+        PromptBudget::chars(budget.message_limit_chars)
+    } else if roadmap_config.count_context_tokens {
+        PromptBudget::tokens(model, roadmap_config.message_limit_tokens)
     } else {
-        bail!("No reply from ChatGPT")
+        PromptBudget::chars(roadmap_config.message_limit_chars)
     }
 }
 
-pub(crate) async fn create_roadmap(
-    message: String,
-    context: Vec<String>,
-) -> anyhow::Result<RoadmapProvided> {
-    let chat_completion = ChatCompletion::builder(
-        "gpt-4o-mini",
-        build_message(message, context, system_message_creation()),
+/// The conversation in `channel_id` before `before`, for use as creation context when
+/// `requester` asks for a roadmap with `message`. Holds `context_length` messages at most,
+/// trimmed to fit alongside `message` in the context budget, apart from the newest
+/// `min_context_messages`.
+pub(crate) async fn fetch_channel_context(
+    fetcher: &dyn MessageFetcher,
+    channel_id: ChannelId,
+    before: MessageId,
+    requester: UserId,
+    message: &str,
+    bot_id: UserId,
+    context_budget_override: Option<ContextBudget>,
+) -> anyhow::Result<Vec<(Role, String)>> {
+    let budget = context_budget(
+        &ROADMAP_CONFIG,
+        ROADMAP_CONFIG.creation_model.as_str(),
+        context_budget_override,
+    );
+    channel_context::fetch_context(
+        fetcher,
+        channel_id,
+        before,
+        requester,
+        bot_id,
+        ROADMAP_CONFIG.min_context_messages
+            ..=context_length(&ROADMAP_CONFIG, context_budget_override),
+        |context| budget.allows(format!("{context}\n{message}").as_str()),
     )
-    .create()
-    .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        info!("Generated Roadmap - {}", content.as_str());
-        Ok(RoadmapProvided { roadmap: content })
+    .await
+}
+
+/// The scrubber for `roadmap_config`, built once for as long as it's unchanged.
+fn scrubber(roadmap_config: &RoadmapConfig) -> Arc<Scrubber> {
+    let profanity: &[String] = if roadmap_config.mask_profanity {
+        &roadmap_config.profanity_words
     } else {
-        bail!("No reply from ChatGPT")
-    }
+        &[]
+    };
+    Scrubber::shared(roadmap_config.scrub_pii, profanity)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// `message` and `context` with PII redacted and profanity masked, as far as
+/// `roadmap_config` asks.
+fn scrub(
+    roadmap_config: &RoadmapConfig,
+    message: String,
+    context: Vec<(Role, String)>,
+) -> (String, Vec<(Role, String)>) {
+    let scrubber = scrubber(roadmap_config);
+    if !scrubber.is_enabled() {
+        return (message, context);
+    }
+    (
+        scrubber.scrub(&message),
+        context
+            .into_iter()
+            .map(|(role, text)| (role, scrubber.scrub(&text)))
+            .collect(),
+    )
+}
+
+/// Builds the prompt for `model`, one message per context entry, trimming context so the
+/// whole prompt, system message included, stays within `max_prompt_tokens`. The context
+/// budget itself is counted in chars unless `count_context_tokens` is set, and `budget`
+/// replaces it and `context_length` when given. The newest `min_context_messages` entries
+/// are sent whatever the budget, cut down if need be. With `clean_context`, blank and
+/// repeated context is dropped first. With `scrub_pii` or `mask_profanity`, the message and
+/// context are scrubbed before anything is counted.
+///
+/// Makes no calls, so prompt composition can be checked on its own. The result is always
+/// `system_message`, then the kept context oldest first with its roles, then `message`
+/// as the user. Context is dropped oldest first, and an older entry that only partly fits
+/// the chars left is cut down to its end. `build_message_docs_hold` walks through both.
+pub(crate) fn build_message(
+    roadmap_config: &RoadmapConfig,
+    model: &str,
+    message: String,
+    context: Vec<(Role, String)>,
+    system_message: ChatCompletionMessage,
+    budget: Option<ContextBudget>,
+) -> Vec<ChatCompletionMessage> {
+    build_message_with_stats(
+        roadmap_config,
+        model,
+        message,
+        context,
+        system_message,
+        None,
+        budget,
+    )
+    .0
+}
+
+/// How much of the context made it into a prompt, for telling when the budget is too tight.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct PromptStats {
+    /// Context entries sent, whole or cut down.
+    pub(crate) context_used: usize,
+    /// Context entries left out for `context_length` or the budget. Blank and repeated
+    /// entries dropped by `clean_context` aren't counted.
+    pub(crate) context_dropped: usize,
+    /// Chars in every message of the prompt, the system message included.
+    pub(crate) total_chars: usize,
+}
+
+/// `build_message`, also saying how much of the context was sent. `pinned` goes right
+/// after the system message and, like it, is never trimmed.
+pub(crate) fn build_message_with_stats(
+    roadmap_config: &RoadmapConfig,
+    model: &str,
+    message: String,
+    context: Vec<(Role, String)>,
+    system_message: ChatCompletionMessage,
+    pinned: Option<ChatCompletionMessage>,
+    budget: Option<ContextBudget>,
+) -> (Vec<ChatCompletionMessage>, PromptStats) {
+    // The triggering message's label, and the newline after it, aren't budgeted
+    let reserved_tokens =
+        utilities::count_prompt_tokens(model, std::slice::from_ref(&system_message))
+            + pinned.as_ref().map_or(0, |pinned| {
+                utilities::count_prompt_tokens(model, std::slice::from_ref(pinned))
+            })
+            + utilities::TOKENS_PER_MESSAGE
+            + utilities::count_tokens(model, format!("{CURRENT_MESSAGE_LABEL}\n").as_str());
+    let (message, context) = scrub(roadmap_config, message, context);
+    let context = if roadmap_config.clean_context {
+        utilities::clean_context(context)
+    } else {
+        context
+    };
+    let context_given = context.len();
+    let mut messages = utilities::build_conversation(
+        message,
+        context,
+        system_message,
+        context_length(roadmap_config, budget),
+        roadmap_config.min_context_messages,
+        &[
+            context_budget(roadmap_config, model, budget),
+            PromptBudget::tokens(
+                model,
+                roadmap_config
+                    .max_prompt_tokens
+                    .saturating_sub(reserved_tokens),
+            ),
+        ],
+    );
+    // Everything but the system message and the triggering message is context
+    let context_used = messages.len() - 2;
+    if let Some(pinned) = pinned {
+        if context_used == 0 {
+            // Set the request apart from the pinned message, as from context
+            let request = messages.pop().expect("Prompts always end with the request");
+            let content = request.content.unwrap_or_default();
+            messages.push(utilities::user_message(utilities::labeled_message(content)));
+        }
+        messages.insert(1, pinned);
+    }
+    let stats = PromptStats {
+        context_used,
+        context_dropped: context_given - context_used,
+        total_chars: messages
+            .iter()
+            .filter_map(|message| message.content.as_deref())
+            .map(|content| content.chars().count())
+            .sum(),
+    };
+    debug!(
+        "Roadmap prompt uses {} of {} tokens and {} of {} context messages",
+        utilities::count_prompt_tokens(model, &messages),
+        roadmap_config.max_prompt_tokens,
+        stats.context_used,
+        context_given
+    );
+    (messages, stats)
+}
+
+/// Label the links and text sent with a request are introduced by.
+const ATTACHMENTS_LABEL: &str = "Attached material:";
+
+/// `text` cut to its first `limit` chars, the cut marked with an ellipsis.
+fn cut_to(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(limit.saturating_sub(1)).collect();
+    format!("{kept}…")
+}
+
+/// `attachments` as one labeled block, each cut to `attachment_limit_chars` and stopping
+/// once `max_attachment_chars` are used, or `None` when they're all blank.
+fn attachment_block(roadmap_config: &RoadmapConfig, attachments: &[String]) -> Option<String> {
+    let mut remaining = roadmap_config.max_attachment_chars;
+    let mut entries = vec![];
+    for attachment in attachments.iter().map(|attachment| attachment.trim()) {
+        if remaining == 0 {
+            break;
+        }
+        if attachment.is_empty() {
+            continue;
+        }
+        let entry = cut_to(
+            attachment,
+            roadmap_config.attachment_limit_chars.min(remaining),
+        );
+        remaining -= entry.chars().count();
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        return None;
+    }
+    Some(format!("{ATTACHMENTS_LABEL}\n{}", entries.join("\n\n")))
+}
+
+/// `messages` with `attachments` sent just before the request, as many of them as fit in
+/// what's left of `max_prompt_tokens`. The request and its context come first, so
+/// attachments that don't fit are dropped, last first. Attachments are scrubbed like the
+/// request.
+fn add_attachments(
+    roadmap_config: &RoadmapConfig,
+    model: &str,
+    mut messages: Vec<ChatCompletionMessage>,
+    attachments: &[String],
+) -> Vec<ChatCompletionMessage> {
+    let scrubber = scrubber(roadmap_config);
+    let attachments: Vec<String> = attachments
+        .iter()
+        .map(|attachment| scrubber.scrub(attachment))
+        .collect();
+    let has_context = messages.len() > 2;
+    let mut used = utilities::count_prompt_tokens(model, &messages) + utilities::TOKENS_PER_MESSAGE;
+    if !has_context {
+        // The request gets labeled to set it apart from the attachments
+        used += utilities::count_tokens(model, format!("{CURRENT_MESSAGE_LABEL}\n").as_str());
+    }
+    let remaining = roadmap_config.max_prompt_tokens.saturating_sub(used);
+    for kept in (1..=attachments.len()).rev() {
+        let Some(block) = attachment_block(roadmap_config, &attachments[..kept]) else {
+            continue;
+        };
+        if utilities::count_tokens(model, block.as_str()) > remaining {
+            continue;
+        }
+        if kept < attachments.len() {
+            warn!(
+                "Dropping {} of {} attachments to fit the prompt budget",
+                attachments.len() - kept,
+                attachments.len()
+            );
+        }
+        let request = messages.pop().expect("Prompts always end with the request");
+        let content = request.content.unwrap_or_default();
+        messages.push(utilities::user_message(block));
+        messages.push(utilities::user_message(if has_context {
+            content
+        } else {
+            utilities::labeled_message(content)
+        }));
+        return messages;
+    }
+    if attachment_block(roadmap_config, &attachments).is_some() {
+        warn!("Dropping every attachment, none fit the prompt budget");
+    }
+    messages
+}
+
+/// The params the default service creates with, unless a `RoadmapRequest` overrides them.
+fn creation_params() -> ChatParams {
+    ROADMAP_SERVICE.creation_params()
+}
+
+/// Strips the triple-backtick fences, with or without a language tag, that models like to
+/// wrap JSON in. Clean JSON comes back untouched.
+pub(crate) fn extract_json(raw: &str) -> &str {
+    let trimmed = raw.trim();
+    let Some(fenced) = trimmed.strip_prefix("```") else {
+        return trimmed;
+    };
+    let body = match fenced.split_once('\n') {
+        Some((_language, body)) => body,
+        None => fenced,
+    };
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+/// Finds the JSON object in a model reply, ignoring any prose around it.
+pub(crate) fn extract_json_object(raw: &str) -> Option<&str> {
+    let start = raw.find('{')?;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, character) in raw[start..].char_indices() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '{' if !in_string => depth += 1,
+            '}' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&raw[start..=start + index]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses the detection reply, tolerating markdown fences and chatty text around the JSON.
+/// The raw model output is kept in the error so prompt drift can be debugged from the logs.
+pub(crate) fn parse_detection_response(raw: &str) -> Result<RequestingRoadmap, RoadmapError> {
+    extract_json_object(extract_json(raw))
+        .context("no JSON object found")
+        .and_then(|json| Ok(serde_json::from_str(json)?))
+        .map_err(|cause| RoadmapError::ParseFailed {
+            what: "detection response",
+            reply: raw.to_string(),
+            cause,
+        })
+}
+
+/// Records `usage` on the current span, as the model call it's instrumenting reported it.
+pub(crate) fn record_usage(usage: Option<Usage>) {
+    if let Some(usage) = usage {
+        Span::current()
+            .record("prompt_tokens", usage.prompt_tokens)
+            .record("completion_tokens", usage.completion_tokens);
+    }
+}
+
+/// User-written text as it may appear in logs. Only built with the `log-message-content`
+/// feature is the text itself logged, otherwise just its length.
+pub(crate) fn loggable(text: &str) -> String {
+    if cfg!(feature = "log-message-content") {
+        text.to_string()
+    } else {
+        format!("<{} chars>", text.chars().count())
+    }
+}
+
+#[instrument(
+    name = "llm_detection",
+    skip_all,
+    fields(
+        model = %params.model,
+        message_len = message.len(),
+        context_count = context.len(),
+        latency_ms = field::Empty,
+        prompt_tokens = field::Empty,
+        completion_tokens = field::Empty,
+    )
+)]
+async fn detect_cached(
+    roadmap_config: &RoadmapConfig,
+    backend: &dyn ChatBackend,
+    params: &ChatParams,
+    cache: &DetectionCache,
+    message: String,
+    context: Vec<(Role, String)>,
+    budget: Option<ContextBudget>,
+) -> Result<RequestingRoadmap, RoadmapError> {
+    let started = Instant::now();
+    let cache_key = DetectionCache::key(params.model.as_str(), message.as_str(), &context);
+    if let Some(roadmap_request) = cache.get(cache_key) {
+        let (hits, misses) = cache.stats();
+        debug!("Detection cache hit ({hits} hits, {misses} misses)");
+        return Ok(RequestingRoadmap {
+            usage: None,
+            elapsed: started.elapsed(),
+            ..roadmap_request
+        });
+    }
+    let roadmap_request = cache
+        .single_flight(
+            cache_key,
+            detect_roadmap_request(roadmap_config, backend, params, message, context, budget),
+        )
+        .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    Span::current().record("latency_ms", latency_ms);
+    debug!("Roadmap detection took {latency_ms}ms");
+    let roadmap_request = RequestingRoadmap {
+        model: params.model.clone(),
+        elapsed: started.elapsed(),
+        ..roadmap_request?
+    };
+    record_usage(roadmap_request.usage);
+    cache.insert(cache_key, roadmap_request.clone());
+    Ok(roadmap_request)
+}
+
+async fn detect_roadmap_request(
+    roadmap_config: &RoadmapConfig,
+    backend: &dyn ChatBackend,
+    params: &ChatParams,
+    message: String,
+    context: Vec<(Role, String)>,
+    budget: Option<ContextBudget>,
+) -> Result<RequestingRoadmap, RoadmapError> {
+    let mut messages = build_message(
+        roadmap_config,
+        params.model.as_str(),
+        message.clone(),
+        context,
+        system_message_detection(),
+        budget,
+    );
+    let reply = backend.complete(messages.clone(), params).await?;
+    let content = reply.content;
+    let roadmap_request = match parse_detection_response(content.as_str()) {
+        Ok(roadmap_request) => RequestingRoadmap {
+            usage: reply.usage,
+            ..roadmap_request
+        },
+        Err(e) => {
+            warn!("Asking for JSON again after unparseable detection reply ({e})");
+            messages.push(ChatCompletionMessage {
+                role: ChatCompletionMessageRole::Assistant,
+                content: Some(content),
+                name: None,
+                function_call: None,
+            });
+            messages.push(utilities::user_message(JSON_REPAIR_PROMPT.to_string()));
+            let repaired = backend.complete(messages, params).await?;
+            let roadmap_request = parse_detection_response(repaired.content.as_str())
+                .inspect_err(|e| warn!("Giving up on unparseable detection reply ({e})"))?;
+            RequestingRoadmap {
+                usage: llm::add_usage(reply.usage, repaired.usage),
+                ..roadmap_request
+            }
+        }
+    };
+    if let Some(usage) = roadmap_request.usage {
+        debug!(
+            "Roadmap detection used {} prompt and {} completion tokens",
+            usage.prompt_tokens, usage.completion_tokens
+        );
+    }
+    debug!("Detection was asked about {}", loggable(message.as_str()));
+    if roadmap_request.is_roadmap {
+        info!(
+            confidence = roadmap_request.confidence,
+            reason = roadmap_request.reason.as_str(),
+            "Detected roadmap request"
+        );
+    } else {
+        info!(
+            reason = roadmap_request.reason.as_str(),
+            "Ignoring roadmap request"
+        );
+    }
+    Ok(roadmap_request)
+}
+
+#[instrument(
+    skip_all,
+    fields(
+        model = %params.model,
+        message_len = message.len(),
+        context_count = context.len(),
+        latency_ms = field::Empty,
+        prompt_tokens = field::Empty,
+        completion_tokens = field::Empty,
+    )
+)]
+async fn write_roadmap(
+    roadmap_config: &RoadmapConfig,
+    backend: &dyn ChatBackend,
+    params: &ChatParams,
+    message: String,
+    context: Vec<(Role, String)>,
+    instructions: &Instructions,
+    chunks: Option<mpsc::Sender<String>>,
+) -> Result<RoadmapProvided, RoadmapError> {
+    let messages = build_creation_message(
+        roadmap_config,
+        params.model.as_str(),
+        message,
+        context,
+        instructions,
+    );
+    let started = Instant::now();
+    let content = match chunks {
+        Some(chunks) => backend.complete_stream(messages, params, chunks).await,
+        None => backend.complete(messages, params).await,
+    };
+    let elapsed = started.elapsed();
+    let latency_ms = elapsed.as_millis() as u64;
+    Span::current().record("latency_ms", latency_ms);
+    let reply = content?;
+    record_usage(reply.usage);
+    info!(
+        "Generated Roadmap in {latency_ms}ms using {} tokens",
+        reply.usage.map_or(0, |usage| usage.total_tokens)
+    );
+    debug!("Generated Roadmap {}", loggable(reply.content.as_str()));
+    Ok(RoadmapProvided {
+        roadmap: reply.content,
+        structured: None,
+        usage: reply.usage,
+        model: params.model.clone(),
+        elapsed,
+        thread_id: None,
+    })
+}
+
+/// Detects whether `message` asks for a roadmap with the default service.
+pub(crate) async fn is_message_roadmap_request(
+    message: String,
+    context: Vec<(Role, String)>,
+) -> Result<RequestingRoadmap, RoadmapError> {
+    ROADMAP_SERVICE.detect(message, context).await
+}
+
+/// The roadmap `request` asks for as a stream of chunks as it's written, ending in an
+/// error if creation fails. Dropping the stream stops creation, so no more tokens are
+/// paid for.
+pub(crate) fn create_roadmap_stream(request: RoadmapRequest) -> RoadmapStream {
+    let (items, received) = mpsc::channel(64);
+    let (created, roadmap) = oneshot::channel();
+    tokio::spawn(async move {
+        let (chunks, mut chunks_received) = mpsc::channel(64);
+        let forwarded = items.clone();
+        // Owns the receiver, so the backend's sends fail as soon as the consumer is gone
+        let forward_chunks = async move {
+            while let Some(chunk) = chunks_received.recv().await {
+                if forwarded.send(Ok(chunk)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let creation = async { tokio::join!(request.create_streaming(chunks), forward_chunks) };
+        tokio::select! {
+            (streamed, ()) = creation => match streamed {
+                Ok(streamed) => {
+                    let _ = created.send(streamed);
+                }
+                Err(e) => {
+                    let _ = items.send(Err(e.into())).await;
+                }
+            },
+            // Nobody is listening, even before the first chunk
+            _ = items.closed() => {}
+        }
+    });
+    RoadmapStream {
+        chunks: received,
+        roadmap,
+    }
+}
+
+/// A roadmap's chunks from `create_roadmap_stream`, with the whole roadmap to collect once
+/// they've all arrived.
+pub(crate) struct RoadmapStream {
+    chunks: mpsc::Receiver<anyhow::Result<String>>,
+    roadmap: oneshot::Receiver<RoadmapProvided>,
+}
+
+impl Stream for RoadmapStream {
+    type Item = anyhow::Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().chunks.poll_recv(cx)
+    }
+}
+
+impl RoadmapStream {
+    /// The whole roadmap, with its usage and model, once any chunks left are drained.
+    pub(crate) async fn collect_roadmap(mut self) -> anyhow::Result<RoadmapProvided> {
+        while let Some(chunk) = self.next().await {
+            chunk?;
+        }
+        Ok(self.roadmap.await?)
+    }
+}
+
+/// Detects over a backlog of messages with the default service, see
+/// `RoadmapService::detect_batch`.
+pub(crate) async fn detect_batch(
+    messages: Vec<(String, Vec<String>)>,
+) -> Vec<Result<RequestingRoadmap, RoadmapError>> {
+    ROADMAP_SERVICE.detect_batch(messages).await
+}
+
+/// Detects whether `message` asks for a roadmap and, when it's confident enough to
+/// `Create`, runs `create` with the detection. The detection comes back either way, with
+/// whatever `create` made, so callers can still act on a message it wasn't sure about.
+pub(crate) async fn detect_then_create<T, F, Fut>(
+    message: String,
+    context: Vec<(Role, String)>,
+    create: F,
+) -> Result<(RequestingRoadmap, Option<T>), RoadmapError>
+where
+    F: FnOnce(RequestingRoadmap) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let detection = is_message_roadmap_request(message, context).await?;
+    Ok(create_if_confident(detection, create).await)
+}
+
+async fn create_if_confident<T, F, Fut>(
+    detection: RequestingRoadmap,
+    create: F,
+) -> (RequestingRoadmap, Option<T>)
+where
+    F: FnOnce(RequestingRoadmap) -> Fut,
+    Fut: Future<Output = T>,
+{
+    if !detection.should_create() {
+        return (detection, None);
+    }
+    let created = create(detection.clone()).await;
+    (detection, Some(created))
+}
+
+/// Writes a roadmap for `message` following `instructions` with the default service.
+pub(crate) async fn create_roadmap(
+    message: String,
+    context: Vec<(Role, String)>,
+    instructions: &Instructions,
+) -> Result<RoadmapProvided, RoadmapError> {
+    ROADMAP_SERVICE.create(message, context, instructions).await
+}
+
+/// A single roadmap creation, with what it's asked for beyond the message.
+///
+/// `RoadmapRequest::new(message).conversation(context).attachments(links).create().await`
+#[derive(Clone)]
+pub(crate) struct RoadmapRequest {
+    backend: Arc<dyn ChatBackend>,
+    message: String,
+    context: Vec<(Role, String)>,
+    model: Option<String>,
+    max_tokens: Option<u64>,
+    temperature: Option<f32>,
+    instructions: Instructions,
+}
+
+impl RoadmapRequest {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        RoadmapRequest {
+            backend: OPENAI_BACKEND.clone(),
+            message: message.into(),
+            context: vec![],
+            model: None,
+            max_tokens: None,
+            temperature: None,
+            instructions: Instructions::default(),
+        }
+    }
+
+    /// Context as a conversation, so the bot's earlier replies are sent as its own.
+    pub(crate) fn conversation(mut self, conversation: Vec<(Role, String)>) -> Self {
+        self.context = conversation;
+        self
+    }
+
+    /// What the creation prompt asks for beyond the message, replacing any set so far.
+    pub(crate) fn instructions(mut self, instructions: Instructions) -> Self {
+        self.instructions = instructions;
+        self
+    }
+
+    /// Limits the context sent with this request instead of the config's limits.
+    pub(crate) fn context_budget(mut self, context_budget: ContextBudget) -> Self {
+        self.instructions.context_budget = Some(context_budget);
+        self
+    }
+
+    /// Links or text the message refers to, like a job posting or course page, sent along
+    /// to ground the roadmap in them.
+    pub(crate) fn attachments(mut self, attachments: Vec<String>) -> Self {
+        self.instructions.attachments = attachments;
+        self
+    }
+
+    /// Revises `previous` following the message, instead of writing a new roadmap.
+    pub(crate) fn revising(mut self, previous: PreviousRoadmap) -> Self {
+        self.instructions.revising = Some(previous);
+        self
+    }
+
+    /// The model `create` writes the roadmap with.
+    pub(crate) fn creation_model(&self) -> String {
+        self.apply_overrides(creation_params()).model
+    }
+
+    fn apply_overrides(&self, mut params: ChatParams) -> ChatParams {
+        if let Some(model) = &self.model {
+            params = params.model(model);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            params = params.max_tokens(max_tokens);
+        }
+        if let Some(temperature) = self.temperature {
+            params = params.temperature(temperature);
+        }
+        params
+    }
+
+    /// The prompt `create` will send, for inspecting prompt changes without calling OpenAI.
+    fn creation_prompt(&self) -> Vec<ChatCompletionMessage> {
+        let model = self.apply_overrides(creation_params()).model;
+        build_creation_message(
+            &ROADMAP_CONFIG,
+            model.as_str(),
+            self.message.clone(),
+            self.context.clone(),
+            &self.instructions,
+        )
+    }
+
+    /// Tokens `create` will send and the most it can cost, replying with every token it's
+    /// allowed, so oversized requests can be turned away before they're sent.
+    pub(crate) fn estimate(&self) -> (usize, f64) {
+        let params = self.apply_overrides(creation_params());
+        let prompt_tokens = estimate_prompt_tokens(&self.creation_prompt());
+        let max_completion_tokens = params.max_tokens.unwrap_or_default() as usize;
+        (
+            prompt_tokens,
+            estimate_cost_usd(prompt_tokens, max_completion_tokens),
+        )
+    }
+
+    pub(crate) async fn create(self) -> Result<RoadmapProvided, RoadmapError> {
+        let params = self.apply_overrides(creation_params());
+        ROADMAP_SERVICE
+            .create_with(
+                &*self.backend,
+                &params,
+                self.message,
+                self.context,
+                &self.instructions,
+                None,
+            )
+            .await
+    }
+
+    /// Asks for a `StructuredRoadmap`. A reply that doesn't parse is repaired rather than
+    /// asked for again, and only fails if there's no roadmap to be had from it.
+    pub(crate) async fn create_structured(self) -> Result<RoadmapProvided, RoadmapError> {
+        let params = self
+            .apply_overrides(creation_params())
+            .force_function(roadmap_function());
+        let attempt = ROADMAP_SERVICE
+            .create_with(
+                &*self.backend,
+                &params,
+                self.message.clone(),
+                self.context.clone(),
+                &self.instructions,
+                None,
+            )
+            .await?;
+        match parse_structured_roadmap(attempt.roadmap.as_str()) {
+            Ok(structured) => Ok(RoadmapProvided {
+                roadmap: structured.to_text(),
+                structured: Some(structured),
+                ..attempt
+            }),
+            Err(e) => {
+                let Some((roadmap, structured)) = repair_structured_roadmap(&attempt.roadmap)
+                else {
+                    return Err(e);
+                };
+                warn!("{e:#}, repaired it instead");
+                Ok(RoadmapProvided {
+                    roadmap,
+                    structured,
+                    ..attempt
+                })
+            }
+        }
+    }
+
+    pub(crate) async fn create_streaming(
+        self,
+        chunks: mpsc::Sender<String>,
+    ) -> Result<RoadmapProvided, RoadmapError> {
+        let params = self.apply_overrides(creation_params());
+        ROADMAP_SERVICE
+            .create_with(
+                &*self.backend,
+                &params,
+                self.message,
+                self.context,
+                &self.instructions,
+                Some(chunks),
+            )
+            .await
+    }
+}
+
+/// Overrides of the config for a single request, which the bot itself never needs.
+#[cfg(test)]
+impl RoadmapRequest {
+    pub(crate) fn backend(mut self, backend: Arc<dyn ChatBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub(crate) fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    pub(crate) fn max_tokens(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub(crate) fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+}
+
+/// Detection through a request, which the bot leaves to `is_message_roadmap_request`.
+#[cfg(test)]
+impl RoadmapRequest {
+    /// Context as plain lines, each sent as something the user said.
+    pub(crate) fn context(mut self, context: Vec<String>) -> Self {
+        self.context = context.into_iter().map(|line| (Role::User, line)).collect();
+        self
+    }
+
+    /// Detects with `heuristic_keywords`, the model or both, as `detection_mode` says.
+    pub(crate) async fn detect(self) -> Result<RequestingRoadmap, RoadmapError> {
+        let params = self.apply_overrides(ROADMAP_SERVICE.detection_params());
+        ROADMAP_SERVICE
+            .detect_with(
+                self.backend,
+                params,
+                self.instructions.context_budget,
+                self.message,
+                self.context,
+            )
+            .await
+    }
+}
+
+/// A config and backend with their own detection cache, so a host can keep roadmaps
+/// alongside its other message handlers. `is_message_roadmap_request`, `create_roadmap` and
+/// `RoadmapRequest` use one built from the environment.
+pub(crate) struct RoadmapService {
+    config: RoadmapConfig,
+    backend: Arc<dyn ChatBackend>,
+    detection_cache: DetectionCache,
+    heuristic: HeuristicDetector,
+}
+
+impl RoadmapService {
+    pub(crate) fn new(config: RoadmapConfig, backend: Arc<dyn ChatBackend>) -> Self {
+        RoadmapService {
+            detection_cache: config.detection_cache(),
+            heuristic: HeuristicDetector::new(&config.heuristic_keywords),
+            config,
+            backend,
+        }
+    }
+
+    /// Detects with `heuristic_keywords`, the model or both, as `detection_mode` says.
+    pub(crate) async fn detect(
+        &self,
+        message: String,
+        context: Vec<(Role, String)>,
+    ) -> Result<RequestingRoadmap, RoadmapError> {
+        self.detect_with(
+            self.backend.clone(),
+            self.detection_params(),
+            None,
+            message,
+            context,
+        )
+        .await
+    }
+
+    /// Writes a roadmap for `message` following `instructions`, in the language `message`
+    /// is written in unless they name one.
+    pub(crate) async fn create(
+        &self,
+        message: String,
+        context: Vec<(Role, String)>,
+        instructions: &Instructions,
+    ) -> Result<RoadmapProvided, RoadmapError> {
+        self.create_with(
+            &*self.backend,
+            &self.creation_params(),
+            message,
+            context,
+            instructions,
+            None,
+        )
+        .await
+    }
+
+    /// Runs detection over a backlog of `(message, context)` pairs, `batch_concurrency` at
+    /// a time, returning each result at its message's index.
+    pub(crate) async fn detect_batch(
+        &self,
+        messages: Vec<(String, Vec<String>)>,
+    ) -> Vec<Result<RequestingRoadmap, RoadmapError>> {
+        let mut detections: Vec<_> = stream::iter(messages.into_iter().enumerate())
+            .map(|(index, (message, context))| async move {
+                let context = context.into_iter().map(|line| (Role::User, line)).collect();
+                (index, self.detect(message, context).await)
+            })
+            .buffer_unordered(self.config.batch_concurrency)
+            .collect()
+            .await;
+        detections.sort_by_key(|(index, _)| *index);
+        detections
+            .into_iter()
+            .map(|(_, detection)| detection)
+            .collect()
+    }
+
+    fn detection_params(&self) -> ChatParams {
+        ChatParams::new(self.config.detection_model.as_str())
+            .max_tokens(self.config.detection_max_tokens)
+            .temperature(self.config.detection_temperature)
+            .force_function(detection_function())
+    }
+
+    fn creation_params(&self) -> ChatParams {
+        ChatParams::new(self.config.creation_model.as_str())
+            .max_tokens(self.config.creation_max_tokens)
+            .temperature(self.config.creation_temperature)
+    }
+
+    #[instrument(name = "detection", skip_all)]
+    async fn detect_with(
+        &self,
+        backend: Arc<dyn ChatBackend>,
+        params: ChatParams,
+        context_budget: Option<ContextBudget>,
+        message: String,
+        context: Vec<(Role, String)>,
+    ) -> Result<RequestingRoadmap, RoadmapError> {
+        let llm = LlmDetector {
+            service: self,
+            backend,
+            params,
+            context_budget,
+        };
+        let detection = heuristic_detection::detect_with(
+            self.config.detection_mode,
+            &self.heuristic,
+            &llm,
+            message,
+            context,
+        )
+        .await;
+        let outcome = match &detection {
+            Ok(detection) if detection.is_roadmap => "roadmap",
+            Ok(_) => "not_roadmap",
+            Err(_) => "error",
+        };
+        metrics::increment(metrics::ROADMAP_DETECTIONS, &[("outcome", outcome)]);
+        detection
+    }
+
+    /// Creation under `creation_timeout_secs`, streaming into `chunks` when given.
+    #[instrument(name = "creation", skip_all)]
+    async fn create_with(
+        &self,
+        backend: &dyn ChatBackend,
+        params: &ChatParams,
+        message: String,
+        context: Vec<(Role, String)>,
+        instructions: &Instructions,
+        chunks: Option<mpsc::Sender<String>>,
+    ) -> Result<RoadmapProvided, RoadmapError> {
+        let created = with_call_timeout(
+            "creation",
+            Duration::from_secs(self.config.creation_timeout_secs),
+            write_roadmap(
+                &self.config,
+                backend,
+                params,
+                message,
+                context,
+                instructions,
+                chunks,
+            ),
+        )
+        .await;
+        let outcome = if created.is_ok() { "ok" } else { "error" };
+        metrics::increment(metrics::ROADMAP_CREATIONS, &[("outcome", outcome)]);
+        created
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ChatReply, MockChatBackend};
+
+    fn no_cache() -> DetectionCache {
+        DetectionCache::new(0, Duration::ZERO)
+    }
+
+    /// Detection as the default service does it, with the given backend and cache.
+    async fn detect_with_backend(
+        backend: &dyn ChatBackend,
+        params: &ChatParams,
+        cache: &DetectionCache,
+        message: String,
+        context: Vec<(Role, String)>,
+        budget: Option<ContextBudget>,
+    ) -> Result<RequestingRoadmap, RoadmapError> {
+        detect_cached(
+            &ROADMAP_CONFIG,
+            backend,
+            params,
+            cache,
+            message,
+            context,
+            budget,
+        )
+        .await
+    }
+
+    fn detection_params() -> ChatParams {
+        ROADMAP_SERVICE.detection_params()
+    }
+
+    /// Creation as the default service does it, with the given backend and params.
+    async fn create_with_backend(
+        backend: &dyn ChatBackend,
+        params: &ChatParams,
+        message: String,
+        context: Vec<(Role, String)>,
+        instructions: &Instructions,
+    ) -> Result<RoadmapProvided, RoadmapError> {
+        write_roadmap(
+            &ROADMAP_CONFIG,
+            backend,
+            params,
+            message,
+            context,
+            instructions,
+            None,
+        )
+        .await
+    }
 
     #[test]
     fn emit_prompt() {
-        dbg!(build_message(
+        dbg!(RoadmapRequest::new("I'd like a roadmap").creation_prompt());
+    }
+
+    #[test]
+    fn build_message_preserves_context_order() {
+        let messages = build_message(
+            &RoadmapConfig::default(),
+            "gpt-4o-mini",
+            "Can someone give me a roadmap?".to_string(),
+            vec![
+                (Role::User, "I've finished a Python course".to_string()),
+                (Role::Assistant, "Start with statistics".to_string()),
+                (Role::User, "But I don't know where to start".to_string()),
+            ],
+            system_message_detection(),
+            None,
+        );
+        let turns: Vec<String> = messages[1..]
+            .iter()
+            .map(|message| {
+                format!(
+                    "{:?}: {}",
+                    message.role,
+                    message.content.as_deref().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            turns,
+            [
+                "User: I've finished a Python course",
+                "Assistant: Start with statistics",
+                "User: But I don't know where to start",
+                "User: Current message:\nCan someone give me a roadmap?",
+            ]
+        );
+    }
+
+    /// The example in `build_message`'s docs, which aren't run as doc tests.
+    #[test]
+    fn build_message_docs_hold() {
+        let context = vec![
+            (Role::User, "ada: I know Python".to_string()),
+            (Role::Assistant, "Try pandas next".to_string()),
+            (Role::User, "ada: Done, what now?".to_string()),
+        ];
+        let messages = build_message(
+            &RoadmapConfig::default(),
+            "gpt-4o-mini",
+            "Can I get a roadmap?".to_string(),
+            context.clone(),
+            utilities::system_message("You write roadmaps".to_string()),
+            None,
+        );
+        assert_eq!(messages.len(), 5);
+        let messages = build_message(
+            &RoadmapConfig::default(),
+            "gpt-4o-mini",
+            "Can I get a roadmap?".to_string(),
+            context,
+            utilities::system_message("You write roadmaps".to_string()),
+            Some(ContextBudget {
+                context_length: 1,
+                message_limit_chars: 2048,
+            }),
+        );
+        assert_eq!(messages[1].content.as_deref(), Some("ada: Done, what now?"));
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn prompt_stats_count_context_dropped_for_the_budget() {
+        let context = vec![
+            (Role::User, "I know Python".to_string()),
+            (Role::User, "I know Python".to_string()),
+            (Role::Assistant, "Try pandas next".to_string()),
+            (Role::User, "Done, what now?".to_string()),
+        ];
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .clean_context(true)
+            .build()
+            .unwrap();
+        let (messages, stats) = build_message_with_stats(
+            &roadmap_config,
+            "gpt-4o-mini",
+            "Can I get a roadmap?".to_string(),
+            context.clone(),
+            utilities::system_message("You write roadmaps".to_string()),
+            None,
+            Some(ContextBudget {
+                context_length: 2,
+                message_limit_chars: 2048,
+            }),
+        );
+        // The repeat is cleaned away rather than dropped for the budget
+        assert_eq!(stats.context_used, 2);
+        assert_eq!(stats.context_dropped, 1);
+        assert_eq!(
+            stats.total_chars,
+            messages
+                .iter()
+                .map(|message| message.content.as_deref().unwrap().chars().count())
+                .sum::<usize>()
+        );
+        let (_, stats) = build_message_with_stats(
+            &roadmap_config,
+            "gpt-4o-mini",
+            "Can I get a roadmap?".to_string(),
+            context,
+            utilities::system_message("You write roadmaps".to_string()),
+            None,
+            Some(ContextBudget {
+                context_length: 10,
+                message_limit_chars: "Can I get a roadmap?".len(),
+            }),
+        );
+        // No room left after the message itself
+        assert_eq!((stats.context_used, stats.context_dropped), (0, 3));
+    }
+
+    #[test]
+    fn build_message_cleans_context_unless_disabled() {
+        let context = vec![
+            (Role::User, "I know Python".to_string()),
+            (Role::User, "I know Python".to_string()),
+            (Role::User, " ".to_string()),
+        ];
+        let build = |clean_context| {
+            build_message(
+                &RoadmapConfigBuilder::new()
+                    .clean_context(clean_context)
+                    .build()
+                    .unwrap(),
+                "gpt-4o-mini",
+                "What next?".to_string(),
+                context.clone(),
+                system_message_detection(),
+                None,
+            )
+            .len()
+        };
+        // System message, context, then the request
+        assert_eq!(build(true), 3);
+        assert_eq!(build(false), 5);
+    }
+
+    #[test]
+    fn build_message_scrubs_message_and_context_when_enabled() {
+        let build = |roadmap_config: &RoadmapConfig| -> Vec<String> {
+            build_message(
+                roadmap_config,
+                "gpt-4o-mini",
+                "Roadmap please, shit, reach me on 555-123-4567".to_string(),
+                vec![(Role::User, "ada: I'm ada@example.com".to_string())],
+                system_message_detection(),
+                None,
+            )[1..]
+                .iter()
+                .map(|message| message.content.clone().unwrap())
+                .collect()
+        };
+        assert_eq!(
+            build(
+                &RoadmapConfigBuilder::new()
+                    .scrub_pii(true)
+                    .mask_profanity(true)
+                    .build()
+                    .unwrap()
+            ),
+            [
+                "ada: I'm [email]".to_string(),
+                format!("{CURRENT_MESSAGE_LABEL}\nRoadmap please, ****, reach me on [phone]")
+            ]
+        );
+        assert!(build(&RoadmapConfig::default())[0].contains("ada@example.com"));
+    }
+
+    #[test]
+    fn build_message_drops_context_when_system_prompt_fills_budget() {
+        let model = "gpt-4o-mini";
+        let message = "Can someone give me a roadmap?";
+        let system_tokens = utilities::count_prompt_tokens(model, &[system_message_detection()]);
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .max_prompt_tokens(
+                system_tokens
+                    + utilities::TOKENS_PER_MESSAGE
+                    + utilities::count_tokens(model, format!("{CURRENT_MESSAGE_LABEL}\n").as_str())
+                    + utilities::count_tokens(model, message),
+            )
+            .build()
+            .unwrap();
+        let messages = build_message(
+            &roadmap_config,
+            model,
+            message.to_string(),
+            vec![(Role::User, "I've finished a Python course".to_string())],
+            system_message_detection(),
+            None,
+        );
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages.last().unwrap().content.as_deref().unwrap(),
+            message
+        );
+        assert!(
+            utilities::count_prompt_tokens(model, &messages) <= roadmap_config.max_prompt_tokens
+        );
+    }
+
+    #[test]
+    fn build_message_counts_context_in_tokens_when_enabled() {
+        let model = "gpt-4o-mini";
+        let message = "Can someone give me a roadmap?";
+        let context = vec![(Role::User, "I've finished a Python course".to_string())];
+        let budget_tokens = utilities::count_tokens(model, message)
+            + utilities::count_tokens(model, &context[0].1)
+            + utilities::TOKENS_PER_MESSAGE;
+        // Room for the message by itself in chars, but not its context
+        let by_chars = RoadmapConfigBuilder::new().message_limit_chars(message.chars().count());
+        let by_tokens = by_chars
+            .clone()
+            .count_context_tokens(true)
+            .message_limit_tokens(budget_tokens)
+            .build()
+            .unwrap();
+        let by_chars = by_chars.build().unwrap();
+        let contents = |roadmap_config: &RoadmapConfig| -> Vec<String> {
+            build_message(
+                roadmap_config,
+                model,
+                message.to_string(),
+                context.clone(),
+                system_message_detection(),
+                None,
+            )[1..]
+                .iter()
+                .map(|message| message.content.clone().unwrap())
+                .collect()
+        };
+        assert_eq!(contents(&by_chars), [message]);
+        assert_eq!(
+            contents(&by_tokens),
+            [
+                context[0].1.clone(),
+                format!("{CURRENT_MESSAGE_LABEL}\n{message}")
+            ]
+        );
+    }
+
+    #[test]
+    fn attachments_are_cut_to_their_limits() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .attachment_limit_chars(10)
+            .max_attachment_chars(15)
+            .build()
+            .unwrap();
+        let attachments = [
+            "https://jobs.example/data-analyst-role",
+            "  ",
+            "SQL",
+            "Python",
+        ]
+        .map(str::to_string);
+        assert_eq!(
+            attachment_block(&roadmap_config, &attachments).as_deref(),
+            Some("Attached material:\nhttps://j…\n\nSQL\n\nP…")
+        );
+        assert_eq!(attachment_block(&roadmap_config, &attachments[1..2]), None);
+    }
+
+    #[test]
+    fn attachments_go_before_the_request_if_they_fit() {
+        let prompt = |max_prompt_tokens: usize, attachments: &[&str]| -> Vec<String> {
+            let roadmap_config = RoadmapConfigBuilder::new()
+                .max_prompt_tokens(max_prompt_tokens)
+                .build()
+                .unwrap();
+            let messages = build_message(
+                &roadmap_config,
+                "gpt-4o-mini",
+                "Make me a roadmap for this".to_string(),
+                vec![],
+                utilities::system_message("You write roadmaps".to_string()),
+                None,
+            );
+            let attachments: Vec<String> = attachments.iter().map(|a| a.to_string()).collect();
+            add_attachments(&roadmap_config, "gpt-4o-mini", messages, &attachments)[1..]
+                .iter()
+                .map(|message| message.content.clone().unwrap())
+                .collect()
+        };
+        let posting = "Data Analyst at Example Corp: SQL, Tableau, and A/B testing";
+        assert_eq!(
+            prompt(4096, &[posting]),
+            [
+                format!("Attached material:\n{posting}"),
+                "Current message:\nMake me a roadmap for this".to_string()
+            ]
+        );
+        // Room for the request, but not the posting
+        assert_eq!(prompt(40, &[posting]), ["Make me a roadmap for this"]);
+        assert_eq!(prompt(4096, &[]), ["Make me a roadmap for this"]);
+        let long = "A long course description ".repeat(10);
+        let sent = prompt(80, &[posting, long.as_str()]);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], format!("Attached material:\n{posting}"));
+    }
+
+    #[test]
+    fn attachments_are_scrubbed_like_the_request() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .scrub_pii(true)
+            .mask_profanity(true)
+            .build()
+            .unwrap();
+        let messages = build_message(
+            &roadmap_config,
+            "gpt-4o-mini",
+            "Make me a roadmap for this".to_string(),
+            vec![],
+            utilities::system_message("You write roadmaps".to_string()),
+            None,
+        );
+        let attachments = ["Shit pay, apply at jobs@example.com".to_string()];
+        let sent = add_attachments(&roadmap_config, "gpt-4o-mini", messages, &attachments);
+        assert_eq!(
+            sent[1].content.as_deref(),
+            Some("Attached material:\n**** pay, apply at [email]")
+        );
+    }
+
+    #[tokio::test]
+    async fn context_budget_overrides_the_config() {
+        let context = vec![
+            (Role::User, "I've finished a Python course".to_string()),
+            (Role::User, "and some SQL".to_string()),
+            (Role::User, "but I'm stuck on statistics".to_string()),
+        ];
+        let sent_context = |budget: Option<ContextBudget>| -> Vec<String> {
+            build_message(
+                &RoadmapConfig::default(),
+                "gpt-4o-mini",
+                "What next?".to_string(),
+                context.clone(),
+                system_message_detection(),
+                budget,
+            )[1..]
+                .iter()
+                .map(|message| message.content.clone().unwrap())
+                .collect()
+        };
+        assert_eq!(sent_context(None).len(), 4);
+        let tighter = ContextBudget {
+            context_length: 2,
+            message_limit_chars: "What next?".len() + "but I'm stuck on statistics".len() + 1,
+        };
+        // Only the newest context fits whole, with whatever is left of the budget spent on
+        // the end of the one before
+        let sent = sent_context(Some(tighter));
+        assert_eq!(sent.len(), 3);
+        assert!("and some SQL".ends_with(sent[0].as_str()));
+        assert_eq!(
+            sent[1..],
+            [
+                "but I'm stuck on statistics",
+                "Current message:\nWhat next?"
+            ]
+        );
+
+        let backend = Arc::new(MockChatBackend::new(&["1. Learn statistics"]));
+        RoadmapRequest::new("What next?")
+            .conversation(context)
+            .context_budget(ContextBudget {
+                context_length: 1,
+                message_limit_chars: 2048,
+            })
+            .backend(backend.clone())
+            .create()
+            .await
+            .unwrap();
+        // System message, the last context message, then the request
+        assert_eq!(backend.prompts()[0].len(), 3);
+    }
+
+    #[test]
+    fn detection_function_matches_requesting_roadmap() {
+        let parameters = detection_function().parameters.unwrap();
+        let schema_fields: Vec<&String> = parameters["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect();
+        let struct_fields = serde_json::to_value(RequestingRoadmap {
+            reason: "Asking for a roadmap".to_string(),
+            is_roadmap: true,
+            confidence: 1.0,
+            is_followup: false,
+            usage: None,
+            model: String::new(),
+            elapsed: Duration::ZERO,
+        })
+        .unwrap();
+        let struct_fields: Vec<&String> = struct_fields.as_object().unwrap().keys().collect();
+        assert_eq!(schema_fields, struct_fields);
+        assert_eq!(
+            parameters["required"].as_array().unwrap().len(),
+            struct_fields.len()
+        );
+    }
+
+    #[test]
+    fn extract_json_strips_fences() {
+        let json = "{\"is_roadmap\": true}";
+        assert_eq!(extract_json(json), json);
+        assert_eq!(extract_json(&format!("  {json}\n")), json);
+        assert_eq!(extract_json(&format!("```\n{json}\n```")), json);
+        assert_eq!(extract_json(&format!("```json\n{json}\n```\n")), json);
+        assert_eq!(extract_json(&format!("```JSON\n{json}")), json);
+    }
+
+    #[test]
+    fn parse_fenced_detection_response() {
+        let roadmap_request = parse_detection_response(
+            "```json\n{\"reason\": \"Asking for a roadmap about AWS\", \"is_roadmap\": true, \"confidence\": 0.9}\n```",
+        )
+        .unwrap();
+        assert!(roadmap_request.is_roadmap);
+        assert_eq!(roadmap_request.reason, "Asking for a roadmap about AWS");
+    }
+
+    #[test]
+    fn parse_detection_response_with_leading_prose() {
+        let roadmap_request = parse_detection_response(
+            "Here is the result: {\"reason\": \"Meta discussion\", \"is_roadmap\": false, \"confidence\": 0.8}",
+        )
+        .unwrap();
+        assert!(!roadmap_request.is_roadmap);
+    }
+
+    #[test]
+    fn parse_detection_response_with_trailing_commentary() {
+        let roadmap_request = parse_detection_response(
+            "{\"reason\": \"Uses {braces} and \\\"quotes\\\"\", \"is_roadmap\": true, \"confidence\": 1}\n\
+             Let me know if you need anything else!",
+        )
+        .unwrap();
+        assert_eq!(roadmap_request.reason, "Uses {braces} and \"quotes\"");
+    }
+
+    #[test]
+    fn parse_malformed_detection_response() {
+        assert!(parse_detection_response("I think this is a roadmap request").is_err());
+        assert!(parse_detection_response("{\"reason\": \"Truncated\", \"is_roadmap\"").is_err());
+        assert!(parse_detection_response("{\"reason\": \"Missing is_roadmap\"}").is_err());
+    }
+
+    #[test]
+    fn parse_error_keeps_the_raw_response() {
+        let raw = "{\"reason\": \"Missing is_roadmap\"}";
+        let error = parse_detection_response(raw).unwrap_err();
+        assert!(matches!(&error, RoadmapError::ParseFailed { reply, .. } if reply == raw));
+        let error = anyhow::Error::from(error);
+        assert_eq!(
+            error.to_string(),
+            format!("failed to parse detection response: {}", loggable(raw))
+        );
+        assert!(format!("{error:#}").contains("missing field `is_roadmap`"));
+    }
+
+    #[test]
+    fn decision_respects_threshold_boundaries() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .detection_threshold(0.7)
+            .uncertain_threshold(0.4)
+            .build()
+            .unwrap();
+        let decide = |reply: &str| {
+            parse_detection_response(reply)
+                .unwrap()
+                .decide(&roadmap_config)
+        };
+        let cases = [
+            (
+                r#"{"reason": "r", "is_roadmap": true, "confidence": 1.0}"#,
+                RoadmapDecision::Create,
+            ),
+            (
+                r#"{"reason": "r", "is_roadmap": true, "confidence": 0.7}"#,
+                RoadmapDecision::Create,
+            ),
+            (
+                r#"{"reason": "r", "is_roadmap": true, "confidence": 0.69}"#,
+                RoadmapDecision::Unsure,
+            ),
+            (
+                r#"{"reason": "r", "is_roadmap": true, "confidence": 0.4}"#,
+                RoadmapDecision::Unsure,
+            ),
+            (
+                r#"{"reason": "r", "is_roadmap": true, "confidence": 0.39}"#,
+                RoadmapDecision::Ignore,
+            ),
+            (
+                r#"{"reason": "r", "is_roadmap": false, "confidence": 1.0}"#,
+                RoadmapDecision::Ignore,
+            ),
+        ];
+        for (reply, expected) in cases {
+            assert_eq!(decide(reply), expected, "Failed on reply: {}", reply);
+        }
+    }
+
+    #[test]
+    fn should_create_respects_threshold_boundary() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .detection_threshold(0.7)
+            .build()
+            .unwrap();
+        let should_create = |is_roadmap: bool, confidence: f32| {
+            RequestingRoadmap {
+                reason: String::new(),
+                is_roadmap,
+                confidence,
+                is_followup: false,
+                usage: None,
+                model: String::new(),
+                elapsed: Duration::ZERO,
+            }
+            .should_create_with(&roadmap_config)
+        };
+        assert!(should_create(true, 0.7));
+        assert!(should_create(true, 1.0));
+        assert!(!should_create(true, 0.69));
+        assert!(!should_create(true, 0.0));
+        assert!(!should_create(false, 0.7));
+        assert!(!should_create(false, 1.0));
+    }
+
+    #[test]
+    fn explanation_presents_reason() {
+        let explanation = |is_roadmap: bool, reason: &str| {
+            RequestingRoadmap {
+                reason: reason.to_string(),
+                is_roadmap,
+                confidence: 0.9,
+                is_followup: false,
+                usage: None,
+                model: String::new(),
+                elapsed: Duration::ZERO,
+            }
+            .explanation()
+        };
+        assert_eq!(
+            explanation(true, "  Asks how to\nget into data science "),
+            "I think you're asking for a roadmap because: Asks how to get into data science"
+        );
+        assert_eq!(
+            explanation(false, "Just chatting"),
+            "I don't think you're asking for a roadmap because: Just chatting"
+        );
+        assert_eq!(
+            explanation(true, " \n"),
+            "I think you're asking for a roadmap."
+        );
+        let long = explanation(true, "word ".repeat(200).as_str());
+        let reason = long
+            .strip_prefix("I think you're asking for a roadmap because: ")
+            .unwrap();
+        assert!(reason.chars().count() <= EXPLANATION_REASON_LIMIT);
+        assert!(reason.ends_with("word…"));
+    }
+
+    #[test]
+    fn missing_confidence_defaults_to_half() {
+        let roadmap_request =
+            parse_detection_response(r#"{"reason": "Asking for a roadmap", "is_roadmap": true}"#)
+                .unwrap();
+        assert_eq!(roadmap_request.confidence, 0.5);
+        assert_eq!(
+            roadmap_request.decide(&RoadmapConfig::default()),
+            RoadmapDecision::Unsure
+        );
+    }
+
+    #[test]
+    fn reject_inverted_thresholds() {
+        let built = RoadmapConfigBuilder::new()
+            .detection_threshold(0.5)
+            .uncertain_threshold(0.6)
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[tokio::test]
+    async fn detect_roadmap_with_mock_backend() {
+        let backend = MockChatBackend::new(&[
+            r#"{"reason": "Asking for a roadmap about AWS", "is_roadmap": true, "confidence": 0.9}"#,
+        ]);
+        let roadmap_request = detect_with_backend(
+            &backend,
+            &detection_params(),
+            &no_cache(),
+            "I want to start learning AWS can anyone suggest a roadmap".to_string(),
+            vec![],
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(roadmap_request.is_roadmap);
+        assert_eq!(roadmap_request.reason, "Asking for a roadmap about AWS");
+        assert_eq!(roadmap_request.confidence, 0.9);
+        assert_eq!(backend.prompts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn detection_usage_includes_repair() {
+        let backend = MockChatBackend::new(&[
+            "Sure! This looks like a roadmap request.",
+            r#"{"reason": "Asking for a roadmap", "is_roadmap": true}"#,
+        ])
+        .usage(Usage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+            total_tokens: 120,
+        });
+        let roadmap_request = detect_with_backend(
+            &backend,
+            &detection_params(),
+            &no_cache(),
+            "Roadmap please".to_string(),
+            vec![],
+            None,
+        )
+        .await
+        .unwrap();
+        let usage = roadmap_request.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 200);
+        assert_eq!(usage.total_tokens, 240);
+    }
+
+    #[tokio::test]
+    async fn detect_roadmap_uses_cached_result() {
+        let backend = MockChatBackend::new(&[
+            r#"{"reason": "Asking for a roadmap", "is_roadmap": true, "confidence": 0.9}"#,
+        ]);
+        let cache = DetectionCache::new(8, Duration::from_secs(60));
+        for message in ["Roadmap please", "  roadmap PLEASE"] {
+            let roadmap_request = detect_with_backend(
+                &backend,
+                &detection_params(),
+                &cache,
+                message.to_string(),
+                vec![],
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(roadmap_request.is_roadmap);
+        }
+        assert_eq!(backend.prompts().len(), 1);
+        assert_eq!(cache.stats(), (1, 1));
+    }
+
+    /// Answers every prompt after a short delay, counting how often it was asked.
+    struct CountingChatBackend {
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    #[serenity::async_trait]
+    impl ChatBackend for CountingChatBackend {
+        async fn complete(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(ChatReply {
+                content: r#"{"reason": "Asking for a roadmap", "is_roadmap": true}"#.to_string(),
+                usage: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_detections_share_one_call() {
+        let backend = Arc::new(CountingChatBackend {
+            calls: Default::default(),
+        });
+        let cache = Arc::new(no_cache());
+        let detections: Vec<_> = (0..5)
+            .map(|_| {
+                let backend = backend.clone();
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    detect_with_backend(
+                        &*backend,
+                        &detection_params(),
+                        &cache,
+                        "Roadmap please".to_string(),
+                        vec![],
+                        None,
+                    )
+                    .await
+                })
+            })
+            .collect();
+        for detection in detections {
+            assert!(detection.await.unwrap().unwrap().is_roadmap);
+        }
+        assert_eq!(backend.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn detect_roadmap_repairs_unparseable_reply() {
+        let backend = MockChatBackend::new(&[
+            "Sure! This looks like a roadmap request.",
+            r#"{"reason": "Asking for a roadmap", "is_roadmap": true}"#,
+        ]);
+        let roadmap_request = detect_with_backend(
+            &backend,
+            &detection_params(),
+            &no_cache(),
+            "Roadmap please".to_string(),
+            vec![],
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(roadmap_request.is_roadmap);
+        let repair_prompt = backend.prompts().pop().unwrap();
+        assert_eq!(
+            repair_prompt.last().unwrap().content.as_deref(),
+            Some(JSON_REPAIR_PROMPT)
+        );
+    }
+
+    #[tokio::test]
+    async fn detect_roadmap_fails_after_failed_repair() {
+        let backend = MockChatBackend::new(&["Not JSON", "Still not JSON"]);
+        assert!(detect_with_backend(
+            &backend,
+            &detection_params(),
+            &no_cache(),
+            "Roadmap please".to_string(),
+            vec![],
+            None
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn create_roadmap_with_mock_backend() {
+        let backend = MockChatBackend::new(&["1. Learn Python\n2. Learn statistics"]);
+        let created_roadmap = create_with_backend(
+            &backend,
+            &creation_params(),
+            "I'd like a roadmap".to_string(),
+            vec![(Role::User, "I'm new to data science".to_string())],
+            &Instructions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            created_roadmap.roadmap,
+            "1. Learn Python\n2. Learn statistics"
+        );
+        let prompt = backend.prompts().pop().unwrap();
+        assert_eq!(prompt[0].content.as_deref(), Some(CREATE_ROADMAP_PROMPT));
+        assert_eq!(
+            prompt[1].content.as_deref(),
+            Some("I'm new to data science")
+        );
+        assert_eq!(
+            prompt[2].content.as_deref(),
+            Some("Current message:\nI'd like a roadmap")
+        );
+    }
+
+    #[tokio::test]
+    async fn roadmap_request_overrides_reach_the_backend() {
+        let backend = Arc::new(MockChatBackend::new(&["1. Learn Python"]));
+        RoadmapRequest::new("I'd like a roadmap")
+            .backend(backend.clone())
+            .model("gpt-4o")
+            .temperature(0.2)
+            .create()
+            .await
+            .unwrap();
+        let params = backend.params().pop().unwrap();
+        assert_eq!(params.model, "gpt-4o");
+        assert_eq!(params.temperature, Some(0.2));
+        assert_eq!(params.max_tokens, Some(ROADMAP_CONFIG.creation_max_tokens));
+        assert!(params.function.is_none());
+        let backend = Arc::new(MockChatBackend::new(&["1. Learn Python"]));
+        RoadmapRequest::new("I'd like a roadmap")
+            .backend(backend.clone())
+            .max_tokens(200)
+            .create()
+            .await
+            .unwrap();
+        let params = backend.params().pop().unwrap();
+        assert_eq!(params.model, ROADMAP_CONFIG.creation_model);
+        assert_eq!(params.max_tokens, Some(200));
+    }
+
+    #[tokio::test]
+    async fn only_confident_detections_are_created() {
+        let detection = |confidence: f32| RequestingRoadmap {
+            reason: "Asking for a roadmap".to_string(),
+            is_roadmap: true,
+            is_followup: false,
+            confidence,
+            usage: None,
+            model: String::new(),
+            elapsed: Duration::ZERO,
+        };
+        let (detected, created) =
+            create_if_confident(detection(1.0), |detected| async move { detected.reason }).await;
+        assert_eq!(created.as_deref(), Some(detected.reason.as_str()));
+        let (_, created) =
+            create_if_confident(detection(0.0), |_| async { unreachable!("not confident") }).await;
+        assert_eq!(created, None::<()>);
+    }
+
+    /// Says every message is a roadmap request, giving the message as the reason, and
+    /// tracks how many completions run at once.
+    #[derive(Default)]
+    struct EchoingChatBackend {
+        running: std::sync::atomic::AtomicUsize,
+        most_running: std::sync::atomic::AtomicUsize,
+    }
+
+    #[serenity::async_trait]
+    impl ChatBackend for EchoingChatBackend {
+        async fn complete(
+            &self,
+            messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            use std::sync::atomic::Ordering;
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            let message = messages.last().unwrap().content.clone().unwrap();
+            // Later messages finish first, so results come back out of order
+            let delay = 50 - message.parse::<u64>().unwrap() * 4;
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            if message == "7" {
+                anyhow::bail!("OpenAI is down");
+            }
+            Ok(ChatReply {
+                content: json!({ "reason": message, "is_roadmap": true }).to_string(),
+                usage: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_detection_keeps_order_and_limits_concurrency() {
+        let backend = Arc::new(EchoingChatBackend::default());
+        let service = RoadmapService::new(
+            RoadmapConfigBuilder::new()
+                .detection_mode(DetectionMode::Llm)
+                .batch_concurrency(3)
+                .build()
+                .unwrap(),
+            backend.clone(),
+        );
+        let messages = (0..10).map(|index| (index.to_string(), vec![])).collect();
+        let detections = service.detect_batch(messages).await;
+        assert_eq!(detections.len(), 10);
+        for (index, detection) in detections.iter().enumerate() {
+            match detection {
+                Err(RoadmapError::ApiError(_)) => assert_eq!(index, 7),
+                Ok(detection) => assert_eq!(detection.reason, index.to_string()),
+                Err(e) => panic!("Unexpected error {e}"),
+            }
+        }
+        let most_running = backend
+            .most_running
+            .load(std::sync::atomic::Ordering::SeqCst);
+        assert!(
+            (2..=3).contains(&most_running),
+            "{most_running} ran at once"
+        );
+    }
+
+    #[tokio::test]
+    async fn service_uses_its_own_config_and_backend() {
+        let backend = Arc::new(MockChatBackend::new(&[
+            r#"{"reason": "Asking for a roadmap", "is_roadmap": true, "confidence": 0.9}"#,
+            "1. Learn Rust",
+        ]));
+        let service = RoadmapService::new(
+            RoadmapConfigBuilder::new()
+                .detection_mode(DetectionMode::Llm)
+                .detection_model("detector".to_string())
+                .creation_model("writer".to_string())
+                .build()
+                .unwrap(),
+            backend.clone(),
+        );
+        let detection = service
+            .detect("Can I get a Rust roadmap?".to_string(), vec![])
+            .await
+            .unwrap();
+        assert!(detection.is_roadmap);
+        let created = service
+            .create(
+                "Can I get a Rust roadmap?".to_string(),
+                vec![],
+                &Instructions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(created.roadmap, "1. Learn Rust");
+        let models: Vec<_> = backend
+            .params()
+            .into_iter()
+            .map(|params| params.model)
+            .collect();
+        assert_eq!(models, ["detector", "writer"]);
+    }
+
+    #[tokio::test]
+    async fn creation_reports_usage_and_model() {
+        let backend = MockChatBackend::new(&["1. Learn Python"]).usage(Usage {
+            prompt_tokens: 300,
+            completion_tokens: 50,
+            total_tokens: 350,
+        });
+        let created_roadmap = create_with_backend(
+            &backend,
+            &creation_params().model("gpt-4o"),
             "I'd like a roadmap".to_string(),
             vec![],
-            system_message_creation()
+            &Instructions::default(),
+        )
+        .await
+        .unwrap();
+        let usage = created_roadmap.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 300);
+        assert_eq!(usage.completion_tokens, 50);
+        assert_eq!(created_roadmap.model, "gpt-4o");
+    }
+
+    #[tokio::test]
+    async fn structured_creation_parses_steps() {
+        let backend = Arc::new(MockChatBackend::new(&[r#"{
+            "title": "Python to ML",
+            "intro": "A short plan.",
+            "steps": [
+                {"name": "Python", "description": "Learn the basics.", "duration": "1 month",
+                 "resources": ["https://docs.python.org/3/tutorial/"]},
+                {"title": "Pandas", "description": "Load and clean data."}
+            ]
+        }"#]));
+        let created_roadmap = RoadmapRequest::new("I'd like a roadmap")
+            .backend(backend.clone())
+            .create_structured()
+            .await
+            .unwrap();
+        let structured = created_roadmap.structured.unwrap();
+        assert_eq!(structured.steps[0].name, "Python");
+        assert_eq!(structured.steps[1].name, "Pandas");
+        assert!(structured.steps[1].resources.is_empty());
+        assert_eq!(
+            created_roadmap.roadmap,
+            "Python to ML\n\nA short plan.\n\n1. Python (1 month)\nLearn the basics.\n\
+             - https://docs.python.org/3/tutorial/\n\n2. Pandas\nLoad and clean data.\n"
+        );
+        let function = backend.params()[0].function.clone().unwrap();
+        assert_eq!(function.name, ROADMAP_FUNCTION);
+    }
+
+    #[tokio::test]
+    async fn structured_creation_takes_a_free_text_reply_as_it_is() {
+        let backend = Arc::new(
+            MockChatBackend::new(&["1. Learn Python", "1. Learn Python"]).usage(Usage {
+                prompt_tokens: 100,
+                completion_tokens: 10,
+                total_tokens: 110,
+            }),
+        );
+        let created_roadmap = RoadmapRequest::new("I'd like a roadmap")
+            .backend(backend.clone())
+            .create_structured()
+            .await
+            .unwrap();
+        assert!(created_roadmap.structured.is_none());
+        assert_eq!(created_roadmap.roadmap, "1. Learn Python");
+        assert_eq!(created_roadmap.usage.unwrap().total_tokens, 110);
+        assert_eq!(backend.params().len(), 1);
+    }
+
+    #[test]
+    fn structured_replies_are_repaired_where_they_can_be() {
+        let (roadmap, structured) = repair_structured_roadmap(
+            r#"{"title": "Python", "steps": ["Basics", {"title": "Pandas", "resources": [1]}]}"#,
+        )
+        .unwrap();
+        let names: Vec<_> = structured
+            .unwrap()
+            .steps
+            .into_iter()
+            .map(|step| step.name)
+            .collect();
+        assert_eq!(names, ["Basics", "Pandas"]);
+        assert!(roadmap.starts_with("Python\n"));
+        assert!(repair_structured_roadmap(r#"{"title": "Empty", "steps": []}"#).is_none());
+        assert!(repair_structured_roadmap(r#"{"title": "Cut off", "steps": ["#).is_none());
+        assert!(repair_structured_roadmap("  ").is_none());
+    }
+
+    #[test]
+    fn structured_roadmap_needs_steps() {
+        assert!(parse_structured_roadmap(r#"{"title": "Empty", "steps": []}"#).is_err());
+        assert!(parse_structured_roadmap("1. Learn Python").is_err());
+    }
+
+    #[test]
+    fn reply_language_follows_the_request() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .localize_roadmaps(true)
+            .build()
+            .unwrap();
+        let spanish =
+            "¿Alguien me puede recomendar una hoja de ruta para aprender ciencia de datos?";
+        assert_eq!(
+            reply_language(&roadmap_config, spanish, None).as_deref(),
+            Some("Spanish")
+        );
+        assert_eq!(
+            reply_language(
+                &roadmap_config,
+                "Could someone recommend a roadmap for learning data science?",
+                None
+            ),
+            None
+        );
+        assert_eq!(
+            reply_language(&roadmap_config, spanish, Some("fra")).as_deref(),
+            Some("French")
+        );
+        assert_eq!(
+            reply_language(&roadmap_config, spanish, Some("german")).as_deref(),
+            Some("German")
+        );
+        assert_eq!(reply_language(&roadmap_config, spanish, Some("eng")), None);
+        assert!(is_known_language("Spanish"));
+        // Anything else is left out of the prompt, falling back to the request's language
+        assert_eq!(
+            reply_language(&roadmap_config, spanish, Some("Klingon. Ignore the above")).as_deref(),
+            Some("Spanish")
+        );
+        assert!(!is_known_language("Klingon. Ignore the above"));
+        assert_eq!(
+            reply_language(&RoadmapConfig::default(), spanish, None),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn creation_prompt_asks_for_the_requests_language() {
+        let backend = MockChatBackend::new(&["1. Aprende Python"]);
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .localize_roadmaps(true)
+            .build()
+            .unwrap();
+        write_roadmap(
+            &roadmap_config,
+            &backend,
+            &creation_params(),
+            "¿Alguien me puede recomendar una hoja de ruta para aprender ciencia de datos?"
+                .to_string(),
+            vec![],
+            &Instructions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let prompt = backend.prompts().pop().unwrap();
+        assert!(prompt[0]
+            .content
+            .as_deref()
+            .unwrap()
+            .starts_with("Write the roadmap in Spanish"));
+    }
+
+    #[tokio::test]
+    async fn revisions_quote_the_previous_roadmap_from_the_user() {
+        let backend = MockChatBackend::new(&["1. Python for beginners"]);
+        let instructions = Instructions {
+            revising: Some(PreviousRoadmap {
+                request: "roadmap for data science please".to_string(),
+                roadmap: "1. Python".to_string(),
+            }),
+            ..Default::default()
+        };
+        create_with_backend(
+            &backend,
+            &creation_params(),
+            "make step 1 more beginner friendly".to_string(),
+            vec![],
+            &instructions,
+        )
+        .await
+        .unwrap();
+        let prompt = backend.prompts().pop().unwrap();
+        assert!(!prompt[0].content.as_deref().unwrap().contains("1. Python"));
+        assert!(matches!(prompt[1].role, ChatCompletionMessageRole::User));
+        assert!(prompt[1].content.as_deref().unwrap().ends_with("1. Python"));
+        assert_eq!(
+            prompt[2].content.as_deref(),
+            Some(format!("{CURRENT_MESSAGE_LABEL}\nmake step 1 more beginner friendly").as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn streaming_creation_sends_chunks() {
+        let backend = MockChatBackend::new(&["1. Learn Python"]);
+        let (chunks, mut received) = mpsc::channel(8);
+        let created_roadmap = write_roadmap(
+            &RoadmapConfig::default(),
+            &backend,
+            &creation_params(),
+            "I'd like a roadmap".to_string(),
+            vec![],
+            &Instructions::default(),
+            Some(chunks),
+        )
+        .await
+        .unwrap();
+        assert_eq!(created_roadmap.roadmap, "1. Learn Python");
+        assert_eq!(received.recv().await.as_deref(), Some("1. Learn Python"));
+        assert_eq!(received.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn roadmap_stream_yields_chunks_then_collects() {
+        let backend = Arc::new(MockChatBackend::new(&["1. Learn Python"]));
+        let request = RoadmapRequest::new("I'd like a roadmap").backend(backend.clone());
+        let chunks: Vec<_> = create_roadmap_stream(request.clone()).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_deref().unwrap(), "1. Learn Python");
+
+        let backend = Arc::new(MockChatBackend::new(&["1. Learn Python"]));
+        let created = create_roadmap_stream(request.backend(backend))
+            .collect_roadmap()
+            .await
+            .unwrap();
+        assert_eq!(created.roadmap, "1. Learn Python");
+        assert_eq!(created.model, ROADMAP_CONFIG.creation_model);
+
+        let request =
+            RoadmapRequest::new("I'd like a roadmap").backend(Arc::new(MockChatBackend::new(&[])));
+        let chunks: Vec<_> = create_roadmap_stream(request.clone()).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_err());
+        assert!(create_roadmap_stream(request)
+            .collect_roadmap()
+            .await
+            .is_err());
+    }
+
+    /// Streams numbered chunks until nobody takes them, then says it stopped.
+    struct EndlessChatBackend(mpsc::Sender<()>);
+
+    #[serenity::async_trait]
+    impl ChatBackend for EndlessChatBackend {
+        async fn complete(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            bail!("Only streams")
+        }
+
+        async fn complete_stream(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+            chunks: mpsc::Sender<String>,
+        ) -> anyhow::Result<ChatReply> {
+            for step in 1.. {
+                if chunks.send(format!("{step}. Learn more\n")).await.is_err() {
+                    break;
+                }
+            }
+            let _ = self.0.send(()).await;
+            bail!("Roadmap stream was dropped")
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_a_roadmap_stream_stops_creation() {
+        let (stopped, mut has_stopped) = mpsc::channel(1);
+        let request = RoadmapRequest::new("I'd like a roadmap")
+            .backend(Arc::new(EndlessChatBackend(stopped)));
+        let mut chunks = create_roadmap_stream(request);
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "1. Learn more\n");
+        drop(chunks);
+        tokio::time::timeout(Duration::from_secs(5), has_stopped.recv())
+            .await
+            .unwrap();
+    }
+
+    /// Turns down every prompt, like OpenAI stopping a reply with its content filter.
+    struct RefusingChatBackend;
+
+    #[serenity::async_trait]
+    impl ChatBackend for RefusingChatBackend {
+        async fn complete(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            Err(RoadmapError::Refused {
+                model: params.model.clone(),
+            }
+            .into())
+        }
+    }
+
+    #[tokio::test]
+    async fn refusal_gets_its_own_apology() {
+        let error = RoadmapRequest::new("A roadmap for something dodgy please")
+            .backend(Arc::new(RefusingChatBackend))
+            .create_structured()
+            .await
+            .unwrap_err();
+        assert!(matches!(error, RoadmapError::Refused { .. }));
+        assert_eq!(
+            apology(&anyhow::Error::from(error)),
+            Some("I can't help with that.")
+        );
+    }
+
+    /// Fails like OpenAI does when `OPENAI_KEY` is wrong, with the body of its 401.
+    struct UnauthenticatedChatBackend;
+
+    #[serenity::async_trait]
+    impl ChatBackend for UnauthenticatedChatBackend {
+        async fn complete(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            let error: openai::OpenAiError = serde_json::from_value(serde_json::json!({
+                "message": "Incorrect API key provided: sk-abc. You can find your API key at https://platform.openai.com/account/api-keys.",
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "invalid_api_key",
+            }))?;
+            Err(error.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_api_key_is_reported_as_such() {
+        let error = RoadmapRequest::new("A roadmap for data science please")
+            .backend(Arc::new(UnauthenticatedChatBackend))
+            .create_structured()
+            .await
+            .unwrap_err();
+        assert!(matches!(error, RoadmapError::Unauthenticated));
+        assert_eq!(
+            error.to_string(),
+            "OpenAI API key is missing or invalid, check OPENAI_KEY"
+        );
+        assert!(apology(&anyhow::Error::from(error)).is_some());
+    }
+
+    /// Never answers, like an OpenAI request stuck on a stalled connection.
+    struct StalledChatBackend;
+
+    #[serenity::async_trait]
+    impl ChatBackend for StalledChatBackend {
+        async fn complete(
+            &self,
+            _messages: Vec<ChatCompletionMessage>,
+            _params: &ChatParams,
+        ) -> anyhow::Result<ChatReply> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_detection_times_out() {
+        let error = with_call_timeout(
+            "detection",
+            Duration::from_millis(10),
+            detect_with_backend(
+                &StalledChatBackend,
+                &detection_params(),
+                &no_cache(),
+                "I'd like a roadmap".to_string(),
+                vec![],
+                None,
+            ),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            RoadmapError::Timeout {
+                call: "detection",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn cost_estimate_uses_configured_prices() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .prompt_price_per_million(0.15)
+            .completion_price_per_million(0.6)
+            .build()
+            .unwrap();
+        assert_eq!(roadmap_config.cost_usd(0, 0), 0.0);
+        assert!((roadmap_config.cost_usd(1_000_000, 0) - 0.15).abs() < 1e-9);
+        assert!((roadmap_config.cost_usd(2_000, 1_000) - 0.0009).abs() < 1e-9);
+    }
+
+    #[test]
+    fn prompt_token_estimate_matches_tokenizer() {
+        let hello = || utilities::user_message("hello world".to_string());
+        // 2 tokens of text plus the per-message overhead
+        assert_eq!(estimate_prompt_tokens(&[hello()]), 6);
+        assert_eq!(estimate_prompt_tokens(&[hello(), hello()]), 12);
+        assert_eq!(estimate_prompt_tokens(&[]), 0);
+    }
+
+    #[test]
+    fn request_estimate_covers_the_whole_prompt() {
+        let short = RoadmapRequest::new("A roadmap for Rust?");
+        let long = short
+            .clone()
+            .context(vec!["I've used Python for a few years".to_string()]);
+        let (short_tokens, short_cost) = short.estimate();
+        // The creation prompt and the labelled message
+        assert_eq!(short_tokens, 242);
+        // 10 tokens of context plus the per-message overhead
+        assert_eq!(long.estimate().0, 256);
+        // The prompt and all 1024 allowed completion tokens at gpt-4o-mini prices
+        assert!((short_cost - 0.0006507).abs() < 1e-9);
+    }
+
+    #[test]
+    fn system_prompt_prefix_and_suffix_wrap_the_prompt() {
+        assert_eq!(
+            customize_prompt(&RoadmapConfig::default(), CREATE_ROADMAP_PROMPT),
+            CREATE_ROADMAP_PROMPT
+        );
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .system_prompt_prefix("This is the Rust Study server.".to_string())
+            .system_prompt_suffix("Prefer Rust resources.\n".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(
+            customize_prompt(&roadmap_config, DETECT_ROADMAP_PROMPT),
+            format!(
+                "This is the Rust Study server.\n\n{DETECT_ROADMAP_PROMPT}\n\nPrefer Rust resources."
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn unparseable_detection_is_a_parse_failure() {
+        let backend = Arc::new(MockChatBackend::new(&["Sure!", "Still not JSON"]));
+        let error = RoadmapRequest::new("I'd like a roadmap")
+            .backend(backend)
+            .detect()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RoadmapError::ParseFailed {
+                what: "detection response",
+                ref reply,
+                ..
+            } if reply == "Still not JSON"
+        ));
+        assert_eq!(error.apology(), None);
+    }
+
+    #[tokio::test]
+    async fn backend_failures_are_api_errors() {
+        let backend = Arc::new(MockChatBackend::new(&[]));
+        let error = RoadmapRequest::new("I'd like a roadmap")
+            .backend(backend)
+            .create()
+            .await
+            .unwrap_err();
+        assert!(matches!(error, RoadmapError::ApiError(_)));
+        assert_eq!(error.to_string(), "MockChatBackend ran out of replies");
+        assert_eq!(error.apology(), None);
+    }
+
+    #[test]
+    fn roadmap_errors_survive_anyhow() {
+        let wrapped = anyhow::Error::from(RoadmapError::Refused {
+            model: "gpt-4o-mini".to_string(),
+        })
+        .context("while creating a roadmap");
+        assert!(matches!(
+            RoadmapError::from(wrapped),
+            RoadmapError::Refused { .. }
+        ));
+        let exhausted = anyhow::anyhow!("rate limited").context(RetriesExhausted { retries: 2 });
+        let error = RoadmapError::from(exhausted);
+        assert!(matches!(error, RoadmapError::ApiError(_)));
+        assert_eq!(
+            apology(&anyhow::Error::from(error)),
+            Some("the AI is busy right now, try again in a minute.")
+        );
+    }
+
+    #[tokio::test]
+    async fn call_timeout_passes_through_results() {
+        let backend = MockChatBackend::new(&["1. Learn Python"]);
+        let created_roadmap = with_call_timeout(
+            "creation",
+            Duration::from_secs(1),
+            create_with_backend(
+                &backend,
+                &creation_params(),
+                "I'd like a roadmap".to_string(),
+                vec![],
+                &Instructions::default(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(created_roadmap.roadmap, "1. Learn Python");
+    }
+
+    #[tokio::test]
+    async fn health_check_sends_one_token_probe() {
+        let backend = MockChatBackend::new(&["pong"]);
+        probe_llm(&backend, "gpt-4o-mini").await.unwrap();
+        let params = backend.params();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].max_tokens, Some(1));
+        assert!(params[0].function.is_none());
+        let error = probe_llm(&MockChatBackend::new(&[]), "gpt-4o-mini")
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("Health check completion with gpt-4o-mini"));
+        let spent = BudgetedBackend::new(
+            MockChatBackend::new(&[]),
+            Arc::new(SpendBudget::new(Some(0.0), 1.0, 1.0, None)),
+        );
+        probe_llm(&spent, "gpt-4o-mini").await.unwrap();
+    }
+
+    #[test]
+    fn load_config_from_file() {
+        let path = env::temp_dir().join("roadmaps_load_config_from_file.toml");
+        std::fs::write(&path, "context_length = 10\ncreation_model = \"gpt-4o\"\n").unwrap();
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(roadmap_config.context_length, 10);
+        assert_eq!(roadmap_config.creation_model, "gpt-4o");
+        assert_eq!(roadmap_config.detection_model, "gpt-4o-mini");
+        assert_eq!(roadmap_config.message_limit_chars, 2048);
+        assert_eq!(roadmap_config.creation_max_tokens, 1024);
+        assert_eq!(roadmap_config.detection_temperature, 0.0);
+    }
+
+    #[test]
+    fn deprecated_model_key_sets_both_models() {
+        let path = env::temp_dir().join("roadmaps_deprecated_model_key_sets_both_models.toml");
+        std::fs::write(&path, "model = \"gpt-4o\"\n").unwrap();
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(roadmap_config.detection_model, "gpt-4o");
+        assert_eq!(roadmap_config.creation_model, "gpt-4o");
+        std::fs::write(&path, "model = \"gpt-4o\"\ncreation_model = \"gpt-4\"\n").unwrap();
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(roadmap_config.detection_model, "gpt-4o");
+        assert_eq!(roadmap_config.creation_model, "gpt-4");
+    }
+
+    #[test]
+    fn deprecated_max_tokens_key_sets_both_limits() {
+        let path = env::temp_dir().join("roadmaps_deprecated_max_tokens_key_sets_both_limits.toml");
+        std::fs::write(&path, "max_tokens = 512\ndetection_max_tokens = 128\n").unwrap();
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(roadmap_config.detection_max_tokens, 128);
+        assert_eq!(roadmap_config.creation_max_tokens, 512);
+    }
+
+    #[test]
+    fn channels_can_have_their_own_context_budget() {
+        let path = env::temp_dir().join("roadmaps_channels_can_have_their_own_context_budget.toml");
+        std::fs::write(
+            &path,
+            "[[channel_context_budgets]]\nchannel_id = 42\ncontext_length = 10\nmessage_limit_chars = 8000\n",
+        )
+        .unwrap();
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(
+            roadmap_config.channel_context_budget(ChannelId::new(42)),
+            Some(ContextBudget {
+                context_length: 10,
+                message_limit_chars: 8000,
+            })
+        );
+        assert_eq!(
+            roadmap_config.channel_context_budget(ChannelId::new(43)),
+            None
+        );
+    }
+
+    #[test]
+    fn load_config_from_json() {
+        let path = env::temp_dir().join("roadmaps_load_config_from_json.json");
+        std::fs::write(&path, "{\"message_limit_chars\": 4096}").unwrap();
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(roadmap_config.message_limit_chars, 4096);
+        assert_eq!(roadmap_config.context_length, 3);
+    }
+
+    #[test]
+    fn builder_overrides_only_what_is_set() {
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .context_length(10)
+            .creation_model("gpt-4o".to_string())
+            .daily_budget_usd(Some(5.0))
+            .build()
+            .unwrap();
+        assert_eq!(roadmap_config.context_length, 10);
+        assert_eq!(roadmap_config.creation_model, "gpt-4o");
+        assert_eq!(roadmap_config.daily_budget_usd, Some(5.0));
+        assert_eq!(roadmap_config.detection_model, "gpt-4o-mini");
+        assert_eq!(roadmap_config.message_limit_chars, 2048);
+    }
+
+    #[test]
+    fn builder_rejects_invalid_config() {
+        let built = RoadmapConfigBuilder::new()
+            .detection_threshold(0.2)
+            .uncertain_threshold(0.8)
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[test]
+    fn missing_config_uses_defaults() {
+        let path = env::temp_dir().join("roadmaps_missing_config_uses_defaults.toml");
+        let _ = std::fs::remove_file(&path);
+        let roadmap_config = RoadmapConfig::load(&path).unwrap();
+        assert_eq!(roadmap_config.context_length, 3);
+    }
+
+    #[test]
+    fn malformed_config_is_an_error() {
+        let path = env::temp_dir().join("roadmaps_malformed_config_is_an_error.toml");
+        std::fs::write(&path, "context_length = \"lots\"").unwrap();
+        let error = RoadmapConfig::load(&path).unwrap_err();
+        assert!(format!("{error:#}").contains("Failed to parse roadmap config"));
+    }
+
+    #[test]
+    fn prompts_default_to_embedded() {
+        let prompts = RoadmapPrompts::load(&RoadmapConfig::default()).unwrap();
+        assert_eq!(prompts.detect, DETECT_ROADMAP_PROMPT);
+        assert_eq!(prompts.create, CREATE_ROADMAP_PROMPT);
+    }
+
+    #[test]
+    fn prompts_load_from_files() {
+        let path = env::temp_dir().join("roadmaps_prompts_load_from_files.txt");
+        std::fs::write(&path, "Write a short roadmap.").unwrap();
+        let prompts = RoadmapPrompts::load(
+            &RoadmapConfigBuilder::new()
+                .create_prompt_path(Some(path))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(prompts.detect, DETECT_ROADMAP_PROMPT);
+        assert_eq!(prompts.create, "Write a short roadmap.");
+    }
+
+    #[test]
+    fn changed_prompts_reload_unless_broken() {
+        let path = env::temp_dir().join("roadmaps_changed_prompts_reload.txt");
+        std::fs::write(&path, "Write a short roadmap.").unwrap();
+        let roadmap_config = RoadmapConfigBuilder::new()
+            .create_prompt_path(Some(path.clone()))
+            .build()
+            .unwrap();
+        let cache = PromptCache::load(&roadmap_config).unwrap();
+        assert_eq!(cache.current().create, "Write a short roadmap.");
+        std::fs::write(&path, " \n").unwrap();
+        cache.refresh(&roadmap_config);
+        assert_eq!(cache.current().create, "Write a short roadmap.");
+        std::fs::write(&path, "Write a long roadmap.").unwrap();
+        cache.refresh(&roadmap_config);
+        assert_eq!(cache.current().create, "Write a long roadmap.");
+        assert_eq!(cache.current().detect, DETECT_ROADMAP_PROMPT);
+    }
+
+    #[test]
+    fn only_changes_to_prompt_files_reload() {
+        let paths = [Path::new("prompts/create_roadmap_for_user.txt")];
+        let event = |kind, path: &str| notify::Event::new(kind).add_path(PathBuf::from(path));
+        let modified = notify::EventKind::Modify(notify::event::ModifyKind::Any);
+        assert!(touches_prompt(
+            &event(modified, "/bot/prompts/create_roadmap_for_user.txt"),
+            &paths
+        ));
+        assert!(!touches_prompt(
+            &event(modified, "/bot/prompts/detect_roadmap.txt"),
+            &paths
         ));
+        let read = notify::EventKind::Access(notify::event::AccessKind::Any);
+        assert!(!touches_prompt(
+            &event(read, "/bot/prompts/create_roadmap_for_user.txt"),
+            &paths
+        ));
+    }
+
+    #[test]
+    fn empty_or_missing_prompt_is_an_error() {
+        let path = env::temp_dir().join("roadmaps_empty_prompt_is_an_error.txt");
+        std::fs::write(&path, " \n").unwrap();
+        let error = RoadmapPrompts::load(
+            &RoadmapConfigBuilder::new()
+                .detect_prompt_path(Some(path))
+                .build()
+                .unwrap(),
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("is empty"));
+        let path = env::temp_dir().join("roadmaps_missing_prompt_is_an_error.txt");
+        let _ = std::fs::remove_file(&path);
+        assert!(RoadmapPrompts::load(
+            &RoadmapConfigBuilder::new()
+                .detect_prompt_path(Some(path))
+                .build()
+                .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn detection_cache_is_off_by_default() {
+        let cache = RoadmapConfig::default().detection_cache();
+        cache.insert(
+            1,
+            parse_detection_response(r#"{"reason": "", "is_roadmap": true}"#).unwrap(),
+        );
+        assert!(cache.get(1).is_none());
+        let cache = RoadmapConfigBuilder::new()
+            .detection_cache(true)
+            .build()
+            .unwrap()
+            .detection_cache();
+        cache.insert(
+            1,
+            parse_detection_response(r#"{"reason": "", "is_roadmap": true}"#).unwrap(),
+        );
+        assert!(cache.get(1).is_some());
+    }
+
+    #[test]
+    fn loggable_hides_message_content() {
+        let expected = if cfg!(feature = "log-message-content") {
+            "héllo"
+        } else {
+            "<5 chars>"
+        };
+        assert_eq!(loggable("héllo"), expected);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let invalid = [
+            RoadmapConfigBuilder::new().message_limit_chars(0),
+            RoadmapConfigBuilder::new().creation_temperature(2.5),
+            RoadmapConfigBuilder::new().thread_archive_minutes(90),
+            RoadmapConfigBuilder::new().min_context_messages(4),
+            // Shorter than a last retry could take
+            RoadmapConfigBuilder::new().detection_timeout_secs(10),
+        ];
+        for builder in invalid {
+            assert!(builder.clone().build().is_err(), "{builder:?}");
+        }
+        for api_base_url in ["localhost:8000/v1", "ftp://models.example/v1", ""] {
+            let built = RoadmapConfigBuilder::new()
+                .api_base_url(Some(api_base_url.to_string()))
+                .build();
+            assert!(built.is_err(), "{api_base_url}");
+        }
+        let built = RoadmapConfigBuilder::new()
+            .api_base_url(Some("http://localhost:8000/v1".to_string()))
+            .build();
+        assert!(built.is_ok());
+        assert!(RoadmapConfig::default().validate().is_ok());
     }
 }
@@ -0,0 +1,384 @@
+use crate::spam_detection;
+use crate::strikes::Severity;
+use crate::text_normalization::normalize_for_matching;
+use anyhow::{bail, ensure, Context as _};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use serenity::all::{
+    ChannelId, CommandInteraction, CommandOptionType, Context, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Message, Permissions,
+};
+use serenity::prelude::TypeMapKey;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+/// Name of the slash command that reloads the scam rules.
+pub(crate) const COMMAND_NAME: &str = "scam-rules";
+
+/// What a rule suggests doing with a message it matches, weakest first.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RuleAction {
+    /// Only add to the message's spam score.
+    Score,
+    /// Send the message to moderators, or score it when there's no review channel.
+    Review,
+    /// Treat the message as spam whatever else it scores.
+    Delete,
+}
+
+/// A rule as written in the rules file.
+#[derive(Deserialize, Debug, Clone)]
+struct RuleSpec {
+    name: String,
+    pattern: String,
+    #[serde(default = "default_severity")]
+    severity: Severity,
+    #[serde(default = "default_action")]
+    action: RuleAction,
+    /// Channels the rule applies in, every channel when empty.
+    #[serde(default)]
+    channels: Vec<u64>,
+}
+
+fn default_severity() -> Severity {
+    Severity::Medium
+}
+
+fn default_action() -> RuleAction {
+    RuleAction::Score
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct RulesFile {
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Debug, Clone)]
+struct ScamRule {
+    name: String,
+    regex: Regex,
+    severity: Severity,
+    action: RuleAction,
+    channels: Vec<u64>,
+}
+
+impl ScamRule {
+    fn applies_in(&self, channel_id: ChannelId) -> bool {
+        self.channels.is_empty() || self.channels.contains(&channel_id.get())
+    }
+}
+
+/// The rule that decided a message's fate, out of all that matched it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RuleMatch {
+    pub(crate) name: String,
+    pub(crate) severity: Severity,
+    pub(crate) action: RuleAction,
+}
+
+impl RuleMatch {
+    /// How much the match adds to the message's spam score.
+    pub(crate) fn weight(&self) -> f32 {
+        match self.severity {
+            Severity::Low => 0.15,
+            Severity::Medium => 0.3,
+            Severity::High => 0.5,
+        }
+    }
+}
+
+/// Phrases moderators have seen in scams, compiled from the rules file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ScamRules {
+    rules: Vec<ScamRule>,
+}
+
+impl ScamRules {
+    /// Reads and compiles a TOML or JSON file of `[[rules]]`, or no rules if the file
+    /// doesn't exist. Any bad pattern fails the whole file, naming the rule.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<ScamRules> {
+        let rules_file: RulesFile = config::Config::builder()
+            .add_source(config::File::from(path).required(false))
+            .build()
+            .and_then(|loaded| loaded.try_deserialize())
+            .with_context(|| format!("Failed to parse scam rules {}", path.display()))?;
+        let rules = rules_file
+            .rules
+            .into_iter()
+            .map(|spec| {
+                ensure!(!spec.name.trim().is_empty(), "Every rule needs a name");
+                let regex = RegexBuilder::new(spec.pattern.as_str())
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("Rule \"{}\" has a bad pattern", spec.name))?;
+                Ok(ScamRule {
+                    name: spec.name,
+                    regex,
+                    severity: spec.severity,
+                    action: spec.action,
+                    channels: spec.channels,
+                })
+            })
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("Invalid scam rules {}", path.display()))?;
+        Ok(ScamRules { rules })
+    }
+
+    /// The strongest rule matching `text` in `channel_id`: the most severe, then the one
+    /// with the strongest action, then whichever comes first in the file.
+    pub(crate) fn best_match(&self, text: &str, channel_id: ChannelId) -> Option<RuleMatch> {
+        let text = normalize_for_matching(text);
+        self.rules
+            .iter()
+            .rev()
+            .filter(|rule| rule.applies_in(channel_id) && rule.regex.is_match(text.as_str()))
+            .max_by_key(|rule| (rule.severity, rule.action))
+            .map(|rule| RuleMatch {
+                name: rule.name.clone(),
+                severity: rule.severity,
+                action: rule.action,
+            })
+    }
+
+    fn describe(&self) -> String {
+        match self.rules.len() {
+            0 => "No scam rules.".to_string(),
+            count => format!(
+                "{count} scam rule(s): {}.",
+                self.rules
+                    .iter()
+                    .map(|rule| format!("{} ({:?}, {:?})", rule.name, rule.severity, rule.action))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// The scam rules in use, replaced by `/scam-rules reload`.
+pub(crate) struct ScamRuleSet;
+
+impl TypeMapKey for ScamRuleSet {
+    type Value = Arc<RwLock<ScamRules>>;
+}
+
+/// The scam rules on disk. A bad file is logged and leaves the bot running without rules
+/// until it's fixed and reloaded.
+pub(crate) fn load_scam_rules() -> ScamRules {
+    ScamRules::load(&spam_detection::scam_rules_path())
+        .inspect_err(|e| error!("Starting without scam rules: {e:#}"))
+        .unwrap_or_default()
+}
+
+async fn scam_rules(ctx: &Context) -> Arc<RwLock<ScamRules>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<ScamRuleSet>()
+        .expect("Expected ScamRuleSet in TypeMap.")
+        .clone()
+}
+
+/// The strongest scam rule `message` matches, if any.
+pub(crate) async fn check_rules(ctx: &Context, message: &Message) -> Option<RuleMatch> {
+    scam_rules(ctx)
+        .await
+        .read()
+        .await
+        .best_match(message.content.as_str(), message.channel_id)
+}
+
+/// `/scam-rules reload|show`, for members who can manage the server.
+pub(crate) fn command() -> CreateCommand {
+    CreateCommand::new(COMMAND_NAME)
+        .description("Reload or show the scam keyword rules")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "reload",
+            "Read the scam rules file again",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "show",
+            "Show the scam rules in use",
+        ))
+}
+
+/// Applies a `/scam-rules` command and replies privately. A file that fails to load
+/// leaves the rules in use unchanged and says why.
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+) -> anyhow::Result<()> {
+    let scam_rules = scam_rules(ctx).await;
+    let options = command.data.options();
+    let Some(subcommand) = options.first() else {
+        bail!("/{COMMAND_NAME} was sent without a subcommand");
+    };
+    let reply = match subcommand.name {
+        "show" => scam_rules.read().await.describe(),
+        "reload" => match ScamRules::load(&spam_detection::scam_rules_path()) {
+            Ok(reloaded) => {
+                info!("Reloaded scam rules {}", reloaded.describe());
+                let reply = format!("Reloaded. {}", reloaded.describe());
+                *scam_rules.write().await = reloaded;
+                reply
+            }
+            Err(e) => format!("Kept the current rules, the file didn't load: {e:#}"),
+        },
+        name => bail!("Unknown /{COMMAND_NAME} subcommand {name}"),
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(reply)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const GENERAL: ChannelId = ChannelId::new(1);
+    const TRADING: ChannelId = ChannelId::new(2);
+
+    fn load(name: &str, rules: &str) -> anyhow::Result<ScamRules> {
+        let path = env::temp_dir().join(format!("{name}.toml"));
+        std::fs::write(&path, rules).unwrap();
+        ScamRules::load(&path)
+    }
+
+    /// The shared rules, written to a file named after the test so tests running at the
+    /// same time don't overwrite each other's.
+    fn rules(name: &str) -> ScamRules {
+        load(
+            name,
+            r#"
+            [[rules]]
+            name = "nitro"
+            pattern = 'claim\s+your\s+nitro'
+            severity = "high"
+            action = "review"
+
+            [[rules]]
+            name = "nitro link"
+            pattern = 'nitro.*https?://'
+            severity = "high"
+            action = "delete"
+
+            [[rules]]
+            name = "steam gift"
+            pattern = '\d+\s*\$\s*steam\s*gift'
+
+            [[rules]]
+            name = "any nitro"
+            pattern = 'nitro'
+            severity = "low"
+
+            [[rules]]
+            name = "telegram handle"
+            pattern = 't\.me/\w+'
+            severity = "medium"
+            channels = [1]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn matched(rules: &ScamRules, text: &str, channel_id: ChannelId) -> Option<String> {
+        rules
+            .best_match(text, channel_id)
+            .map(|rule_match| rule_match.name)
+    }
+
+    #[test]
+    fn most_severe_then_strongest_then_first_wins() {
+        let rules = rules("most_severe_then_strongest_then_first_wins");
+        for (text, expected) in [
+            ("I love nitro", Some("any nitro")),
+            ("CLAIM YOUR NITRO now", Some("nitro")),
+            ("Claim your nitro at https://x.example", Some("nitro link")),
+            ("50$ steam gift, nitro too", Some("steam gift")),
+            ("How do I start with pandas?", None),
+        ] {
+            assert_eq!(
+                matched(&rules, text, GENERAL).as_deref(),
+                expected,
+                "{text}"
+            );
+        }
+        let rules = load(
+            "scam_rules_tie",
+            r#"
+            [[rules]]
+            name = "first"
+            pattern = 'airdrop'
+            [[rules]]
+            name = "second"
+            pattern = 'airdrop'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules.best_match("airdrop", GENERAL).unwrap(),
+            RuleMatch {
+                name: "first".to_string(),
+                severity: Severity::Medium,
+                action: RuleAction::Score,
+            }
+        );
+    }
+
+    #[test]
+    fn scoped_rules_only_apply_in_their_channels() {
+        let rules = rules("scoped_rules_only_apply_in_their_channels");
+        assert_eq!(
+            matched(&rules, "dm t.me/scammer", GENERAL).as_deref(),
+            Some("telegram handle")
+        );
+        assert_eq!(matched(&rules, "dm t.me/scammer", TRADING), None);
+        assert_eq!(
+            matched(&rules, "nitro at t.me/scammer", TRADING).as_deref(),
+            Some("any nitro")
+        );
+    }
+
+    #[test]
+    fn rules_match_normalized_text() {
+        let rules = rules("rules_match_normalized_text");
+        assert_eq!(
+            matched(&rules, "ｃｌａｉｍ ｙｏｕｒ ＮIТRO", GENERAL).as_deref(),
+            Some("nitro")
+        );
+    }
+
+    #[test]
+    fn bad_rules_fail_to_load_with_their_name() {
+        let e = load(
+            "scam_rules_bad",
+            "[[rules]]\nname = \"broken\"\npattern = 'free (nitro'\n",
+        )
+        .unwrap_err();
+        assert!(format!("{e:#}").contains("Rule \"broken\" has a bad pattern"));
+        assert!(load(
+            "scam_rules_bad_severity",
+            "[[rules]]\nname = \"x\"\npattern = 'x'\nseverity = \"extreme\"\n"
+        )
+        .is_err());
+        let missing = env::temp_dir().join("scam_rules_missing.toml");
+        let _ = std::fs::remove_file(&missing);
+        assert!(ScamRules::load(&missing).unwrap().rules.is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref EMAIL_REGEX: Regex =
+        Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b").unwrap();
+    // Runs of digits with the usual separators, checked for a phone number's length after
+    static ref PHONE_REGEX: Regex = Regex::new(r"\+?\(?\d[\d ().-]{6,}\d").unwrap();
+    // Scrubbers by what they were built from. There's one per config, which rarely changes.
+    static ref SHARED: Mutex<HashMap<ScrubberKey, Arc<Scrubber>>> = Mutex::new(HashMap::new());
+}
+
+/// Whether PII is redacted, and the profanity masked.
+type ScrubberKey = (bool, Vec<String>);
+
+/// Fewest and most digits a run can have to be taken for a phone number. Shorter runs are
+/// usually dates or years, and longer ones Discord IDs.
+const PHONE_DIGITS: std::ops::RangeInclusive<usize> = 9..=15;
+
+pub(crate) fn default_profanity_words() -> Vec<String> {
+    [
+        "fuck",
+        "fucking",
+        "fucked",
+        "motherfucker",
+        "shit",
+        "shitty",
+        "bullshit",
+        "bitch",
+        "bastard",
+        "asshole",
+        "dickhead",
+        "cunt",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Redacts emails and phone numbers and masks profanity in text bound for the model.
+pub(crate) struct Scrubber {
+    pii: bool,
+    profanity: Option<Regex>,
+}
+
+impl Scrubber {
+    /// Redacts PII when `pii` is set, and masks `profanity` as whole words, ignoring case.
+    pub(crate) fn new(pii: bool, profanity: &[String]) -> Self {
+        let words: Vec<_> = profanity
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect();
+        Scrubber {
+            pii,
+            profanity: (!words.is_empty())
+                .then(|| Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|"))).unwrap()),
+        }
+    }
+
+    /// Like `new`, but reuses the scrubber already built from the same `pii` and
+    /// `profanity`, so the profanity regex isn't compiled for every prompt.
+    pub(crate) fn shared(pii: bool, profanity: &[String]) -> Arc<Self> {
+        SHARED
+            .lock()
+            .unwrap()
+            .entry((pii, profanity.to_vec()))
+            .or_insert_with(|| Arc::new(Scrubber::new(pii, profanity)))
+            .clone()
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.pii || self.profanity.is_some()
+    }
+
+    pub(crate) fn scrub(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if self.pii {
+            text = EMAIL_REGEX.replace_all(&text, "[email]").into_owned();
+            text = PHONE_REGEX
+                .replace_all(&text, |caps: &Captures| {
+                    let digits = caps[0].chars().filter(char::is_ascii_digit).count();
+                    if PHONE_DIGITS.contains(&digits) {
+                        "[phone]".to_string()
+                    } else {
+                        caps[0].to_string()
+                    }
+                })
+                .into_owned();
+        }
+        if let Some(profanity) = &self.profanity {
+            text = profanity
+                .replace_all(&text, |caps: &Captures| "*".repeat(caps[0].chars().count()))
+                .into_owned();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pii() -> Scrubber {
+        Scrubber::new(true, &[])
+    }
+
+    #[test]
+    fn emails_are_redacted() {
+        assert_eq!(
+            pii().scrub("Mail me at Ada.Lovelace+bot@example.co.uk, or ada@mail.io."),
+            "Mail me at [email], or [email]."
+        );
+        assert_eq!(pii().scrub("I use @rust and a@b"), "I use @rust and a@b");
+    }
+
+    #[test]
+    fn phone_numbers_are_redacted() {
+        assert_eq!(
+            pii().scrub("Call +1 (555) 123-4567 or 020 7946 0958, text 555.123.4567"),
+            "Call [phone] or [phone], text [phone]"
+        );
+    }
+
+    #[test]
+    fn dates_years_and_ids_are_not_phone_numbers() {
+        for text in [
+            "Started on 2024-01-15",
+            "From 2019 to 2023, about 1.5 years each",
+            "Thanks <@123456789012345678>",
+            "Version 1.2.3",
+        ] {
+            assert_eq!(pii().scrub(text), text);
+        }
+    }
+
+    #[test]
+    fn profanity_is_masked_as_whole_words() {
+        let scrubber = Scrubber::new(false, &default_profanity_words());
+        assert_eq!(
+            scrubber.scrub("This SHIT is hard, but shitake and ada@example.com are fine"),
+            "This **** is hard, but shitake and ada@example.com are fine"
+        );
+    }
+
+    #[test]
+    fn shared_scrubbers_are_reused_until_the_words_change() {
+        let words = default_profanity_words();
+        let scrubber = Scrubber::shared(true, &words);
+        assert!(Arc::ptr_eq(&scrubber, &Scrubber::shared(true, &words)));
+        let fewer = Scrubber::shared(true, &words[..1]);
+        assert!(!Arc::ptr_eq(&scrubber, &fewer));
+        assert_eq!(fewer.scrub("shit and fuck"), "shit and ****");
+    }
+
+    #[test]
+    fn disabled_scrubber_changes_nothing() {
+        let scrubber = Scrubber::new(false, &[" ".to_string()]);
+        assert!(!scrubber.is_enabled());
+        assert_eq!(
+            scrubber.scrub("shit, ada@example.com"),
+            "shit, ada@example.com"
+        );
+    }
+}
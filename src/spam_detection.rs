@@ -1,18 +1,181 @@
-use anyhow::bail;
+use crate::honeypot::HoneypotConfig;
+use crate::llm::{ChatBackend, ChatParams};
+use crate::member_risk::RiskWeights;
+use crate::roadmaps;
+use crate::roadmaps::{extract_json, extract_json_object};
+use crate::strikes;
+use crate::strikes::{Escalation, Rung};
+use crate::utilities;
+use crate::utilities::PromptBudget;
+use anyhow::{ensure, Context};
 use lazy_static::lazy_static;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+use openai::chat::{
+    ChatCompletionFunctionDefinition, ChatCompletionMessage, ChatCompletionMessageRole,
+};
 use serde::Deserialize;
+use serde_json::json;
+use serenity::all::RoleId;
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, field, info, instrument, Span};
 
 lazy_static! {
-    static ref SPAM_CONFIG: SpamConfig = SpamConfig::default();
+    static ref SPAM_CONFIG: SpamConfig =
+        SpamConfig::from_env().expect("Invalid spam configuration");
 }
 
-static SPAM_PROMPT: &str = include_str!("../prompts/spam_role.txt");
+/// Environment variable pointing at an alternative spam config file (TOML or JSON).
+const SPAM_CONFIG_ENV: &str = "SPAM_CONFIG_PATH";
 
-#[derive(Deserialize)]
-struct SpamConfig {
+/// Config file looked for next to the binary when `SPAM_CONFIG_PATH` is unset.
+const SPAM_CONFIG_FILE: &str = "spam.toml";
+
+static SPAM_PROMPT: &str = include_str!("../prompts/classify_spam.txt");
+
+/// Name of the function spam classification replies through.
+pub(crate) const SPAM_FUNCTION: &str = "classify_spam";
+
+/// What's done to someone caught spamming, on top of deleting their messages.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SpamAction {
+    Delete,
+    Timeout,
+    Ban,
+}
+
+impl SpamAction {
+    /// The least the strike ladder does for this, as the poster's strikes may call for
+    /// more.
+    pub(crate) fn least_escalation(self) -> Escalation {
+        match self {
+            SpamAction::Delete => Escalation::Delete,
+            SpamAction::Timeout => Escalation::LongTimeout,
+            SpamAction::Ban => Escalation::Ban,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct SpamConfig {
     context_length: usize,
     message_limit_chars: usize,
+    /// Distinct channels the same message has to turn up in to count as spam.
+    duplicate_channels: usize,
+    /// Seconds a message is remembered for when counting channels it was posted in.
+    duplicate_window_secs: u64,
+    /// Messages shorter than this, once normalized, are never counted, so "thanks" and
+    /// the like can be posted everywhere.
+    duplicate_min_chars: usize,
+    /// How alike two messages must be, from 0.0 to 1.0, to count as the same. Spammers
+    /// change a word or add emoji between copies, so 1.0 is rarely useful.
+    duplicate_similarity: f64,
+    duplicate_action: SpamAction,
+    /// Roles whose members are never treated as spammers.
+    trusted_roles: Vec<u64>,
+    /// Model asked about messages the heuristics can't decide on.
+    classification_model: String,
+    /// Heuristic scores below this pass without asking the model.
+    clean_below: f32,
+    /// Heuristic scores from this up are spam without asking the model.
+    spam_from: f32,
+    /// How sure the model must be to call a grey-zone message spam.
+    min_spam_confidence: f32,
+    /// Distinct users and roles a message can mention before it's treated as spam.
+    max_mentions: usize,
+    /// TOML or JSON file of `blocked` and `allowed` link domains, reread by
+    /// `/domain-lists reload`.
+    domain_lists_path: String,
+    /// Follow links from `shortener_domains` that aren't on either list, to check where
+    /// they really go.
+    expand_shorteners: bool,
+    shortener_domains: Vec<String>,
+    /// Redirects followed per shortened link, and seconds allowed for each.
+    max_redirects: usize,
+    redirect_timeout_secs: u64,
+    /// Invite codes to our own and partner servers, until `/invite-allowlist` changes them.
+    allowed_invites: Vec<String>,
+    /// Where the invite allowlist is saved once changed by `/invite-allowlist`.
+    invite_allowlist_path: String,
+    /// TOML or JSON file of scam rules, reloaded by `/scam-rules reload`.
+    scam_rules_path: String,
+    /// Seconds after posting an unknown invite in which doing it again gets a timeout.
+    invite_offense_window_secs: u64,
+    /// Be told when members join, for probation and raid detection. Needs the privileged
+    /// Server Members intent, which the bot can't connect without once it's asked for.
+    watch_joins: bool,
+    /// Clean messages someone who joins has to post before they're off probation.
+    probation_messages: usize,
+    /// Risk, from 0.0 to 1.0, from which members on probation can't post links or copies.
+    probation_min_risk: f32,
+    risk_weights: RiskWeights,
+    /// Joins within `raid_window_secs` that turn raid mode on.
+    raid_joins: usize,
+    raid_window_secs: u64,
+    /// Seconds without a join before raid mode lifts slowmode and starts cooling down.
+    raid_quiet_secs: u64,
+    /// Seconds spam thresholds stay tight after slowmode is lifted.
+    raid_cooldown_secs: u64,
+    /// Channels slowed down to `raid_slowmode_secs` during a raid.
+    raid_channels: Vec<u64>,
+    raid_slowmode_secs: u16,
+    /// Role pinged in the bot channel when a raid is detected.
+    raid_mod_role: Option<u64>,
+    /// `clean_below` and `spam_from` while raid mode is on or cooling down.
+    raid_clean_below: f32,
+    raid_spam_from: f32,
+    /// Where hashes of images marked as spam are saved.
+    spam_images_path: String,
+    /// Larger images aren't downloaded to be checked.
+    max_image_bytes: usize,
+    image_timeout_secs: u64,
+    /// Bits out of 64 an image's hash can differ from a spam image's and still match.
+    image_hash_distance: u32,
+    honeypot: HoneypotConfig,
+    /// Channel uncertain verdicts are sent to for moderators to decide on, instead of
+    /// being deleted.
+    review_channel: Option<u64>,
+    /// Role allowed to decide on reviews. Anyone who can manage messages when unset.
+    review_mod_role: Option<u64>,
+    /// How sure the model must be that a message is spam, short of `min_spam_confidence`,
+    /// for it to be reviewed rather than let through.
+    review_min_confidence: f32,
+    /// Hours a review waits for a decision before it expires, leaving the message up.
+    review_expiry_hours: u64,
+    /// Where reviews waiting for a decision are saved.
+    review_queue_path: String,
+    /// Reports each member can make in a burst, then one more every `report_refill_minutes`.
+    report_limit: u32,
+    report_refill_minutes: u64,
+    /// 🚩 reactions that report a message, or `None` to only take `/report`.
+    report_reaction_threshold: Option<usize>,
+    /// Channel every automated action is posted to as an embed. Only logged when unset.
+    mod_log_channel: Option<u64>,
+    /// Post every roadmap written to the mod log too.
+    mod_log_roadmaps: bool,
+    /// SQLite file every message is archived to before the bot deletes it, for
+    /// `/evidence`.
+    evidence_path: String,
+    /// Days archived messages are kept before they're purged.
+    evidence_retention_days: u64,
+    /// Larger attachments are archived by address only, without a hash of their contents.
+    evidence_max_attachment_bytes: usize,
+    evidence_timeout_secs: u64,
+    /// Strikes from which each action is taken, highest reached wins.
+    strike_ladder: Vec<Rung>,
+    /// Hours for someone's strikes to halve.
+    strike_half_life_hours: u64,
+    /// SQLite file state that has to survive restarts is kept in: strikes, stored
+    /// roadmaps, OpenAI spend and roadmap quotas.
+    database_path: String,
+    /// Where strikes were kept before the database, imported when it's first created.
+    strikes_path: String,
+    /// Serve Prometheus metrics on `metrics_address`.
+    metrics: bool,
+    metrics_address: SocketAddr,
 }
 
 impl Default for SpamConfig {
@@ -20,73 +183,647 @@ impl Default for SpamConfig {
         SpamConfig {
             context_length: 3,
             message_limit_chars: 2048,
+            duplicate_channels: 3,
+            duplicate_window_secs: 60,
+            duplicate_min_chars: 10,
+            duplicate_similarity: 0.85,
+            duplicate_action: SpamAction::Timeout,
+            trusted_roles: vec![],
+            classification_model: "gpt-4o-mini".to_string(),
+            clean_below: 0.3,
+            spam_from: 0.8,
+            min_spam_confidence: 0.7,
+            max_mentions: 5,
+            domain_lists_path: "domain_lists.toml".to_string(),
+            expand_shorteners: false,
+            shortener_domains: [
+                "bit.ly",
+                "tinyurl.com",
+                "t.co",
+                "goo.gl",
+                "is.gd",
+                "ow.ly",
+                "cutt.ly",
+                "rb.gy",
+                "shorturl.at",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            max_redirects: 5,
+            redirect_timeout_secs: 5,
+            allowed_invites: vec![],
+            invite_allowlist_path: "invite_allowlist.json".to_string(),
+            scam_rules_path: "scam_rules.toml".to_string(),
+            invite_offense_window_secs: 86400,
+            watch_joins: false,
+            probation_messages: 5,
+            probation_min_risk: 0.5,
+            risk_weights: RiskWeights::default(),
+            raid_joins: 30,
+            raid_window_secs: 120,
+            raid_quiet_secs: 600,
+            raid_cooldown_secs: 900,
+            raid_channels: vec![],
+            raid_slowmode_secs: 30,
+            raid_mod_role: None,
+            raid_clean_below: 0.1,
+            raid_spam_from: 0.5,
+            spam_images_path: "spam_images.json".to_string(),
+            max_image_bytes: 8 * 1024 * 1024,
+            image_timeout_secs: 10,
+            image_hash_distance: 6,
+            honeypot: HoneypotConfig::default(),
+            review_channel: None,
+            review_mod_role: None,
+            review_min_confidence: 0.4,
+            review_expiry_hours: 24,
+            review_queue_path: "review_queue.json".to_string(),
+            report_limit: 5,
+            report_refill_minutes: 10,
+            report_reaction_threshold: None,
+            mod_log_channel: None,
+            mod_log_roadmaps: false,
+            evidence_path: "evidence.db".to_string(),
+            evidence_retention_days: 90,
+            evidence_max_attachment_bytes: 8 * 1024 * 1024,
+            evidence_timeout_secs: 5,
+            strike_ladder: strikes::default_ladder(),
+            strike_half_life_hours: 168,
+            database_path: "bot.db".to_string(),
+            strikes_path: "strikes.json".to_string(),
+            metrics: false,
+            metrics_address: SocketAddr::from(([127, 0, 0, 1], 9185)),
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub(crate) struct IsSpamResult {
+impl SpamConfig {
+    /// Loads the config from `SPAM_CONFIG_PATH`, or `spam.toml` next to the binary.
+    fn from_env() -> anyhow::Result<SpamConfig> {
+        let path = match env::var(SPAM_CONFIG_ENV) {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => env::current_exe()?
+                .parent()
+                .map(|directory| directory.join(SPAM_CONFIG_FILE))
+                .unwrap_or_else(|| PathBuf::from(SPAM_CONFIG_FILE)),
+        };
+        let spam_config = SpamConfig::load(&path)?;
+        info!(
+            "Loaded spam config {:?} from {}",
+            spam_config,
+            path.display()
+        );
+        Ok(spam_config)
+    }
+
+    /// Reads a TOML or JSON config file like `RoadmapConfig::load`, falling back to the
+    /// defaults for a missing file or field.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<SpamConfig> {
+        let spam_config: SpamConfig = config::Config::builder()
+            .add_source(config::File::from(path).required(false))
+            .build()
+            .and_then(|loaded| loaded.try_deserialize())
+            .with_context(|| format!("Failed to parse spam config {}", path.display()))?;
+        spam_config
+            .validate()
+            .with_context(|| format!("Invalid spam config {}", path.display()))?;
+        Ok(spam_config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.duplicate_channels >= 2,
+            "duplicate_channels must be at least 2"
+        );
+        ensure!(
+            self.duplicate_window_secs > 0,
+            "duplicate_window_secs must be greater than 0"
+        );
+        ensure!(
+            self.duplicate_similarity > 0.0 && self.duplicate_similarity <= 1.0,
+            "duplicate_similarity must be above 0 and at most 1"
+        );
+        ensure!(
+            self.trusted_roles.iter().all(|&role_id| role_id != 0),
+            "trusted_roles must be role IDs"
+        );
+        ensure!(
+            0.0 <= self.clean_below && self.clean_below <= self.spam_from && self.spam_from <= 1.0,
+            "clean_below and spam_from must be between 0 and 1, clean_below first"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.min_spam_confidence),
+            "min_spam_confidence must be between 0 and 1"
+        );
+        ensure!(
+            !self.classification_model.trim().is_empty(),
+            "classification_model must not be empty"
+        );
+        ensure!(
+            self.redirect_timeout_secs > 0,
+            "redirect_timeout_secs must be greater than 0"
+        );
+        ensure!(
+            self.invite_offense_window_secs > 0,
+            "invite_offense_window_secs must be greater than 0"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.probation_min_risk),
+            "probation_min_risk must be between 0 and 1"
+        );
+        let weights = &self.risk_weights;
+        ensure!(
+            [
+                weights.account_age,
+                weights.join_age,
+                weights.default_avatar,
+                weights.suspicious_name
+            ]
+            .iter()
+            .all(|&weight| weight >= 0.0),
+            "risk_weights must not be negative"
+        );
+        ensure!(self.raid_joins >= 2, "raid_joins must be at least 2");
+        ensure!(
+            self.raid_window_secs > 0,
+            "raid_window_secs must be greater than 0"
+        );
+        ensure!(
+            self.raid_slowmode_secs <= 21600,
+            "raid_slowmode_secs can be at most 21600, Discord's limit"
+        );
+        ensure!(
+            0.0 <= self.raid_clean_below
+                && self.raid_clean_below <= self.raid_spam_from
+                && self.raid_spam_from <= 1.0,
+            "raid_clean_below and raid_spam_from must be between 0 and 1, raid_clean_below first"
+        );
+        ensure!(
+            self.image_timeout_secs > 0,
+            "image_timeout_secs must be greater than 0"
+        );
+        ensure!(
+            self.image_hash_distance < 64,
+            "image_hash_distance must be less than 64"
+        );
+        ensure!(
+            0.0 <= self.review_min_confidence
+                && self.review_min_confidence <= self.min_spam_confidence,
+            "review_min_confidence must be between 0 and min_spam_confidence"
+        );
+        ensure!(
+            self.review_expiry_hours > 0,
+            "review_expiry_hours must be greater than 0"
+        );
+        ensure!(
+            self.report_limit > 0 && self.report_refill_minutes > 0,
+            "report_limit and report_refill_minutes must be greater than 0"
+        );
+        ensure!(
+            self.evidence_retention_days > 0 && self.evidence_timeout_secs > 0,
+            "evidence_retention_days and evidence_timeout_secs must be greater than 0"
+        );
+        ensure!(
+            self.report_reaction_threshold != Some(0),
+            "report_reaction_threshold must be greater than 0"
+        );
+        ensure!(
+            self.strike_ladder.iter().all(|rung| rung.strikes > 0.0),
+            "strike_ladder strikes must be greater than 0"
+        );
+        ensure!(
+            self.strike_half_life_hours > 0,
+            "strike_half_life_hours must be greater than 0"
+        );
+        Ok(())
+    }
+}
+
+/// Loads the spam config now so a broken file stops the bot at startup.
+pub(crate) fn init_config() {
+    lazy_static::initialize(&SPAM_CONFIG);
+}
+
+/// Whether any of `roles` makes its member trusted, exempting them from spam checks.
+pub(crate) fn is_trusted(roles: &[RoleId]) -> bool {
+    roles
+        .iter()
+        .any(|role_id| SPAM_CONFIG.trusted_roles.contains(&role_id.get()))
+}
+
+/// How many channels, within what time, make a repeated message spam, the shortest
+/// message that counts, and how alike messages must be to be repeats.
+pub(crate) fn duplicate_limits() -> (usize, Duration, usize, f64) {
+    (
+        SPAM_CONFIG.duplicate_channels,
+        Duration::from_secs(SPAM_CONFIG.duplicate_window_secs),
+        SPAM_CONFIG.duplicate_min_chars,
+        SPAM_CONFIG.duplicate_similarity,
+    )
+}
+
+pub(crate) fn duplicate_action() -> SpamAction {
+    SPAM_CONFIG.duplicate_action
+}
+
+/// The heuristic score below which messages are clean, the score from which they're spam,
+/// and how sure the model must be about the ones in between.
+pub(crate) fn spam_bands() -> (f32, f32, f32) {
+    (
+        SPAM_CONFIG.clean_below,
+        SPAM_CONFIG.spam_from,
+        SPAM_CONFIG.min_spam_confidence,
+    )
+}
+
+pub(crate) fn max_mentions() -> usize {
+    SPAM_CONFIG.max_mentions
+}
+
+pub(crate) fn domain_lists_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.domain_lists_path)
+}
+
+/// Whether shortened links are expanded, which domains are shorteners, and how many
+/// redirects are followed with how long for each.
+pub(crate) fn shortener_settings() -> (bool, &'static [String], usize, Duration) {
+    (
+        SPAM_CONFIG.expand_shorteners,
+        &SPAM_CONFIG.shortener_domains,
+        SPAM_CONFIG.max_redirects,
+        Duration::from_secs(SPAM_CONFIG.redirect_timeout_secs),
+    )
+}
+
+/// The invite codes allowed before `/invite-allowlist` was ever used.
+pub(crate) fn allowed_invites() -> &'static [String] {
+    &SPAM_CONFIG.allowed_invites
+}
+
+pub(crate) fn invite_allowlist_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.invite_allowlist_path)
+}
+
+pub(crate) fn scam_rules_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.scam_rules_path)
+}
+
+pub(crate) fn invite_offense_window() -> Duration {
+    Duration::from_secs(SPAM_CONFIG.invite_offense_window_secs)
+}
+
+/// How many clean messages end probation, and the risk from which its rules apply.
+pub(crate) fn probation_settings() -> (usize, f32) {
+    (
+        SPAM_CONFIG.probation_messages,
+        SPAM_CONFIG.probation_min_risk,
+    )
+}
+
+pub(crate) fn risk_weights() -> &'static RiskWeights {
+    &SPAM_CONFIG.risk_weights
+}
+
+/// Joins within what time start a raid, and how long it lasts once they stop, then how
+/// long it cools down for.
+pub(crate) fn raid_limits() -> (usize, Duration, Duration, Duration) {
+    (
+        SPAM_CONFIG.raid_joins,
+        Duration::from_secs(SPAM_CONFIG.raid_window_secs),
+        Duration::from_secs(SPAM_CONFIG.raid_quiet_secs),
+        Duration::from_secs(SPAM_CONFIG.raid_cooldown_secs),
+    )
+}
+
+/// The channels slowed down during a raid, and their slowmode in seconds.
+pub(crate) fn raid_slowmode() -> (&'static [u64], u16) {
+    (&SPAM_CONFIG.raid_channels, SPAM_CONFIG.raid_slowmode_secs)
+}
+
+pub(crate) fn raid_mod_role() -> Option<u64> {
+    SPAM_CONFIG.raid_mod_role
+}
+
+pub(crate) fn spam_images_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.spam_images_path)
+}
+
+/// The largest image downloaded, how long a download can take, and how far an image's
+/// hash can be from a spam image's to match.
+pub(crate) fn image_settings() -> (usize, Duration, u32) {
+    (
+        SPAM_CONFIG.max_image_bytes,
+        Duration::from_secs(SPAM_CONFIG.image_timeout_secs),
+        SPAM_CONFIG.image_hash_distance,
+    )
+}
+
+pub(crate) fn honeypot() -> &'static HoneypotConfig {
+    &SPAM_CONFIG.honeypot
+}
+
+pub(crate) fn review_channel() -> Option<u64> {
+    SPAM_CONFIG.review_channel
+}
+
+pub(crate) fn review_mod_role() -> Option<u64> {
+    SPAM_CONFIG.review_mod_role
+}
+
+/// How sure the model must be for a message to be reviewed, or `None` when there's no
+/// review channel.
+pub(crate) fn review_min_confidence() -> Option<f32> {
+    SPAM_CONFIG
+        .review_channel
+        .map(|_| SPAM_CONFIG.review_min_confidence)
+}
+
+pub(crate) fn review_expiry() -> Duration {
+    Duration::from_secs(SPAM_CONFIG.review_expiry_hours * 60 * 60)
+}
+
+pub(crate) fn review_queue_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.review_queue_path)
+}
+
+/// Reports each member can make in a burst, and how long until they can make another.
+pub(crate) fn report_rate() -> (u32, Duration) {
+    (
+        SPAM_CONFIG.report_limit,
+        Duration::from_secs(SPAM_CONFIG.report_refill_minutes * 60),
+    )
+}
+
+/// Whether anything needs member joins, and so the Server Members intent.
+pub(crate) fn watch_joins() -> bool {
+    SPAM_CONFIG.watch_joins
+}
+
+pub(crate) fn report_reaction_threshold() -> Option<usize> {
+    SPAM_CONFIG.report_reaction_threshold
+}
+
+pub(crate) fn mod_log_channel() -> Option<u64> {
+    SPAM_CONFIG.mod_log_channel
+}
+
+pub(crate) fn mod_log_roadmaps() -> bool {
+    SPAM_CONFIG.mod_log_roadmaps
+}
+
+pub(crate) fn evidence_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.evidence_path)
+}
+
+pub(crate) fn evidence_retention() -> Duration {
+    Duration::from_secs(SPAM_CONFIG.evidence_retention_days * 24 * 60 * 60)
+}
+
+/// The largest attachment downloaded to be hashed for evidence, and how long it can take.
+pub(crate) fn evidence_attachment_settings() -> (usize, Duration) {
+    (
+        SPAM_CONFIG.evidence_max_attachment_bytes,
+        Duration::from_secs(SPAM_CONFIG.evidence_timeout_secs),
+    )
+}
+
+/// The strike ladder and how long strikes take to halve.
+pub(crate) fn strike_settings() -> (&'static [Rung], Duration) {
+    (
+        &SPAM_CONFIG.strike_ladder,
+        Duration::from_secs(SPAM_CONFIG.strike_half_life_hours * 60 * 60),
+    )
+}
+
+pub(crate) fn database_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.database_path)
+}
+
+/// Where strikes were kept before the database.
+pub(crate) fn strikes_path() -> PathBuf {
+    PathBuf::from(&SPAM_CONFIG.strikes_path)
+}
+
+/// Where to serve `/metrics`, if anywhere.
+pub(crate) fn metrics_address() -> Option<SocketAddr> {
+    SPAM_CONFIG.metrics.then_some(SPAM_CONFIG.metrics_address)
+}
+
+/// `spam_bands` for while raid mode is on.
+pub(crate) fn raid_spam_bands() -> (f32, f32, f32) {
+    (
+        SPAM_CONFIG.raid_clean_below,
+        SPAM_CONFIG.raid_spam_from,
+        SPAM_CONFIG.min_spam_confidence,
+    )
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct SpamClassification {
     pub reason: String,
     pub is_spam: bool,
+    #[serde(default = "default_confidence")]
+    pub confidence: f32,
 }
 
-fn system_message() -> ChatCompletionMessage {
-    ChatCompletionMessage {
-        role: ChatCompletionMessageRole::System,
-        content: Some(SPAM_PROMPT.to_string()),
-        name: None,
-        function_call: None,
+/// Confidence assumed when the model leaves the field out.
+fn default_confidence() -> f32 {
+    0.5
+}
+
+/// Function whose parameters mirror `SpamClassification`, so replies arrive as JSON
+/// arguments rather than free text.
+fn spam_function() -> ChatCompletionFunctionDefinition {
+    ChatCompletionFunctionDefinition {
+        name: SPAM_FUNCTION.to_string(),
+        description: Some("Record whether a message is spam".to_string()),
+        parameters: Some(json!({
+            "type": "object",
+            "properties": {
+                "reason": {
+                    "type": "string",
+                    "description": "A short reason for the classification",
+                },
+                "is_spam": {
+                    "type": "boolean",
+                    "description": "Whether the message is spam",
+                },
+                "confidence": {
+                    "type": "number",
+                    "minimum": 0.0,
+                    "maximum": 1.0,
+                    "description": "How sure the classification is, from 0.0 to 1.0",
+                },
+            },
+            "required": ["reason", "is_spam", "confidence"],
+        })),
     }
 }
 
-fn user_message(message: String) -> ChatCompletionMessage {
+/// The params `is_message_spam` is called with.
+pub(crate) fn spam_params() -> ChatParams {
+    ChatParams::new(SPAM_CONFIG.classification_model.as_str())
+        .max_tokens(256)
+        .temperature(0.0)
+        .force_function(spam_function())
+}
+
+/// Parses a spam classification reply. The error only shows the raw model output as
+/// `loggable` allows, since it can quote the message being classified.
+pub(crate) fn parse_spam_classification(raw: &str) -> anyhow::Result<SpamClassification> {
+    extract_json_object(extract_json(raw))
+        .context("no JSON object found")
+        .and_then(|json| Ok(serde_json::from_str(json)?))
+        .with_context(|| {
+            format!(
+                "failed to parse spam classification: {}",
+                roadmaps::loggable(raw)
+            )
+        })
+}
+
+fn system_message() -> ChatCompletionMessage {
     ChatCompletionMessage {
-        role: ChatCompletionMessageRole::User,
-        content: Some(message),
+        role: ChatCompletionMessageRole::System,
+        content: Some(SPAM_PROMPT.to_string()),
         name: None,
         function_call: None,
     }
 }
 
 fn build_message(message: String, context: Vec<String>) -> Vec<ChatCompletionMessage> {
-    let mut messages: Vec<ChatCompletionMessage> = vec![system_message()];
-    let mut message_length: usize = message.len();
-    let mut message_buffer: String = message;
-    for contextual_message in context.into_iter().take(SPAM_CONFIG.context_length) {
-        if message_length + contextual_message.len() > SPAM_CONFIG.message_limit_chars {
-            break;
-        }
-        message_length += contextual_message.len();
-        message_buffer.insert_str(0, contextual_message.as_str());
-    }
-    messages.push(user_message(message_buffer));
-    messages
+    utilities::build_message(
+        message,
+        context,
+        system_message(),
+        SPAM_CONFIG.context_length,
+        &[PromptBudget::chars(SPAM_CONFIG.message_limit_chars)],
+    )
 }
 
-pub(crate) async fn classify_message_spam(
+/// Asks the model whether `message` is spam, like `is_message_roadmap_request` does for
+/// roadmaps. Costs a completion, so it's only for messages the heuristics can't decide.
+#[instrument(
+    skip_all,
+    fields(
+        model = %params.model,
+        message_len = message.len(),
+        latency_ms = field::Empty,
+        prompt_tokens = field::Empty,
+        completion_tokens = field::Empty,
+    )
+)]
+pub(crate) async fn is_message_spam(
+    backend: &dyn ChatBackend,
+    params: &ChatParams,
     message: String,
     context: Vec<String>,
-) -> anyhow::Result<IsSpamResult> {
-    let chat_completion = ChatCompletion::builder("gpt-4o-mini", build_message(message, context))
-        .create()
+) -> anyhow::Result<SpamClassification> {
+    let started = Instant::now();
+    let reply = backend
+        .complete(build_message(message, context), params)
         .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        Ok(serde_json::from_str(content.as_str())?)
-    } else {
-        bail!("No reply from ChatGPT")
-    }
+    let latency_ms = started.elapsed().as_millis() as u64;
+    Span::current().record("latency_ms", latency_ms);
+    roadmaps::record_usage(reply.usage);
+    let classification = parse_spam_classification(reply.content.as_str())?;
+    debug!("Spam classification took {latency_ms}ms");
+    Ok(classification)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::MockChatBackend;
+    use crate::strikes::Escalation;
 
     #[test]
     fn parse_json() {
-        let result: IsSpamResult =
-            serde_json::from_str("{\"reason\": \"Unlikely to be spam\", \"is_spam\": false}")
+        let result =
+            parse_spam_classification("{\"reason\": \"Unlikely to be spam\", \"is_spam\": false}")
                 .unwrap();
-        dbg!(result);
+        assert!(!result.is_spam);
+        assert_eq!(result.confidence, 0.5);
+        let result = parse_spam_classification(
+            "```json\n{\"reason\": \"Phishing\", \"is_spam\": true, \"confidence\": 0.9}\n```",
+        )
+        .unwrap();
+        assert!(result.is_spam);
+        assert!(parse_spam_classification("Looks like spam to me").is_err());
+    }
+
+    #[tokio::test]
+    async fn is_message_spam_forces_the_spam_function() {
+        let backend = MockChatBackend::new(&[
+            r#"{"reason": "Phishing", "is_spam": true, "confidence": 0.9}"#,
+        ]);
+        let classification = is_message_spam(
+            &backend,
+            &spam_params(),
+            "free nitro http://bad.example".to_string(),
+            vec![],
+        )
+        .await
+        .unwrap();
+        assert!(classification.is_spam);
+        let params = backend.params().pop().unwrap();
+        assert_eq!(params.function.unwrap().name, SPAM_FUNCTION);
+    }
+
+    #[test]
+    fn load_spam_config_from_file() {
+        let path = env::temp_dir().join("spam_load_spam_config_from_file.toml");
+        std::fs::write(
+            &path,
+            "duplicate_channels = 5\nduplicate_action = \"ban\"\ntrusted_roles = [42]\n",
+        )
+        .unwrap();
+        let spam_config = SpamConfig::load(&path).unwrap();
+        assert_eq!(spam_config.duplicate_channels, 5);
+        assert_eq!(spam_config.duplicate_action, SpamAction::Ban);
+        assert_eq!(spam_config.trusted_roles, [42]);
+        assert_eq!(spam_config.duplicate_window_secs, 60);
+    }
+
+    #[test]
+    fn load_strike_ladder_from_file() {
+        let path = env::temp_dir().join("spam_load_strike_ladder_from_file.toml");
+        std::fs::write(
+            &path,
+            "strike_half_life_hours = 24
+
+[[strike_ladder]]
+strikes = 3
+action = \"timeout_24h\"
+
+[[strike_ladder]]
+strikes = 5.5
+action = \"ban\"
+",
+        )
+        .unwrap();
+        let spam_config = SpamConfig::load(&path).unwrap();
+        assert_eq!(spam_config.strike_half_life_hours, 24);
+        assert_eq!(
+            spam_config.strike_ladder,
+            [
+                Rung {
+                    strikes: 3.0,
+                    action: Escalation::LongTimeout
+                },
+                Rung {
+                    strikes: 5.5,
+                    action: Escalation::Ban
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_spam_config() {
+        let path = env::temp_dir().join("spam_reject_invalid_spam_config.toml");
+        std::fs::write(&path, "duplicate_channels = 1\n").unwrap();
+        let error = SpamConfig::load(&path).unwrap_err();
+        assert!(format!("{error:#}").contains("duplicate_channels"));
     }
 }
@@ -0,0 +1,532 @@
+use crate::dm_advertising;
+use crate::duplicate_spam;
+use crate::llm::ChatBackend;
+use crate::mention_spam;
+use crate::messaging;
+use crate::scam_rules;
+use crate::scam_rules::{RuleAction, RuleMatch};
+use crate::spam_detection;
+use crate::text_normalization::normalize_for_matching;
+use crate::user_info;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serenity::all::{Context, Message};
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+lazy_static! {
+    /// Phrases that turn up again and again in scams, each counted once per message.
+    static ref SPAM_KEYWORD_REGEX: Regex = Regex::new(
+        r"(?i)\b(free\s+nitro|airdrop|giveaway|crypto|bitcoin|usdt|forex|investment|dm\s+me|onlyfans|steam\s*gift|gift\s*card|whatsapp|telegram|earn\s+\$\d+)"
+    )
+    .unwrap();
+}
+
+/// Distinct spam keywords in `content`, however they're obfuscated.
+pub(crate) fn keyword_hits(content: &str) -> usize {
+    let content = normalize_for_matching(content);
+    SPAM_KEYWORD_REGEX
+        .find_iter(content.as_str())
+        .map(|found| found.as_str().to_string())
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// What the local heuristics noticed about a message, none of which costs an API call.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct SpamSignals {
+    /// The account joined within the last hour.
+    pub(crate) new_account: bool,
+    /// The author is on probation, so they're scored however long ago they joined.
+    pub(crate) on_probation: bool,
+    /// A link not on the allowlist or the built-in okay websites.
+    pub(crate) suspicious_link: bool,
+    pub(crate) everyone: bool,
+    /// Distinct users and roles mentioned.
+    pub(crate) mentions: usize,
+    pub(crate) keyword_hits: usize,
+    /// Channels the author recently posted copies of the message in, this one included.
+    pub(crate) duplicate_channels: usize,
+    /// How much the message looks like bait to take a scam to DMs, never set for
+    /// trusted roles.
+    pub(crate) dm_advertising: f32,
+    /// The strongest scam rule the message matches.
+    pub(crate) rule: Option<RuleMatch>,
+}
+
+impl SpamSignals {
+    /// How spammy the signals look together, from 0.0 to 1.0. No one signal is enough
+    /// to reach the default `spam_from` on its own.
+    pub(crate) fn score(&self) -> f32 {
+        let mut score = 0.0;
+        if self.new_account {
+            score += 0.2;
+        }
+        if self.suspicious_link {
+            score += 0.25;
+        }
+        if self.everyone {
+            score += 0.3;
+        }
+        score += (0.05 * self.mentions as f32).min(0.2);
+        score += (0.25 * self.keyword_hits as f32).min(0.5);
+        if self.duplicate_channels >= 2 {
+            score += 0.3;
+        }
+        score += self.dm_advertising;
+        score += self.rule.as_ref().map_or(0.0, RuleMatch::weight);
+        f32::min(score, 1.0)
+    }
+
+    /// The signals that fired, for logs and the mod channel.
+    pub(crate) fn describe(&self) -> String {
+        let mut fired = vec![];
+        if self.new_account {
+            fired.push("new account".to_string());
+        }
+        if self.suspicious_link {
+            fired.push("suspicious link".to_string());
+        }
+        if self.everyone {
+            fired.push("@everyone".to_string());
+        }
+        if self.mentions > 0 {
+            fired.push(format!("{} mentions", self.mentions));
+        }
+        if self.keyword_hits > 0 {
+            fired.push(format!("{} spam keywords", self.keyword_hits));
+        }
+        if self.duplicate_channels >= 2 {
+            fired.push(format!("posted in {} channels", self.duplicate_channels));
+        }
+        if self.dm_advertising > 0.0 {
+            fired.push(dm_advertising::REASON.to_string());
+        }
+        if let Some(rule) = &self.rule {
+            fired.push(format!("scam rule \"{}\"", rule.name));
+        }
+        fired.join(", ")
+    }
+}
+
+/// Collects the heuristics' signals for `message`. `links_allowed` is whether every link
+/// goes to an allowed domain.
+pub(crate) async fn gather_signals(
+    ctx: &Context,
+    message: &Message,
+    links_allowed: bool,
+    on_probation: bool,
+) -> SpamSignals {
+    let content = message.content.as_str();
+    let mentions = mention_spam::count_mentions(content);
+    let roles = message
+        .member
+        .as_ref()
+        .map_or(&[][..], |member| member.roles.as_slice());
+    let dm_advertising = dm_advertising_signal(content, spam_detection::is_trusted(roles));
+    SpamSignals {
+        new_account: messaging::is_new_user(
+            user_info::get_user_join_date(ctx, &message.author).await,
+        ),
+        on_probation,
+        suspicious_link: !links_allowed && messaging::is_suspicious_url(content),
+        everyone: mentions.everyone || message.mention_everyone,
+        mentions: mentions.distinct,
+        keyword_hits: keyword_hits(content),
+        duplicate_channels: duplicate_spam::channels_with_copies(ctx, message).await,
+        dm_advertising,
+        rule: scam_rules::check_rules(ctx, message).await,
+    }
+}
+
+/// How much `content` reads like an ad for DMs, which staff are `trusted` to post.
+fn dm_advertising_signal(content: &str, trusted: bool) -> f32 {
+    if trusted {
+        return 0.0;
+    }
+    dm_advertising::dm_advertising_score(&normalize_for_matching(content))
+}
+
+/// Scores at or below which messages are clean, and at or above which they're spam.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SpamBands {
+    pub(crate) clean_below: f32,
+    pub(crate) spam_from: f32,
+    /// How sure the model must be to call a grey-zone message spam.
+    pub(crate) min_confidence: f32,
+    /// How sure the model must be, short of `min_confidence`, for a message to go to
+    /// moderators for review. `None` lets all of them through.
+    pub(crate) review_from: Option<f32>,
+    /// `spam_from` and `min_confidence` outside a raid. Spam only by the tighter raid
+    /// bands is `SpamVerdict::RaidSpam`, so it never times anyone out on its own.
+    pub(crate) usual_spam_from: f32,
+    pub(crate) usual_min_confidence: f32,
+}
+
+impl SpamBands {
+    pub(crate) fn from_config() -> Self {
+        let (clean_below, spam_from, min_confidence) = spam_detection::spam_bands();
+        SpamBands {
+            clean_below,
+            spam_from,
+            min_confidence,
+            review_from: spam_detection::review_min_confidence(),
+            usual_spam_from: spam_from,
+            usual_min_confidence: min_confidence,
+        }
+    }
+
+    /// The tighter bands used while raid mode is on.
+    pub(crate) fn during_raid() -> Self {
+        let (clean_below, spam_from, min_confidence) = spam_detection::raid_spam_bands();
+        let usual = SpamBands::from_config();
+        SpamBands {
+            clean_below,
+            spam_from,
+            min_confidence,
+            review_from: usual.review_from,
+            usual_spam_from: usual.spam_from,
+            usual_min_confidence: usual.min_confidence,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SpamVerdict {
+    Spam(String),
+    /// Spam by the tighter bands used during a raid, but not by the usual ones. Removed,
+    /// but never grounds for a timeout by itself.
+    RaidSpam(String),
+    Clean,
+    /// The model leans towards spam, but not surely enough to delete it.
+    Review(String),
+    /// In the grey zone, and the model couldn't be asked. Never grounds for removing the
+    /// message, as it's usually an OpenAI outage rather than anything the author did.
+    Unsure,
+}
+
+/// Decides on a message from its heuristic `signals`, only asking `backend` when the
+/// score falls between the bands. A matching scam rule that says to delete or review the
+/// message is followed whatever the score. Otherwise only new accounts and members on
+/// probation are scored, so established members are never punished for a keyword.
+pub(crate) async fn classify(
+    backend: &dyn ChatBackend,
+    bands: SpamBands,
+    signals: &SpamSignals,
+    message: String,
+    context: Vec<String>,
+) -> SpamVerdict {
+    if let Some(rule) = &signals.rule {
+        let reason = format!("matched scam rule \"{}\"", rule.name);
+        match rule.action {
+            RuleAction::Delete => return SpamVerdict::Spam(reason),
+            RuleAction::Review if bands.review_from.is_some() => {
+                return SpamVerdict::Review(reason)
+            }
+            _ => {}
+        }
+    }
+    if !signals.new_account && !signals.on_probation {
+        return SpamVerdict::Clean;
+    }
+    let score = signals.score();
+    if score < bands.clean_below {
+        return SpamVerdict::Clean;
+    }
+    if score >= bands.spam_from {
+        info!(
+            "Heuristics scored message {score:.2} ({}), spam without asking",
+            signals.describe()
+        );
+        let reason = format!("spam signals: {}", signals.describe());
+        return if score >= bands.usual_spam_from {
+            SpamVerdict::Spam(reason)
+        } else {
+            SpamVerdict::RaidSpam(reason)
+        };
+    }
+    let params = spam_detection::spam_params();
+    match spam_detection::is_message_spam(backend, &params, message, context).await {
+        Ok(classification) => {
+            info!(
+                "Heuristics scored message {score:.2} ({}), model says spam: {} with confidence {} due to {}",
+                signals.describe(),
+                classification.is_spam,
+                classification.confidence,
+                classification.reason
+            );
+            if !classification.is_spam {
+                SpamVerdict::Clean
+            } else if classification.confidence >= bands.usual_min_confidence {
+                SpamVerdict::Spam(classification.reason)
+            } else if classification.confidence >= bands.min_confidence {
+                SpamVerdict::RaidSpam(classification.reason)
+            } else if bands
+                .review_from
+                .is_some_and(|review_from| classification.confidence >= review_from)
+            {
+                SpamVerdict::Review(classification.reason)
+            } else {
+                SpamVerdict::Clean
+            }
+        }
+        Err(e) => {
+            warn!("Failed to classify grey-zone message due to {e:#}");
+            SpamVerdict::Unsure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::MockChatBackend;
+    use crate::strikes::Severity;
+
+    const BANDS: SpamBands = SpamBands {
+        clean_below: 0.3,
+        spam_from: 0.8,
+        min_confidence: 0.7,
+        review_from: None,
+        usual_spam_from: 0.8,
+        usual_min_confidence: 0.7,
+    };
+
+    fn clean() -> SpamSignals {
+        SpamSignals::default()
+    }
+
+    /// A new account posting an unknown link.
+    fn grey() -> SpamSignals {
+        SpamSignals {
+            new_account: true,
+            suspicious_link: true,
+            ..Default::default()
+        }
+    }
+
+    fn obvious() -> SpamSignals {
+        SpamSignals {
+            new_account: true,
+            suspicious_link: true,
+            keyword_hits: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_distinct_keywords() {
+        assert_eq!(keyword_hits("FREE NITRO giveaway, free nitro!"), 2);
+        assert_eq!(keyword_hits("Earn $500 a day, DM me"), 2);
+        assert_eq!(keyword_hits("How do I start with pandas?"), 0);
+        assert_eq!(keyword_hits("ＦＲＥＥ ＮIТRO ɢɪᴠᴇᴀᴡᴀʏ"), 2);
+    }
+
+    #[test]
+    fn scores_fall_in_the_expected_bands() {
+        assert!(clean().score() < BANDS.clean_below);
+        let link_only = SpamSignals {
+            suspicious_link: true,
+            ..Default::default()
+        };
+        assert!(link_only.score() < BANDS.clean_below);
+        assert!((BANDS.clean_below..BANDS.spam_from).contains(&grey().score()));
+        assert!(obvious().score() >= BANDS.spam_from);
+        let everything = SpamSignals {
+            new_account: true,
+            on_probation: true,
+            suspicious_link: true,
+            everyone: true,
+            mentions: 10,
+            keyword_hits: 5,
+            duplicate_channels: 3,
+            dm_advertising: 0.6,
+            rule: None,
+        };
+        assert_eq!(everything.score(), 1.0);
+    }
+
+    #[test]
+    fn dm_advertising_is_grey_until_blatant() {
+        let signals = |text: &str| SpamSignals {
+            keyword_hits: keyword_hits(text),
+            dm_advertising: dm_advertising_signal(text, false),
+            ..Default::default()
+        };
+        let rephrased = signals("dm me for a method to make $500/day");
+        assert!((BANDS.clean_below..BANDS.spam_from).contains(&rephrased.score()));
+        let blatant = signals("dm me for a method to make $500/day, on telegram @rich");
+        assert!(blatant.score() >= BANDS.spam_from);
+        let no_offer = signals("DM me your address for the giveaway");
+        assert_eq!(no_offer.dm_advertising, 0.0);
+    }
+
+    #[test]
+    fn trusted_members_can_advertise_dms() {
+        let blatant = "dm me for a method to make $500/day, on telegram @rich";
+        assert!(dm_advertising_signal(blatant, false) > 0.0);
+        assert_eq!(dm_advertising_signal(blatant, true), 0.0);
+    }
+
+    #[tokio::test]
+    async fn only_the_grey_zone_asks_the_model() {
+        let backend = MockChatBackend::new(&[]);
+        let verdict = classify(&backend, BANDS, &clean(), "hi".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Clean);
+        let verdict = classify(&backend, BANDS, &obvious(), "spam".to_string(), vec![]).await;
+        assert_eq!(
+            verdict,
+            SpamVerdict::Spam(
+                "spam signals: new account, suspicious link, 2 spam keywords".to_string()
+            )
+        );
+        assert!(backend.prompts().is_empty());
+
+        let backend = MockChatBackend::new(&[
+            r#"{"reason": "Phishing", "is_spam": true, "confidence": 0.9}"#,
+            r#"{"reason": "Sharing a course", "is_spam": false, "confidence": 0.8}"#,
+            r#"{"reason": "Might be an ad", "is_spam": true, "confidence": 0.4}"#,
+        ]);
+        let verdict = classify(&backend, BANDS, &grey(), "claim".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Spam("Phishing".to_string()));
+        let verdict = classify(&backend, BANDS, &grey(), "course".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Clean);
+        let verdict = classify(&backend, BANDS, &grey(), "ad".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Clean);
+        assert_eq!(backend.prompts().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn spam_only_by_raid_bands_is_told_apart() {
+        let raid = SpamBands {
+            clean_below: 0.1,
+            spam_from: 0.4,
+            min_confidence: 0.5,
+            ..BANDS
+        };
+        let backend = MockChatBackend::new(&[]);
+        let verdict = classify(&backend, raid, &grey(), "claim".to_string(), vec![]).await;
+        assert_eq!(
+            verdict,
+            SpamVerdict::RaidSpam("spam signals: new account, suspicious link".to_string())
+        );
+        let verdict = classify(&backend, raid, &obvious(), "spam".to_string(), vec![]).await;
+        assert!(matches!(verdict, SpamVerdict::Spam(_)));
+
+        let backend = MockChatBackend::new(&[
+            r#"{"reason": "Might be an ad", "is_spam": true, "confidence": 0.6}"#,
+            r#"{"reason": "Phishing", "is_spam": true, "confidence": 0.9}"#,
+        ]);
+        let new_account = SpamSignals {
+            new_account: true,
+            ..Default::default()
+        };
+        let verdict = classify(&backend, raid, &new_account, "ad".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::RaidSpam("Might be an ad".to_string()));
+        let verdict = classify(&backend, raid, &new_account, "claim".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Spam("Phishing".to_string()));
+    }
+
+    #[tokio::test]
+    async fn medium_confidence_goes_to_review() {
+        let bands = SpamBands {
+            review_from: Some(0.4),
+            ..BANDS
+        };
+        let backend = MockChatBackend::new(&[
+            r#"{"reason": "Might be an ad", "is_spam": true, "confidence": 0.5}"#,
+            r#"{"reason": "Probably fine", "is_spam": true, "confidence": 0.2}"#,
+            r#"{"reason": "Phishing", "is_spam": true, "confidence": 0.9}"#,
+        ]);
+        let verdict = classify(&backend, bands, &grey(), "ad".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Review("Might be an ad".to_string()));
+        let verdict = classify(&backend, bands, &grey(), "fine".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Clean);
+        let verdict = classify(&backend, bands, &grey(), "claim".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Spam("Phishing".to_string()));
+    }
+
+    #[tokio::test]
+    async fn scam_rules_score_or_decide() {
+        let rule = |severity, action| SpamSignals {
+            rule: Some(RuleMatch {
+                name: "nitro".to_string(),
+                severity,
+                action,
+            }),
+            ..clean()
+        };
+        let backend = MockChatBackend::new(&[]);
+        let verdict = classify(
+            &backend,
+            BANDS,
+            &rule(Severity::Low, RuleAction::Delete),
+            "nitro".to_string(),
+            vec![],
+        )
+        .await;
+        assert_eq!(
+            verdict,
+            SpamVerdict::Spam("matched scam rule \"nitro\"".to_string())
+        );
+        let verdict = classify(
+            &backend,
+            BANDS,
+            &rule(Severity::Low, RuleAction::Review),
+            "nitro".to_string(),
+            vec![],
+        )
+        .await;
+        assert_eq!(verdict, SpamVerdict::Clean);
+        let bands = SpamBands {
+            review_from: Some(0.4),
+            ..BANDS
+        };
+        let verdict = classify(
+            &backend,
+            bands,
+            &rule(Severity::Low, RuleAction::Review),
+            "nitro".to_string(),
+            vec![],
+        )
+        .await;
+        assert_eq!(
+            verdict,
+            SpamVerdict::Review("matched scam rule \"nitro\"".to_string())
+        );
+        assert!(backend.prompts().is_empty());
+        let high = SpamSignals {
+            keyword_hits: 2,
+            ..rule(Severity::High, RuleAction::Score)
+        };
+        assert!(high.score() >= BANDS.spam_from);
+        assert!(rule(Severity::Medium, RuleAction::Score).score() >= BANDS.clean_below);
+    }
+
+    #[tokio::test]
+    async fn established_members_are_not_scored() {
+        let backend = MockChatBackend::new(&[]);
+        let established = SpamSignals {
+            keyword_hits: 2,
+            duplicate_channels: 2,
+            ..Default::default()
+        };
+        let verdict = classify(&backend, BANDS, &established, "spam".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Clean);
+        let on_probation = SpamSignals {
+            on_probation: true,
+            ..established
+        };
+        let verdict = classify(&backend, BANDS, &on_probation, "spam".to_string(), vec![]).await;
+        assert!(matches!(verdict, SpamVerdict::Spam(_)));
+        assert!(backend.prompts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn grey_zone_is_unsure_when_the_model_fails() {
+        let backend = MockChatBackend::new(&[]);
+        let verdict = classify(&backend, BANDS, &grey(), "claim".to_string(), vec![]).await;
+        assert_eq!(verdict, SpamVerdict::Unsure);
+        assert_eq!(backend.prompts().len(), 1);
+    }
+}
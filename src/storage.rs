@@ -0,0 +1,758 @@
+use crate::budget::DailySpend;
+use crate::quota::UserUsage;
+use crate::roadmap_store::{RoadmapStore, StoredRoadmap};
+use crate::roadmaps;
+use crate::roadmaps::RoadmapProvided;
+use crate::spam_detection;
+use crate::strikes::Strike;
+use anyhow::Context as _;
+use chrono::{DateTime, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serenity::all::{ChannelId, UserId};
+use serenity::async_trait;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref STORAGE: Storage = Storage::open_importing(
+        &spam_detection::database_path(),
+        &LegacyFiles {
+            strikes: Some(spam_detection::strikes_path()),
+            budget: Some(roadmaps::budget_path()),
+            quota: Some(roadmaps::quota_path()),
+            roadmap_store: roadmaps::roadmap_store_path(),
+        }
+    )
+    .expect("Failed to open the database");
+}
+
+/// Schema changes, applied in order to bring any database up to date. Each runs once,
+/// tracked by SQLite's `user_version`, so they can only ever be appended to.
+const MIGRATIONS: &[&str] = &["CREATE TABLE strikes (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        at INTEGER NOT NULL,
+        strikes REAL NOT NULL,
+        reason TEXT NOT NULL
+    );
+    CREATE INDEX strikes_by_user ON strikes (user_id, at);
+    CREATE TABLE roadmaps (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        message TEXT NOT NULL,
+        roadmap TEXT NOT NULL,
+        structured TEXT,
+        model TEXT NOT NULL,
+        thread_id INTEGER
+    );
+    CREATE INDEX roadmaps_by_user ON roadmaps (user_id, id);
+    CREATE TABLE spend (
+        day TEXT PRIMARY KEY,
+        spent_usd REAL NOT NULL
+    );
+    CREATE TABLE roadmap_usage (
+        user_id INTEGER PRIMARY KEY,
+        day TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        last TEXT NOT NULL
+    );"];
+
+/// Files state was kept in before the database, imported into it when it's first created.
+/// Any that don't exist are skipped.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LegacyFiles {
+    pub(crate) strikes: Option<PathBuf>,
+    pub(crate) budget: Option<PathBuf>,
+    pub(crate) quota: Option<PathBuf>,
+    pub(crate) roadmap_store: Option<PathBuf>,
+}
+
+/// How `strikes_path` was laid out.
+#[derive(Deserialize)]
+struct LegacyStrikes {
+    users: HashMap<UserId, Vec<Strike>>,
+}
+
+/// The bot's state that has to survive restarts, in one SQLite file: strikes, roadmaps
+/// people were given, OpenAI spend and roadmap cooldowns. Clones share the connection.
+#[derive(Clone)]
+pub(crate) struct Storage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    /// Opens the database at `path` with nothing to import, for tests.
+    #[cfg(test)]
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        Storage::open_importing(path, &LegacyFiles::default())
+    }
+
+    /// Opens the database at `path`, creating it if needed, and migrates it to the
+    /// current schema. A new database starts with the state in `legacy_files`.
+    pub(crate) fn open_importing(path: &Path, legacy_files: &LegacyFiles) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(path)
+            .with_context(|| format!("failed to open database {}", path.display()))?;
+        migrate(&mut connection, legacy_files)
+            .with_context(|| format!("failed to migrate database {}", path.display()))?;
+        Ok(Storage {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `read` on a blocking thread, like `write`.
+    async fn read<T: Send + 'static>(
+        &self,
+        read: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let connection = self.connection.clone();
+        let read = tokio::task::spawn_blocking(move || read(&connection.lock().unwrap())).await?;
+        Ok(read?)
+    }
+
+    /// Runs `write` on a blocking thread, so a slow disk never stalls the gateway.
+    async fn write<T: Send + 'static>(
+        &self,
+        write: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let connection = self.connection.clone();
+        let written =
+            tokio::task::spawn_blocking(move || write(&mut connection.lock().unwrap())).await?;
+        Ok(written?)
+    }
+
+    /// Everyone's saved strikes, oldest first.
+    pub(crate) async fn strikes(&self) -> anyhow::Result<HashMap<UserId, Vec<Strike>>> {
+        self.read(|connection| {
+            let mut query = connection
+                .prepare("SELECT user_id, at, strikes, reason FROM strikes ORDER BY at, id")?;
+            let rows = query.query_map([], |row| {
+                Ok((
+                    UserId::new(row.get::<_, i64>(0)? as u64),
+                    Strike {
+                        at: row.get(1)?,
+                        strikes: row.get(2)?,
+                        reason: row.get(3)?,
+                    },
+                ))
+            })?;
+            let mut strikes: HashMap<UserId, Vec<Strike>> = HashMap::new();
+            for row in rows {
+                let (user_id, strike) = row?;
+                strikes.entry(user_id).or_default().push(strike);
+            }
+            Ok(strikes)
+        })
+        .await
+    }
+
+    /// Replaces `user_id`'s saved strikes with `strikes`.
+    pub(crate) async fn set_strikes(
+        &self,
+        user_id: UserId,
+        strikes: Vec<Strike>,
+    ) -> anyhow::Result<()> {
+        self.write(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM strikes WHERE user_id = ?1",
+                params![user_id.get() as i64],
+            )?;
+            for strike in &strikes {
+                transaction.execute(
+                    "INSERT INTO strikes (user_id, at, strikes, reason) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        user_id.get() as i64,
+                        strike.at,
+                        strike.strikes,
+                        strike.reason
+                    ],
+                )?;
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    /// Estimated OpenAI spend on `day`, if anything was spent.
+    pub(crate) async fn spend(&self, day: NaiveDate) -> anyhow::Result<Option<f64>> {
+        self.read(move |connection| {
+            connection
+                .query_row(
+                    "SELECT spent_usd FROM spend WHERE day = ?1",
+                    params![day],
+                    |row| row.get(0),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    /// Adds `cost_usd` to the spend on `day`.
+    pub(crate) async fn add_spend(&self, day: NaiveDate, cost_usd: f64) -> anyhow::Result<()> {
+        self.write(move |connection| {
+            connection.execute(
+                "INSERT INTO spend (day, spent_usd) VALUES (?1, ?2)
+                 ON CONFLICT (day) DO UPDATE SET spent_usd = spent_usd + excluded.spent_usd",
+                params![day, cost_usd],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Everyone's saved roadmap usage.
+    pub(crate) async fn roadmap_usage(&self) -> anyhow::Result<HashMap<UserId, UserUsage>> {
+        self.read(|connection| {
+            let mut query =
+                connection.prepare("SELECT user_id, day, count, last FROM roadmap_usage")?;
+            let rows = query.query_map([], |row| {
+                Ok((
+                    UserId::new(row.get::<_, i64>(0)? as u64),
+                    UserUsage {
+                        day: row.get(1)?,
+                        count: row.get(2)?,
+                        last: row.get(3)?,
+                    },
+                ))
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Saves `usage` as `user_id`'s roadmap usage.
+    pub(crate) async fn set_roadmap_usage(
+        &self,
+        user_id: UserId,
+        usage: UserUsage,
+    ) -> anyhow::Result<()> {
+        self.write(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO roadmap_usage (user_id, day, count, last)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![user_id.get() as i64, usage.day, usage.count, usage.last],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Forgets usage from before `today` whose cooldown ran out before `cooldown_start`,
+    /// returning how much.
+    pub(crate) async fn forget_roadmap_usage(
+        &self,
+        today: NaiveDate,
+        cooldown_start: DateTime<Utc>,
+    ) -> anyhow::Result<usize> {
+        self.write(move |connection| {
+            connection.execute(
+                "DELETE FROM roadmap_usage WHERE day < ?1 AND last <= ?2",
+                params![today, cooldown_start],
+            )
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl RoadmapStore for Storage {
+    async fn save(
+        &self,
+        user_id: UserId,
+        message: &str,
+        roadmap: &RoadmapProvided,
+        created_at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let structured = roadmap
+            .structured
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        let message = message.to_string();
+        let roadmap = roadmap.clone();
+        self.write(move |connection| {
+            connection.execute(
+                "INSERT INTO roadmaps (user_id, created_at, message, roadmap, structured, model, thread_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    user_id.get() as i64,
+                    created_at.to_rfc3339(),
+                    message,
+                    roadmap.roadmap,
+                    structured,
+                    roadmap.model,
+                    roadmap.thread_id.map(|thread_id| thread_id.get() as i64),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_roadmap(&self, user_id: UserId) -> anyhow::Result<Option<RoadmapProvided>> {
+        let row = self
+            .read(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT roadmap, structured, model, thread_id FROM roadmaps
+                     WHERE user_id = ?1 ORDER BY id DESC LIMIT 1",
+                        params![user_id.get() as i64],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, Option<String>>(1)?,
+                                row.get::<_, String>(2)?,
+                                row.get::<_, Option<i64>>(3)?,
+                            ))
+                        },
+                    )
+                    .optional()
+            })
+            .await?;
+        let Some((roadmap, structured, model, thread_id)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(RoadmapProvided {
+            roadmap,
+            structured: structured
+                .map(|structured| serde_json::from_str(&structured))
+                .transpose()?,
+            usage: None,
+            model,
+            elapsed: Duration::ZERO,
+            thread_id: thread_id
+                .filter(|thread_id| *thread_id > 0)
+                .map(|thread_id| ChannelId::new(thread_id as u64)),
+        }))
+    }
+
+    async fn history(&self, user_id: UserId) -> anyhow::Result<Vec<StoredRoadmap>> {
+        self.read(move |connection| {
+            let mut query = connection.prepare(
+                "SELECT created_at, message, roadmap FROM roadmaps WHERE user_id = ?1 ORDER BY id",
+            )?;
+            let rows = query.query_map(params![user_id.get() as i64], |row| {
+                Ok(StoredRoadmap {
+                    created_at: row.get(0)?,
+                    message: row.get(1)?,
+                    roadmap: row.get(2)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+}
+
+/// Where a roadmap store kept in the database file itself is moved before the first
+/// migration, until it's imported.
+const LEGACY_ROADMAPS: &str = "legacy_roadmaps";
+
+/// Applies the migrations `connection` hasn't had yet, each in its own transaction. The
+/// first also imports `legacy_files`, so they're only ever read once.
+fn migrate(connection: &mut Connection, legacy_files: &LegacyFiles) -> anyhow::Result<()> {
+    let applied: usize =
+        connection.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let transaction = connection.transaction()?;
+        if version == 0 {
+            set_aside_roadmap_store(&transaction)?;
+        }
+        transaction.execute_batch(migration)?;
+        if version == 0 {
+            import_legacy_files(&transaction, legacy_files)?;
+        }
+        transaction.pragma_update(None, "user_version", version as i64 + 1)?;
+        transaction.commit()?;
+        info!("Migrated the database to version {}", version + 1);
+    }
+    Ok(())
+}
+
+/// Copies whatever `legacy_files` has into the freshly created tables.
+fn import_legacy_files(
+    transaction: &Transaction,
+    legacy_files: &LegacyFiles,
+) -> anyhow::Result<()> {
+    if let Some(legacy) = read_legacy::<LegacyStrikes>(legacy_files.strikes.as_deref()) {
+        for (user_id, strikes) in legacy.users {
+            for strike in strikes {
+                transaction.execute(
+                    "INSERT INTO strikes (user_id, at, strikes, reason) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        user_id.get() as i64,
+                        strike.at,
+                        strike.strikes,
+                        strike.reason
+                    ],
+                )?;
+            }
+        }
+    }
+    if let Some(spend) = read_legacy::<DailySpend>(legacy_files.budget.as_deref()) {
+        transaction.execute(
+            "INSERT INTO spend (day, spent_usd) VALUES (?1, ?2)",
+            params![spend.day, spend.spent_usd],
+        )?;
+    }
+    if let Some(usage) = read_legacy::<HashMap<UserId, UserUsage>>(legacy_files.quota.as_deref()) {
+        for (user_id, usage) in usage {
+            transaction.execute(
+                "INSERT INTO roadmap_usage (user_id, day, count, last) VALUES (?1, ?2, ?3, ?4)",
+                params![user_id.get() as i64, usage.day, usage.count, usage.last],
+            )?;
+        }
+    }
+    if let Some(path) = &legacy_files.roadmap_store {
+        import_roadmap_store(transaction, path)
+            .with_context(|| format!("failed to import roadmaps from {}", path.display()))?;
+    }
+    if has_table(transaction, LEGACY_ROADMAPS)? {
+        info!("Importing the roadmaps already in the database");
+        transaction.execute_batch(&format!(
+            "INSERT INTO roadmaps (user_id, created_at, message, roadmap, structured, model, thread_id)
+             SELECT user_id, created_at, message, roadmap, structured, model, thread_id
+             FROM {LEGACY_ROADMAPS} ORDER BY id;
+             DROP TABLE {LEGACY_ROADMAPS};"
+        ))?;
+    }
+    Ok(())
+}
+
+/// Renames the `roadmaps` table a `roadmap_store_path` pointing at the database left
+/// behind, so the first migration can create its own and import it from there.
+fn set_aside_roadmap_store(transaction: &Transaction) -> rusqlite::Result<()> {
+    if has_table(transaction, "roadmaps")? {
+        transaction.execute_batch(&format!(
+            "DROP INDEX IF EXISTS roadmaps_by_user;
+             ALTER TABLE roadmaps RENAME TO {LEGACY_ROADMAPS};"
+        ))?;
+    }
+    Ok(())
+}
+
+fn has_table(transaction: &Transaction, name: &str) -> rusqlite::Result<bool> {
+    transaction.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![name],
+        |row| row.get(0),
+    )
+}
+
+/// The JSON file at `path`, if there is one. Files that don't parse are left alone, as
+/// they were when they were still in use.
+fn read_legacy<T: DeserializeOwned>(path: Option<&Path>) -> Option<T> {
+    let path = path?;
+    let saved = std::fs::read_to_string(path).ok()?;
+    info!("Importing {} into the database", path.display());
+    serde_json::from_str(&saved)
+        .inspect_err(|e| warn!("Not importing unreadable {}: {e}", path.display()))
+        .ok()
+}
+
+/// Copies the roadmaps kept at `roadmap_store_path` before the database existed. A store
+/// that is the database itself was set aside by `set_aside_roadmap_store` instead.
+fn import_roadmap_store(transaction: &Transaction, path: &Path) -> anyhow::Result<()> {
+    let main_path = transaction.path().map(PathBuf::from);
+    let same_file = |main_path: &PathBuf| {
+        std::fs::canonicalize(main_path).ok() == std::fs::canonicalize(path).ok()
+    };
+    if !path.exists() || main_path.as_ref().is_some_and(same_file) {
+        return Ok(());
+    }
+    info!("Importing {} into the database", path.display());
+    let store = Connection::open(path)?;
+    let mut query = store.prepare(
+        "SELECT user_id, created_at, message, roadmap, structured, model, thread_id
+         FROM roadmaps ORDER BY id",
+    )?;
+    let mut rows = query.query([])?;
+    while let Some(row) = rows.next()? {
+        transaction.execute(
+            "INSERT INTO roadmaps (user_id, created_at, message, roadmap, structured, model, thread_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, Option<i64>>(6)?,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// The database at `database_path`, shared by everything that keeps state.
+pub(crate) fn shared() -> Storage {
+    STORAGE.clone()
+}
+
+/// Opens and migrates the database now so a bad path stops the bot at startup.
+pub(crate) fn init_database() {
+    lazy_static::initialize(&STORAGE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roadmaps::{RoadmapStep, StructuredRoadmap};
+    use chrono::TimeZone;
+    use std::env;
+
+    const ADA: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+
+    fn storage(name: &str) -> (Storage, std::path::PathBuf) {
+        let path = env::temp_dir().join(format!("{name}.db"));
+        let _ = std::fs::remove_file(&path);
+        (Storage::open(&path).unwrap(), path)
+    }
+
+    fn strike(at: i64, reason: &str) -> Strike {
+        Strike {
+            at,
+            strikes: 1.5,
+            reason: reason.to_string(),
+        }
+    }
+
+    async fn version(storage: &Storage) -> i64 {
+        storage
+            .read(|connection| {
+                connection.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_file_migrates_once() {
+        let (storage, path) = storage("empty_file_migrates_once");
+        assert_eq!(version(&storage).await, MIGRATIONS.len() as i64);
+        assert!(storage.strikes().await.unwrap().is_empty());
+        drop(storage);
+        // Running the migrations again would fail on the existing tables
+        let reopened = Storage::open(&path).unwrap();
+        assert_eq!(version(&reopened).await, MIGRATIONS.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn legacy_files_are_imported_once() {
+        let file = |name: &str, contents: &str| {
+            let path = env::temp_dir().join(format!("legacy_files_are_imported_once_{name}"));
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let roadmap_store = env::temp_dir().join("legacy_files_are_imported_once_roadmaps.db");
+        let _ = std::fs::remove_file(&roadmap_store);
+        Connection::open(&roadmap_store)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE roadmaps (
+                    id INTEGER PRIMARY KEY,
+                    user_id INTEGER NOT NULL,
+                    created_at TEXT NOT NULL,
+                    message TEXT NOT NULL,
+                    roadmap TEXT NOT NULL,
+                    structured TEXT,
+                    model TEXT NOT NULL,
+                    thread_id INTEGER
+                );
+                INSERT INTO roadmaps (user_id, created_at, message, roadmap, model)
+                VALUES (2, '2024-05-01T12:00:00+00:00', 'roadmap please', 'imported', 'gpt-4o');",
+            )
+            .unwrap();
+        let legacy_files = LegacyFiles {
+            strikes: Some(file(
+                "strikes.json",
+                r#"{"users": {"1": [{"at": 10, "strikes": 1.5, "reason": "spam"}]}}"#,
+            )),
+            budget: Some(file(
+                "budget.json",
+                r#"{"day": "2024-05-01", "spent_usd": 0.5}"#,
+            )),
+            quota: Some(file(
+                "quota.json",
+                r#"{"1": {"day": "2024-05-01", "count": 2, "last": "2024-05-01T12:00:00Z"}}"#,
+            )),
+            roadmap_store: Some(roadmap_store),
+        };
+        let path = env::temp_dir().join("legacy_files_are_imported_once.db");
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::open_importing(&path, &legacy_files).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        assert_eq!(
+            storage.strikes().await.unwrap()[&ADA],
+            vec![strike(10, "spam")]
+        );
+        assert_eq!(storage.spend(day).await.unwrap(), Some(0.5));
+        assert_eq!(storage.roadmap_usage().await.unwrap()[&ADA].count, 2);
+        let imported = storage.get_roadmap(BOB).await.unwrap();
+        assert_eq!(imported.unwrap().roadmap, "imported");
+        drop(storage);
+        // An existing database isn't imported into again
+        let reopened = Storage::open_importing(&path, &legacy_files).unwrap();
+        assert_eq!(reopened.history(BOB).await.unwrap().len(), 1);
+        assert_eq!(reopened.spend(day).await.unwrap(), Some(0.5));
+    }
+
+    #[tokio::test]
+    async fn roadmap_store_files_migrate_in_place() {
+        let path = env::temp_dir().join("roadmap_store_files_migrate_in_place.db");
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE roadmaps (
+                    id INTEGER PRIMARY KEY,
+                    user_id INTEGER NOT NULL,
+                    created_at TEXT NOT NULL,
+                    message TEXT NOT NULL,
+                    roadmap TEXT NOT NULL,
+                    structured TEXT,
+                    model TEXT NOT NULL,
+                    thread_id INTEGER
+                );
+                CREATE INDEX IF NOT EXISTS roadmaps_by_user ON roadmaps (user_id, id);
+                INSERT INTO roadmaps (user_id, created_at, message, roadmap, model)
+                VALUES (1, '2024-05-01T12:00:00+00:00', 'roadmap please', 'kept', 'gpt-4o');",
+            )
+            .unwrap();
+        let legacy_files = LegacyFiles {
+            roadmap_store: Some(path.clone()),
+            ..Default::default()
+        };
+        let storage = Storage::open_importing(&path, &legacy_files).unwrap();
+        let kept = storage.history(ADA).await.unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].roadmap, "kept");
+        let set_aside = storage
+            .read(|connection| {
+                connection.query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE name = ?1",
+                    params![LEGACY_ROADMAPS],
+                    |row| row.get::<_, i64>(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(set_aside, 0);
+    }
+
+    #[tokio::test]
+    async fn strikes_are_replaced_per_user() {
+        let (storage, _) = storage("strikes_are_replaced_per_user");
+        storage
+            .set_strikes(ADA, vec![strike(10, "spam"), strike(20, "scam")])
+            .await
+            .unwrap();
+        storage
+            .set_strikes(BOB, vec![strike(15, "invite")])
+            .await
+            .unwrap();
+        storage
+            .set_strikes(ADA, vec![strike(20, "scam")])
+            .await
+            .unwrap();
+        let strikes = storage.strikes().await.unwrap();
+        assert_eq!(strikes[&ADA], vec![strike(20, "scam")]);
+        assert_eq!(strikes[&BOB], vec![strike(15, "invite")]);
+        storage.set_strikes(BOB, vec![]).await.unwrap();
+        assert!(!storage.strikes().await.unwrap().contains_key(&BOB));
+    }
+
+    #[tokio::test]
+    async fn spend_adds_up_per_day() {
+        let (storage, _) = storage("spend_adds_up_per_day");
+        let today = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let tomorrow = NaiveDate::from_ymd_opt(2024, 5, 2).unwrap();
+        assert_eq!(storage.spend(today).await.unwrap(), None);
+        storage.add_spend(today, 0.25).await.unwrap();
+        storage.add_spend(today, 0.5).await.unwrap();
+        storage.add_spend(tomorrow, 1.0).await.unwrap();
+        assert_eq!(storage.spend(today).await.unwrap(), Some(0.75));
+        assert_eq!(storage.spend(tomorrow).await.unwrap(), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn stale_roadmap_usage_is_forgotten() {
+        let (storage, _) = storage("stale_roadmap_usage_is_forgotten");
+        let at = |day: u32, hour: u32| Utc.with_ymd_and_hms(2024, 5, day, hour, 0, 0).unwrap();
+        let usage = |last: DateTime<Utc>, count: u32| UserUsage {
+            day: last.date_naive(),
+            count,
+            last,
+        };
+        storage
+            .set_roadmap_usage(ADA, usage(at(1, 9), 1))
+            .await
+            .unwrap();
+        storage
+            .set_roadmap_usage(ADA, usage(at(1, 23), 2))
+            .await
+            .unwrap();
+        storage
+            .set_roadmap_usage(BOB, usage(at(1, 12), 1))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.roadmap_usage().await.unwrap()[&ADA],
+            usage(at(1, 23), 2)
+        );
+        // Just after midnight, ADA's cooldown is still running but BOB's has run out
+        let forgotten = storage
+            .forget_roadmap_usage(at(2, 0).date_naive(), at(1, 22))
+            .await
+            .unwrap();
+        assert_eq!(forgotten, 1);
+        let usage = storage.roadmap_usage().await.unwrap();
+        assert!(usage.contains_key(&ADA));
+        assert!(!usage.contains_key(&BOB));
+    }
+
+    #[tokio::test]
+    async fn latest_roadmap_survives_restart_with_structure() {
+        let (storage, path) = storage("latest_roadmap_survives_restart_with_structure");
+        let structured = StructuredRoadmap {
+            title: "Python".to_string(),
+            intro: String::new(),
+            steps: vec![RoadmapStep {
+                name: "Basics".to_string(),
+                description: "Syntax and types.".to_string(),
+                duration: "2 weeks".to_string(),
+                resources: vec![],
+            }],
+        };
+        let saved = RoadmapProvided {
+            structured: Some(structured.clone()),
+            thread_id: Some(ChannelId::new(42)),
+            roadmap: structured.to_text(),
+            usage: None,
+            model: "gpt-4o".to_string(),
+            elapsed: Duration::from_secs(3),
+        };
+        storage
+            .save(ADA, "roadmap please", &saved, Utc::now())
+            .await
+            .unwrap();
+        drop(storage);
+        let loaded = Storage::open(&path)
+            .unwrap()
+            .get_roadmap(ADA)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.structured, Some(structured));
+        assert_eq!(loaded.thread_id, Some(ChannelId::new(42)));
+    }
+}
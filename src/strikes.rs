@@ -0,0 +1,363 @@
+use crate::spam_detection;
+use crate::storage;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use serenity::all::{Context, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// Strikes that have decayed below this are dropped from someone's history.
+const FORGOTTEN_BELOW: f64 = 0.05;
+
+/// How bad an offense is, which decides how many strikes it's worth.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Severity {
+    /// The model thought it was probably spam, or an invite to another server.
+    Low,
+    /// Clearly spam, breaking probation, mass mentions or the same message across
+    /// channels.
+    Medium,
+    /// Known scam links and images.
+    High,
+}
+
+impl Severity {
+    pub(crate) fn strikes(self) -> f64 {
+        match self {
+            Severity::Low => 1.0,
+            Severity::Medium => 2.0,
+            Severity::High => 3.0,
+        }
+    }
+
+    /// The least that's done for this, however few strikes someone has. Scams always
+    /// get a day's timeout, as they did before the ladder.
+    pub(crate) fn least_escalation(self) -> Escalation {
+        match self {
+            Severity::Low | Severity::Medium => Escalation::Delete,
+            Severity::High => Escalation::LongTimeout,
+        }
+    }
+}
+
+/// What's done to someone once their strikes reach a rung of the ladder, on top of
+/// deleting the message. Ordered from mildest to harshest.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Escalation {
+    #[serde(rename = "delete")]
+    Delete,
+    #[serde(rename = "timeout_10m")]
+    ShortTimeout,
+    #[serde(rename = "timeout_24h")]
+    LongTimeout,
+    #[serde(rename = "kick")]
+    Kick,
+    #[serde(rename = "ban")]
+    Ban,
+}
+
+impl Escalation {
+    /// How long a timeout lasts, if this is one.
+    pub(crate) fn timeout(self) -> Option<Duration> {
+        match self {
+            Escalation::ShortTimeout => Some(Duration::from_secs(10 * 60)),
+            Escalation::LongTimeout => Some(Duration::from_secs(24 * 60 * 60)),
+            _ => None,
+        }
+    }
+
+    /// The name it's configured by in `strike_ladder`.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Escalation::Delete => "delete",
+            Escalation::ShortTimeout => "timeout_10m",
+            Escalation::LongTimeout => "timeout_24h",
+            Escalation::Kick => "kick",
+            Escalation::Ban => "ban",
+        }
+    }
+
+    /// What was done, to follow "I deleted it" in the bot channel.
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            Escalation::Delete => "",
+            Escalation::ShortTimeout => " and timed them out for 10 minutes",
+            Escalation::LongTimeout => " and timed them out for 24 hours",
+            Escalation::Kick => " and kicked them",
+            Escalation::Ban => " and banned them",
+        }
+    }
+}
+
+/// One rung of the ladder: `action` for anyone with at least `strikes`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Rung {
+    pub(crate) strikes: f64,
+    pub(crate) action: Escalation,
+}
+
+pub(crate) fn default_ladder() -> Vec<Rung> {
+    vec![
+        Rung {
+            strikes: 2.0,
+            action: Escalation::ShortTimeout,
+        },
+        Rung {
+            strikes: 4.0,
+            action: Escalation::LongTimeout,
+        },
+        Rung {
+            strikes: 7.0,
+            action: Escalation::Kick,
+        },
+        Rung {
+            strikes: 10.0,
+            action: Escalation::Ban,
+        },
+    ]
+}
+
+/// The action of the highest rung `strikes` reaches, or just deleting below them all.
+pub(crate) fn escalation(ladder: &[Rung], strikes: f64) -> Escalation {
+    ladder
+        .iter()
+        .filter(|rung| strikes >= rung.strikes)
+        .max_by(|a, b| a.strikes.total_cmp(&b.strikes))
+        .map_or(Escalation::Delete, |rung| rung.action)
+}
+
+/// `strikes` after `elapsed_secs`, halving every `half_life`.
+pub(crate) fn decayed(strikes: f64, elapsed_secs: i64, half_life: Duration) -> f64 {
+    let half_lives = elapsed_secs.max(0) as f64 / half_life.as_secs_f64();
+    strikes * 0.5f64.powf(half_lives)
+}
+
+/// Strikes given for one offense at unix time `at`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Strike {
+    pub(crate) at: i64,
+    pub(crate) strikes: f64,
+    pub(crate) reason: String,
+}
+
+/// Everyone's strikes, saved so they're still counted after a restart.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct StrikeRecords {
+    users: HashMap<UserId, Vec<Strike>>,
+}
+
+impl StrikeRecords {
+    /// Loads the strikes saved in `storage`, or none if they can't be read.
+    pub(crate) async fn load(storage: &Storage) -> StrikeRecords {
+        match storage.strikes().await {
+            Ok(users) => StrikeRecords { users },
+            Err(e) => {
+                warn!("Ignoring unreadable strikes: {e:#}");
+                StrikeRecords::default()
+            }
+        }
+    }
+
+    /// `user_id`'s strikes at unix time `now`, each decayed since it was given.
+    pub(crate) fn total(&self, user_id: UserId, now: i64, half_life: Duration) -> f64 {
+        self.history(user_id)
+            .iter()
+            .map(|strike| decayed(strike.strikes, now - strike.at, half_life))
+            .sum()
+    }
+
+    /// Gives `user_id` a strike, forgetting old ones that have all but decayed, and
+    /// returns their total.
+    pub(crate) fn add(&mut self, user_id: UserId, strike: Strike, half_life: Duration) -> f64 {
+        let now = strike.at;
+        let history = self.users.entry(user_id).or_default();
+        history.retain(|old| decayed(old.strikes, now - old.at, half_life) >= FORGOTTEN_BELOW);
+        history.push(strike);
+        self.total(user_id, now, half_life)
+    }
+
+    /// `user_id`'s strikes that haven't been forgotten, oldest first.
+    pub(crate) fn history(&self, user_id: UserId) -> &[Strike] {
+        self.users.get(&user_id).map_or(&[], Vec::as_slice)
+    }
+}
+
+pub(crate) struct Strikes;
+
+impl TypeMapKey for Strikes {
+    type Value = Arc<RwLock<StrikeRecords>>;
+}
+
+async fn strike_records(ctx: &Context) -> Arc<RwLock<StrikeRecords>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Strikes>()
+        .expect("Expected Strikes in TypeMap.")
+        .clone()
+}
+
+/// Gives `user_id` strikes for `severity` and saves them, returning their total, what
+/// the ladder says to do about it and their history.
+pub(crate) async fn record(
+    ctx: &Context,
+    user_id: UserId,
+    severity: Severity,
+    reason: &str,
+    now: i64,
+) -> anyhow::Result<(f64, Escalation, Vec<Strike>)> {
+    let (ladder, half_life) = spam_detection::strike_settings();
+    let strike_records = strike_records(ctx).await;
+    let mut strike_records = strike_records.write().await;
+    let total = strike_records.add(
+        user_id,
+        Strike {
+            at: now,
+            strikes: severity.strikes(),
+            reason: reason.to_string(),
+        },
+        half_life,
+    );
+    let history = strike_records.history(user_id).to_vec();
+    storage::shared()
+        .set_strikes(user_id, history.clone())
+        .await?;
+    let action = escalation(ladder, total).max(severity.least_escalation());
+    Ok((total, action, history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const HOUR: i64 = 60 * 60;
+
+    fn strike(at: i64, strikes: f64) -> Strike {
+        Strike {
+            at,
+            strikes,
+            reason: "spam".to_string(),
+        }
+    }
+
+    #[test]
+    fn strikes_halve_every_half_life() {
+        let half_life = Duration::from_secs(HOUR as u64);
+        for (strikes, elapsed, expected) in [
+            (4.0, 0, 4.0),
+            (4.0, HOUR, 2.0),
+            (4.0, 2 * HOUR, 1.0),
+            (4.0, 3 * HOUR, 0.5),
+            (2.0, HOUR / 2, 2.0 * 0.5f64.sqrt()),
+            (3.0, -HOUR, 3.0),
+            (0.0, HOUR, 0.0),
+        ] {
+            let actual = decayed(strikes, elapsed, half_life);
+            assert!(
+                (actual - expected).abs() < 1e-9,
+                "{strikes} after {elapsed}s: {actual} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn thresholds_pick_the_highest_rung_reached() {
+        let ladder = default_ladder();
+        for (strikes, expected) in [
+            (0.0, Escalation::Delete),
+            (1.0, Escalation::Delete),
+            (1.99, Escalation::Delete),
+            (2.0, Escalation::ShortTimeout),
+            (3.5, Escalation::ShortTimeout),
+            (4.0, Escalation::LongTimeout),
+            (7.0, Escalation::Kick),
+            (9.9, Escalation::Kick),
+            (10.0, Escalation::Ban),
+            (100.0, Escalation::Ban),
+        ] {
+            assert_eq!(escalation(&ladder, strikes), expected, "{strikes} strikes");
+        }
+        assert_eq!(escalation(&[], 100.0), Escalation::Delete);
+        let unordered = vec![
+            Rung {
+                strikes: 5.0,
+                action: Escalation::Ban,
+            },
+            Rung {
+                strikes: 1.0,
+                action: Escalation::Kick,
+            },
+        ];
+        assert_eq!(escalation(&unordered, 3.0), Escalation::Kick);
+        assert_eq!(escalation(&unordered, 6.0), Escalation::Ban);
+    }
+
+    #[test]
+    fn scams_get_a_long_timeout_below_the_ladder() {
+        let ladder = default_ladder();
+        for (severity, strikes, expected) in [
+            (Severity::Low, 1.0, Escalation::Delete),
+            (Severity::Medium, 2.0, Escalation::ShortTimeout),
+            (Severity::High, 3.0, Escalation::LongTimeout),
+            (Severity::High, 7.0, Escalation::Kick),
+        ] {
+            let action = escalation(&ladder, strikes).max(severity.least_escalation());
+            assert_eq!(action, expected, "{severity:?} on {strikes} strikes");
+        }
+    }
+
+    #[test]
+    fn repeat_offenses_climb_then_decay() {
+        let half_life = Duration::from_secs(HOUR as u64);
+        let ladder = default_ladder();
+        let user_id = UserId::new(1);
+        let mut strike_records = StrikeRecords::default();
+        for (at, strikes, total, action) in [
+            (0, Severity::Low.strikes(), 1.0, Escalation::Delete),
+            (0, Severity::Low.strikes(), 2.0, Escalation::ShortTimeout),
+            (0, Severity::Medium.strikes(), 4.0, Escalation::LongTimeout),
+            (HOUR, Severity::High.strikes(), 5.0, Escalation::LongTimeout),
+            (
+                6 * HOUR,
+                Severity::Low.strikes(),
+                1.0 + 3.0 / 32.0,
+                Escalation::Delete,
+            ),
+        ] {
+            let actual = strike_records.add(user_id, strike(at, strikes), half_life);
+            assert!(
+                (actual - total).abs() < 1e-9,
+                "at {at}: {actual} != {total}"
+            );
+            assert_eq!(escalation(&ladder, actual), action, "at {at}");
+        }
+        // The first three have decayed to under FORGOTTEN_BELOW and were dropped
+        assert_eq!(strike_records.history(user_id).len(), 2);
+        assert_eq!(strike_records.total(UserId::new(2), 0, half_life), 0.0);
+    }
+
+    #[tokio::test]
+    async fn strikes_survive_restart() {
+        let path = env::temp_dir().join("strikes_survive_restart.db");
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::open(&path).unwrap();
+        assert_eq!(
+            StrikeRecords::load(&storage).await,
+            StrikeRecords::default()
+        );
+        let mut strike_records = StrikeRecords::default();
+        let user_id = UserId::new(1);
+        strike_records.add(user_id, strike(0, 2.0), Duration::from_secs(60));
+        storage
+            .set_strikes(user_id, strike_records.history(user_id).to_vec())
+            .await
+            .unwrap();
+        let restarted = Storage::open(&path).unwrap();
+        assert_eq!(StrikeRecords::load(&restarted).await, strike_records);
+    }
+}
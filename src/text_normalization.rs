@@ -0,0 +1,128 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters that render as nothing, used to split up words and links so filters miss them.
+pub(crate) fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}' | '\u{180E}' | '\u{200B}'..='\u{200F}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}'
+    )
+}
+
+/// `c` as the ASCII character it's made to look like, for the fullwidth forms and the
+/// small capital, Cyrillic and Greek letters spammers swap into words and links. Only
+/// letters that can't be mistaken for anything else are folded.
+pub(crate) fn fold_lookalike(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3002}' | '\u{FF61}' | '\u{2024}' | '\u{FE52}' => '.',
+        '\u{2215}' | '\u{2044}' | '\u{29F8}' => '/',
+        'а' | 'α' => 'a',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ε' => 'e',
+        'ɡ' => 'g',
+        'һ' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        'ᴀ' => 'a',
+        'ʙ' => 'b',
+        'ᴄ' => 'c',
+        'ᴅ' => 'd',
+        'ᴇ' => 'e',
+        'ɢ' => 'g',
+        'ʜ' => 'h',
+        'ɪ' => 'i',
+        'ᴊ' => 'j',
+        'ᴋ' => 'k',
+        'ʟ' => 'l',
+        'ᴍ' => 'm',
+        'ɴ' => 'n',
+        'ᴏ' => 'o',
+        'ᴘ' => 'p',
+        'ʀ' => 'r',
+        'ꜱ' => 's',
+        'ᴛ' => 't',
+        'ᴜ' => 'u',
+        'ᴠ' => 'v',
+        'ᴡ' => 'w',
+        'ʏ' => 'y',
+        'ᴢ' => 'z',
+        'А' | 'Α' => 'A',
+        'В' | 'Β' => 'B',
+        'С' => 'C',
+        'Е' | 'Ε' => 'E',
+        'Н' | 'Η' => 'H',
+        'І' | 'Ι' => 'I',
+        'Ј' => 'J',
+        'К' | 'Κ' => 'K',
+        'М' | 'Μ' => 'M',
+        'Ν' => 'N',
+        'О' | 'Ο' => 'O',
+        'Р' | 'Ρ' => 'P',
+        'Ѕ' => 'S',
+        'Т' | 'Τ' => 'T',
+        'Х' | 'Χ' => 'X',
+        'У' | 'Υ' => 'Y',
+        'Ζ' => 'Z',
+        _ => c,
+    }
+}
+
+/// `text` the way the spam heuristics compare it: without invisible characters or
+/// combining marks (accents and zalgo), NFKC normalized so fullwidth and styled letters
+/// become plain ones, lookalike letters folded to ASCII, and lowercased.
+pub(crate) fn normalize_for_matching(text: &str) -> String {
+    text.nfkd()
+        .filter(|&c| !is_invisible(c) && !is_combining_mark(c))
+        .nfkc()
+        .map(fold_lookalike)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Obfuscated text seen in spam, and what it should match as. Add new tricks here.
+    const CORPUS: &[(&str, &str)] = &[
+        ("ＦＲＥＥ ＮIТRO", "free nitro"),
+        (
+            "F\u{200B}R\u{200C}E\u{200D}E n\u{2060}i\u{FEFF}t\u{00AD}r\u{200B}o",
+            "free nitro",
+        ),
+        ("f̸̢r̷̛e̵͜ȩ̶ ̴n̷̈i̶͠t̷̕r̶͝ö̵", "free nitro"),
+        ("𝐅𝐑𝐄𝐄 𝐍𝐈𝐓𝐑𝐎", "free nitro"),
+        ("𝓯𝓻𝓮𝓮 𝓷𝓲𝓽𝓻𝓸", "free nitro"),
+        ("ⓕⓡⓔⓔ ⓝⓘⓣⓡⓞ", "free nitro"),
+        ("АІRDRОР іѕ lіvе", "airdrop is live"),
+        ("Сlаim уоur ѕtеаm gіft", "claim your steam gift"),
+        ("ΒΙΤСΟΙΝ ΙΝVΕЅΤΜΕΝΤ", "bitcoin investment"),
+        ("DM ｍｅ ｏｎ ｔｅｌｅｇｒａｍ", "dm me on telegram"),
+        ("ｅａｒｎ ＄５００ ｄａｉｌｙ", "earn $500 daily"),
+        ("ᴀᴅᴅ ᴍᴇ ᴏɴ ᴡʜᴀᴛꜱᴀᴘᴘ", "add me on whatsapp"),
+        ("Café résumé", "cafe resume"),
+        ("How do I start with pandas?", "how do i start with pandas?"),
+    ];
+
+    #[test]
+    fn obfuscated_samples_normalize() {
+        for (sample, normalized) in CORPUS {
+            assert_eq!(normalize_for_matching(sample), *normalized, "{sample}");
+        }
+    }
+
+    #[test]
+    fn normalizing_is_idempotent() {
+        for (sample, _) in CORPUS {
+            let once = normalize_for_matching(sample);
+            assert_eq!(normalize_for_matching(&once), once, "{sample}");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::roadmaps;
+use lru::LruCache;
+use serenity::all::{ChannelId, Context, CreateThread, GuildId, MessageId};
+use serenity::prelude::TypeMapKey;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// Discord's limit on a thread name.
+const THREAD_NAME_LIMIT: usize = 100;
+
+/// How many roadmap threads are remembered, the least recently used forgotten first.
+const REMEMBERED_THREADS: usize = 1000;
+
+/// Threads roadmaps were posted in, by the message they were started from. Discord only
+/// allows one thread per message, so making the roadmap again reuses it.
+pub(crate) struct RoadmapThreads;
+
+impl TypeMapKey for RoadmapThreads {
+    type Value = Arc<RwLock<LruCache<MessageId, ChannelId>>>;
+}
+
+/// An empty cache for `RoadmapThreads`.
+pub(crate) fn remembered_threads() -> LruCache<MessageId, ChannelId> {
+    LruCache::new(NonZeroUsize::new(REMEMBERED_THREADS).expect("REMEMBERED_THREADS is non-zero"))
+}
+
+async fn roadmap_threads(ctx: &Context) -> Arc<RwLock<LruCache<MessageId, ChannelId>>> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<RoadmapThreads>()
+        .expect("Expected RoadmapThreads in TypeMap.")
+        .clone()
+}
+
+/// "Roadmap for <name>", cut down to fit Discord's limit.
+fn thread_name(author_name: &str) -> String {
+    format!("Roadmap for {author_name}")
+        .chars()
+        .take(THREAD_NAME_LIMIT)
+        .collect()
+}
+
+/// The thread to post a roadmap for `author_name` in, started off `message_id` in
+/// `channel_id`. `None` when threads are off for the guild, outside guilds, or when the
+/// thread couldn't be made, e.g. for lack of permission or inside another thread, in
+/// which case the roadmap goes in `channel_id` as usual.
+pub(crate) async fn roadmap_thread(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    author_name: &str,
+) -> Option<ChannelId> {
+    if !guild_id.is_some_and(roadmaps::threads_enabled) {
+        return None;
+    }
+    let threads = roadmap_threads(ctx).await;
+    if let Some(thread_id) = threads.write().await.get(&message_id) {
+        return Some(*thread_id);
+    }
+    let thread = CreateThread::new(thread_name(author_name))
+        .auto_archive_duration(roadmaps::thread_archive_duration());
+    match channel_id
+        .create_thread_from_message(&ctx.http, message_id, thread)
+        .await
+    {
+        Ok(thread) => {
+            info!("Started roadmap thread {} for {author_name}", thread.id);
+            threads.write().await.put(message_id, thread.id);
+            Some(thread.id)
+        }
+        Err(e) => {
+            warn!("Failed to start roadmap thread due to {e}, replying inline");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_names_fit_discord_limit() {
+        assert_eq!(thread_name("ada"), "Roadmap for ada");
+        let name = thread_name("ä".repeat(200).as_str());
+        assert_eq!(name.chars().count(), THREAD_NAME_LIMIT);
+        assert!(name.starts_with("Roadmap for ä"));
+    }
+}
@@ -1,10 +1,11 @@
 use chrono::Duration;
-use serenity::all::{Context, Message, Timestamp, User, UserId};
+use serenity::all::{Context, GuildId, Message, Permissions, RoleId, Timestamp, User, UserId};
 use serenity::prelude::TypeMapKey;
 use std::cmp::Ordering;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::warn;
 
 pub async fn update_user_join_date(ctx: &Context, user: &User, join_date: i64) {
     if get_user_join_date(ctx, user).await.is_none() {
@@ -34,6 +35,46 @@ pub async fn get_user_join_date(ctx: &Context, user: &User) -> Option<i64> {
     user_date_info.get(&user.id).copied()
 }
 
+/// Permissions a member gets from `member_roles`, given every role in `guild_id` with its
+/// permissions. The @everyone role shares the guild's ID and applies to everyone.
+pub fn role_permissions(
+    guild_id: GuildId,
+    member_roles: &[RoleId],
+    guild_roles: &[(RoleId, Permissions)],
+) -> Permissions {
+    guild_roles
+        .iter()
+        .filter(|(role_id, _)| role_id.get() == guild_id.get() || member_roles.contains(role_id))
+        .fold(Permissions::empty(), |granted, (_, permissions)| {
+            granted | *permissions
+        })
+}
+
+/// Whether the author of `message` has any of `permissions` through their roles,
+/// administrators having them all. Asks Discord for the guild's roles, so it's only worth
+/// calling once a message is about to be acted on.
+pub async fn has_any_permission(
+    ctx: &Context,
+    message: &Message,
+    permissions: Permissions,
+) -> bool {
+    let (Some(guild_id), Some(member)) = (message.guild_id, message.member.as_ref()) else {
+        return false;
+    };
+    let guild_roles = match ctx.http.get_guild_roles(guild_id).await {
+        Ok(roles) => roles
+            .into_iter()
+            .map(|role| (role.id, role.permissions))
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            warn!("Failed to read roles of {guild_id} due to {e}");
+            return false;
+        }
+    };
+    let granted = role_permissions(guild_id, &member.roles, &guild_roles);
+    granted.contains(Permissions::ADMINISTRATOR) || granted.intersects(permissions)
+}
+
 pub struct UserJoinDate;
 
 impl TypeMapKey for UserJoinDate {
@@ -145,3 +186,26 @@ pub async fn retrieve_user_context(ctx: &Context, message: &Message) -> Vec<Stri
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permissions_come_from_everyone_and_own_roles() {
+        let guild_id = GuildId::new(1);
+        let roles = [
+            (RoleId::new(1), Permissions::SEND_MESSAGES),
+            (RoleId::new(2), Permissions::MENTION_EVERYONE),
+            (RoleId::new(3), Permissions::ADMINISTRATOR),
+        ];
+        assert_eq!(
+            role_permissions(guild_id, &[], &roles),
+            Permissions::SEND_MESSAGES
+        );
+        assert_eq!(
+            role_permissions(guild_id, &[RoleId::new(2)], &roles),
+            Permissions::SEND_MESSAGES | Permissions::MENTION_EVERYONE
+        );
+    }
+}
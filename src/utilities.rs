@@ -1,31 +1,1083 @@
-use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole};
+use crate::metrics;
+use crate::roadmaps::RoadmapError;
+use anyhow::bail;
+use lazy_static::lazy_static;
+use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionRequest};
+use openai::OpenAiError;
+use rand::Rng;
+use regex::Regex;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Delay before the first retry of a transient OpenAI failure, doubled on each attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Finish reason OpenAI gives when its content filter stopped the reply.
+pub(crate) const CONTENT_FILTER: &str = "content_filter";
+
+lazy_static! {
+    static ref RETRY_AFTER_REGEX: Regex =
+        Regex::new(r"(?i)try again in (\d+(?:\.\d+)?)\s*(ms|s)\b").unwrap();
+}
+
+pub(crate) use openai::chat::ChatCompletionMessageRole as Role;
 
 pub(crate) fn user_message(message: String) -> ChatCompletionMessage {
+    role_message(Role::User, message)
+}
+
+pub(crate) fn system_message(message: String) -> ChatCompletionMessage {
+    role_message(Role::System, message)
+}
+
+fn role_message(role: Role, message: String) -> ChatCompletionMessage {
     ChatCompletionMessage {
-        role: ChatCompletionMessageRole::User,
+        role,
         content: Some(message),
         name: None,
         function_call: None,
     }
 }
 
+/// Tokens the chat format adds around every message, on top of its content.
+pub(crate) const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Counts tokens the way `model` would, falling back to the gpt-4o tokenizer for
+/// models tiktoken doesn't know about (self-hosted or brand new ones).
+pub(crate) fn count_tokens(model: &str, text: &str) -> usize {
+    tiktoken_rs::bpe_for_model(model)
+        .unwrap_or_else(|_| tiktoken_rs::o200k_base_singleton())
+        .encode_ordinary(text)
+        .len()
+}
+
+/// Tokens `messages` will use once sent, including the chat format overhead.
+pub(crate) fn count_prompt_tokens(model: &str, messages: &[ChatCompletionMessage]) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            TOKENS_PER_MESSAGE + count_tokens(model, message.content.as_deref().unwrap_or(""))
+        })
+        .sum()
+}
+
+/// A limit on the user message, measured in whatever unit `measure` counts.
+pub(crate) struct PromptBudget<'a> {
+    limit: usize,
+    measure: Box<dyn Fn(&str) -> usize + Send + Sync + 'a>,
+    /// Extra cost of each message when context is sent as separate messages.
+    per_message: usize,
+}
+
+impl<'a> PromptBudget<'a> {
+    pub(crate) fn chars(limit: usize) -> Self {
+        PromptBudget {
+            limit,
+            measure: Box::new(|text| text.chars().count()),
+            per_message: 0,
+        }
+    }
+
+    pub(crate) fn tokens(model: &'a str, limit: usize) -> Self {
+        PromptBudget {
+            limit,
+            measure: Box::new(move |text| count_tokens(model, text)),
+            per_message: TOKENS_PER_MESSAGE,
+        }
+    }
+
+    /// Whether `text` fits in this budget on its own.
+    pub(crate) fn allows(&self, text: &str) -> bool {
+        (self.measure)(text) <= self.limit
+    }
+
+    fn remaining(&self, used: usize) -> usize {
+        self.limit.saturating_sub(used)
+    }
+}
+
+/// Heads the triggering message when it's sent along with context, so the model knows
+/// which message it's deciding on.
+pub(crate) const CURRENT_MESSAGE_LABEL: &str = "Current message:";
+/// Heads the context in `build_message`.
+pub(crate) const CONTEXT_LABEL: &str = "Recent conversation:";
+
+/// `message` under `CURRENT_MESSAGE_LABEL`.
+pub(crate) fn labeled_message(message: String) -> String {
+    format!("{CURRENT_MESSAGE_LABEL}\n{message}")
+}
+
+/// Builds the prompt from a system message, the triggering message and its context.
+///
+/// `context` is expected oldest first. The most recent `context_length` entries that fit
+/// every budget are kept, and they're emitted in chronological order, one per line, under
+/// `CONTEXT_LABEL`, followed by the triggering message under `CURRENT_MESSAGE_LABEL`.
+/// Without context the message is sent unlabeled. The oldest message that only partly
+/// fits is cut down to its last few chars rather than dropped, never splitting a code
+/// point. A triggering message over budget by itself is cut down the same way, keeping
+/// its end. Budgets cover the two sections, not the labels.
 pub(crate) fn build_message(
     message: String,
     context: Vec<String>,
     system_message: ChatCompletionMessage,
     context_length: usize,
-    message_limit_chars: usize,
+    budgets: &[PromptBudget],
+) -> Vec<ChatCompletionMessage> {
+    let message = fit_message(message, budgets);
+    let lines: Vec<String> = fit_context(
+        message.as_str(),
+        context.into_iter().map(|line| ((), line)).collect(),
+        context_length,
+        0,
+        budgets,
+        // Account for the newline separating each line from the next one
+        |budget| (budget.measure)("\n"),
+    )
+    .into_iter()
+    .map(|(_, line)| line)
+    .collect();
+    let prompt = if lines.is_empty() {
+        message
+    } else {
+        format!(
+            "{CONTEXT_LABEL}\n{}\n\n{}",
+            lines.join("\n"),
+            labeled_message(message)
+        )
+    };
+    vec![system_message, user_message(prompt)]
+}
+
+/// Like `build_message`, but sends each context entry as its own message with its role,
+/// so the model can tell its own earlier replies from what users said. The entries are
+/// set apart by being messages of their own, so only the triggering message, always sent
+/// as the user, is labeled when there's context. The newest `min_context` entries are
+/// kept even over budget, cut down to share what's left of it.
+pub(crate) fn build_conversation(
+    message: String,
+    context: Vec<(Role, String)>,
+    system_message: ChatCompletionMessage,
+    context_length: usize,
+    min_context: usize,
+    budgets: &[PromptBudget],
 ) -> Vec<ChatCompletionMessage> {
-    let mut messages: Vec<ChatCompletionMessage> = vec![system_message];
-    let mut message_length: usize = message.len();
-    let mut message_buffer: String = message;
-    for contextual_message in context.into_iter().take(context_length) {
-        if message_length + contextual_message.len() > message_limit_chars {
+    let message = fit_message(message, budgets);
+    let context = fit_context(
+        message.as_str(),
+        context,
+        context_length,
+        min_context,
+        budgets,
+        |budget| budget.per_message,
+    );
+    let message = if context.is_empty() {
+        message
+    } else {
+        labeled_message(message)
+    };
+    let mut messages = vec![system_message];
+    messages.extend(
+        context
+            .into_iter()
+            .map(|(role, text)| role_message(role, text)),
+    );
+    messages.push(user_message(message));
+    messages
+}
+
+/// `context` with each entry trimmed, blank entries dropped and runs of the same text
+/// collapsed to one, so repeats and noise don't use up the budget.
+pub(crate) fn clean_context<R>(context: Vec<(R, String)>) -> Vec<(R, String)> {
+    let mut cleaned: Vec<(R, String)> = Vec::with_capacity(context.len());
+    for (role, text) in context {
+        let text = text.trim();
+        if text.is_empty() || cleaned.last().is_some_and(|(_, last)| last == text) {
+            continue;
+        }
+        cleaned.push((role, text.to_string()));
+    }
+    cleaned
+}
+
+/// `message` cut down to fit every budget if it's too long by itself. The end is kept,
+/// since that's usually where the actual request is, and the cut is marked with an
+/// ellipsis.
+fn fit_message(message: String, budgets: &[PromptBudget]) -> String {
+    if budgets.iter().all(|budget| budget.allows(message.as_str())) {
+        return message;
+    }
+    let kept = keep_last_fitting(message.as_str(), |tail| {
+        let truncated = format!("…{tail}");
+        budgets
+            .iter()
+            .all(|budget| budget.allows(truncated.as_str()))
+    });
+    warn!(
+        "Truncating a {} char message to its last {} chars to fit the prompt budget",
+        message.chars().count(),
+        kept.chars().count()
+    );
+    format!("…{kept}")
+}
+
+/// The most recent `context_length` entries of `context` that fit every budget alongside
+/// `message`, oldest first, with each entry costing `separator` on top of its text.
+///
+/// `context_length` caps the count and the budgets cap the size, each on its own, so
+/// whichever is hit first ends the context. The newest `min_context` entries are kept
+/// even when they're over budget: what's left of it is split evenly between them, shorter
+/// entries handing what they don't need to longer ones, and each is cut down to the end
+/// of its share. Entries left with no room at all, as when `message` uses up the budget,
+/// are still dropped.
+fn fit_context<R>(
+    message: &str,
+    context: Vec<(R, String)>,
+    context_length: usize,
+    min_context: usize,
+    budgets: &[PromptBudget],
+    separator: impl Fn(&PromptBudget) -> usize,
+) -> Vec<(R, String)> {
+    let mut used: Vec<usize> = budgets
+        .iter()
+        .map(|budget| (budget.measure)(message))
+        .collect();
+    let fits_share = |text: &str, used: &[usize], shares: usize| {
+        budgets.iter().zip(used).all(|(budget, used)| {
+            (budget.measure)(text) + separator(budget) <= budget.remaining(*used) / shares
+        })
+    };
+    let fits = |text: &str, used: &[usize]| fits_share(text, used, 1);
+    let mut newest_first = context.into_iter().rev().take(context_length);
+    let mut guaranteed: Vec<(usize, R, String)> = newest_first
+        .by_ref()
+        .take(min_context)
+        .enumerate()
+        .map(|(age, (role, text))| (age, role, text))
+        .collect();
+    let mut trial = used.clone();
+    let all_fit = guaranteed.iter().all(|(_, _, text)| {
+        let fit = fits(text.as_str(), &trial);
+        for (budget, used) in budgets.iter().zip(trial.iter_mut()) {
+            *used += (budget.measure)(text.as_str()) + separator(budget);
+        }
+        fit
+    });
+    if !all_fit {
+        // Shortest first, so whatever short entries leave over goes to the longer ones
+        guaranteed.sort_by_key(|(_, _, text)| text.chars().count());
+        let count = guaranteed.len();
+        for (index, (_, _, text)) in guaranteed.iter_mut().enumerate() {
+            let shares = count - index;
+            if !fits_share(text.as_str(), &used, shares) {
+                *text = keep_last_fitting(text.as_str(), |tail| fits_share(tail, &used, shares));
+                if text.is_empty() {
+                    continue;
+                }
+            }
+            for (budget, used) in budgets.iter().zip(used.iter_mut()) {
+                *used += (budget.measure)(text.as_str()) + separator(budget);
+            }
+        }
+        guaranteed.sort_by_key(|(age, _, _)| *age);
+        guaranteed.retain(|(_, _, text)| !text.is_empty());
+        debug!("Splitting what's left of the prompt budget between the newest {count} context messages");
+    } else {
+        used = trial;
+    }
+    let mut included_context: Vec<(R, String)> = guaranteed
+        .into_iter()
+        .map(|(_, role, text)| (role, text))
+        .collect();
+    for (role, contextual_message) in newest_first {
+        if !fits(contextual_message.as_str(), &used) {
+            let truncated =
+                keep_last_fitting(contextual_message.as_str(), |text| fits(text, &used));
+            if !truncated.is_empty() {
+                included_context.push((role, truncated));
+            }
             break;
         }
-        message_length += contextual_message.len();
-        message_buffer.insert_str(0, contextual_message.as_str());
+        for (budget, used) in budgets.iter().zip(used.iter_mut()) {
+            *used += (budget.measure)(contextual_message.as_str()) + separator(budget);
+        }
+        included_context.push((role, contextual_message));
+    }
+    included_context.reverse();
+    included_context
+}
+
+/// Keeps the longest tail of `text` that `fits`, never splitting a code point.
+fn keep_last_fitting(text: &str, fits: impl Fn(&str) -> bool) -> String {
+    let boundaries: Vec<usize> = text.char_indices().map(|(index, _)| index).collect();
+    // Binary search for the earliest char to start from that still fits
+    let (mut low, mut high) = (0, boundaries.len());
+    while low < high {
+        let middle = (low + high) / 2;
+        if fits(&text[boundaries[middle]..]) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    boundaries
+        .get(low)
+        .map(|&start| text[start..].to_string())
+        .unwrap_or_default()
+}
+
+/// Pulls the reply text out of a completion, or the call's arguments when the model
+/// called a function, erroring rather than panicking when OpenAI sends back no choices
+/// or an empty message. A reply stopped by the content filter is `RoadmapError::Refused`.
+pub(crate) fn reply_content(chat_completion: ChatCompletion) -> Result<String, RoadmapError> {
+    let Some(choice) = chat_completion.choices.into_iter().next() else {
+        return Err(RoadmapError::NoChoices {
+            model: chat_completion.model,
+        });
+    };
+    if choice.finish_reason == CONTENT_FILTER {
+        return Err(RoadmapError::Refused {
+            model: chat_completion.model,
+        });
+    }
+    if let Some(function_call) = choice.message.function_call {
+        Ok(function_call.arguments)
+    } else if let Some(content) = choice.message.content {
+        Ok(content)
+    } else {
+        Err(RoadmapError::EmptyContent {
+            model: chat_completion.model,
+            finish_reason: choice.finish_reason,
+        })
+    }
+}
+
+/// Rate limits, server errors and dropped connections are worth retrying, anything
+/// else (bad requests, invalid keys) will fail the same way again.
+pub(crate) fn is_transient(error: &OpenAiError) -> bool {
+    matches!(
+        error.error_type.as_str(),
+        "reqwest" | "server_error" | "requests" | "tokens"
+    ) || matches!(error.code.as_deref(), Some("rate_limit_exceeded"))
+}
+
+/// OpenAI's answer to a mistyped or revoked API key, going by the error's code and type
+/// since the `openai` crate drops the 401 status. A missing key never gets this far, as
+/// `set_api_key` refuses blank ones.
+pub(crate) fn is_auth_error(error: &OpenAiError) -> bool {
+    error.code.as_deref() == Some("invalid_api_key") || error.error_type == "authentication_error"
+}
+
+/// Turns an OpenAI failure caused by the API key into `RoadmapError::Unauthenticated`,
+/// leaving anything else as it was.
+pub(crate) fn flag_auth_error(error: anyhow::Error) -> anyhow::Error {
+    if error
+        .downcast_ref::<OpenAiError>()
+        .is_some_and(is_auth_error)
+    {
+        RoadmapError::Unauthenticated.into()
+    } else {
+        error
+    }
+}
+
+/// How hard to retry transient OpenAI failures.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: usize,
+    /// Delay before the first retry, doubled on each one after.
+    pub(crate) base_delay: Duration,
+    /// No retry is started if it would finish waiting after this much time in total.
+    pub(crate) deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: RETRY_BASE_DELAY,
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Attached to a transient failure that's still failing once retries ran out, so
+/// callers can tell "busy, try later" apart from a permanent error.
+#[derive(Debug)]
+pub(crate) struct RetriesExhausted {
+    pub(crate) retries: usize,
+}
+
+impl Display for RetriesExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpenAI still failing after {} retries", self.retries)
+    }
+}
+
+/// OpenAI repeats the `Retry-After` header in rate limit messages ("Please try again in
+/// 1.5s"), which is the only place the `openai` crate lets us see it.
+fn retry_after(error: &OpenAiError) -> Option<Duration> {
+    let captures = RETRY_AFTER_REGEX.captures(error.message.as_str())?;
+    let amount: f64 = captures[1].parse().ok()?;
+    match &captures[2] {
+        "ms" => Some(Duration::from_secs_f64(amount / 1_000.0)),
+        _ => Some(Duration::from_secs_f64(amount)),
+    }
+}
+
+/// Runs `operation`, retrying transient OpenAI failures with exponential backoff and
+/// jitter (or the server's requested delay) within `retry_policy`, and giving up straight
+/// away on anything else.
+pub(crate) async fn retry_transient<T, F, Fut>(
+    retry_policy: &RetryPolicy,
+    mut operation: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let started = Instant::now();
+    let mut retries = 0;
+    loop {
+        let error = match operation().await {
+            Ok(result) => return Ok(result),
+            Err(error) => error,
+        };
+        let Some(openai_error) = error
+            .downcast_ref::<OpenAiError>()
+            .filter(|e| is_transient(e))
+        else {
+            return Err(error);
+        };
+        let delay = retry_after(openai_error)
+            .unwrap_or_else(|| with_jitter(retry_policy.base_delay * 2u32.pow(retries as u32)));
+        if retries >= retry_policy.max_retries || started.elapsed() + delay > retry_policy.deadline
+        {
+            return Err(error.context(RetriesExhausted { retries }));
+        }
+        retries += 1;
+        metrics::increment(metrics::OPENAI_RETRIES, &[]);
+        warn!(
+            "OpenAI request failed with {error}, retry {retries}/{} in {delay:?}",
+            retry_policy.max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Bounds how long an OpenAI request may take, so a stalled API can't hang a handler.
+pub(crate) async fn with_timeout<T, E>(
+    request_timeout: Duration,
+    request: impl Future<Output = Result<T, E>>,
+) -> anyhow::Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match tokio::time::timeout(request_timeout, request).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => bail!("OpenAI request timed out after {request_timeout:?}"),
+    }
+}
+
+/// Adds up to half of `delay` again so concurrent retries don't fire in lockstep.
+fn with_jitter(delay: Duration) -> Duration {
+    delay + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+}
+
+/// Sends a chat completion, giving each attempt `request_timeout` and retrying transient
+/// failures according to `retry_policy`.
+pub(crate) async fn create_completion(
+    request: &ChatCompletionRequest,
+    retry_policy: &RetryPolicy,
+    request_timeout: Duration,
+) -> anyhow::Result<ChatCompletion> {
+    retry_transient(retry_policy, || {
+        with_timeout(request_timeout, ChatCompletion::create(request))
+    })
+    .await
+    .map_err(flag_auth_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai::chat::{ChatCompletionChoice, ChatCompletionFunctionCall};
+
+    fn completion(choices: Vec<ChatCompletionChoice>) -> ChatCompletion {
+        ChatCompletion {
+            id: "chatcmpl-test".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: "gpt-4o-mini".to_string(),
+            choices,
+            usage: None,
+        }
+    }
+
+    fn system_message() -> ChatCompletionMessage {
+        ChatCompletionMessage {
+            role: Role::System,
+            content: Some("system".to_string()),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    fn user_content(messages: &[ChatCompletionMessage]) -> &str {
+        messages.last().unwrap().content.as_deref().unwrap()
+    }
+
+    fn context(messages: &[&str]) -> Vec<String> {
+        messages.iter().map(|message| message.to_string()).collect()
+    }
+
+    /// The prompt `build_message` should send for `context` lines and `message`.
+    fn labeled(context: &str, message: &str) -> String {
+        format!("Recent conversation:\n{context}\n\nCurrent message:\n{message}")
+    }
+
+    #[test]
+    fn build_message_without_context() {
+        let messages = build_message(
+            "I'd like a roadmap".to_string(),
+            vec![],
+            system_message(),
+            3,
+            &[PromptBudget::chars(2048)],
+        );
+        assert_eq!(messages.len(), 2);
+        assert_eq!(user_content(&messages), "I'd like a roadmap");
+    }
+
+    #[test]
+    fn build_message_with_single_context() {
+        let messages = build_message(
+            "I'd like a roadmap".to_string(),
+            context(&["first"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars(2048)],
+        );
+        assert_eq!(
+            user_content(&messages),
+            labeled("first", "I'd like a roadmap")
+        );
+    }
+
+    #[test]
+    fn build_message_keeps_most_recent_context_in_order() {
+        let messages = build_message(
+            "I'd like a roadmap".to_string(),
+            context(&["first", "second", "third", "fourth"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars(2048)],
+        );
+        assert_eq!(
+            user_content(&messages),
+            labeled("second\nthird\nfourth", "I'd like a roadmap")
+        );
+    }
+
+    #[test]
+    fn build_message_drops_context_over_budget() {
+        let messages = build_message(
+            "roadmap".to_string(),
+            context(&["older", "newer"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars("roadmap".len() + "newer".len() + 1)],
+        );
+        assert_eq!(user_content(&messages), labeled("newer", "roadmap"));
+    }
+
+    #[test]
+    fn build_message_truncates_oversized_message() {
+        let message = format!("{}Can I get a roadmap?", "x".repeat(10_000));
+        let messages = build_message(
+            message,
+            context(&["older"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars(2048)],
+        );
+        let content = user_content(&messages);
+        assert_eq!(content.chars().count(), 2048);
+        assert!(content.starts_with('…'));
+        assert!(content.ends_with("Can I get a roadmap?"));
+    }
+
+    #[test]
+    fn build_message_truncates_partially_fitting_context() {
+        let messages = build_message(
+            "roadmap".to_string(),
+            context(&["older", "newer"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars(
+                "roadmap".len() + "newer".len() + 1 + "er".len() + 1,
+            )],
+        );
+        assert_eq!(user_content(&messages), labeled("er\nnewer", "roadmap"));
+    }
+
+    #[test]
+    fn build_message_counts_chars_not_bytes() {
+        // 5 chars but 20 bytes each, so byte counting would drop both messages
+        let messages = build_message(
+            "路线图请求".to_string(),
+            context(&["🚀🚀🚀🚀🚀", "数据科学家"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars(17)],
+        );
+        assert_eq!(
+            user_content(&messages),
+            labeled("🚀🚀🚀🚀🚀\n数据科学家", "路线图请求")
+        );
+    }
+
+    #[test]
+    fn build_message_truncates_multibyte_context_on_char_boundary() {
+        let messages = build_message(
+            "路线图".to_string(),
+            context(&["🚀a🚀b🚀"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars("路线图".chars().count() + 1 + 3)],
+        );
+        assert_eq!(user_content(&messages), labeled("🚀b🚀", "路线图"));
+        // The budget covers the context and message, the labels come on top
+        let labels = labeled("", "").chars().count();
+        assert!(user_content(&messages).chars().count() <= 7 + labels);
+    }
+
+    #[test]
+    fn build_message_applies_every_budget() {
+        let model = "gpt-4o-mini";
+        let newer = "Now I want to get into ML";
+        let token_limit =
+            count_tokens(model, "roadmap") + count_tokens(model, newer) + count_tokens(model, "\n");
+        let messages = build_message(
+            "roadmap".to_string(),
+            context(&["I've finished a Python course", newer]),
+            system_message(),
+            3,
+            &[
+                PromptBudget::chars(2048),
+                PromptBudget::tokens(model, token_limit),
+            ],
+        );
+        assert_eq!(user_content(&messages), labeled(newer, "roadmap"));
+    }
+
+    #[test]
+    fn build_conversation_keeps_roles_in_order() {
+        let messages = build_conversation(
+            "And after that?".to_string(),
+            vec![
+                (Role::User, "ada: I know Python".to_string()),
+                (Role::Assistant, "Try pandas next".to_string()),
+            ],
+            system_message(),
+            3,
+            0,
+            &[PromptBudget::chars(2048)],
+        );
+        let turns: Vec<(String, &str)> = messages
+            .iter()
+            .map(|message| {
+                (
+                    format!("{:?}", message.role),
+                    message.content.as_deref().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            turns[1..],
+            [
+                ("User".to_string(), "ada: I know Python"),
+                ("Assistant".to_string(), "Try pandas next"),
+                ("User".to_string(), "Current message:\nAnd after that?"),
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_set_the_message_apart_from_context() {
+        let messages = build_message(
+            "Can I get a roadmap?".to_string(),
+            context(&["ada: I know Python"]),
+            system_message(),
+            3,
+            &[PromptBudget::chars(2048)],
+        );
+        let prompt = user_content(&messages);
+        let context_at = prompt.find(CONTEXT_LABEL).unwrap();
+        let message_at = prompt.find(CURRENT_MESSAGE_LABEL).unwrap();
+        assert!(context_at < prompt.find("ada: I know Python").unwrap());
+        assert!(message_at > prompt.find("ada: I know Python").unwrap());
+        assert!(message_at < prompt.find("Can I get a roadmap?").unwrap());
+        // Context that doesn't fit leaves nothing to tell apart
+        let messages = build_message(
+            "Can I get a roadmap?".to_string(),
+            context(&["ada: I know Python"]),
+            system_message(),
+            0,
+            &[PromptBudget::chars(2048)],
+        );
+        assert_eq!(user_content(&messages), "Can I get a roadmap?");
+    }
+
+    #[test]
+    fn prompt_token_count_matches_tokenizer() {
+        let hello = || user_message("hello world".to_string());
+        // 2 tokens of text plus the per-message overhead
+        assert_eq!(count_prompt_tokens("gpt-4o-mini", &[hello()]), 6);
+        assert_eq!(count_prompt_tokens("gpt-4o-mini", &[hello(), hello()]), 12);
+        assert_eq!(
+            count_prompt_tokens(
+                "gpt-4o-mini",
+                &[user_message(
+                    "The quick brown fox jumps over the lazy dog".to_string()
+                )]
+            ),
+            13
+        );
+        assert_eq!(count_prompt_tokens("gpt-4o-mini", &[]), 0);
+    }
+
+    #[test]
+    fn clean_context_drops_blanks_and_repeats() {
+        let cleaned = clean_context(vec![
+            ((), "  how do I start with rust?\n".to_string()),
+            ((), "how do I start with rust?".to_string()),
+            ((), "   ".to_string()),
+            ((), String::new()),
+            ((), "anyone?".to_string()),
+            ((), "how do I start with rust?".to_string()),
+            ((), "anyone?".to_string()),
+            ((), "anyone?".to_string()),
+        ]);
+        let texts: Vec<&str> = cleaned.iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "how do I start with rust?",
+                "anyone?",
+                "how do I start with rust?",
+                "anyone?",
+            ]
+        );
+    }
+
+    #[test]
+    fn build_conversation_counts_message_overhead() {
+        let model = "gpt-4o-mini";
+        let newer = "Now I want to get into ML";
+        let token_limit =
+            count_tokens(model, "roadmap") + count_tokens(model, newer) + TOKENS_PER_MESSAGE;
+        let messages = build_conversation(
+            "roadmap".to_string(),
+            vec![
+                (Role::User, "I've finished a Python course".to_string()),
+                (Role::User, newer.to_string()),
+            ],
+            system_message(),
+            3,
+            0,
+            &[PromptBudget::tokens(model, token_limit)],
+        );
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].content.as_deref(), Some(newer));
+    }
+
+    #[test]
+    fn min_context_holds_when_budget_and_count_disagree() {
+        let context = || {
+            vec![
+                (Role::User, "first entry".to_string()),
+                (Role::User, "second one".to_string()),
+                (Role::User, "third one!".to_string()),
+                (Role::User, "fourth one".to_string()),
+            ]
+        };
+        let contents = |messages: Vec<ChatCompletionMessage>| -> Vec<String> {
+            messages[1..messages.len() - 1]
+                .iter()
+                .map(|message| message.content.clone().unwrap())
+                .collect()
+        };
+        // Room for all four, but only three are allowed
+        let messages = build_conversation(
+            "roadmap".to_string(),
+            context(),
+            system_message(),
+            3,
+            2,
+            &[PromptBudget::chars(2048)],
+        );
+        assert_eq!(
+            contents(messages),
+            ["second one", "third one!", "fourth one"]
+        );
+        // Three are allowed, but there's only room for one, so the two guaranteed share it
+        let messages = build_conversation(
+            "roadmap".to_string(),
+            context(),
+            system_message(),
+            3,
+            2,
+            &[PromptBudget::chars("roadmap".len() + 10)],
+        );
+        assert_eq!(contents(messages), [" one!", "h one"]);
+        // Without the guarantee the newest takes the whole budget
+        let messages = build_conversation(
+            "roadmap".to_string(),
+            context(),
+            system_message(),
+            3,
+            0,
+            &[PromptBudget::chars("roadmap".len() + 10)],
+        );
+        assert_eq!(contents(messages), ["fourth one"]);
+    }
+
+    #[test]
+    fn count_tokens_matches_tokenizer() {
+        assert_eq!(count_tokens("gpt-4o-mini", ""), 0);
+        assert_eq!(count_tokens("gpt-4o-mini", "hello world"), 2);
+        assert_eq!(count_tokens("my-local-model", "hello world"), 2);
+    }
+
+    #[test]
+    fn reply_content_without_choices_is_an_error() {
+        let error = reply_content(completion(vec![])).unwrap_err();
+        assert!(matches!(error, RoadmapError::NoChoices { .. }));
+        assert_eq!(
+            error.to_string(),
+            "OpenAI returned no choices from gpt-4o-mini"
+        );
+    }
+
+    #[test]
+    fn reply_content_refusal_is_a_roadmap_error() {
+        let choice = ChatCompletionChoice {
+            index: 0,
+            finish_reason: "content_filter".to_string(),
+            message: ChatCompletionMessage {
+                role: Role::Assistant,
+                content: None,
+                name: None,
+                function_call: None,
+            },
+        };
+        let error = reply_content(completion(vec![choice])).unwrap_err();
+        assert!(matches!(error, RoadmapError::Refused { .. }));
+    }
+
+    #[test]
+    fn reply_content_without_content_reports_finish_reason() {
+        let choice = ChatCompletionChoice {
+            index: 0,
+            finish_reason: "length".to_string(),
+            message: ChatCompletionMessage {
+                role: Role::Assistant,
+                content: None,
+                name: None,
+                function_call: None,
+            },
+        };
+        let error = reply_content(completion(vec![choice])).unwrap_err();
+        assert!(matches!(error, RoadmapError::EmptyContent { .. }));
+        assert_eq!(
+            error.to_string(),
+            "No reply from gpt-4o-mini (finish reason `length`)"
+        );
+    }
+
+    #[test]
+    fn reply_content_prefers_function_arguments() {
+        let choice = ChatCompletionChoice {
+            index: 0,
+            finish_reason: "stop".to_string(),
+            message: ChatCompletionMessage {
+                role: Role::Assistant,
+                content: None,
+                name: None,
+                function_call: Some(ChatCompletionFunctionCall {
+                    name: "classify".to_string(),
+                    arguments: "{\"is_roadmap\": true}".to_string(),
+                }),
+            },
+        };
+        assert_eq!(
+            reply_content(completion(vec![choice])).unwrap(),
+            "{\"is_roadmap\": true}"
+        );
+    }
+
+    fn openai_error(error_type: &str, code: Option<&str>) -> OpenAiError {
+        serde_json::from_value(serde_json::json!({
+            "message": "failed",
+            "type": error_type,
+            "param": null,
+            "code": code,
+        }))
+        .unwrap()
+    }
+
+    fn retry_policy(max_retries: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::ZERO,
+            deadline: Duration::from_secs(30),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_transient_retries_until_success() {
+        let mut calls = 0;
+        let result = retry_transient(&retry_policy(2), || {
+            calls += 1;
+            let result = if calls < 3 {
+                Err(openai_error("requests", Some("rate_limit_exceeded")).into())
+            } else {
+                Ok(calls)
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_gives_up_after_max_retries() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = retry_transient(&retry_policy(2), || {
+            calls += 1;
+            async { Err(openai_error("server_error", None).into()) }
+        })
+        .await;
+        assert_eq!(calls, 3);
+        let error = result.unwrap_err();
+        assert_eq!(error.downcast_ref::<RetriesExhausted>().unwrap().retries, 2);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_does_not_retry_client_errors() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = retry_transient(&retry_policy(3), || {
+            calls += 1;
+            async { Err(openai_error("invalid_request_error", Some("invalid_api_key")).into()) }
+        })
+        .await;
+        assert_eq!(calls, 1);
+        assert!(result
+            .unwrap_err()
+            .downcast_ref::<RetriesExhausted>()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn retry_transient_stops_at_deadline() {
+        let mut calls = 0;
+        let retry_policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_secs(60),
+            deadline: Duration::from_secs(30),
+        };
+        let result: anyhow::Result<()> = retry_transient(&retry_policy, || {
+            calls += 1;
+            async { Err(openai_error("server_error", None).into()) }
+        })
+        .await;
+        assert_eq!(calls, 1);
+        assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<RetriesExhausted>()
+                .unwrap()
+                .retries,
+            0
+        );
+    }
+
+    #[test]
+    fn key_failures_are_unauthenticated() {
+        for error in [
+            openai_error("invalid_request_error", Some("invalid_api_key")),
+            openai_error("authentication_error", None),
+        ] {
+            assert!(is_auth_error(&error), "{error:?}");
+            let flagged = flag_auth_error(error.into());
+            assert!(matches!(
+                flagged.downcast_ref::<RoadmapError>(),
+                Some(RoadmapError::Unauthenticated)
+            ));
+        }
+        // Mentions the key, but the key is fine
+        let mut not_allowed = openai_error("invalid_request_error", Some("model_not_found"));
+        not_allowed.message = "Your API key doesn't have access to gpt-4o".to_string();
+        assert!(!is_auth_error(&not_allowed));
+        let rate_limited = openai_error("requests", Some("rate_limit_exceeded"));
+        assert!(!is_auth_error(&rate_limited));
+        assert!(flag_auth_error(rate_limited.into())
+            .downcast_ref::<OpenAiError>()
+            .is_some());
+    }
+
+    #[test]
+    fn retry_after_reads_openai_hint() {
+        let mut error = openai_error("requests", Some("rate_limit_exceeded"));
+        error.message = "Rate limit reached for gpt-4o-mini. Please try again in 1.5s.".to_string();
+        assert_eq!(retry_after(&error), Some(Duration::from_millis(1_500)));
+        error.message = "Please try again in 250ms.".to_string();
+        assert_eq!(retry_after(&error), Some(Duration::from_millis(250)));
+        error.message = "Rate limit reached.".to_string();
+        assert_eq!(retry_after(&error), None);
+    }
+
+    #[tokio::test]
+    async fn with_timeout_fires_on_slow_requests() {
+        let slow_request = std::future::pending::<Result<(), OpenAiError>>();
+        let error = with_timeout(Duration::from_millis(10), slow_request)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "OpenAI request timed out after 10ms");
+    }
+
+    #[tokio::test]
+    async fn with_timeout_passes_through_results() {
+        let request = async { Ok::<_, OpenAiError>(42) };
+        assert_eq!(
+            with_timeout(Duration::from_secs(1), request).await.unwrap(),
+            42
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_half_the_delay() {
+        let delay = Duration::from_millis(800);
+        for _ in 0..100 {
+            let jittered = with_jitter(delay);
+            assert!(jittered >= delay && jittered < delay + delay / 2);
+        }
+    }
+
+    #[test]
+    fn reply_content_returns_first_choice() {
+        let choice = ChatCompletionChoice {
+            index: 0,
+            finish_reason: "stop".to_string(),
+            message: ChatCompletionMessage {
+                role: Role::Assistant,
+                content: Some("hello".to_string()),
+                name: None,
+                function_call: None,
+            },
+        };
+        assert_eq!(reply_content(completion(vec![choice])).unwrap(), "hello");
     }
-    messages.push(user_message(message_buffer));
-    messages
 }